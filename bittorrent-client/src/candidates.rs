@@ -0,0 +1,118 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+/// The largest number of candidates kept by default. Peers learned beyond this are dropped.
+pub const DEFAULT_MAX_CANDIDATES: usize = 1000;
+
+/// Where a candidate peer was learned from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Manual,
+}
+
+/// Peers which may be connected to, in the order they were learned. Each address is only
+/// accepted once, so peers reported by several sources, or by the same source repeatedly, aren't
+/// tried again.
+#[derive(Debug, Clone)]
+pub struct CandidatePool {
+    candidates: VecDeque<(SocketAddr, PeerSource)>,
+    seen: HashSet<SocketAddr>,
+    max_candidates: usize,
+}
+
+impl CandidatePool {
+    pub fn new() -> Self {
+        Self::with_max_candidates(DEFAULT_MAX_CANDIDATES)
+    }
+
+    pub fn with_max_candidates(max_candidates: usize) -> Self {
+        Self {
+            candidates: VecDeque::new(),
+            seen: HashSet::new(),
+            max_candidates,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Adds `address` unless it has been seen before or the pool is full. Returns whether it
+    /// was added.
+    pub fn add(&mut self, address: SocketAddr, source: PeerSource) -> bool {
+        if self.candidates.len() >= self.max_candidates || !self.seen.insert(address) {
+            return false;
+        }
+        self.candidates.push_back((address, source));
+        true
+    }
+
+    /// Adds every address in `addresses`, returning the number added.
+    pub fn extend(
+        &mut self,
+        addresses: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        addresses
+            .into_iter()
+            .filter(|address| self.add(*address, source))
+            .count()
+    }
+
+    /// Takes the candidate which was learned first.
+    pub fn pop(&mut self) -> Option<(SocketAddr, PeerSource)> {
+        self.candidates.pop_front()
+    }
+
+    /// Allows `address` to be added again, e.g. once a connection to it has closed.
+    pub fn forget(&mut self, address: SocketAddr) {
+        if !self
+            .candidates
+            .iter()
+            .any(|(candidate, _)| *candidate == address)
+        {
+            self.seen.remove(&address);
+        }
+    }
+}
+
+impl Default for CandidatePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_test() {
+        let peer = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let mut pool = CandidatePool::with_max_candidates(3);
+        assert!(pool.add(peer(1), PeerSource::Tracker));
+        assert!(!pool.add(peer(1), PeerSource::Pex));
+        assert_eq!(
+            pool.extend(vec![peer(2), peer(1), peer(3), peer(4)], PeerSource::Pex),
+            2
+        );
+        assert_eq!(pool.len(), 3);
+
+        assert_eq!(pool.pop(), Some((peer(1), PeerSource::Tracker)));
+        assert!(!pool.add(peer(1), PeerSource::Dht));
+        pool.forget(peer(1));
+        assert!(pool.add(peer(1), PeerSource::Dht));
+        // Forgetting a peer which is still queued doesn't let it be queued twice
+        pool.forget(peer(2));
+        assert!(!pool.add(peer(2), PeerSource::Dht));
+    }
+}
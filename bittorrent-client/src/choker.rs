@@ -0,0 +1,418 @@
+use std::{
+    cmp::Reverse, collections::HashSet, fmt::Debug, net::SocketAddr, sync::Arc, time::Duration,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+/// How often the choker should be run.
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of rounds between rotations of the optimistic unchoke, i.e. every 30 seconds.
+pub const OPTIMISTIC_UNCHOKE_ROUNDS: u32 = 3;
+
+/// Peers connected for less than this are more likely to be unchoked optimistically, since
+/// they have nothing to reciprocate with yet.
+pub const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+
+/// How much more likely a new peer is to be unchoked optimistically.
+pub const NEW_PEER_WEIGHT: u32 = 3;
+
+/// The number of peers unchoked at once by default, including the optimistic unchoke.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// What the choker knows about a peer, measured since the previous round.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerStats {
+    address: SocketAddr,
+    interested: bool,
+    download_rate: u64,
+    upload_rate: u64,
+    progress: f64,
+    connected_for: Duration,
+}
+
+impl PeerStats {
+    /// `interested`: whether the peer is interested in us.
+    ///
+    /// `download_rate`: how fast the peer has been sending to us, in bytes per second.
+    ///
+    /// `upload_rate`: how fast we have been sending to the peer, in bytes per second.
+    ///
+    /// `progress`: the fraction of the pieces the peer has, between 0 and 1.
+    ///
+    /// `connected_for`: how long the peer has been connected.
+    pub fn new(
+        address: SocketAddr,
+        interested: bool,
+        download_rate: u64,
+        upload_rate: u64,
+        progress: f64,
+        connected_for: Duration,
+    ) -> Self {
+        Self {
+            address,
+            interested,
+            download_rate,
+            upload_rate,
+            progress,
+            connected_for,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn interested(&self) -> bool {
+        self.interested
+    }
+
+    pub fn download_rate(&self) -> u64 {
+        self.download_rate
+    }
+
+    pub fn upload_rate(&self) -> u64 {
+        self.upload_rate
+    }
+
+    pub fn progress(&self) -> f64 {
+        self.progress
+    }
+
+    pub fn connected_for(&self) -> Duration {
+        self.connected_for
+    }
+
+    /// The rate peers are ranked by: how fast they send to us, or when seeding, how fast we
+    /// can send to them.
+    pub fn rate(&self, seeding: bool) -> u64 {
+        if seeding {
+            self.upload_rate
+        } else {
+            self.download_rate
+        }
+    }
+}
+
+/// Decides which peers get the regular upload slots in each round of a [`Choker`].
+pub trait ChokeStrategy: Debug + Send + Sync {
+    /// Picks up to `slots` of the interested `peers` to unchoke, best first.
+    fn unchoke(&self, peers: &[&PeerStats], slots: usize, seeding: bool) -> Vec<SocketAddr>;
+}
+
+/// The standard tit-for-tat: every slot goes to the fastest peers.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct FixedSlots;
+
+impl ChokeStrategy for FixedSlots {
+    fn unchoke(&self, peers: &[&PeerStats], slots: usize, seeding: bool) -> Vec<SocketAddr> {
+        by_rate(peers, seeding)
+            .into_iter()
+            .take(slots)
+            .map(PeerStats::address)
+            .collect()
+    }
+}
+
+/// Unchokes the fastest peers, but only opens another slot while each peer so far is faster
+/// than a threshold which rises with every slot. Slow swarms then get fewer, faster slots.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RateBased {
+    step: u64,
+}
+
+impl RateBased {
+    /// `step`: the rate the first peer must beat to open a second slot, in bytes per second.
+    /// Each further slot needs another `step` on top.
+    pub fn new(step: u64) -> Self {
+        Self { step }
+    }
+}
+
+impl Default for RateBased {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl ChokeStrategy for RateBased {
+    fn unchoke(&self, peers: &[&PeerStats], slots: usize, seeding: bool) -> Vec<SocketAddr> {
+        let mut threshold = 0;
+        by_rate(peers, seeding)
+            .into_iter()
+            .take(slots)
+            .take_while(|peer| {
+                let open = peer.rate(seeding) >= threshold;
+                threshold += self.step;
+                open
+            })
+            .map(PeerStats::address)
+            .collect()
+    }
+}
+
+/// When seeding, prefers peers which have just started or have nearly finished over those in
+/// the middle, which can get most of what they're missing from each other. This keeps peers
+/// which leave as soon as they finish from taking every slot. While downloading, it's the
+/// same as [`FixedSlots`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct AntiLeech;
+
+impl ChokeStrategy for AntiLeech {
+    fn unchoke(&self, peers: &[&PeerStats], slots: usize, seeding: bool) -> Vec<SocketAddr> {
+        if !seeding {
+            return FixedSlots.unchoke(peers, slots, seeding);
+        }
+        // Scores run from 0 for peers halfway through to 1000 for new or finished ones
+        let score = |peer: &PeerStats| ((peer.progress - 0.5).abs() * 2000.0) as u64;
+        let mut peers = peers.to_vec();
+        peers.sort_by_key(|peer| Reverse((score(peer), peer.upload_rate)));
+        peers
+            .into_iter()
+            .take(slots)
+            .map(PeerStats::address)
+            .collect()
+    }
+}
+
+fn by_rate<'a>(peers: &[&'a PeerStats], seeding: bool) -> Vec<&'a PeerStats> {
+    let mut peers = peers.to_vec();
+    peers.sort_by_key(|peer| Reverse(peer.rate(seeding)));
+    peers
+}
+
+/// Decides which peers to upload to. Each round gives all but one of the upload slots to the
+/// peers chosen by a [`ChokeStrategy`], and the last to a random other peer. This optimistic
+/// unchoke moves to another peer every few rounds, to find faster peers and to give new ones
+/// something to reciprocate with.
+///
+/// Rounds are counted rather than timed, so the choker should be run every
+/// [`UNCHOKE_INTERVAL`]. Randomness comes from a single RNG, so that a seeded choker always
+/// chooses the same way.
+#[derive(Debug)]
+pub struct Choker<R = StdRng> {
+    strategy: Arc<dyn ChokeStrategy>,
+    upload_slots: usize,
+    optimistic: Option<SocketAddr>,
+    round: u32,
+    rng: R,
+}
+
+impl Choker {
+    /// `upload_slots`: the number of peers unchoked at once, including the optimistic unchoke.
+    pub fn new(strategy: Arc<dyn ChokeStrategy>, upload_slots: usize) -> Self {
+        Self::with_rng(strategy, upload_slots, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> Choker<R> {
+    /// Like [`Choker::new`], but makes every random choice using `rng`.
+    pub fn with_rng(strategy: Arc<dyn ChokeStrategy>, upload_slots: usize, rng: R) -> Self {
+        Self {
+            strategy,
+            upload_slots,
+            optimistic: None,
+            round: 0,
+            rng,
+        }
+    }
+
+    pub fn upload_slots(&self) -> usize {
+        self.upload_slots
+    }
+
+    /// The peer currently unchoked optimistically.
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// Runs a round over every connected peer, returning the peers to unchoke. Every other
+    /// peer should be choked. Peers which aren't interested are never unchoked.
+    pub fn run(&mut self, peers: &[PeerStats], seeding: bool) -> HashSet<SocketAddr> {
+        let interested = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .collect::<Vec<_>>();
+        let regular_slots = self.upload_slots.saturating_sub(1);
+        let mut unchoked = self
+            .strategy
+            .unchoke(&interested, regular_slots, seeding)
+            .into_iter()
+            .take(regular_slots)
+            .collect::<HashSet<_>>();
+
+        let candidates = interested
+            .into_iter()
+            .filter(|peer| !unchoked.contains(&peer.address))
+            .collect::<Vec<_>>();
+        let keep = !self.round.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS)
+            && candidates
+                .iter()
+                .any(|peer| Some(peer.address) == self.optimistic);
+        if !keep {
+            self.optimistic = candidates
+                .choose_weighted(&mut self.rng, |peer| {
+                    if peer.connected_for < NEW_PEER_AGE {
+                        NEW_PEER_WEIGHT
+                    } else {
+                        1
+                    }
+                })
+                .ok()
+                .map(|peer| peer.address);
+        }
+        self.round += 1;
+
+        if let Some(optimistic) = self.optimistic.filter(|_| self.upload_slots > 0) {
+            unchoked.insert(optimistic);
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    /// A long-connected, interested peer which sends at `download_rate` and receives at
+    /// `upload_rate`.
+    fn stats(i: u8, download_rate: u64, upload_rate: u64) -> PeerStats {
+        PeerStats::new(
+            peer(i),
+            true,
+            download_rate,
+            upload_rate,
+            0.5,
+            Duration::from_secs(600),
+        )
+    }
+
+    fn seeded(strategy: Arc<dyn ChokeStrategy>, slots: usize, seed: u64) -> Choker<StdRng> {
+        Choker::with_rng(strategy, slots, StdRng::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn tit_for_tat_test() {
+        let mut peers = (1..=8)
+            .map(|i| stats(i, i as u64 * 1000, (9 - i) as u64 * 1000))
+            .collect::<Vec<_>>();
+        peers[7].interested = false;
+        let mut choker = seeded(Arc::new(FixedSlots), 4, 1);
+
+        // The three fastest interested uploaders, plus one optimistic unchoke
+        let unchoked = choker.run(&peers, false);
+        assert_eq!(unchoked.len(), 4);
+        for i in [5, 6, 7] {
+            assert!(unchoked.contains(&peer(i)));
+        }
+        let optimistic = choker.optimistic().unwrap();
+        assert!(unchoked.contains(&optimistic));
+        assert!((1..=4).map(peer).any(|p| p == optimistic));
+        assert!(!unchoked.contains(&peer(8)));
+
+        // When seeding, peers are ranked by how fast they take data instead
+        let unchoked = choker.run(&peers, true);
+        for i in [1, 2, 3] {
+            assert!(unchoked.contains(&peer(i)));
+        }
+        assert!(choker.run(&peers, true).len() <= 4);
+    }
+
+    #[test]
+    fn optimistic_test() {
+        let peers = (1..=20).map(|i| stats(i, 0, 0)).collect::<Vec<_>>();
+        let mut choker = seeded(Arc::new(FixedSlots), 1, 3);
+        let optimistics = (0..12)
+            .map(|_| {
+                let unchoked = choker.run(&peers, false);
+                assert_eq!(unchoked.len(), 1);
+                choker.optimistic().unwrap()
+            })
+            .collect::<Vec<_>>();
+        // The optimistic unchoke only rotates every third round
+        for round in optimistics.chunks(3) {
+            assert!(round.iter().all(|&p| p == round[0]));
+        }
+        assert!(optimistics
+            .chunks(3)
+            .any(|round| round[0] != optimistics[0]));
+
+        // A peer which loses interest is replaced straight away
+        let current = choker.optimistic().unwrap();
+        let peers = peers
+            .into_iter()
+            .filter(|stats| stats.address() != current)
+            .collect::<Vec<_>>();
+        choker.run(&peers, false);
+        assert_ne!(choker.optimistic(), Some(current));
+
+        // No slots means nobody is unchoked
+        let mut choker = seeded(Arc::new(FixedSlots), 0, 1);
+        assert!(choker.run(&peers, false).is_empty());
+    }
+
+    #[test]
+    fn new_peer_weight_test() {
+        let mut peers = (1..=4).map(|i| stats(i, 0, 0)).collect::<Vec<_>>();
+        peers[0].connected_for = Duration::from_secs(5);
+        let new_picks = (0..400)
+            .filter(|&seed| {
+                let mut choker = seeded(Arc::new(FixedSlots), 1, seed);
+                choker.run(&peers, false);
+                choker.optimistic() == Some(peer(1))
+            })
+            .count();
+        // The new peer is expected to be picked half of the time, rather than a quarter
+        assert!(new_picks > 150 && new_picks < 250, "{}", new_picks);
+    }
+
+    #[test]
+    fn rate_based_test() {
+        let peers = [
+            stats(1, 5000, 0),
+            stats(2, 1500, 0),
+            stats(3, 1800, 0),
+            stats(4, 100, 0),
+        ];
+        let interested = peers.iter().collect::<Vec<_>>();
+        // The third slot would need 2048 bytes per second
+        assert_eq!(
+            RateBased::new(1024).unchoke(&interested, 10, false),
+            vec![peer(1), peer(3)]
+        );
+        assert_eq!(
+            RateBased::new(1024).unchoke(&interested, 1, false),
+            vec![peer(1)]
+        );
+        assert_eq!(RateBased::new(0).unchoke(&interested, 10, false).len(), 4);
+    }
+
+    #[test]
+    fn anti_leech_test() {
+        let progress = [0.5, 0.02, 0.6, 0.97, 0.3];
+        let peers = progress
+            .iter()
+            .enumerate()
+            .map(|(i, &progress)| {
+                let mut stats = stats(i as u8 + 1, 0, 1000);
+                stats.progress = progress;
+                stats
+            })
+            .collect::<Vec<_>>();
+        let interested = peers.iter().collect::<Vec<_>>();
+        assert_eq!(
+            AntiLeech.unchoke(&interested, 3, true),
+            vec![peer(2), peer(4), peer(5)]
+        );
+
+        // Downloading falls back to tit-for-tat
+        let mut peers = peers;
+        peers[2].download_rate = 9000;
+        let interested = peers.iter().collect::<Vec<_>>();
+        assert_eq!(AntiLeech.unchoke(&interested, 1, false), vec![peer(3)]);
+    }
+}
@@ -1 +1,11 @@
+pub mod candidates;
+pub mod choker;
 pub mod peer_connection;
+pub mod pex;
+pub mod piece_picker;
+pub mod recheck;
+pub mod storage;
+pub mod torrent;
+
+#[cfg(test)]
+mod test_util;
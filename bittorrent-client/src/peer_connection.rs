@@ -1,44 +1,560 @@
+use std::collections::HashSet;
+
+use futures_util::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
+use tokio_util::codec::Framed;
 
 // TODO: Make a client error type
-use bittorrent_proto::{error::*, Peer};
+use bittorrent_proto::{
+    error::*,
+    peer::{ExtensionRegistry, Handshake, Message, MessageCodec, ReservedBits},
+    InfoHash, Peer,
+};
 
-pub struct PeerConnection {
+pub struct PeerConnection<S = TcpStream> {
     peer: Peer,
-    stream: BufStream<TcpStream>,
+    stream: Framed<S, MessageCodec>,
+    local_reserved: Option<ReservedBits>,
+    remote_reserved: Option<ReservedBits>,
+    extensions: Option<ExtensionRegistry>,
+    pending_requests: HashSet<(u32, u32, u32)>,
 }
 
-impl PeerConnection {
+impl PeerConnection<TcpStream> {
     // TODO: Peer protocol over TCP is rarely used nowadays
     pub async fn new(peer: Peer) -> Result<Self> {
-        let stream = BufStream::new(TcpStream::connect(peer.address()).await?);
-        Ok(Self { peer, stream })
+        let stream = TcpStream::connect(peer.address()).await?;
+        Ok(Self::from_stream(peer, stream))
+    }
+}
+
+impl<S> PeerConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wraps an already established stream to `peer`, using the default `MessageCodec`.
+    pub fn from_stream(peer: Peer, stream: S) -> Self {
+        Self::with_codec(peer, stream, MessageCodec::new())
+    }
+
+    pub fn with_codec(peer: Peer, stream: S, codec: MessageCodec) -> Self {
+        Self {
+            peer,
+            stream: Framed::new(stream, codec),
+            local_reserved: None,
+            remote_reserved: None,
+            extensions: None,
+            pending_requests: HashSet::new(),
+        }
+    }
+
+    /// Uses the extension protocol described in BEP 10 with the extensions in `registry`, if
+    /// both handshakes advertise support for it. The extension handshake is then sent as soon
+    /// as both handshakes have been exchanged, and incoming extended messages are routed to the
+    /// registered handlers instead of being returned by [`PeerConnection::recv`].
+    pub fn with_extensions(mut self, registry: ExtensionRegistry) -> Self {
+        self.extensions = Some(registry);
+        self
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// The extensions in use on this connection, or `None` if the extension protocol hasn't
+    /// been negotiated.
+    pub fn extensions(&self) -> Option<&ExtensionRegistry> {
+        self.extensions
+            .as_ref()
+            .filter(|_| self.extension_protocol())
+    }
+
+    pub fn extensions_mut(&mut self) -> Option<&mut ExtensionRegistry> {
+        if self.extension_protocol() {
+            self.extensions.as_mut()
+        } else {
+            None
+        }
+    }
+
+    /// Whether both handshakes have been exchanged and both advertise the extension protocol.
+    pub fn extension_protocol(&self) -> bool {
+        self.negotiated()
+            .is_some_and(|reserved| reserved.extension_protocol())
+    }
+
+    /// Whether both handshakes have been exchanged and both advertise the fast extension
+    /// described in BEP 6.
+    pub fn fast_extension(&self) -> bool {
+        self.negotiated()
+            .is_some_and(|reserved| reserved.fast_extension())
+    }
+
+    /// The `(index, begin, length)` of every request sent which hasn't been answered yet.
+    ///
+    /// With the fast extension, every request is answered by either a piece or a rejection, so
+    /// requests are only forgotten when one of those arrives. Without it, a choke implicitly
+    /// rejects every pending request.
+    pub fn pending_requests(&self) -> impl Iterator<Item = &(u32, u32, u32)> {
+        self.pending_requests.iter()
     }
 
-    pub async fn send_handshake(&mut self, info_hash: sha1::Digest, peer_id: &[u8]) -> Result<()> {
-        self.stream.write_u8(19).await?;
-        self.stream.write_all(b"BitTorrent protocol").await?;
-        self.stream.write_u64(0b00000000).await?;
-        self.stream.write_all(&info_hash.bytes()).await?;
-        self.stream.write_all(peer_id).await?;
-        self.stream.flush().await?;
+    fn negotiated(&self) -> Option<ReservedBits> {
+        Some(self.local_reserved?.intersection(&self.remote_reserved?))
+    }
+
+    /// Sends `handshake`, which advertises the extensions supported by this side of the
+    /// connection in its reserved bits.
+    pub async fn send_handshake(&mut self, handshake: &Handshake) -> Result<()> {
+        // The handshake isn't length-prefixed, so it bypasses the codec
+        let stream = self.stream.get_mut();
+        stream.write_all(&Vec::from(handshake)).await?;
+        stream.flush().await?;
         log::debug!("Handshake sent");
+        self.local_reserved = Some(handshake.reserved());
+        self.negotiate_extensions().await
+    }
+
+    /// Receives the remote handshake, failing if the peer speaks a different protocol or is
+    /// serving a torrent other than the one identified by `info_hash`.
+    pub async fn recv_handshake(&mut self, info_hash: InfoHash) -> Result<Handshake> {
+        // Nothing has been read through the codec yet, so the handshake is still unbuffered
+        let stream = self.stream.get_mut();
+        let mut buf = vec![stream.read_u8().await?];
+        // Read the rest based on the advertised protocol length, so that decoding can report
+        // an unexpected protocol rather than garbage
+        buf.resize(buf[0] as usize + 49, 0);
+        stream.read_exact(&mut buf[1..]).await?;
+        let handshake = Handshake::decode(&buf)?;
+        log::debug!(
+            "Received handshake with reserved bytes {:?}",
+            handshake.reserved().bytes()
+        );
+
+        if handshake.info_hash() != info_hash {
+            return Err(Error::InfoHashMismatch(info_hash, handshake.info_hash()));
+        }
+        self.remote_reserved = Some(handshake.reserved());
+        self.negotiate_extensions().await?;
+        Ok(handshake)
+    }
+
+    /// Sends `message`, failing if it's part of the fast extension and that hasn't been
+    /// negotiated.
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        if message
+            .message_type()
+            .is_some_and(|message_type| message_type.is_fast_extension())
+            && !self.fast_extension()
+        {
+            return Err(Error::UnsupportedExtension(String::from("fast")));
+        }
+
+        match message {
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                self.pending_requests.insert((index, begin, length));
+            }
+            // Without the fast extension, a cancelled request gets no answer
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } if !self.fast_extension() => {
+                self.pending_requests.remove(&(index, begin, length));
+            }
+            _ => {}
+        }
+        self.stream.send(message).await
+    }
+
+    /// Sends `payload` as a message of the extension `name`, which the peer must have listed in
+    /// its extension handshake.
+    pub async fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> Result<()> {
+        let message = self
+            .extensions()
+            .ok_or_else(|| Error::UnsupportedExtension(name.to_string()))?
+            .message(name, payload)?;
+        self.send(message).await
+    }
+
+    /// Waits for the next message from the peer. Returns `Ok(None)` once the peer has closed
+    /// the connection.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        loop {
+            let message = match self.stream.next().await.transpose()? {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            if let (Message::Extended { id, payload }, Some(registry)) =
+                (&message, self.extensions_mut())
+            {
+                match registry.handle(*id, payload) {
+                    Ok(Some(reply)) => self.send(reply).await?,
+                    Ok(None) => {}
+                    Err(Error::IOError(e)) => return Err(Error::IOError(e)),
+                    // Unknown or malformed extended messages are ignored, as BEP 10 asks
+                    Err(e) => log::debug!("Ignoring extended message with id {}: {}", id, e),
+                }
+                continue;
+            }
+            self.track_received(&message)?;
+            return Ok(Some(message));
+        }
+    }
+
+    /// Updates the pending requests according to a message from the peer.
+    fn track_received(&mut self, message: &Message) -> Result<()> {
+        if let Some(message_type) = message.message_type() {
+            if message_type.is_fast_extension() && !self.fast_extension() {
+                return Err(Error::UnexpectedMessage(format!(
+                    "{:?} without the fast extension",
+                    message_type
+                )));
+            }
+        }
+
+        match message {
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                self.pending_requests
+                    .remove(&(*index, *begin, block.len() as u32));
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                self.pending_requests.remove(&(*index, *begin, *length));
+            }
+            Message::Choke if !self.fast_extension() => self.pending_requests.clear(),
+            _ => {}
+        }
         Ok(())
     }
 
-    pub async fn recv_handshake(&mut self) -> Result<()> {
-        let proto_str_len = self.stream.read_u8().await? as usize;
-        let mut buf = vec![0; proto_str_len];
-        self.stream.read_exact(&mut buf).await?;
-        log::debug!("Protocol is '{}'", String::from_utf8_lossy(&buf));
-        let reserved_bytes = self.stream.read_u64().await?;
-        log::debug!("Reserved bytes: {:#b}", reserved_bytes);
-        buf = vec![0; 20];
-        self.stream.read_exact(&mut buf).await?;
-        log::debug!("Info hash: {:?}", buf);
+    /// Sends the extension handshake once both sides have advertised the extension protocol.
+    async fn negotiate_extensions(&mut self) -> Result<()> {
+        if let Some(registry) = self.extensions() {
+            let handshake = registry.handshake().to_message();
+            log::debug!("Sending extension handshake");
+            self.send(handshake).await?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use bittorrent_proto::peer::{
+        ExtensionHandler, ExtensionHandshake, MetadataMessage, MetadataServer, UT_METADATA,
+    };
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    fn connections() -> (PeerConnection<DuplexStream>, PeerConnection<DuplexStream>) {
+        let (a, b) = duplex(256);
+        (
+            PeerConnection::from_stream(Peer::new(None, "127.0.0.1:6881".parse().unwrap()), a),
+            PeerConnection::from_stream(Peer::new(None, "127.0.0.1:6882".parse().unwrap()), b),
+        )
+    }
+
+    #[tokio::test]
+    async fn handshake_test() {
+        let (mut a, mut b) = connections();
+        let local = Handshake::new(
+            ReservedBits::default().with_extension_protocol(true),
+            InfoHash::new([1; 20]),
+            *b"-BT0000-aaaaaaaaaaaa",
+        );
+
+        let sender = async {
+            a.send_handshake(&local).await.unwrap();
+            a.send(Message::Interested).await.unwrap();
+        };
+        let receiver = async {
+            let remote = b.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+            assert_eq!(remote, local);
+            assert!(remote.reserved().extension_protocol());
+            assert_eq!(b.recv().await.unwrap(), Some(Message::Interested));
+        };
+        tokio::join!(sender, receiver);
+    }
+
+    #[tokio::test]
+    async fn handshake_mismatch_test() {
+        let (mut a, mut b) = connections();
+        let local = Handshake::new(ReservedBits::default(), InfoHash::new([1; 20]), [2; 20]);
+
+        a.send_handshake(&local).await.unwrap();
+        assert!(matches!(
+            b.recv_handshake(InfoHash::new([3; 20])).await,
+            Err(Error::InfoHashMismatch(_, _))
+        ));
+
+        let mut bytes = Vec::from(&local);
+        bytes[1..20].copy_from_slice(b"Not the right proto");
+        a.stream.get_mut().write_all(&bytes).await.unwrap();
+        assert!(matches!(
+            b.recv_handshake(InfoHash::new([1; 20])).await,
+            Err(Error::InvalidProtocol(protocol)) if protocol == "Not the right proto"
+        ));
+    }
+
+    #[tokio::test]
+    async fn message_exchange_test() {
+        let (mut a, mut b) = connections();
+
+        let sender = async move {
+            a.send(Message::Bitfield(vec![0b1000_0000])).await.unwrap();
+            a.send(Message::KeepAlive).await.unwrap();
+            a.send(Message::Piece {
+                index: 0,
+                begin: 0,
+                block: vec![1; 100],
+            })
+            .await
+            .unwrap();
+        };
+        let receiver = async {
+            assert_eq!(
+                b.recv().await.unwrap(),
+                Some(Message::Bitfield(vec![0b1000_0000]))
+            );
+            assert_eq!(b.recv().await.unwrap(), Some(Message::KeepAlive));
+            assert_eq!(
+                b.recv().await.unwrap(),
+                Some(Message::Piece {
+                    index: 0,
+                    begin: 0,
+                    block: vec![1; 100],
+                })
+            );
+        };
+        tokio::join!(sender, receiver);
+
+        // The sender has been dropped, closing its end of the stream
+        assert_eq!(b.recv().await.unwrap(), None);
+    }
+
+    /// Passes every message it receives on to a channel.
+    struct Collect(mpsc::Sender<Vec<u8>>);
+
+    impl ExtensionHandler for Collect {
+        fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.0.send(payload.to_vec()).unwrap();
+            Ok(None)
+        }
+    }
+
+    fn handshake(extension_protocol: bool) -> Handshake {
+        Handshake::new(
+            ReservedBits::default().with_extension_protocol(extension_protocol),
+            InfoHash::new([1; 20]),
+            [2; 20],
+        )
+    }
+
+    #[tokio::test]
+    async fn extension_test() {
+        let (a, b) = connections();
+        let server = MetadataServer::new(b"d4:name4:teste".to_vec());
+        let mut a = a.with_extensions(ExtensionRegistry::from(server));
+        let (sender, received) = mpsc::channel();
+        let mut registry =
+            ExtensionRegistry::with_handshake(ExtensionHandshake::new().with_client("b".into()));
+        registry.register(UT_METADATA, Box::new(Collect(sender)));
+        let mut b = b.with_extensions(registry);
+
+        let a_side = async {
+            a.send_handshake(&handshake(true)).await.unwrap();
+            a.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+            a.send(Message::Interested).await.unwrap();
+            // The extension handshake and metadata request are handled along the way
+            assert_eq!(a.recv().await.unwrap(), Some(Message::NotInterested));
+            let remote = a.extensions().unwrap().remote_handshake().unwrap();
+            assert_eq!(remote.client(), Some("b"));
+            a.send(Message::KeepAlive).await.unwrap();
+        };
+        let b_side = async {
+            b.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+            b.send_handshake(&handshake(true)).await.unwrap();
+            assert!(b.extension_protocol());
+            assert_eq!(b.recv().await.unwrap(), Some(Message::Interested));
+            let remote = b.extensions().unwrap().remote_handshake().unwrap();
+            assert_eq!(remote.metadata_size(), Some(14));
+
+            let request = MetadataMessage::Request { piece: 0 }.encode();
+            b.send_extended(UT_METADATA, request).await.unwrap();
+            b.send(Message::NotInterested).await.unwrap();
+            assert_eq!(b.recv().await.unwrap(), Some(Message::KeepAlive));
+        };
+        tokio::join!(a_side, b_side);
+
+        let data = MetadataMessage::decode(&received.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            data,
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 14,
+                data: b"d4:name4:teste".to_vec()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn extension_ignored_test() {
+        let (a, mut b) = connections();
+        let (sender, received) = mpsc::channel();
+        let mut registry = ExtensionRegistry::new();
+        registry.register(UT_METADATA, Box::new(Collect(sender)));
+        let mut a = a.with_extensions(registry);
+
+        let a_side = async {
+            a.send_handshake(&handshake(true)).await.unwrap();
+            a.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+            assert_eq!(a.recv().await.unwrap(), Some(Message::Choke));
+        };
+        let b_side = async {
+            b.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+            b.send_handshake(&handshake(true)).await.unwrap();
+            // Without a registry, the extension handshake is passed through
+            assert!(matches!(
+                b.recv().await.unwrap(),
+                Some(Message::Extended { id: 0, .. })
+            ));
+            // An id which was never assigned, then a malformed extension handshake
+            for (id, payload) in [(42, b"de".to_vec()), (0, b"garbage".to_vec())] {
+                b.send(Message::Extended { id, payload }).await.unwrap();
+            }
+            b.send(Message::Choke).await.unwrap();
+        };
+        tokio::join!(a_side, b_side);
+        assert!(received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn extension_unsupported_test() {
+        let (a, mut b) = connections();
+        let mut a = a.with_extensions(ExtensionRegistry::new());
+
+        // Only one side sets the reserved bit, so no extension handshake is sent
+        a.send_handshake(&handshake(true)).await.unwrap();
+        b.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+        b.send_handshake(&handshake(false)).await.unwrap();
+        a.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+        assert!(!a.extension_protocol());
+        assert!(a.extensions().is_none());
+        assert!(matches!(
+            a.send_extended(UT_METADATA, Vec::new()).await,
+            Err(Error::UnsupportedExtension(_))
+        ));
+
+        // Extended messages are passed through when there's no registry to route them to
+        let message = Message::Extended {
+            id: 3,
+            payload: b"de".to_vec(),
+        };
+        b.send(message.clone()).await.unwrap();
+        b.send(Message::Choke).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), Some(message));
+        assert_eq!(a.recv().await.unwrap(), Some(Message::Choke));
+    }
+
+    /// Exchanges handshakes with the same reserved bits on both sides.
+    async fn exchange_handshakes(
+        a: &mut PeerConnection<DuplexStream>,
+        b: &mut PeerConnection<DuplexStream>,
+        reserved: ReservedBits,
+    ) {
+        let handshake = Handshake::new(reserved, InfoHash::new([1; 20]), [2; 20]);
+        a.send_handshake(&handshake).await.unwrap();
+        b.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+        b.send_handshake(&handshake).await.unwrap();
+        a.recv_handshake(InfoHash::new([1; 20])).await.unwrap();
+    }
+
+    fn request(begin: u32) -> Message {
+        Message::Request {
+            index: 0,
+            begin,
+            length: 64,
+        }
+    }
+
+    #[tokio::test]
+    async fn fast_extension_test() {
+        let (mut a, mut b) = connections();
+        let reserved = ReservedBits::default().with_fast_extension(true);
+        exchange_handshakes(&mut a, &mut b, reserved).await;
+        assert!(a.fast_extension());
+
+        for begin in [0, 64, 128] {
+            a.send(request(begin)).await.unwrap();
+        }
+        b.send(Message::HaveAll).await.unwrap();
+        b.send(Message::Choke).await.unwrap();
+        b.send(Message::RejectRequest {
+            index: 0,
+            begin: 64,
+            length: 64,
+        })
+        .await
+        .unwrap();
+        b.send(Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; 64],
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(a.recv().await.unwrap(), Some(Message::HaveAll));
+        // A choke leaves requests pending until they're explicitly rejected
+        assert_eq!(a.recv().await.unwrap(), Some(Message::Choke));
+        assert_eq!(a.pending_requests().count(), 3);
+        a.recv().await.unwrap();
+        a.recv().await.unwrap();
+        assert_eq!(
+            a.pending_requests().collect::<Vec<_>>(),
+            vec![&(0, 128, 64)]
+        );
+    }
+
+    #[tokio::test]
+    async fn fast_extension_unsupported_test() {
+        let (mut a, mut b) = connections();
+        exchange_handshakes(&mut a, &mut b, ReservedBits::default()).await;
+        assert!(!a.fast_extension());
+
+        // Without the fast extension a choke drops every pending request
+        a.send(request(0)).await.unwrap();
+        a.send(request(64)).await.unwrap();
+        b.send(Message::Choke).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), Some(Message::Choke));
+        assert_eq!(a.pending_requests().count(), 0);
+
+        assert!(matches!(
+            a.send(Message::HaveNone).await,
+            Err(Error::UnsupportedExtension(_))
+        ));
+        b.stream
+            .send(Message::AllowedFast { index: 1 })
+            .await
+            .unwrap();
+        assert!(matches!(a.recv().await, Err(Error::UnexpectedMessage(_))));
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex};
+
+use bittorrent_proto::{
+    error::*,
+    peer::{ExtensionHandler, ExtensionRegistry, PexMessage, PexState, MAX_PEX_PEERS, UT_PEX},
+    Info,
+};
+
+use crate::candidates::{CandidatePool, PeerSource};
+
+/// Feeds the peers added in incoming peer exchange messages into a candidate pool.
+#[derive(Debug, Clone)]
+pub struct PexReceiver {
+    pool: Arc<Mutex<CandidatePool>>,
+}
+
+impl PexReceiver {
+    pub fn new(pool: Arc<Mutex<CandidatePool>>) -> Self {
+        Self { pool }
+    }
+}
+
+impl ExtensionHandler for PexReceiver {
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let message = PexMessage::decode(payload)?;
+        // Anything beyond the recommended limit is ignored rather than trusted
+        let added = self
+            .pool
+            .lock()
+            .expect("candidate pool lock poisoned")
+            .extend(
+                message
+                    .added()
+                    .iter()
+                    .take(MAX_PEX_PEERS)
+                    .map(|(address, _)| *address),
+                PeerSource::Pex,
+            );
+        log::debug!(
+            "Peer exchange added {} of {} peers",
+            added,
+            message.added().len()
+        );
+        Ok(None)
+    }
+}
+
+/// Enables peer exchange on a connection by registering a [`PexReceiver`] in `registry`.
+/// Returns the state to produce outgoing messages with, or `None` if the torrent is private, in
+/// which case peers may only come from its trackers and nothing is registered.
+pub fn enable_pex(
+    registry: &mut ExtensionRegistry,
+    info: &Info,
+    pool: Arc<Mutex<CandidatePool>>,
+) -> Option<PexState> {
+    if info.private() == Some(true) {
+        return None;
+    }
+    registry.register(UT_PEX, Box::new(PexReceiver::new(pool)));
+    Some(PexState::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+    use bittorrent_proto::peer::{Message, PexFlags};
+
+    use super::*;
+
+    fn info(private: Option<bool>) -> Info {
+        Info::new(
+            String::from("test"),
+            16384,
+            vec![0; 20],
+            Some(1),
+            None,
+            private,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn enable_pex_test() {
+        let pool = Arc::new(Mutex::new(CandidatePool::new()));
+        let mut registry = ExtensionRegistry::new();
+        assert!(enable_pex(&mut registry, &info(Some(true)), pool.clone()).is_none());
+        assert_eq!(registry.local_id(UT_PEX), None);
+
+        let mut state = enable_pex(&mut registry, &info(Some(false)), pool.clone()).unwrap();
+        let id = registry.local_id(UT_PEX).unwrap();
+        assert_eq!(registry.handshake().extension_id(UT_PEX), Some(id));
+
+        // A message produced on one side fills the pool on the other
+        let connected = (1..=3)
+            .map(|port| (SocketAddr::from(([10, 0, 0, 1], port)), PexFlags::default()))
+            .collect::<HashMap<_, _>>();
+        let message = state.next_message(&connected, Instant::now()).unwrap();
+        assert_eq!(
+            registry.handle(id, &message.encode()).unwrap(),
+            None::<Message>
+        );
+        assert_eq!(pool.lock().unwrap().len(), 3);
+        assert!(registry.handle(id, b"garbage").is_err());
+    }
+}
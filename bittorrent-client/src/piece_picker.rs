@@ -0,0 +1,546 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
+
+use bittorrent_proto::{error::*, peer::Bitfield, Info};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+/// The length of the blocks pieces are requested in. The last block of a piece may be shorter.
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// The number of pieces picked at random by default, before switching to rarest first.
+pub const DEFAULT_RANDOM_FIRST_PIECES: u32 = 4;
+
+/// How urgently a piece should be downloaded. Pieces are picked in order of priority before
+/// rarity.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default)]
+pub enum Priority {
+    /// The piece isn't wanted, and is never picked.
+    Skip,
+    #[default]
+    Normal,
+    High,
+}
+
+/// The outcome of receiving a block, from [`PiecePicker::block_received`].
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct BlockReceived {
+    cancels: Vec<SocketAddr>,
+    piece_complete: bool,
+}
+
+impl BlockReceived {
+    /// Other peers the block was also requested from, which should be sent a cancel.
+    pub fn cancels(&self) -> &[SocketAddr] {
+        &self.cancels
+    }
+
+    /// Whether every block of the piece has now been received, so that it can be verified.
+    pub fn piece_complete(&self) -> bool {
+        self.piece_complete
+    }
+}
+
+#[derive(Debug, Clone)]
+enum BlockState {
+    Missing,
+    /// Requested from each of these peers, more than one only in endgame mode.
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+/// Decides which blocks to request from which peers.
+///
+/// Pieces which have been started are finished first. The first few pieces are then picked at
+/// random, so that there's soon something to share, and later ones rarest first, with ties
+/// broken at random. Once every missing block has been requested, endgame mode requests blocks
+/// from several peers at once, and the duplicates are cancelled as blocks arrive.
+///
+/// The picker only keeps track of state, and doesn't send anything itself. Randomness comes
+/// from a single RNG, so that a seeded picker always picks the same way.
+#[derive(Debug, Clone)]
+pub struct PiecePicker<R = StdRng> {
+    have: Bitfield,
+    piece_sizes: Vec<u32>,
+    priorities: Vec<Priority>,
+    /// The number of connected peers which have each piece.
+    availability: Vec<u32>,
+    peers: HashMap<SocketAddr, Bitfield>,
+    /// The state of each block of the pieces which have been started but not verified.
+    downloading: BTreeMap<u32, Vec<BlockState>>,
+    random_first_pieces: u32,
+    rng: R,
+}
+
+impl PiecePicker {
+    /// `info`: the torrent whose pieces are picked.
+    ///
+    /// `have`: the pieces which are already complete, e.g. from a recheck.
+    pub fn new(info: &Info, have: Bitfield) -> Result<Self> {
+        Self::with_rng(info, have, StdRng::from_entropy())
+    }
+}
+
+impl<R: Rng> PiecePicker<R> {
+    /// Like [`PiecePicker::new`], but makes every random choice using `rng`.
+    pub fn with_rng(info: &Info, have: Bitfield, rng: R) -> Result<Self> {
+        let piece_count = info.piece_count();
+        check_len(&have, piece_count)?;
+        Ok(Self {
+            have,
+            piece_sizes: (0..piece_count)
+                .map(|index| info.piece_size(index).unwrap_or(0) as u32)
+                .collect(),
+            priorities: vec![Priority::Normal; piece_count as usize],
+            availability: vec![0; piece_count as usize],
+            peers: HashMap::new(),
+            downloading: BTreeMap::new(),
+            random_first_pieces: DEFAULT_RANDOM_FIRST_PIECES,
+            rng,
+        })
+    }
+
+    /// Picks pieces at random until `count` pieces are complete.
+    pub fn with_random_first_pieces(mut self, count: u32) -> Self {
+        self.random_first_pieces = count;
+        self
+    }
+
+    /// The pieces which have been verified.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// The number of connected peers which have the piece at `index`.
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn priority(&self, index: u32) -> Priority {
+        self.priorities
+            .get(index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Changes the priority of the piece at `index`. Pieces beyond the end are ignored.
+    pub fn set_priority(&mut self, index: u32, priority: Priority) {
+        if let Some(current) = self.priorities.get_mut(index as usize) {
+            *current = priority;
+        }
+    }
+
+    /// Whether every wanted piece has been verified.
+    pub fn is_complete(&self) -> bool {
+        (0..self.have.len()).all(|index| !self.is_wanted(index))
+    }
+
+    /// Whether every block of the wanted pieces has been either received or requested, so that
+    /// blocks are requested from several peers at once.
+    pub fn is_endgame(&self) -> bool {
+        (0..self.have.len())
+            .filter(|&index| self.is_wanted(index))
+            .all(|index| {
+                self.downloading.get(&index).is_some_and(|blocks| {
+                    blocks
+                        .iter()
+                        .all(|block| !matches!(block, BlockState::Missing))
+                })
+            })
+    }
+
+    /// Whether `peer` has any piece which is wanted.
+    pub fn is_interesting(&self, peer: SocketAddr) -> bool {
+        self.peers
+            .get(&peer)
+            .is_some_and(|pieces| pieces.iter().any(|index| self.is_wanted(index)))
+    }
+
+    /// Records the pieces `peer` has from its `bitfield`, replacing anything known before.
+    /// `have all` and `have none` can be recorded with [`Bitfield::full`] and [`Bitfield::new`].
+    pub fn peer_bitfield(&mut self, peer: SocketAddr, bitfield: Bitfield) -> Result<()> {
+        check_len(&bitfield, self.have.len())?;
+        if let Some(old) = self.peers.remove(&peer) {
+            self.remove_availability(&old);
+        }
+        for index in bitfield.iter() {
+            self.availability[index as usize] += 1;
+        }
+        self.peers.insert(peer, bitfield);
+        Ok(())
+    }
+
+    /// Records that `peer` has the piece at `index`, from a `have` message. Pieces beyond the
+    /// end are ignored.
+    pub fn peer_has(&mut self, peer: SocketAddr, index: u32) {
+        let len = self.have.len();
+        let pieces = self.peers.entry(peer).or_insert_with(|| Bitfield::new(len));
+        if index < len && !pieces.has(index) {
+            pieces.set(index, true);
+            self.availability[index as usize] += 1;
+        }
+    }
+
+    /// The pieces `peer` is known to have, or `None` if nothing is known about it.
+    pub fn peer_pieces(&self, peer: SocketAddr) -> Option<&Bitfield> {
+        self.peers.get(&peer)
+    }
+
+    /// Forgets `peer` once it has disconnected, releasing its requests.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(pieces) = self.peers.remove(&peer) {
+            self.remove_availability(&pieces);
+        }
+        self.release_all(peer);
+    }
+
+    /// Picks up to `max` blocks to request from `peer`, as `(index, begin, length)`, and
+    /// records them as requested.
+    pub fn pick(&mut self, peer: SocketAddr, max: usize) -> Vec<(u32, u32, u32)> {
+        let pieces = match self.peers.get(&peer) {
+            Some(pieces) => pieces.clone(),
+            None => return Vec::new(),
+        };
+        let mut picked = Vec::new();
+
+        // Finish started pieces first, so that they can be verified and shared sooner
+        let mut started = self
+            .downloading
+            .keys()
+            .copied()
+            .filter(|&index| pieces.has(index) && self.is_wanted(index))
+            .collect::<Vec<_>>();
+        started.sort_by_key(|&index| Reverse(self.priority(index)));
+        for index in started {
+            self.pick_missing(peer, index, max, &mut picked);
+        }
+
+        if picked.len() < max {
+            for index in self.new_pieces(&pieces) {
+                if picked.len() >= max {
+                    break;
+                }
+                let blocks = self.piece_sizes[index as usize].div_ceil(BLOCK_SIZE);
+                self.downloading
+                    .insert(index, vec![BlockState::Missing; blocks as usize]);
+                self.pick_missing(peer, index, max, &mut picked);
+            }
+        }
+
+        if picked.len() < max && self.is_endgame() {
+            self.pick_duplicates(peer, &pieces, max, &mut picked);
+        }
+        picked
+    }
+
+    /// Records that the block at `begin` within the piece at `index` has arrived from `peer`.
+    /// Blocks which weren't requested are still accepted, but duplicates are ignored.
+    pub fn block_received(&mut self, peer: SocketAddr, index: u32, begin: u32) -> BlockReceived {
+        let blocks = match self.downloading.get_mut(&index) {
+            Some(blocks) => blocks,
+            None => return BlockReceived::default(),
+        };
+        let block = match blocks.get_mut((begin / BLOCK_SIZE) as usize) {
+            Some(block) if begin.is_multiple_of(BLOCK_SIZE) => block,
+            _ => return BlockReceived::default(),
+        };
+
+        let cancels = match std::mem::replace(block, BlockState::Received) {
+            BlockState::Requested(peers) => peers.into_iter().filter(|&p| p != peer).collect(),
+            BlockState::Missing => Vec::new(),
+            BlockState::Received => return BlockReceived::default(),
+        };
+        BlockReceived {
+            cancels,
+            piece_complete: blocks
+                .iter()
+                .all(|block| matches!(block, BlockState::Received)),
+        }
+    }
+
+    /// Records that the piece at `index` matched its hash.
+    pub fn piece_verified(&mut self, index: u32) {
+        self.downloading.remove(&index);
+        self.have.set(index, true);
+    }
+
+    /// Records that the piece at `index` didn't match its hash, so that it's downloaded again.
+    pub fn piece_failed(&mut self, index: u32) {
+        self.downloading.remove(&index);
+    }
+
+    /// Releases the request for a block from `peer`, e.g. when the peer rejects it, so that it
+    /// can be picked again.
+    pub fn release(&mut self, peer: SocketAddr, index: u32, begin: u32) {
+        if let Some(block) = self
+            .downloading
+            .get_mut(&index)
+            .and_then(|blocks| blocks.get_mut((begin / BLOCK_SIZE) as usize))
+        {
+            release_block(block, peer);
+        }
+    }
+
+    /// Releases every request to `peer`, e.g. when it chokes without the fast extension.
+    pub fn release_all(&mut self, peer: SocketAddr) {
+        for block in self.downloading.values_mut().flatten() {
+            release_block(block, peer);
+        }
+    }
+
+    fn is_wanted(&self, index: u32) -> bool {
+        !self.have.has(index) && self.priority(index) != Priority::Skip
+    }
+
+    fn remove_availability(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter() {
+            self.availability[index as usize] -= 1;
+        }
+    }
+
+    /// The wanted pieces in `pieces` which haven't been started, best first.
+    fn new_pieces(&mut self, pieces: &Bitfield) -> Vec<u32> {
+        let mut candidates = pieces
+            .iter()
+            .filter(|&index| self.is_wanted(index) && !self.downloading.contains_key(&index))
+            .collect::<Vec<_>>();
+        // Shuffling before a stable sort breaks ties at random
+        candidates.shuffle(&mut self.rng);
+        if self.have.count() < self.random_first_pieces {
+            candidates.sort_by_key(|&index| Reverse(self.priority(index)));
+        } else {
+            candidates
+                .sort_by_key(|&index| (Reverse(self.priority(index)), self.availability(index)));
+        }
+        candidates
+    }
+
+    fn pick_missing(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        max: usize,
+        picked: &mut Vec<(u32, u32, u32)>,
+    ) {
+        let size = self.piece_sizes[index as usize];
+        let blocks = self.downloading.get_mut(&index).expect("piece not started");
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if picked.len() >= max {
+                return;
+            }
+            if let BlockState::Missing = block {
+                *block = BlockState::Requested(vec![peer]);
+                picked.push(block_at(index, i as u32, size));
+            }
+        }
+    }
+
+    /// Requests blocks from `peer` which are already requested from other peers.
+    fn pick_duplicates(
+        &mut self,
+        peer: SocketAddr,
+        pieces: &Bitfield,
+        max: usize,
+        picked: &mut Vec<(u32, u32, u32)>,
+    ) {
+        for (&index, blocks) in self.downloading.iter_mut() {
+            if !pieces.has(index) || self.priorities[index as usize] == Priority::Skip {
+                continue;
+            }
+            let size = self.piece_sizes[index as usize];
+            for (i, block) in blocks.iter_mut().enumerate() {
+                if picked.len() >= max {
+                    return;
+                }
+                if let BlockState::Requested(peers) = block {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                        picked.push(block_at(index, i as u32, size));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_len(bitfield: &Bitfield, piece_count: u32) -> Result<()> {
+    if bitfield.len() != piece_count {
+        return Err(Error::InvalidBitfield(format!(
+            "expected {} pieces, got {}",
+            piece_count,
+            bitfield.len()
+        )));
+    }
+    Ok(())
+}
+
+/// The `(index, begin, length)` of block `i` within a piece of `size` bytes.
+fn block_at(index: u32, i: u32, size: u32) -> (u32, u32, u32) {
+    let begin = i * BLOCK_SIZE;
+    (index, begin, BLOCK_SIZE.min(size - begin))
+}
+
+fn release_block(block: &mut BlockState, peer: SocketAddr) {
+    if let BlockState::Requested(peers) = block {
+        peers.retain(|&p| p != peer);
+        if peers.is_empty() {
+            *block = BlockState::Missing;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    /// A picker for `pieces` pieces of two blocks each, except the last which has one short
+    /// block.
+    fn picker(pieces: u32, seed: u64) -> PiecePicker<StdRng> {
+        let length = (pieces as u64 - 1) * 2 * BLOCK_SIZE as u64 + 100;
+        let info = Info::new(
+            String::from("file"),
+            2 * BLOCK_SIZE as u64,
+            vec![0; pieces as usize * 20],
+            Some(length),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        PiecePicker::with_rng(&info, Bitfield::new(pieces), StdRng::seed_from_u64(seed))
+            .unwrap()
+            .with_random_first_pieces(0)
+    }
+
+    fn bitfield(len: u32, pieces: &[u32]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &index in pieces {
+            bitfield.set(index, true);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn rarest_first_test() {
+        let mut picker = picker(4, 1);
+        picker.peer_bitfield(peer(1), Bitfield::full(4)).unwrap();
+        picker
+            .peer_bitfield(peer(2), bitfield(4, &[0, 1, 3]))
+            .unwrap();
+        picker.peer_has(peer(3), 0);
+        picker.peer_has(peer(3), 3);
+        picker.peer_has(peer(4), 0);
+        assert_eq!(picker.availability(0), 4);
+        assert_eq!(picker.availability(2), 1);
+
+        assert_eq!(
+            picker.pick(peer(1), 3),
+            vec![
+                (2, 0, BLOCK_SIZE),
+                (2, BLOCK_SIZE, BLOCK_SIZE),
+                (1, 0, BLOCK_SIZE)
+            ]
+        );
+        // Started pieces come first, and the last piece is a single short block
+        assert_eq!(
+            picker.pick(peer(2), 3),
+            vec![(1, BLOCK_SIZE, BLOCK_SIZE), (3, 0, 100), (0, 0, BLOCK_SIZE)]
+        );
+        assert!(picker.pick(peer(5), 1).is_empty());
+
+        picker.remove_peer(peer(1));
+        assert_eq!(picker.availability(2), 0);
+        assert!(!picker.is_interesting(peer(1)));
+        assert!(picker.is_interesting(peer(3)));
+        assert!(picker.peer_bitfield(peer(1), Bitfield::new(5)).is_err());
+    }
+
+    #[test]
+    fn random_test() {
+        let picks = |seed| {
+            let mut picker = picker(64, seed).with_random_first_pieces(4);
+            picker.peer_bitfield(peer(1), Bitfield::full(64)).unwrap();
+            picker.peer_has(peer(2), 0);
+            picker.pick(peer(1), 8)
+        };
+        assert_eq!(picks(7), picks(7));
+        assert_ne!(picks(7), picks(8));
+
+        // Ties are broken at random, but the same way for the same seed
+        let first = |seed| picker_with_peer(seed).pick(peer(1), 1)[0].0;
+        let firsts = (0..16).map(first).collect::<Vec<_>>();
+        assert_eq!(firsts, (0..16).map(first).collect::<Vec<_>>());
+        assert!(firsts.iter().any(|&index| index != firsts[0]));
+    }
+
+    fn picker_with_peer(seed: u64) -> PiecePicker<StdRng> {
+        let mut picker = picker(64, seed);
+        picker.peer_bitfield(peer(1), Bitfield::full(64)).unwrap();
+        picker
+    }
+
+    #[test]
+    fn priority_test() {
+        let mut picker = picker(4, 1);
+        picker.peer_bitfield(peer(1), Bitfield::full(4)).unwrap();
+        picker.peer_bitfield(peer(2), bitfield(4, &[0, 1])).unwrap();
+        picker.set_priority(2, Priority::Skip);
+        picker.set_priority(3, Priority::Skip);
+        picker.set_priority(1, Priority::High);
+
+        let picked = picker.pick(peer(1), 10);
+        assert_eq!(
+            picked.iter().map(|block| block.0).collect::<Vec<_>>(),
+            vec![1, 1, 0, 0]
+        );
+        for (index, begin, _) in picked {
+            picker.block_received(peer(1), index, begin);
+        }
+        picker.piece_verified(0);
+        picker.piece_verified(1);
+        assert!(picker.is_complete());
+        assert!(!picker.is_interesting(peer(1)));
+        assert_eq!(picker.have().iter().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn endgame_test() {
+        let mut picker = picker(2, 1);
+        picker.peer_bitfield(peer(1), bitfield(2, &[0])).unwrap();
+        picker.peer_bitfield(peer(2), Bitfield::full(2)).unwrap();
+        assert_eq!(picker.pick(peer(1), 2).len(), 2);
+        picker.peer_has(peer(1), 1);
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick(peer(1), 2), vec![(1, 0, 100)]);
+        assert!(picker.is_endgame());
+
+        // Every block is requested again from the second peer, and cancelled on arrival
+        let duplicates = picker.pick(peer(2), 10);
+        assert_eq!(duplicates.len(), 3);
+        assert!(picker.pick(peer(2), 10).is_empty());
+        let received = picker.block_received(peer(2), 1, 0);
+        assert_eq!(received.cancels(), &[peer(1)]);
+        assert!(received.piece_complete());
+        assert_eq!(
+            picker.block_received(peer(1), 1, 0),
+            BlockReceived::default()
+        );
+
+        // A failed piece is downloaded again
+        picker.piece_failed(1);
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick(peer(1), 10), vec![(1, 0, 100)]);
+
+        // Released blocks can be picked again
+        picker.release(peer(1), 0, 0);
+        picker.release(peer(2), 0, 0);
+        picker.release_all(peer(2));
+        assert_eq!(picker.pick(peer(2), 1), vec![(0, 0, BLOCK_SIZE)]);
+    }
+}
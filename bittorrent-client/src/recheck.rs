@@ -0,0 +1,223 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use bittorrent_proto::{error::*, peer::Bitfield};
+use sha1::Sha1;
+
+use crate::storage::Storage;
+
+/// Stops a running [`recheck`] from another thread or task.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a [`recheck`] has got.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecheckProgress {
+    checked: u32,
+    valid: u32,
+    total: u32,
+}
+
+impl RecheckProgress {
+    /// The number of pieces hashed so far.
+    pub fn checked(&self) -> u32 {
+        self.checked
+    }
+
+    /// The number of pieces hashed so far which matched.
+    pub fn valid(&self) -> u32 {
+        self.valid
+    }
+
+    /// The number of pieces in the torrent.
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+}
+
+/// Hashes every piece in `storage` on up to `threads` threads, and compares them against
+/// [`Info::pieces`](bittorrent_proto::Info::pieces) to find which are already complete.
+/// `on_progress` is called after each piece, in no particular order of pieces.
+///
+/// Reads go through the storage one at a time, while hashing happens in parallel. Returns
+/// `None` if cancelled before every piece was checked. Pieces of v2-only torrents never match,
+/// since they have no SHA-1 piece hashes.
+pub fn recheck<S, F>(
+    storage: &Mutex<S>,
+    threads: usize,
+    cancellation: &Cancellation,
+    mut on_progress: F,
+) -> Result<Option<Bitfield>>
+where
+    S: Storage + ?Sized,
+    F: FnMut(RecheckProgress),
+{
+    let info = storage
+        .lock()
+        .expect("storage lock poisoned")
+        .info()
+        .clone();
+    let total = info.piece_count();
+    let next = AtomicU32::new(0);
+    // Set when the recheck is cancelled or fails, so that every thread stops early
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+
+    let check = |index: u32| -> Result<bool> {
+        let size = info.piece_size(index).unwrap_or(0) as u32;
+        let piece = storage
+            .lock()
+            .expect("storage lock poisoned")
+            .read_block(index, 0, size)?;
+        Ok(info
+            .piece_hash(index)
+            .is_some_and(|hash| Sha1::from(&piece).digest().bytes() == *hash))
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (next, stop, check) = (&next, &stop, &check);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) && !cancellation.is_cancelled() {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= total || sender.send((index, check(index))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut bitfield = Bitfield::new(total);
+        let mut progress = RecheckProgress {
+            checked: 0,
+            valid: 0,
+            total,
+        };
+        for (index, result) in receiver {
+            if cancellation.is_cancelled() {
+                return Ok(None);
+            }
+            match result {
+                Ok(valid) => {
+                    bitfield.set(index, valid);
+                    progress.checked += 1;
+                    progress.valid += valid as u32;
+                    on_progress(progress);
+                }
+                Err(e) => {
+                    stop.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+
+        if progress.checked < total || cancellation.is_cancelled() {
+            Ok(None)
+        } else {
+            Ok(Some(bitfield))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bittorrent_proto::Info;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn stored(pieces: u32, corrupt: &[u32]) -> Mutex<MemoryStorage> {
+        let content = (0..pieces * 16).map(|i| i as u8).collect::<Vec<_>>();
+        let hashes = content
+            .chunks(16)
+            .flat_map(|piece| Sha1::from(piece).digest().bytes())
+            .collect();
+        let info = Info::new(
+            String::from("file"),
+            16,
+            hashes,
+            Some(content.len() as u64),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut storage = MemoryStorage::new(info);
+        for (index, piece) in content.chunks(16).enumerate() {
+            if !corrupt.contains(&(index as u32)) {
+                storage.write_block(index as u32, 0, piece).unwrap();
+            }
+        }
+        Mutex::new(storage)
+    }
+
+    #[test]
+    fn recheck_test() {
+        let storage = stored(50, &[3, 17, 49]);
+        let mut updates = Vec::new();
+        let bitfield = recheck(&storage, 4, &Cancellation::new(), |progress| {
+            updates.push(progress)
+        })
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(bitfield.len(), 50);
+        assert_eq!(bitfield.count(), 47);
+        assert!(!bitfield.has(3) && !bitfield.has(17) && !bitfield.has(49));
+        assert_eq!(updates.len(), 50);
+        assert_eq!(
+            updates.last().unwrap(),
+            &RecheckProgress {
+                checked: 50,
+                valid: 47,
+                total: 50
+            }
+        );
+
+        // Complete data can be seeded straight away
+        let bitfield = recheck(&stored(8, &[]), 1, &Cancellation::new(), |_| {})
+            .unwrap()
+            .unwrap();
+        assert!(bitfield.is_complete());
+    }
+
+    #[test]
+    fn cancel_test() {
+        let storage = stored(50, &[]);
+        let cancellation = Cancellation::new();
+        let mut checked = 0;
+        let result = recheck(&storage, 2, &cancellation, |progress| {
+            checked = progress.checked();
+            if checked == 5 {
+                cancellation.cancel();
+            }
+        })
+        .unwrap();
+        assert_eq!(result, None);
+        assert!(checked < 50);
+
+        cancellation.cancel();
+        assert_eq!(recheck(&storage, 2, &cancellation, |_| {}).unwrap(), None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+
+use bittorrent_proto::{error::*, Info};
+use sha1::Sha1;
+
+mod file;
+mod memory;
+
+pub use file::{Allocation, FileStorage};
+pub use memory::MemoryStorage;
+
+/// Where the pieces of a torrent are kept. Blocks are addressed by piece index and the offset
+/// within the piece, and may span several files.
+pub trait Storage: Send {
+    /// The info dictionary describing the layout of the pieces.
+    fn info(&self) -> &Info;
+
+    /// Reads `length` bytes at `begin` within the piece at `index`.
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>>;
+
+    /// Writes `data` at `begin` within the piece at `index`.
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<()>;
+
+    /// Makes sure everything written so far has reached the underlying storage.
+    fn flush(&mut self) -> Result<()>;
+
+    /// The SHA-1 hash of the piece at `index`, as currently stored.
+    fn hash_piece(&mut self, index: u32) -> Result<[u8; 20]> {
+        let size = piece_size(self.info(), index)?;
+        let data = self.read_block(index, 0, size)?;
+        Ok(Sha1::from(data).digest().bytes())
+    }
+
+    /// Whether the piece at `index` matches its hash in `pieces`. Always `false` for v2-only
+    /// torrents, which don't have SHA-1 piece hashes.
+    fn verify_piece(&mut self, index: u32) -> Result<bool> {
+        let expected = match self.info().piece_hash(index) {
+            Some(hash) => *hash,
+            None => return Ok(false),
+        };
+        Ok(self.hash_piece(index)? == expected)
+    }
+}
+
+/// The length of the piece at `index`.
+fn piece_size(info: &Info, index: u32) -> Result<u32> {
+    info.piece_size(index)
+        .map(|size| size as u32)
+        .ok_or(Error::InvalidBlock(index, 0, 0))
+}
+
+/// The offset of a block from the start of the first piece, after checking that it lies
+/// within its piece.
+fn block_offset(info: &Info, index: u32, begin: u32, length: u32) -> Result<u64> {
+    match info.piece_size(index) {
+        Some(size) if begin as u64 + length as u64 <= size => {
+            Ok(index as u64 * info.piece_length() + begin as u64)
+        }
+        _ => Err(Error::InvalidBlock(index, begin, length)),
+    }
+}
+
+/// Each file of the torrent in layout order: its path relative to the download directory,
+/// its length, and whether it's a padding file, which is never stored.
+fn file_layout(info: &Info) -> Vec<(PathBuf, u64, bool)> {
+    let name = PathBuf::from(info.name());
+    let join = |path: &[String]| path.iter().fold(name.clone(), |p, c| p.join(c));
+
+    if let Some(files) = info.files() {
+        files
+            .iter()
+            .map(|file| (join(file.path()), file.length(), file.is_padding()))
+            .collect()
+    } else if let Some(length) = info.length() {
+        vec![(name, length, false)]
+    } else {
+        let files = info
+            .file_tree()
+            .map(|tree| tree.files())
+            .unwrap_or_default();
+        match files.as_slice() {
+            // The name of a single file is the only entry of its tree
+            [(path, entry)] if path.len() == 1 && path[0] == info.name() => {
+                vec![(name, entry.length(), false)]
+            }
+            _ => files
+                .iter()
+                .map(|(path, entry)| (join(path), entry.length(), false))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bittorrent_proto::{FileTree, FileTreeEntry};
+
+    use super::*;
+
+    #[test]
+    fn file_layout_test() {
+        let single = Info::new(
+            String::from("file"),
+            16,
+            vec![0; 20],
+            Some(10),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            file_layout(&single),
+            vec![(PathBuf::from("file"), 10, false)]
+        );
+
+        let tree = |paths: &[&[&str]]| {
+            FileTree::from_files(paths.iter().map(|path| {
+                (
+                    path.iter().map(|c| c.to_string()).collect(),
+                    FileTreeEntry::new(1, Some([0; 32])),
+                )
+            }))
+            .unwrap()
+        };
+        let v2 = |file_tree| Info::new_v2(String::from("file"), 1 << 14, file_tree, None).unwrap();
+        assert_eq!(
+            file_layout(&v2(tree(&[&["file"]]))),
+            vec![(PathBuf::from("file"), 1, false)]
+        );
+        assert_eq!(
+            file_layout(&v2(tree(&[&["dir", "a"], &["b"]]))),
+            vec![
+                (PathBuf::from("file/b"), 1, false),
+                (PathBuf::from("file/dir/a"), 1, false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use bittorrent_proto::{error::*, Info};
+
+use super::{block_offset, file_layout, Storage};
+
+/// How [`FileStorage`] sets up files which are missing or shorter than they should be.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Allocation {
+    /// Files are extended without writing anything, so that the filesystem only allocates
+    /// space as blocks are written, where it supports sparse files.
+    #[default]
+    Sparse,
+    /// Files are filled with zeros up front, so that running out of space fails early.
+    Full,
+}
+
+/// Stores pieces in files on disk, laid out under a directory named after the torrent. Single
+/// file torrents are stored directly in the download directory instead.
+///
+/// Padding files are never created. Reading them gives zeros, and writes to them are ignored.
+#[derive(Debug)]
+pub struct FileStorage {
+    info: Info,
+    directory: PathBuf,
+    /// An open handle for each file in layout order, or `None` for padding files.
+    files: Vec<Option<File>>,
+}
+
+impl FileStorage {
+    /// Opens or creates every file of `info` under `directory`, along with any missing parent
+    /// directories. Existing data is kept, but files longer than they should be are truncated.
+    ///
+    /// Fails if `info` doesn't pass [`Info::validate`], so that paths in the torrent can't
+    /// escape `directory`.
+    pub fn new(directory: impl Into<PathBuf>, info: Info, allocation: Allocation) -> Result<Self> {
+        info.validate()?;
+        let directory = directory.into();
+        let files = file_layout(&info)
+            .into_iter()
+            .map(|(path, length, padding)| {
+                if padding {
+                    Ok(None)
+                } else {
+                    Ok(Some(open(&directory.join(path), length, allocation)?))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            info,
+            directory,
+            files,
+        })
+    }
+
+    /// The directory the torrent's files are placed in.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
+impl Storage for FileStorage {
+    fn info(&self) -> &Info {
+        &self.info
+    }
+
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let offset = block_offset(&self.info, index, begin, length)?;
+        let segments = self
+            .info
+            .segments(offset, length as u64)
+            .ok_or(Error::InvalidBlock(index, begin, length))?;
+
+        let mut block = vec![0; length as usize];
+        let mut position = 0;
+        for segment in segments {
+            let buf = &mut block[position..position + segment.length() as usize];
+            if let Some(file) = &mut self.files[segment.file_index()] {
+                file.seek(SeekFrom::Start(segment.offset()))?;
+                file.read_exact(buf)?;
+            }
+            position += buf.len();
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<()> {
+        let length = data.len() as u32;
+        let offset = block_offset(&self.info, index, begin, length)?;
+        let segments = self
+            .info
+            .segments(offset, length as u64)
+            .ok_or(Error::InvalidBlock(index, begin, length))?;
+
+        let mut position = 0;
+        for segment in segments {
+            let buf = &data[position..position + segment.length() as usize];
+            if let Some(file) = &mut self.files[segment.file_index()] {
+                file.seek(SeekFrom::Start(segment.offset()))?;
+                file.write_all(buf)?;
+            }
+            position += buf.len();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for file in self.files.iter_mut().flatten() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the file at `path` for reading and writing, creating it and its parent directories if
+/// needed, and makes it exactly `length` bytes long.
+fn open(path: &Path, length: u64, allocation: Allocation) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    let current = file.metadata()?.len();
+    if current > length || (current < length && allocation == Allocation::Sparse) {
+        file.set_len(length)?;
+    } else if current < length {
+        let zeros = vec![0; 1 << 16];
+        file.seek(SeekFrom::Start(current))?;
+        let mut remaining = length - current;
+        while remaining > 0 {
+            let chunk = remaining.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..chunk])?;
+            remaining -= chunk as u64;
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use bendy::value::Value;
+    use bittorrent_proto::FileInfo;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn info(files: &[(&[&str], u64)], pieces: usize) -> Info {
+        let files = files
+            .iter()
+            .map(|(path, length)| {
+                FileInfo::new(*length, path.iter().map(|c| c.to_string()).collect(), None)
+            })
+            .collect();
+        Info::new(
+            String::from("torrent"),
+            16,
+            vec![0; pieces * 20],
+            None,
+            Some(files),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn layout_test() {
+        let dir = temp_dir("file-layout");
+        let info = info(&[(&["a"], 10), (&["sub", "dir", "b"], 20), (&["c"], 18)], 3);
+        let mut storage = FileStorage::new(&dir, info.clone(), Allocation::Sparse).unwrap();
+        let root = dir.join("torrent");
+        assert_eq!(fs::metadata(root.join("a")).unwrap().len(), 10);
+        assert_eq!(fs::metadata(root.join("sub/dir/b")).unwrap().len(), 20);
+
+        // The block spans the end of 'a' and the start of 'b'
+        let block = (0..12).collect::<Vec<u8>>();
+        storage.write_block(0, 4, &block).unwrap();
+        storage.write_block(2, 0, b"cccccc").unwrap();
+        storage.flush().unwrap();
+        assert_eq!(&fs::read(root.join("a")).unwrap()[4..], &block[..6]);
+        assert_eq!(&fs::read(root.join("sub/dir/b")).unwrap()[..6], &block[6..]);
+        assert_eq!(storage.read_block(0, 4, 12).unwrap(), block);
+        assert_eq!(&fs::read(root.join("c")).unwrap()[2..8], b"cccccc");
+
+        assert!(matches!(
+            storage.write_block(2, 12, b"toolong"),
+            Err(Error::InvalidBlock(2, 12, 7))
+        ));
+        assert!(storage.read_block(3, 0, 1).is_err());
+
+        // Existing data survives reopening, even with full allocation
+        drop(storage);
+        let mut storage = FileStorage::new(&dir, info, Allocation::Full).unwrap();
+        assert_eq!(storage.read_block(2, 0, 6).unwrap(), b"cccccc");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn padding_test() {
+        let dir = temp_dir("file-padding");
+        let mut files = vec![
+            FileInfo::new(10, vec![String::from("a")], None),
+            FileInfo::new(6, vec![String::from(".pad"), String::from("6")], None),
+            FileInfo::new(4, vec![String::from("b")], None),
+        ];
+        files[1]
+            .extra_fields_mut()
+            .insert(b"attr".to_vec(), Value::Bytes(b"p"[..].into()));
+        let info = Info::new(
+            String::from("torrent"),
+            16,
+            vec![0; 40],
+            None,
+            Some(files),
+            None,
+            None,
+        )
+        .unwrap();
+        let mut storage = FileStorage::new(&dir, info, Allocation::Full).unwrap();
+        assert!(!dir.join("torrent/.pad").exists());
+
+        storage.write_block(0, 0, &[1; 16]).unwrap();
+        let mut expected = vec![1; 10];
+        expected.extend_from_slice(&[0; 6]);
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), expected);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsafe_path_test() {
+        let dir = temp_dir("file-unsafe");
+        let info = info(&[(&["..", "escaped"], 16)], 1);
+        assert!(matches!(
+            FileStorage::new(&dir, info, Allocation::Sparse),
+            Err(Error::InvalidTorrent(_))
+        ));
+        assert!(!dir.join("escaped").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use bittorrent_proto::{error::*, Info};
+
+use super::{block_offset, Storage};
+
+/// Stores pieces in memory, for tests and other short-lived torrents. The pieces are kept as
+/// one contiguous buffer, which starts out zeroed.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    info: Info,
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: Info) -> Self {
+        let length = info
+            .piece_count()
+            .checked_sub(1)
+            .and_then(|last| {
+                let size = info.piece_size(last)?;
+                Some(last as u64 * info.piece_length() + size)
+            })
+            .unwrap_or(0);
+        Self {
+            info,
+            data: vec![0; length as usize],
+        }
+    }
+
+    /// Every piece, one after the other. For v2-only torrents, the gaps between the end of each
+    /// file and the start of the next piece are included as zeros.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn info(&self) -> &Info {
+        &self.info
+    }
+
+    fn read_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let offset = block_offset(&self.info, index, begin, length)? as usize;
+        Ok(self.data[offset..offset + length as usize].to_vec())
+    }
+
+    fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = block_offset(&self.info, index, begin, data.len() as u32)? as usize;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::Sha1;
+
+    use super::*;
+
+    #[test]
+    fn memory_test() {
+        let content = (0..40).collect::<Vec<u8>>();
+        let pieces = content
+            .chunks(16)
+            .flat_map(|piece| Sha1::from(piece).digest().bytes())
+            .collect();
+        let info = Info::new(String::from("file"), 16, pieces, Some(40), None, None, None).unwrap();
+        let mut storage = MemoryStorage::new(info);
+        assert_eq!(storage.data().len(), 40);
+
+        storage.write_block(0, 0, &content[..16]).unwrap();
+        storage.write_block(2, 0, &content[32..]).unwrap();
+        assert!(storage.verify_piece(0).unwrap());
+        assert!(!storage.verify_piece(1).unwrap());
+        assert!(storage.verify_piece(2).unwrap());
+        assert_eq!(storage.read_block(2, 4, 4).unwrap(), &content[36..]);
+
+        assert!(matches!(
+            storage.write_block(2, 4, &[0; 5]),
+            Err(Error::InvalidBlock(2, 4, 5))
+        ));
+        assert!(storage.hash_piece(3).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+use std::{fs, path::PathBuf};
+
+/// Creates a fresh directory under the system temp directory for a single test.
+pub(crate) fn temp_dir(test: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("bittorrent-client-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,496 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use bittorrent_proto::{error::*, peer::Bitfield, MetaInfo};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    choker::{ChokeStrategy, FixedSlots, DEFAULT_UPLOAD_SLOTS},
+    storage::Storage,
+};
+
+mod disk;
+mod session;
+
+use session::Command;
+
+/// The start of every generated peer ID, in the style of Azureus.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-BR0100-";
+
+/// The port listened on for incoming connections unless another address is configured.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// The largest number of peers connected at once by default.
+pub const DEFAULT_MAX_PEERS: usize = 50;
+
+/// The number of blocks requested from a peer without an answer yet, by default.
+pub const DEFAULT_REQUEST_QUEUE: usize = 16;
+
+/// Settings for a single [`Torrent`].
+#[derive(Debug, Clone)]
+pub struct TorrentConfig {
+    peer_id: [u8; 20],
+    listen_address: SocketAddr,
+    max_peers: usize,
+    request_queue: usize,
+    upload_slots: usize,
+    choke_strategy: Arc<dyn ChokeStrategy>,
+}
+
+impl TorrentConfig {
+    /// Uses a random peer ID, and listens on [`DEFAULT_PORT`] on every interface.
+    pub fn new() -> Self {
+        let mut peer_id = [0; 20];
+        peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+        for (byte, random) in peer_id[8..]
+            .iter_mut()
+            .zip(rand::thread_rng().sample_iter(Alphanumeric))
+        {
+            *byte = random;
+        }
+        Self {
+            peer_id,
+            listen_address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+            max_peers: DEFAULT_MAX_PEERS,
+            request_queue: DEFAULT_REQUEST_QUEUE,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            choke_strategy: Arc::new(FixedSlots),
+        }
+    }
+
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// `listen_address`: where to accept incoming connections. Port 0 picks any free port,
+    /// which is then available from [`Torrent::listen_address`].
+    pub fn with_listen_address(mut self, listen_address: SocketAddr) -> Self {
+        self.listen_address = listen_address;
+        self
+    }
+
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    /// `request_queue`: how many blocks may be requested from a single peer at once.
+    pub fn with_request_queue(mut self, request_queue: usize) -> Self {
+        self.request_queue = request_queue;
+        self
+    }
+
+    /// `upload_slots`: how many peers may be unchoked at once, including the optimistic
+    /// unchoke.
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        self.upload_slots = upload_slots;
+        self
+    }
+
+    /// `choke_strategy`: how the regular upload slots are given out. Defaults to
+    /// [`FixedSlots`].
+    pub fn with_choke_strategy(mut self, choke_strategy: Arc<dyn ChokeStrategy>) -> Self {
+        self.choke_strategy = choke_strategy;
+        self
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        self.listen_address
+    }
+
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    pub fn request_queue(&self) -> usize {
+        self.request_queue
+    }
+
+    pub fn upload_slots(&self) -> usize {
+        self.upload_slots
+    }
+
+    pub fn choke_strategy(&self) -> &Arc<dyn ChokeStrategy> {
+        &self.choke_strategy
+    }
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a [`Torrent`] is currently doing.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TorrentState {
+    /// Not connected to any peers or announcing to trackers, until started.
+    Paused,
+    /// Downloading missing pieces, while uploading those already verified.
+    Downloading,
+    /// Every wanted piece has been verified, and is only being uploaded.
+    Seeding,
+    /// Stopped for good, either explicitly or after an error.
+    Stopped,
+}
+
+/// A snapshot of the progress of a [`Torrent`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TorrentStatus {
+    state: TorrentState,
+    pieces: u32,
+    piece_count: u32,
+    left: u64,
+    downloaded: u64,
+    uploaded: u64,
+    peers: usize,
+}
+
+impl TorrentStatus {
+    pub fn state(&self) -> TorrentState {
+        self.state
+    }
+
+    /// The number of pieces which have been verified.
+    pub fn pieces(&self) -> u32 {
+        self.pieces
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        self.piece_count
+    }
+
+    /// The number of bytes still missing.
+    pub fn left(&self) -> u64 {
+        self.left
+    }
+
+    /// The number of bytes received from peers since the torrent was created, including
+    /// duplicates and pieces which failed verification.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// The number of bytes sent to peers since the torrent was created.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// The number of connected peers.
+    pub fn peers(&self) -> usize {
+        self.peers
+    }
+}
+
+/// A handle to a task which downloads and uploads a single torrent.
+///
+/// The task announces to the torrent's trackers, connects to the peers they return along
+/// with any added manually or learned through peer exchange, and accepts incoming
+/// connections. Peer exchange is disabled for private torrents. Blocks are requested through a
+/// [`PiecePicker`](crate::piece_picker::PiecePicker), and each piece is verified before it's
+/// announced to peers with `have`. Uploads are limited to the peers unchoked by a
+/// [`Choker`](crate::choker::Choker).
+///
+/// Dropping the handle stops the task, but without waiting for it to finish.
+#[derive(Debug)]
+pub struct Torrent {
+    commands: mpsc::UnboundedSender<Command>,
+    status: watch::Receiver<TorrentStatus>,
+    listen_address: SocketAddr,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Torrent {
+    /// Binds the listen address and spawns the task, which starts out paused.
+    ///
+    /// `have`: the pieces which are already in `storage`, e.g. from a
+    /// [`recheck`](crate::recheck::recheck).
+    ///
+    /// Only torrents with v1 piece hashes are supported, since pieces are verified with SHA-1.
+    /// Torrents which fail [`MetaInfo::validate`] are rejected with
+    /// [`Error::InvalidTorrent`].
+    pub async fn new<S: Storage + 'static>(
+        meta_info: MetaInfo,
+        storage: Arc<Mutex<S>>,
+        have: Bitfield,
+        config: TorrentConfig,
+    ) -> Result<Self> {
+        if !meta_info.info().is_v1() {
+            return Err(Error::InvalidMetadata(String::from(
+                "torrents without v1 piece hashes are not supported",
+            )));
+        }
+        meta_info.validate()?;
+        let listener = TcpListener::bind(config.listen_address()).await?;
+        session::spawn(meta_info, storage, have, config, listener)
+    }
+
+    /// Where incoming connections are accepted.
+    pub fn listen_address(&self) -> SocketAddr {
+        self.listen_address
+    }
+
+    /// Starts or resumes downloading and seeding.
+    pub fn start(&self) -> Result<()> {
+        self.send(Command::Start)
+    }
+
+    /// Disconnects from every peer and stops announcing, until started again.
+    pub fn pause(&self) -> Result<()> {
+        self.send(Command::Pause)
+    }
+
+    /// Connects to `address` once started, in addition to the peers from trackers.
+    pub fn add_peer(&self, address: SocketAddr) -> Result<()> {
+        self.send(Command::AddPeer(address))
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Waits until the status satisfies `condition`, and returns it.
+    pub async fn wait_for(
+        &mut self,
+        condition: impl FnMut(&TorrentStatus) -> bool,
+    ) -> Result<TorrentStatus> {
+        match self.status.wait_for(condition).await {
+            Ok(status) => Ok(status.clone()),
+            Err(_) => Err(Error::TorrentStopped),
+        }
+    }
+
+    /// Disconnects from every peer, announces that the torrent has stopped, and flushes the
+    /// storage. Returns the error which stopped the task, if any.
+    pub async fn stop(self) -> Result<()> {
+        // The task may have already stopped after an error, which is returned below
+        let _ = self.commands.send(Command::Stop);
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(Error::TorrentStopped),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| Error::TorrentStopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use bittorrent_proto::{
+        peer::{Handshake, Message, ReservedBits},
+        Info, Peer, TorrentBuilder, ValidationIssue,
+    };
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        peer_connection::PeerConnection,
+        recheck::{recheck, Cancellation},
+        storage::{Allocation, FileStorage, MemoryStorage},
+        test_util::temp_dir,
+    };
+
+    fn config() -> TorrentConfig {
+        TorrentConfig::new().with_listen_address(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    #[tokio::test]
+    async fn transfer_test() {
+        let dir = temp_dir("torrent-transfer");
+        let content = (0..150_000_u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(dir.join("content"), &content).unwrap();
+        let meta_info = TorrentBuilder::new(dir.join("content"), String::new())
+            .with_piece_length(1 << 15)
+            .build()
+            .unwrap();
+        let info = meta_info.info().clone();
+
+        let seeder_storage = Arc::new(Mutex::new(
+            FileStorage::new(&dir, info.clone(), Allocation::Sparse).unwrap(),
+        ));
+        let have = recheck(&seeder_storage, 2, &Cancellation::new(), |_| {})
+            .unwrap()
+            .unwrap();
+        assert!(have.is_complete());
+        let seeder = Torrent::new(meta_info.clone(), seeder_storage, have, config())
+            .await
+            .unwrap();
+        seeder.start().unwrap();
+
+        let leecher_storage = Arc::new(Mutex::new(MemoryStorage::new(info.clone())));
+        let mut leecher = Torrent::new(
+            meta_info,
+            leecher_storage.clone(),
+            Bitfield::new(info.piece_count()),
+            config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(leecher.status().state(), TorrentState::Paused);
+        assert_eq!(leecher.status().left(), content.len() as u64);
+        leecher.add_peer(seeder.listen_address()).unwrap();
+        leecher.start().unwrap();
+
+        let status = time::timeout(
+            Duration::from_secs(30),
+            leecher.wait_for(|status| status.state() == TorrentState::Seeding),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(status.pieces(), info.piece_count());
+        assert_eq!(status.left(), 0);
+        assert!(status.downloaded() >= content.len() as u64);
+        assert_eq!(leecher_storage.lock().unwrap().data(), &content[..]);
+        assert_eq!(seeder.status().state(), TorrentState::Seeding);
+        assert!(seeder.status().uploaded() >= content.len() as u64);
+
+        leecher.pause().unwrap();
+        let status = leecher
+            .wait_for(|status| status.peers() == 0)
+            .await
+            .unwrap();
+        assert_eq!(status.state(), TorrentState::Paused);
+
+        leecher.stop().await.unwrap();
+        seeder.stop().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unrequested_block_test() {
+        let dir = temp_dir("torrent-unrequested");
+        fs::write(dir.join("content"), [1; 100]).unwrap();
+        let meta_info = TorrentBuilder::new(dir.join("content"), String::new())
+            .build()
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        let info = meta_info.info().clone();
+        let storage = Arc::new(Mutex::new(MemoryStorage::new(info.clone())));
+        let torrent = Torrent::new(
+            meta_info.clone(),
+            storage.clone(),
+            Bitfield::new(info.piece_count()),
+            config(),
+        )
+        .await
+        .unwrap();
+        torrent.start().unwrap();
+
+        let mut connection = PeerConnection::new(Peer::new(None, torrent.listen_address()))
+            .await
+            .unwrap();
+        let handshake = Handshake::new(ReservedBits::default(), meta_info.info_hash(), [7; 20]);
+        connection.send_handshake(&handshake).await.unwrap();
+        connection
+            .recv_handshake(meta_info.info_hash())
+            .await
+            .unwrap();
+        connection
+            .send(Message::Piece {
+                index: 0,
+                begin: 0,
+                block: vec![1; 100],
+            })
+            .await
+            .unwrap();
+        // Messages are handled in order, so the block has been dealt with once this is answered
+        connection.send(Message::Interested).await.unwrap();
+        assert_eq!(connection.recv().await.unwrap(), Some(Message::Unchoke));
+
+        assert_eq!(torrent.status().downloaded(), 0);
+        assert_eq!(torrent.status().peers(), 1);
+        torrent.stop().await.unwrap();
+        assert_eq!(storage.lock().unwrap().data(), &[0; 100][..]);
+    }
+
+    #[tokio::test]
+    async fn invalid_torrent_test() {
+        let info = Info::new(
+            String::from("file"),
+            0,
+            vec![0; 20],
+            Some(100),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let meta_info = MetaInfo::new(String::new(), info.clone(), None, None, None, None, None);
+        let storage = Arc::new(Mutex::new(MemoryStorage::new(info)));
+        let result = Torrent::new(meta_info, storage, Bitfield::new(1), config()).await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidTorrent(issues))
+                if issues == vec![ValidationIssue::InvalidPieceLength(0)]
+        ));
+    }
+
+    /// Starts three torrents where `a` is only known to `b`, and `b` only to `c`, then waits
+    /// until `c` is connected to `expected_peers` peers or `timeout` has passed.
+    async fn exchange_peers(private: bool, expected_peers: usize, timeout: Duration) -> bool {
+        let dir = temp_dir(&format!("torrent-pex-{}", private));
+        fs::write(dir.join("content"), [1; 100]).unwrap();
+        let meta_info = TorrentBuilder::new(dir.join("content"), String::new())
+            .with_private(private)
+            .build()
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        let torrent = || async {
+            let info = meta_info.info().clone();
+            let have = Bitfield::new(info.piece_count());
+            let storage = Arc::new(Mutex::new(MemoryStorage::new(info)));
+            Torrent::new(meta_info.clone(), storage, have, config())
+                .await
+                .unwrap()
+        };
+        let (a, mut b, mut c) = (torrent().await, torrent().await, torrent().await);
+
+        a.start().unwrap();
+        b.add_peer(a.listen_address()).unwrap();
+        b.start().unwrap();
+        b.wait_for(|status| status.peers() == 1).await.unwrap();
+        c.add_peer(b.listen_address()).unwrap();
+        c.start().unwrap();
+        let exchanged = time::timeout(
+            timeout,
+            c.wait_for(|status| status.peers() == expected_peers),
+        )
+        .await
+        .is_ok();
+
+        for torrent in [a, b, c] {
+            torrent.stop().await.unwrap();
+        }
+        exchanged
+    }
+
+    #[tokio::test]
+    async fn pex_test() {
+        // c learns about a from b
+        assert!(exchange_peers(false, 2, Duration::from_secs(30)).await);
+        // Private torrents only get peers from their trackers
+        assert!(!exchange_peers(true, 2, Duration::from_secs(3)).await);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use bittorrent_proto::error::*;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::storage::Storage;
+
+/// Work for the storage task, which is done in the order it's queued.
+#[derive(Debug)]
+pub(super) enum DiskJob {
+    /// Reads a block requested by `peer`.
+    Read {
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Write {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    /// Hashes the piece at `index`. Queued after its last block, so every write is done first.
+    Verify(u32),
+    Flush,
+}
+
+/// What the storage task reports back to the session.
+#[derive(Debug)]
+pub(super) enum DiskEvent {
+    Read {
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        length: u32,
+        result: Result<Vec<u8>>,
+    },
+    Verified(u32, Result<bool>),
+}
+
+/// Spawns a blocking task which does `jobs` against `storage`, so that disk access and hashing
+/// don't hold up the session. The storage is flushed once `jobs` is closed, and the result of
+/// that is returned.
+///
+/// Failed writes are only logged, since the piece then fails verification and is downloaded
+/// again.
+pub(super) fn spawn<S: Storage + 'static>(
+    storage: Arc<Mutex<S>>,
+    mut jobs: mpsc::UnboundedReceiver<DiskJob>,
+    events: mpsc::UnboundedSender<DiskEvent>,
+) -> JoinHandle<Result<()>> {
+    tokio::task::spawn_blocking(move || {
+        let lock = || storage.lock().expect("storage lock poisoned");
+        // The session may stop listening for events, but the remaining writes still matter
+        while let Some(job) = jobs.blocking_recv() {
+            match job {
+                DiskJob::Read {
+                    peer,
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let result = lock().read_block(index, begin, length);
+                    let _ = events.send(DiskEvent::Read {
+                        peer,
+                        index,
+                        begin,
+                        length,
+                        result,
+                    });
+                }
+                DiskJob::Write {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    if let Err(e) = lock().write_block(index, begin, &block) {
+                        log::warn!("Failed to write block {} of piece {}: {}", begin, index, e);
+                    }
+                }
+                DiskJob::Verify(index) => {
+                    let result = lock().verify_piece(index);
+                    let _ = events.send(DiskEvent::Verified(index, result));
+                }
+                DiskJob::Flush => {
+                    if let Err(e) = lock().flush() {
+                        log::warn!("Failed to flush storage: {}", e);
+                    }
+                }
+            }
+        }
+        lock().flush()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bittorrent_proto::Info;
+    use sha1::Sha1;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn jobs_test() {
+        let content = (0..32).collect::<Vec<u8>>();
+        let pieces = content
+            .chunks(16)
+            .flat_map(|piece| Sha1::from(piece).digest().bytes())
+            .collect();
+        let info = Info::new(String::from("file"), 16, pieces, Some(32), None, None, None).unwrap();
+        let storage = Arc::new(Mutex::new(MemoryStorage::new(info)));
+        let (jobs, job_receiver) = mpsc::unbounded_channel();
+        let (events, mut event_receiver) = mpsc::unbounded_channel();
+        let task = spawn(storage.clone(), job_receiver, events);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 6881));
+
+        // Jobs are done in order, so each piece is verified after its blocks are written
+        for (index, begin, block) in [(0, 0, &content[..8]), (0, 8, &content[8..16])] {
+            let block = block.to_vec();
+            jobs.send(DiskJob::Write {
+                index,
+                begin,
+                block,
+            })
+            .unwrap();
+        }
+        jobs.send(DiskJob::Verify(0)).unwrap();
+        jobs.send(DiskJob::Verify(1)).unwrap();
+        jobs.send(DiskJob::Read {
+            peer,
+            index: 0,
+            begin: 4,
+            length: 4,
+        })
+        .unwrap();
+        drop(jobs);
+
+        assert!(matches!(
+            event_receiver.recv().await,
+            Some(DiskEvent::Verified(0, Ok(true)))
+        ));
+        assert!(matches!(
+            event_receiver.recv().await,
+            Some(DiskEvent::Verified(1, Ok(false)))
+        ));
+        match event_receiver.recv().await {
+            Some(DiskEvent::Read {
+                peer: from,
+                index: 0,
+                begin: 4,
+                length: 4,
+                result: Ok(block),
+            }) => {
+                assert_eq!(from, peer);
+                assert_eq!(block, &content[4..8]);
+            }
+            other => panic!("expected a block, got {:?}", other),
+        }
+        task.await.unwrap().unwrap();
+        assert!(event_receiver.recv().await.is_none());
+        assert_eq!(&storage.lock().unwrap().data()[..16], &content[..16]);
+    }
+}
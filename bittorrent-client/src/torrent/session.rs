@@ -0,0 +1,1137 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use bittorrent_proto::{
+    error::*,
+    peer::{
+        Bitfield, ExtensionRegistry, Handshake, Message, PexFlags, PexState, ReservedBits, UT_PEX,
+    },
+    tracker::{
+        announce::{self, Event},
+        udp::{AnnounceRequest, UdpTracker},
+        TrackerClient, TrackerList,
+    },
+    InfoHash, MetaInfo, Peer,
+};
+use reqwest::Url;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{self, mpsc, watch},
+    task::JoinHandle,
+    time,
+};
+
+use super::{
+    disk::{self, DiskEvent, DiskJob},
+    Torrent, TorrentConfig, TorrentState, TorrentStatus,
+};
+use crate::{
+    candidates::{CandidatePool, PeerSource},
+    choker::{Choker, PeerStats, UNCHOKE_INTERVAL},
+    peer_connection::PeerConnection,
+    pex::enable_pex,
+    piece_picker::PiecePicker,
+    storage::Storage,
+};
+
+/// How often trackers and candidate peers are checked.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for an outgoing connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before checking the trackers again when there are none to announce to.
+const NO_TRACKER_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait for a single tracker to answer an announce, before moving on to the next.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many times a UDP tracker request is sent again before giving up. Unanswered requests
+/// then take 45 seconds, rather than hours with the defaults of BEP 15.
+const UDP_MAX_RETRANSMISSIONS: u32 = 1;
+
+/// The longest block a peer may request. Longer requests are rejected.
+const MAX_REQUEST_LENGTH: u32 = 1 << 17;
+
+/// The most requests from one peer which can wait to be read from storage. Further requests
+/// are rejected.
+const MAX_QUEUED_REQUESTS: usize = 256;
+
+#[derive(Debug)]
+pub(super) enum Command {
+    Start,
+    Pause,
+    AddPeer(SocketAddr),
+    Stop,
+}
+
+/// What the session sends to a peer connection task.
+#[derive(Debug)]
+enum Outgoing {
+    Message(Message),
+    /// The payload of a message for the named extension.
+    Extended(&'static str, Vec<u8>),
+}
+
+/// What a peer connection task reports back to the session.
+#[derive(Debug)]
+enum PeerEvent {
+    /// Handshakes have been exchanged, and messages for the peer can be sent to `sender`.
+    Connected {
+        address: SocketAddr,
+        sender: mpsc::UnboundedSender<Outgoing>,
+        fast_extension: bool,
+        outgoing: bool,
+    },
+    /// The peer's extension handshake lists peer exchange, which can now be sent with `state`.
+    PexSupported(SocketAddr, PexState),
+    Message(SocketAddr, Message),
+    /// The connection has closed, or couldn't be established.
+    Closed(SocketAddr, Option<Error>),
+}
+
+/// The results of announcing to each tracker tried in one round, in order.
+type AnnounceResults = Vec<(String, Result<announce::Response>)>;
+
+/// The UDP trackers announced to so far, by URL, so that their sockets and connection IDs are
+/// reused from one announce to the next.
+type UdpTrackers = Arc<Mutex<HashMap<String, Arc<sync::Mutex<UdpTracker>>>>>;
+
+#[derive(Debug)]
+struct PeerState {
+    sender: mpsc::UnboundedSender<Outgoing>,
+    fast_extension: bool,
+    /// Whether we connected to the peer, rather than it to us.
+    outgoing: bool,
+    /// What has been sent to the peer through peer exchange, once it supports it.
+    pex: Option<PexState>,
+    /// Whether the peer is choking us.
+    choked: bool,
+    /// Whether we're interested in the peer.
+    interested: bool,
+    /// Whether we're choking the peer.
+    choking: bool,
+    /// Whether the peer is interested in us.
+    peer_interested: bool,
+    /// The blocks requested from the peer which haven't arrived yet, as `(index, begin, length)`.
+    requested: HashSet<(u32, u32, u32)>,
+    /// The blocks the peer has requested which are being read from storage, as
+    /// `(index, begin, length)`.
+    queued: HashSet<(u32, u32, u32)>,
+    connected_at: Instant,
+    /// The bytes received from the peer since the last choking round.
+    round_downloaded: u64,
+    /// The bytes sent to the peer since the last choking round.
+    round_uploaded: u64,
+}
+
+impl PeerState {
+    fn send(&self, message: Message) {
+        // If the connection has closed, the session hears about it separately
+        let _ = self.sender.send(Outgoing::Message(message));
+    }
+
+    fn send_extended(&self, name: &'static str, payload: Vec<u8>) {
+        let _ = self.sender.send(Outgoing::Extended(name, payload));
+    }
+}
+
+/// The state of a torrent, owned by the task which runs it.
+struct Session {
+    meta_info: MetaInfo,
+    /// Jobs for the task which reads and writes the storage.
+    disk: mpsc::UnboundedSender<DiskJob>,
+    picker: PiecePicker,
+    config: TorrentConfig,
+    port: u16,
+    state: TorrentState,
+    peers: HashMap<SocketAddr, PeerState>,
+    /// Outgoing connections which haven't finished their handshakes.
+    connecting: HashSet<SocketAddr>,
+    /// Shared with the peer exchange handlers of each connection.
+    candidates: Arc<Mutex<CandidatePool>>,
+    choker: Choker,
+    last_choke: Instant,
+    trackers: TrackerList,
+    tracker_client: TrackerClient,
+    udp_trackers: UdpTrackers,
+    /// Identifies this session to UDP trackers.
+    key: u32,
+    next_announce: Instant,
+    /// The event to send with the next announce, kept until an announce succeeds.
+    pending_event: Option<Event>,
+    announcing: bool,
+    left: u64,
+    downloaded: u64,
+    uploaded: u64,
+    status: watch::Sender<TorrentStatus>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+    announces: mpsc::UnboundedSender<AnnounceResults>,
+}
+
+/// Spawns a task which runs the torrent, accepting connections from `listener`.
+pub(super) fn spawn<S: Storage + 'static>(
+    meta_info: MetaInfo,
+    storage: Arc<Mutex<S>>,
+    have: Bitfield,
+    config: TorrentConfig,
+    listener: TcpListener,
+) -> Result<Torrent> {
+    let info = meta_info.info();
+    let picker = PiecePicker::new(info, have)?;
+    let left = (0..info.piece_count())
+        .filter(|&index| !picker.have().has(index))
+        .filter_map(|index| info.piece_size(index))
+        .sum();
+    let trackers = TrackerList::from_meta_info(&meta_info);
+    let (commands, command_receiver) = mpsc::unbounded_channel();
+    let (events, event_receiver) = mpsc::unbounded_channel();
+    let (announces, announce_receiver) = mpsc::unbounded_channel();
+    let (disk, disk_jobs) = mpsc::unbounded_channel();
+    let (disk_events, disk_event_receiver) = mpsc::unbounded_channel();
+    let disk_task = disk::spawn(storage, disk_jobs, disk_events);
+    let (status, status_receiver) = watch::channel(TorrentStatus {
+        state: TorrentState::Paused,
+        pieces: picker.have().count(),
+        piece_count: info.piece_count(),
+        left,
+        downloaded: 0,
+        uploaded: 0,
+        peers: 0,
+    });
+
+    let listen_address = listener.local_addr()?;
+    let choker = Choker::new(config.choke_strategy().clone(), config.upload_slots());
+    let session = Session {
+        meta_info,
+        disk,
+        picker,
+        config,
+        port: listen_address.port(),
+        state: TorrentState::Paused,
+        peers: HashMap::new(),
+        connecting: HashSet::new(),
+        candidates: Arc::new(Mutex::new(CandidatePool::new())),
+        choker,
+        last_choke: Instant::now(),
+        trackers,
+        tracker_client: TrackerClient::new()?,
+        udp_trackers: UdpTrackers::default(),
+        key: rand::random(),
+        next_announce: Instant::now(),
+        pending_event: None,
+        announcing: false,
+        left,
+        downloaded: 0,
+        uploaded: 0,
+        status,
+        events,
+        announces,
+    };
+    let task = tokio::spawn(session.run(
+        listener,
+        command_receiver,
+        event_receiver,
+        announce_receiver,
+        disk_event_receiver,
+        disk_task,
+    ));
+    Ok(Torrent {
+        commands,
+        status: status_receiver,
+        listen_address,
+        task,
+    })
+}
+
+impl Session {
+    async fn run(
+        mut self,
+        listener: TcpListener,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut events: mpsc::UnboundedReceiver<PeerEvent>,
+        mut announces: mpsc::UnboundedReceiver<AnnounceResults>,
+        mut disk_events: mpsc::UnboundedReceiver<DiskEvent>,
+        disk_task: JoinHandle<Result<()>>,
+    ) -> Result<()> {
+        let mut tick = time::interval(TICK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Start) => self.start(),
+                    Some(Command::Pause) => self.pause(),
+                    Some(Command::AddPeer(address)) => {
+                        self.candidates().add(address, PeerSource::Manual);
+                        self.connect_candidates();
+                    }
+                    Some(Command::Stop) | None => break,
+                },
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, address)) => self.accept(stream, address),
+                    Err(e) => log::warn!("Failed to accept connection: {}", e),
+                },
+                Some(event) = events.recv() => self.handle_event(event),
+                Some(results) = announces.recv() => self.announced(results),
+                Some(event) = disk_events.recv() => self.handle_disk_event(event),
+                _ = tick.tick() => {
+                    if self.is_active() && !self.announcing && Instant::now() >= self.next_announce {
+                        self.announce(self.pending_event);
+                    }
+                    if self.last_choke.elapsed() >= UNCHOKE_INTERVAL {
+                        self.rechoke();
+                    }
+                    self.exchange_peers();
+                    self.connect_candidates();
+                }
+            }
+            self.update_status();
+        }
+
+        self.pause();
+        self.state = TorrentState::Stopped;
+        self.update_status();
+        // Closing the job queue lets the storage task finish the remaining jobs and flush
+        drop(self);
+        disk_task.await.map_err(io::Error::other)?
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(
+            self.state,
+            TorrentState::Downloading | TorrentState::Seeding
+        )
+    }
+
+    fn start(&mut self) {
+        if self.is_active() {
+            return;
+        }
+        self.state = if self.picker.is_complete() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+        self.pending_event = Some(Event::Started);
+        self.next_announce = Instant::now();
+        self.connect_candidates();
+    }
+
+    fn pause(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+        self.state = TorrentState::Paused;
+        self.disconnect_all();
+        self.announce(Some(Event::Stopped));
+    }
+
+    fn candidates(&self) -> MutexGuard<'_, CandidatePool> {
+        self.candidates
+            .lock()
+            .expect("candidate pool lock poisoned")
+    }
+
+    fn disconnect_all(&mut self) {
+        // Dropping the senders ends each connection task
+        let addresses = self
+            .peers
+            .drain()
+            .map(|(address, _)| address)
+            .collect::<Vec<_>>();
+        for address in addresses {
+            self.picker.remove_peer(address);
+            self.candidates().forget(address);
+        }
+    }
+
+    /// The handshake and extensions for a new connection. Private torrents don't use the
+    /// extension protocol, since peer exchange is its only use.
+    fn connection_setup(&self) -> (Handshake, Option<(ExtensionRegistry, PexState)>) {
+        let mut registry = ExtensionRegistry::new();
+        let extensions = enable_pex(
+            &mut registry,
+            self.meta_info.info(),
+            self.candidates.clone(),
+        )
+        .map(|pex| (registry, pex));
+        let handshake = Handshake::new(
+            ReservedBits::default()
+                .with_fast_extension(true)
+                .with_extension_protocol(extensions.is_some()),
+            self.meta_info.info_hash(),
+            *self.config.peer_id(),
+        );
+        (handshake, extensions)
+    }
+
+    fn accept(&mut self, stream: TcpStream, address: SocketAddr) {
+        if !self.is_active() || self.peers.len() >= self.config.max_peers() {
+            log::debug!("Refusing connection from {}", address);
+            return;
+        }
+        let (handshake, extensions) = self.connection_setup();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let result = run_peer(
+                stream,
+                address,
+                false,
+                handshake,
+                extensions,
+                events.clone(),
+            )
+            .await;
+            let _ = events.send(PeerEvent::Closed(address, result.err()));
+        });
+    }
+
+    /// Connects to candidates until the peer limit is reached.
+    fn connect_candidates(&mut self) {
+        while self.is_active() && self.peers.len() + self.connecting.len() < self.config.max_peers()
+        {
+            let address = match self.candidates().pop() {
+                Some((address, _)) => address,
+                None => return,
+            };
+            if self.peers.contains_key(&address) {
+                continue;
+            }
+            self.connecting.insert(address);
+
+            let (handshake, extensions) = self.connection_setup();
+            let events = self.events.clone();
+            tokio::spawn(async move {
+                let result = async {
+                    let stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                    run_peer(stream, address, true, handshake, extensions, events.clone()).await
+                }
+                .await;
+                let _ = events.send(PeerEvent::Closed(address, result.err()));
+            });
+        }
+    }
+
+    fn handle_event(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::Connected {
+                address,
+                sender,
+                fast_extension,
+                outgoing,
+            } => {
+                self.connecting.remove(&address);
+                if !self.is_active()
+                    || self.peers.len() >= self.config.max_peers()
+                    || self.peers.contains_key(&address)
+                {
+                    return;
+                }
+                log::debug!("Connected to {}", address);
+                let peer = PeerState {
+                    sender,
+                    fast_extension,
+                    outgoing,
+                    pex: None,
+                    choked: true,
+                    interested: false,
+                    choking: true,
+                    peer_interested: false,
+                    requested: HashSet::new(),
+                    queued: HashSet::new(),
+                    connected_at: Instant::now(),
+                    round_downloaded: 0,
+                    round_uploaded: 0,
+                };
+                // Without the fast extension, a bitfield may be left out when it's empty
+                if fast_extension || self.picker.have().count() > 0 {
+                    peer.send(self.picker.have().to_message(fast_extension));
+                }
+                self.peers.insert(address, peer);
+            }
+            PeerEvent::PexSupported(address, state) => {
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    peer.pex = Some(state);
+                }
+            }
+            PeerEvent::Message(address, message) => {
+                if self.peers.contains_key(&address) {
+                    if let Err(e) = self.handle_message(address, message) {
+                        log::debug!("Disconnecting from {}: {}", address, e);
+                        self.disconnect(address);
+                    }
+                }
+            }
+            PeerEvent::Closed(address, error) => {
+                match error {
+                    Some(e) => log::debug!("Connection to {} closed: {}", address, e),
+                    None => log::debug!("Connection to {} closed", address),
+                }
+                self.connecting.remove(&address);
+                self.disconnect(address);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, address: SocketAddr) {
+        if self.peers.remove(&address).is_some() {
+            self.picker.remove_peer(address);
+        }
+        self.candidates().forget(address);
+    }
+
+    fn handle_message(&mut self, address: SocketAddr, message: Message) -> Result<()> {
+        let piece_count = self.picker.have().len();
+        match message {
+            Message::Choke => {
+                let peer = self.peer_mut(address);
+                peer.choked = true;
+                // Without the fast extension, a choke implicitly rejects every request
+                if !peer.fast_extension {
+                    peer.requested.clear();
+                    self.picker.release_all(address);
+                }
+            }
+            Message::Unchoke => {
+                self.peer_mut(address).choked = false;
+                self.request_blocks(address);
+            }
+            Message::Interested => {
+                // Rather than wait for the next round, use a free slot straight away
+                let unchoked = self.peers.values().filter(|peer| !peer.choking).count();
+                let free_slot = unchoked < self.config.upload_slots();
+                let peer = self.peer_mut(address);
+                peer.peer_interested = true;
+                if peer.choking && free_slot {
+                    peer.choking = false;
+                    peer.send(Message::Unchoke);
+                }
+            }
+            Message::NotInterested => self.peer_mut(address).peer_interested = false,
+            Message::Have { index } => {
+                self.picker.peer_has(address, index);
+                self.update_interest(address);
+            }
+            Message::Bitfield(bytes) => {
+                let bitfield = Bitfield::from_bytes(&bytes, piece_count)?;
+                self.picker.peer_bitfield(address, bitfield)?;
+                self.update_interest(address);
+            }
+            Message::HaveAll => {
+                self.picker
+                    .peer_bitfield(address, Bitfield::full(piece_count))?;
+                self.update_interest(address);
+            }
+            Message::HaveNone => {
+                self.picker
+                    .peer_bitfield(address, Bitfield::new(piece_count))?;
+                self.update_interest(address);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => self.serve(address, index, begin, length),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive(address, index, begin, block),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                if self
+                    .peer_mut(address)
+                    .requested
+                    .remove(&(index, begin, length))
+                {
+                    self.picker.release(address, index, begin);
+                    self.request_blocks(address);
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                self.peer_mut(address)
+                    .queued
+                    .remove(&(index, begin, length));
+            }
+            Message::KeepAlive
+            | Message::SuggestPiece { .. }
+            | Message::AllowedFast { .. }
+            | Message::Extended { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn peer_mut(&mut self, address: SocketAddr) -> &mut PeerState {
+        self.peers.get_mut(&address).expect("unknown peer")
+    }
+
+    /// Tells the peer whether it has anything wanted, and requests blocks if it does.
+    fn update_interest(&mut self, address: SocketAddr) {
+        let interesting =
+            self.state == TorrentState::Downloading && self.picker.is_interesting(address);
+        let peer = self.peer_mut(address);
+        if peer.interested != interesting {
+            peer.interested = interesting;
+            peer.send(if interesting {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            });
+        }
+        self.request_blocks(address);
+    }
+
+    /// Fills the peer's request queue, if it's unchoked and has anything wanted.
+    fn request_blocks(&mut self, address: SocketAddr) {
+        let peer = &self.peers[&address];
+        if self.state != TorrentState::Downloading || peer.choked || !peer.interested {
+            return;
+        }
+        let wanted = self
+            .config
+            .request_queue()
+            .saturating_sub(peer.requested.len());
+        let blocks = self.picker.pick(address, wanted);
+        let peer = self.peer_mut(address);
+        for (index, begin, length) in blocks {
+            peer.requested.insert((index, begin, length));
+            peer.send(Message::Request {
+                index,
+                begin,
+                length,
+            });
+        }
+    }
+
+    fn serve(&mut self, address: SocketAddr, index: u32, begin: u32, length: u32) {
+        let peer = self.peer_mut(address);
+        if peer.queued.contains(&(index, begin, length)) {
+            return;
+        }
+        let refused =
+            peer.choking || length > MAX_REQUEST_LENGTH || peer.queued.len() >= MAX_QUEUED_REQUESTS;
+        if refused || !self.picker.have().has(index) {
+            let peer = &self.peers[&address];
+            if peer.fast_extension {
+                peer.send(Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+            return;
+        }
+
+        self.peer_mut(address).queued.insert((index, begin, length));
+        self.queue(DiskJob::Read {
+            peer: address,
+            index,
+            begin,
+            length,
+        });
+    }
+
+    fn receive(&mut self, address: SocketAddr, index: u32, begin: u32, block: Vec<u8>) {
+        let length = block.len() as u32;
+        let peer = self.peer_mut(address);
+        // Only blocks we asked for are written, so a peer can't overwrite anything else
+        if !peer.requested.remove(&(index, begin, length)) {
+            log::debug!(
+                "Ignoring unrequested block {} of piece {} from {}",
+                begin,
+                index,
+                address
+            );
+            return;
+        }
+        peer.round_downloaded += block.len() as u64;
+        self.downloaded += block.len() as u64;
+        if self.picker.have().has(index) {
+            return;
+        }
+
+        let received = self.picker.block_received(address, index, begin);
+        for other in received.cancels() {
+            if let Some(peer) = self.peers.get_mut(other) {
+                peer.requested.remove(&(index, begin, length));
+                peer.send(Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+        }
+        self.queue(DiskJob::Write {
+            index,
+            begin,
+            block,
+        });
+        if received.piece_complete() {
+            self.queue(DiskJob::Verify(index));
+        }
+        self.request_blocks(address);
+    }
+
+    fn queue(&self, job: DiskJob) {
+        self.disk.send(job).expect("storage task stopped");
+    }
+
+    fn handle_disk_event(&mut self, event: DiskEvent) {
+        match event {
+            DiskEvent::Read {
+                peer: address,
+                index,
+                begin,
+                length,
+                result,
+            } => {
+                // The request may have been cancelled, or the peer choked, in the meantime
+                let queued = self
+                    .peers
+                    .get_mut(&address)
+                    .is_some_and(|peer| peer.queued.remove(&(index, begin, length)));
+                if !queued {
+                    return;
+                }
+                match result {
+                    Ok(block) => {
+                        self.uploaded += block.len() as u64;
+                        let peer = self.peer_mut(address);
+                        peer.round_uploaded += block.len() as u64;
+                        peer.send(Message::Piece {
+                            index,
+                            begin,
+                            block,
+                        });
+                    }
+                    Err(e) => {
+                        log::debug!("Disconnecting from {}: {}", address, e);
+                        self.disconnect(address);
+                    }
+                }
+            }
+            DiskEvent::Verified(index, Ok(valid)) => self.verified(index, valid),
+            DiskEvent::Verified(index, Err(e)) => {
+                log::warn!("Failed to verify piece {}: {}", index, e);
+                self.verified(index, false);
+            }
+        }
+    }
+
+    fn verified(&mut self, index: u32, valid: bool) {
+        if !valid {
+            log::warn!("Piece {} failed verification", index);
+            self.picker.piece_failed(index);
+            return;
+        }
+
+        self.picker.piece_verified(index);
+        self.left -= self.meta_info.info().piece_size(index).unwrap_or(0);
+        for peer in self.peers.values() {
+            peer.send(Message::Have { index });
+        }
+
+        if self.picker.is_complete() {
+            log::info!("Download complete");
+            self.queue(DiskJob::Flush);
+            // A piece checked after pausing mustn't resume the torrent, and start picks seeding
+            if !self.is_active() {
+                return;
+            }
+            self.state = TorrentState::Seeding;
+            self.pending_event = Some(Event::Completed);
+            self.next_announce = Instant::now();
+            let addresses = self.peers.keys().copied().collect::<Vec<_>>();
+            for address in addresses {
+                self.update_interest(address);
+            }
+        }
+    }
+
+    /// Runs a round of the choker, measuring each peer's rates since the previous round.
+    fn rechoke(&mut self) {
+        let elapsed = self.last_choke.elapsed().as_secs().max(1);
+        self.last_choke = Instant::now();
+        let piece_count = self.picker.have().len().max(1) as f64;
+        let stats = self
+            .peers
+            .iter()
+            .map(|(&address, peer)| {
+                let pieces = self.picker.peer_pieces(address).map_or(0, Bitfield::count);
+                PeerStats::new(
+                    address,
+                    peer.peer_interested,
+                    peer.round_downloaded / elapsed,
+                    peer.round_uploaded / elapsed,
+                    pieces as f64 / piece_count,
+                    peer.connected_at.elapsed(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let unchoked = self.choker.run(&stats, self.state == TorrentState::Seeding);
+        for (address, peer) in &mut self.peers {
+            peer.round_downloaded = 0;
+            peer.round_uploaded = 0;
+            let choking = !unchoked.contains(address);
+            if peer.choking != choking {
+                peer.choking = choking;
+                peer.send(if choking {
+                    Message::Choke
+                } else {
+                    Message::Unchoke
+                });
+            }
+            // Choking discards the queued requests, which the fast extension rejects explicitly
+            if choking {
+                for (index, begin, length) in std::mem::take(&mut peer.queued) {
+                    if peer.fast_extension {
+                        peer.send(Message::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends each peer which supports peer exchange the changes to the peers we're connected to,
+    /// at most once every [`PEX_INTERVAL`](bittorrent_proto::peer::PEX_INTERVAL).
+    fn exchange_peers(&mut self) {
+        let now = Instant::now();
+        // Incoming connections come from ephemeral ports, so only outgoing ones are listed
+        let connected = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.outgoing)
+            .map(|(&address, _)| {
+                let flags = if self
+                    .picker
+                    .peer_pieces(address)
+                    .is_some_and(Bitfield::is_complete)
+                {
+                    PexFlags::OUTGOING.with(PexFlags::SEED)
+                } else {
+                    PexFlags::OUTGOING
+                };
+                (address, flags)
+            })
+            .collect::<HashMap<_, _>>();
+        for (address, peer) in &mut self.peers {
+            if let Some(pex) = &mut peer.pex {
+                let mut others = connected.clone();
+                others.remove(address);
+                if let Some(message) = pex.next_message(&others, now) {
+                    peer.send_extended(UT_PEX, message.encode());
+                }
+            }
+        }
+    }
+
+    /// Announces to the trackers in order in the background, until one of them answers.
+    fn announce(&mut self, event: Option<Event>) {
+        let trackers = self
+            .trackers
+            .iter()
+            .filter(|tracker| !tracker.url().is_empty())
+            .map(|tracker| {
+                (
+                    tracker.url().to_string(),
+                    tracker.tracker_id().map(str::to_string),
+                )
+            })
+            .collect::<Vec<_>>();
+        if trackers.is_empty() {
+            self.next_announce = Instant::now() + NO_TRACKER_INTERVAL;
+            return;
+        }
+
+        let params = AnnounceParams {
+            info_hash: self.meta_info.info_hash(),
+            peer_id: *self.config.peer_id(),
+            port: self.port,
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left,
+            event,
+            key: self.key,
+        };
+        let client = self.tracker_client.clone();
+        let udp_trackers = self.udp_trackers.clone();
+        let announces = self.announces.clone();
+        // Stopping announces are sent on a best-effort basis, and their results are ignored
+        self.announcing = event != Some(Event::Stopped);
+        tokio::spawn(async move {
+            let mut results = Vec::new();
+            for (url, tracker_id) in trackers {
+                let announce = announce_to(&client, &udp_trackers, &url, tracker_id, &params);
+                let result = match time::timeout(ANNOUNCE_TIMEOUT, announce).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+                };
+                let success = result.is_ok();
+                results.push((url, result));
+                if success {
+                    break;
+                }
+            }
+            if event != Some(Event::Stopped) {
+                let _ = announces.send(results);
+            }
+        });
+    }
+
+    fn announced(&mut self, results: AnnounceResults) {
+        self.announcing = false;
+        let now = Instant::now();
+        let mut next_announce = None;
+        for (url, result) in results {
+            match result {
+                Ok(response) => {
+                    let added = self
+                        .candidates()
+                        .extend(response.all_peers().map(Peer::address), PeerSource::Tracker);
+                    log::debug!("Tracker {} returned {} new peers", url, added);
+                    self.trackers.record_success(&url, &response, now);
+                    self.pending_event = None;
+                    next_announce = self.trackers.get(&url).and_then(|t| t.next_announce());
+                }
+                Err(e) => {
+                    log::warn!("Announce to {} failed: {}", url, e);
+                    self.trackers.record_failure(&url, e.to_string(), now);
+                }
+            }
+        }
+
+        // If every tracker failed, try again once the first of them is due
+        self.next_announce = next_announce
+            .or_else(|| {
+                self.trackers
+                    .iter()
+                    .filter_map(|tracker| tracker.next_announce())
+                    .min()
+            })
+            .unwrap_or(now + NO_TRACKER_INTERVAL);
+        self.connect_candidates();
+    }
+
+    fn update_status(&self) {
+        let status = TorrentStatus {
+            state: self.state,
+            pieces: self.picker.have().count(),
+            piece_count: self.picker.have().len(),
+            left: self.left,
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+            peers: self.peers.len(),
+        };
+        self.status.send_if_modified(|current| {
+            let modified = *current != status;
+            *current = status;
+            modified
+        });
+    }
+}
+
+/// The parameters shared by every announce in one round.
+#[derive(Debug, Clone)]
+struct AnnounceParams {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    event: Option<Event>,
+    key: u32,
+}
+
+/// Announces to a single HTTP or UDP tracker.
+async fn announce_to(
+    client: &TrackerClient,
+    udp_trackers: &UdpTrackers,
+    url: &str,
+    tracker_id: Option<String>,
+    params: &AnnounceParams,
+) -> Result<announce::Response> {
+    let parsed = Url::parse(url).map_err(|_| Error::InvalidTrackerUrl(url.to_string()))?;
+    match parsed.scheme() {
+        "http" | "https" => {
+            let request = announce::Request::new(
+                parsed,
+                params.info_hash,
+                params.peer_id,
+                None,
+                params.port,
+                params.uploaded,
+                params.downloaded,
+                params.left,
+                params.event,
+                true,
+                None,
+                None,
+                None,
+                tracker_id,
+            );
+            client.announce(request).await
+        }
+        "udp" => {
+            let request = AnnounceRequest::new(
+                params.info_hash,
+                params.peer_id,
+                params.downloaded,
+                params.left,
+                params.uploaded,
+                params.event,
+                None,
+                params.key,
+                None,
+                params.port,
+            );
+            let tracker = udp_tracker(udp_trackers, url, &parsed).await?;
+            let response = tracker.lock().await.announce(&request).await?;
+            Ok(announce::Response::new(
+                None,
+                None,
+                Some(response.interval() as u64),
+                None,
+                None,
+                Some(response.seeders() as u64),
+                Some(response.leechers() as u64),
+                None,
+                Some(response.peers().to_vec()),
+                None,
+            ))
+        }
+        _ => Err(Error::InvalidTrackerUrl(url.to_string())),
+    }
+}
+
+/// The client for the UDP tracker at `url`, which is created the first time it's needed.
+async fn udp_tracker(
+    trackers: &UdpTrackers,
+    url: &str,
+    parsed: &Url,
+) -> Result<Arc<sync::Mutex<UdpTracker>>> {
+    let lock = || trackers.lock().expect("UDP tracker lock poisoned");
+    if let Some(tracker) = lock().get(url) {
+        return Ok(tracker.clone());
+    }
+    let tracker = UdpTracker::new(parsed)
+        .await?
+        .with_max_retransmissions(UDP_MAX_RETRANSMISSIONS);
+    let tracker = Arc::new(sync::Mutex::new(tracker));
+    // Another announce may have created one in the meantime
+    Ok(lock().entry(url.to_string()).or_insert(tracker).clone())
+}
+
+/// Exchanges handshakes with a peer, then passes messages between it and the session until
+/// either side closes the connection. Once the peer's extension handshake shows that it
+/// supports peer exchange, the state for it is handed to the session.
+async fn run_peer(
+    stream: TcpStream,
+    address: SocketAddr,
+    outgoing: bool,
+    handshake: Handshake,
+    extensions: Option<(ExtensionRegistry, PexState)>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+) -> Result<()> {
+    let mut connection = PeerConnection::from_stream(Peer::new(None, address), stream);
+    let mut pex = None;
+    if let Some((registry, state)) = extensions {
+        connection = connection.with_extensions(registry);
+        pex = Some(state);
+    }
+    let info_hash = handshake.info_hash();
+    let remote = if outgoing {
+        connection.send_handshake(&handshake).await?;
+        connection.recv_handshake(info_hash).await?
+    } else {
+        let remote = connection.recv_handshake(info_hash).await?;
+        connection.send_handshake(&handshake).await?;
+        remote
+    };
+    if remote.peer_id() == handshake.peer_id() {
+        return Err(Error::UnexpectedMessage(String::from(
+            "handshake with own peer ID",
+        )));
+    }
+
+    let (sender, mut messages) = mpsc::unbounded_channel();
+    let connected = PeerEvent::Connected {
+        address,
+        sender,
+        fast_extension: connection.fast_extension(),
+        outgoing,
+    };
+    if events.send(connected).is_err() {
+        return Ok(());
+    }
+    loop {
+        tokio::select! {
+            message = connection.recv() => match message? {
+                Some(message) => {
+                    // The extension handshake is handled within recv, so check after each message
+                    let pex_supported = connection
+                        .extensions()
+                        .is_some_and(|registry| registry.remote_id(UT_PEX).is_some());
+                    if let Some(state) = pex.take_if(|_| pex_supported) {
+                        if events.send(PeerEvent::PexSupported(address, state)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    if events.send(PeerEvent::Message(address, message)).is_err() {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+            message = messages.recv() => match message {
+                Some(Outgoing::Message(message)) => connection.send(message).await?,
+                Some(Outgoing::Extended(name, payload)) => {
+                    connection.send_extended(name, payload).await?
+                }
+                // The session has disconnected from the peer
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn udp_tracker_test() {
+        let trackers = UdpTrackers::default();
+        let url = "udp://127.0.0.1:6969/announce";
+        let parsed = Url::parse(url).unwrap();
+        let first = udp_tracker(&trackers, url, &parsed).await.unwrap();
+        let second = udp_tracker(&trackers, url, &parsed).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let other = "udp://127.0.0.1:6970/announce";
+        let third = udp_tracker(&trackers, other, &Url::parse(other).unwrap())
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(trackers.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use bendy::{
+    decoding::{self, Decoder, FromBencode, Object},
+    value::Value,
+};
+
+/// How decoders treat dictionary keys they don't recognize.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DecodingMode {
+    /// Unknown keys are kept in the extra fields of the decoded struct, and emitted again when
+    /// it is encoded. Real-world torrents and tracker responses often carry extension keys,
+    /// so this is what [`FromBencode`] uses.
+    #[default]
+    Lenient,
+    /// Unknown keys are rejected with an `unexpected_field` error. Useful for validation tools.
+    Strict,
+}
+
+/// The unknown keys of a bencoded dictionary, along with their values. Keys are kept sorted,
+/// as bencode requires.
+pub type ExtraFields = BTreeMap<Vec<u8>, Value<'static>>;
+
+/// Decoding which can be made to accept or reject unknown keys. The [`FromBencode`] impls of
+/// types implementing this trait decode in [`DecodingMode::Lenient`] mode.
+pub trait FromBencodeWithMode: FromBencode {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> Result<Self, decoding::Error>
+    where
+        Self: Sized;
+
+    fn from_bencode_with_mode(bytes: &[u8], mode: DecodingMode) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut decoder = Decoder::new(bytes).with_max_depth(Self::EXPECTED_RECURSION_DEPTH);
+        let object = decoder.next_object()?;
+
+        object.map_or(
+            Err(decoding::Error::unexpected_token("object", "end of input")),
+            |object| Self::decode_bencode_object_with_mode(object, mode),
+        )
+    }
+
+    /// Decodes `bytes`, rejecting any keys which aren't part of the relevant specification.
+    fn from_bencode_strict(bytes: &[u8]) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::from_bencode_with_mode(bytes, DecodingMode::Strict)
+    }
+}
+
+/// Handles a dictionary key that a decoder doesn't recognize, according to `mode`.
+pub(crate) fn decode_extra_field(
+    extra_fields: &mut ExtraFields,
+    mode: DecodingMode,
+    key: &[u8],
+    value: Object,
+) -> Result<(), decoding::Error> {
+    match mode {
+        DecodingMode::Lenient => {
+            let value = Value::decode_bencode_object(value)?.into_owned();
+            extra_fields.insert(key.to_vec(), value);
+            Ok(())
+        }
+        DecodingMode::Strict => Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
+            key,
+        ))),
+    }
+}
+
+/// The length of the single bencoded value at the start of `bytes`, or `None` if it's malformed
+/// or truncated. Useful when a value is followed by raw data, as in BEP 9 metadata messages.
+pub(crate) fn value_length(bytes: &[u8]) -> Option<usize> {
+    let mut position = 0;
+    let mut depth = 0_usize;
+    loop {
+        match *bytes.get(position)? {
+            b'i' => position += bytes[position..].iter().position(|b| *b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                position += 1;
+                continue;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                position += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = bytes[position..].iter().position(|b| *b == b':')?;
+                let length = std::str::from_utf8(&bytes[position..position + colon])
+                    .ok()?
+                    .parse::<usize>()
+                    .ok()?;
+                position = position.checked_add(colon + 1)?.checked_add(length)?;
+                if position > bytes.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_length_test() {
+        assert_eq!(value_length(b"i42e"), Some(4));
+        assert_eq!(value_length(b"4:spamxyz"), Some(6));
+        assert_eq!(value_length(b"d8:msg_typei1e5:piecei0ee\x01\x02"), Some(25));
+        assert_eq!(value_length(b"ld1:ai1eelee4:rest"), Some(12));
+        assert_eq!(value_length(b"d8:msg_typei1e"), None);
+        assert_eq!(value_length(b"10:short"), None);
+        assert_eq!(value_length(b"x"), None);
+        assert_eq!(value_length(b""), None);
+    }
+}
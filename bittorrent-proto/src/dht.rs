@@ -0,0 +1,17 @@
+//! A node of the mainline DHT, as described in BEP 5, which lets peers be found without a
+//! tracker.
+
+pub mod krpc;
+mod node;
+mod node_id;
+mod peer_store;
+mod routing_table;
+mod token;
+
+pub use node::DhtNode;
+pub use node_id::{NodeId, NodeInfo, COMPACT_NODE_LENGTH};
+pub use peer_store::{
+    PeerStore, DEFAULT_MAX_PEERS_PER_TORRENT, DEFAULT_MAX_TORRENTS, PEER_LIFETIME,
+};
+pub use routing_table::{RoutingTable, K};
+pub use token::TokenManager;
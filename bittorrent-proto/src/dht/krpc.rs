@@ -0,0 +1,636 @@
+//! The KRPC protocol spoken between DHT nodes: bencoded dictionaries sent over UDP, each
+//! either a query, a response or an error.
+
+use std::{convert::TryFrom, net::SocketAddr};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::{
+    dht::{NodeId, NodeInfo},
+    error::{Error, Result},
+    InfoHash, Peer,
+};
+
+/// Error codes defined in BEP 5.
+pub const GENERIC_ERROR: i64 = 201;
+pub const SERVER_ERROR: i64 = 202;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+/// A query sent to another node. Every query carries the ID of the querying node.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    /// Asks for the nodes closest to `target`.
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    /// Asks for peers of a torrent, or the nodes closest to it if none are known.
+    GetPeers {
+        id: NodeId,
+        info_hash: InfoHash,
+    },
+    /// Announces that the querying node is a peer of a torrent. `token` must be one received
+    /// from the queried node in a recent `get_peers` response. If `implied_port` is set, the
+    /// source port of the packet is used instead of `port`.
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: InfoHash,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// A query with a method this node doesn't know, which should be answered with a
+    /// [`METHOD_UNKNOWN`] error. Its arguments besides `id` are dropped.
+    Unknown {
+        id: NodeId,
+        method: String,
+    },
+}
+
+impl Query {
+    /// The ID of the querying node.
+    pub fn id(&self) -> NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. }
+            | Query::Unknown { id, .. } => *id,
+        }
+    }
+
+    /// The method name sent in the `q` key.
+    pub fn method(&self) -> &str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Unknown { method, .. } => method,
+        }
+    }
+}
+
+/// The response to any query. Which of the optional fields are present depends on the query:
+/// `find_node` responses have `nodes`, and `get_peers` responses have a `token` along with
+/// either `values` or `nodes`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Response {
+    id: NodeId,
+    nodes: Vec<NodeInfo>,
+    values: Vec<SocketAddr>,
+    token: Option<Vec<u8>>,
+}
+
+impl Response {
+    /// `id`: the ID of the responding node.
+    ///
+    /// `nodes`: nodes close to the queried target.
+    ///
+    /// `values`: peers of the queried torrent.
+    ///
+    /// `token`: a token for a later `announce_peer` query.
+    pub fn new(
+        id: NodeId,
+        nodes: Vec<NodeInfo>,
+        values: Vec<SocketAddr>,
+        token: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            id,
+            nodes,
+            values,
+            token,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn nodes(&self) -> &[NodeInfo] {
+        &self.nodes
+    }
+
+    pub fn values(&self) -> &[SocketAddr] {
+        &self.values
+    }
+
+    pub fn token(&self) -> Option<&[u8]> {
+        self.token.as_deref()
+    }
+}
+
+/// A single KRPC message. The transaction ID is chosen by the querying node and echoed back in
+/// the response or error, to match them up.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    Query {
+        transaction_id: Vec<u8>,
+        query: Query,
+    },
+    Response {
+        transaction_id: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Message {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::from_bencode(bytes).map_err(|e| Error::InvalidDhtMessage(e.to_string()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bencode()
+            .expect("KRPC messages have sorted keys and a fixed depth")
+    }
+
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            Message::Query { transaction_id, .. }
+            | Message::Response { transaction_id, .. }
+            | Message::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+}
+
+/// The union of the keys of every query's arguments.
+#[derive(Default)]
+struct Arguments {
+    id: Option<NodeId>,
+    target: Option<NodeId>,
+    info_hash: Option<InfoHash>,
+    port: Option<u16>,
+    implied_port: bool,
+    token: Option<Vec<u8>>,
+}
+
+impl Arguments {
+    fn decode(object: Object) -> std::result::Result<Self, decoding::Error> {
+        let mut arguments = Self::default();
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"id", val) => arguments.id = Some(NodeId::try_from(val.try_into_bytes()?)?),
+                (b"implied_port", val) => {
+                    arguments.implied_port = u8::decode_bencode_object(val)? != 0
+                }
+                (b"info_hash", val) => {
+                    arguments.info_hash = Some(InfoHash::try_from(val.try_into_bytes()?)?)
+                }
+                (b"port", val) => arguments.port = Some(u16::decode_bencode_object(val)?),
+                (b"target", val) => {
+                    arguments.target = Some(NodeId::try_from(val.try_into_bytes()?)?)
+                }
+                (b"token", val) => arguments.token = Some(val.try_into_bytes()?.to_vec()),
+                // Unknown arguments are ignored, so that future extensions can be added
+                _ => {}
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn into_query(self, method: &[u8]) -> std::result::Result<Query, decoding::Error> {
+        let id = self
+            .id
+            .ok_or_else(|| decoding::Error::missing_field("id"))?;
+        let info_hash = || {
+            self.info_hash
+                .ok_or_else(|| decoding::Error::missing_field("info_hash"))
+        };
+        Ok(match method {
+            b"ping" => Query::Ping { id },
+            b"find_node" => Query::FindNode {
+                id,
+                target: self
+                    .target
+                    .ok_or_else(|| decoding::Error::missing_field("target"))?,
+            },
+            b"get_peers" => Query::GetPeers {
+                id,
+                info_hash: info_hash()?,
+            },
+            b"announce_peer" => Query::AnnouncePeer {
+                id,
+                info_hash: info_hash()?,
+                port: self.port.unwrap_or_default(),
+                implied_port: self.implied_port,
+                token: self
+                    .token
+                    .ok_or_else(|| decoding::Error::missing_field("token"))?,
+            },
+            other => Query::Unknown {
+                id,
+                method: String::from_utf8_lossy(other).into_owned(),
+            },
+        })
+    }
+}
+
+impl FromBencode for Response {
+    const EXPECTED_RECURSION_DEPTH: usize = 2;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut id = None;
+        let mut nodes = Vec::new();
+        let mut values = Vec::new();
+        let mut token = None;
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"id", val) => id = Some(NodeId::try_from(val.try_into_bytes()?)?),
+                (b"nodes", val) => nodes = NodeInfo::decode_compact_list(val.try_into_bytes()?)?,
+                (b"token", val) => token = Some(val.try_into_bytes()?.to_vec()),
+                (b"values", val) => {
+                    let mut list = val.try_into_list()?;
+                    while let Some(peer) = list.next_object()? {
+                        values.push(Peer::try_from(peer.try_into_bytes()?)?.address());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let id = id.ok_or_else(|| decoding::Error::missing_field("id"))?;
+        Ok(Self::new(id, nodes, values, token))
+    }
+}
+
+impl FromBencode for Message {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut arguments = None;
+        let mut error = None;
+        let mut method = None;
+        let mut response = None;
+        let mut transaction_id = None;
+        let mut message_type = None;
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"a", val) => arguments = Some(Arguments::decode(val)?),
+                (b"e", val) => {
+                    let mut list = val.try_into_list()?;
+                    let code = list
+                        .next_object()?
+                        .ok_or_else(|| decoding::Error::missing_field("error code"))?;
+                    let code = i64::decode_bencode_object(code)?;
+                    let message = match list.next_object()? {
+                        Some(message) => {
+                            String::from_utf8_lossy(message.try_into_bytes()?).into_owned()
+                        }
+                        None => String::new(),
+                    };
+                    error = Some((code, message));
+                }
+                (b"q", val) => method = Some(val.try_into_bytes()?.to_vec()),
+                (b"r", val) => response = Some(Response::decode_bencode_object(val)?),
+                (b"t", val) => transaction_id = Some(val.try_into_bytes()?.to_vec()),
+                (b"y", val) => message_type = Some(val.try_into_bytes()?.to_vec()),
+                _ => {}
+            }
+        }
+
+        let transaction_id = transaction_id.ok_or_else(|| decoding::Error::missing_field("t"))?;
+        let message_type = message_type.ok_or_else(|| decoding::Error::missing_field("y"))?;
+        match message_type.as_slice() {
+            b"q" => {
+                let method = method.ok_or_else(|| decoding::Error::missing_field("q"))?;
+                let arguments = arguments.ok_or_else(|| decoding::Error::missing_field("a"))?;
+                Ok(Message::Query {
+                    transaction_id,
+                    query: arguments.into_query(&method)?,
+                })
+            }
+            b"r" => Ok(Message::Response {
+                transaction_id,
+                response: response.ok_or_else(|| decoding::Error::missing_field("r"))?,
+            }),
+            b"e" => {
+                let (code, message) = error.ok_or_else(|| decoding::Error::missing_field("e"))?;
+                Ok(Message::Error {
+                    transaction_id,
+                    code,
+                    message,
+                })
+            }
+            other => Err(decoding::Error::malformed_content(
+                Error::InvalidDhtMessage(format!(
+                    "unknown message type {}",
+                    String::from_utf8_lossy(other)
+                )),
+            )),
+        }
+    }
+}
+
+impl ToBencode for Response {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_dict(|mut encoder| {
+            encoder.emit_pair_with(b"id", |e| e.emit_bytes(self.id.as_bytes()))?;
+            if !self.nodes.is_empty() {
+                let mut nodes = Vec::new();
+                for node in &self.nodes {
+                    node.encode_compact(&mut nodes);
+                }
+                encoder.emit_pair_with(b"nodes", |e| e.emit_bytes(&nodes))?;
+            }
+            if let Some(token) = &self.token {
+                encoder.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+            }
+            if !self.values.is_empty() {
+                encoder.emit_pair_with(b"values", |e| {
+                    e.emit_list(|e| {
+                        for address in &self.values {
+                            e.emit_bytes(&Peer::new(None, *address).to_compact())?;
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl ToBencode for Query {
+    const MAX_DEPTH: usize = 1;
+
+    /// Encodes the arguments of this query, which go in the `a` key of a message.
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_dict(|mut encoder| {
+            encoder.emit_pair_with(b"id", |e| e.emit_bytes(self.id().as_bytes()))?;
+            match self {
+                Query::Ping { .. } | Query::Unknown { .. } => {}
+                Query::FindNode { target, .. } => {
+                    encoder.emit_pair_with(b"target", |e| e.emit_bytes(target.as_bytes()))?;
+                }
+                Query::GetPeers { info_hash, .. } => {
+                    encoder.emit_pair_with(b"info_hash", |e| e.emit_bytes(info_hash.as_bytes()))?;
+                }
+                Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port,
+                    token,
+                    ..
+                } => {
+                    if *implied_port {
+                        encoder.emit_pair(b"implied_port", 1)?;
+                    }
+                    encoder.emit_pair_with(b"info_hash", |e| e.emit_bytes(info_hash.as_bytes()))?;
+                    encoder.emit_pair(b"port", port)?;
+                    encoder.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl ToBencode for Message {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_dict(|mut encoder| {
+            match self {
+                Message::Query { query, .. } => {
+                    encoder.emit_pair(b"a", query)?;
+                    encoder.emit_pair(b"q", query.method())?;
+                }
+                Message::Response { response, .. } => encoder.emit_pair(b"r", response)?,
+                Message::Error { code, message, .. } => {
+                    encoder.emit_pair_with(b"e", |e| {
+                        e.emit_list(|e| {
+                            e.emit_int(*code)?;
+                            e.emit_str(message)
+                        })
+                    })?;
+                }
+            }
+            encoder.emit_pair_with(b"t", |e| e.emit_bytes(self.transaction_id()))?;
+            let message_type = match self {
+                Message::Query { .. } => "q",
+                Message::Response { .. } => "r",
+                Message::Error { .. } => "e",
+            };
+            encoder.emit_pair(b"y", message_type)
+        })
+    }
+}
+
+impl From<&Message> for Vec<u8> {
+    fn from(message: &Message) -> Self {
+        message.encode()
+    }
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Self::decode(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(bytes: &[u8; 20]) -> NodeId {
+        NodeId::new(*bytes)
+    }
+
+    fn round_trip(bytes: &[u8], expected: Message) {
+        let decoded = Message::try_from(bytes).unwrap();
+        assert_eq!(decoded, expected);
+        assert_eq!(decoded.encode(), bytes);
+    }
+
+    // Examples from BEP 5
+    #[test]
+    fn ping_test() {
+        round_trip(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::Ping {
+                    id: id(b"abcdefghij0123456789"),
+                },
+            },
+        );
+        round_trip(
+            b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+            Message::Response {
+                transaction_id: b"aa".to_vec(),
+                response: Response::new(id(b"mnopqrstuvwxyz123456"), vec![], vec![], None),
+            },
+        );
+    }
+
+    #[test]
+    fn find_node_test() {
+        round_trip(
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe",
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::FindNode {
+                    id: id(b"abcdefghij0123456789"),
+                    target: id(b"mnopqrstuvwxyz123456"),
+                },
+            },
+        );
+
+        let mut bytes = b"d1:rd2:id20:0123456789abcdefghij5:nodes26:".to_vec();
+        bytes.extend_from_slice(b"mnopqrstuvwxyz123456");
+        bytes.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        bytes.extend_from_slice(b"e1:t2:aa1:y1:re");
+        round_trip(
+            &bytes,
+            Message::Response {
+                transaction_id: b"aa".to_vec(),
+                response: Response::new(
+                    id(b"0123456789abcdefghij"),
+                    vec![NodeInfo::new(
+                        id(b"mnopqrstuvwxyz123456"),
+                        "127.0.0.1:6881".parse().unwrap(),
+                    )],
+                    vec![],
+                    None,
+                ),
+            },
+        );
+    }
+
+    #[test]
+    fn get_peers_test() {
+        round_trip(
+            b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe",
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::GetPeers {
+                    id: id(b"abcdefghij0123456789"),
+                    info_hash: InfoHash::new(*b"mnopqrstuvwxyz123456"),
+                },
+            },
+        );
+        round_trip(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+            Message::Response {
+                transaction_id: b"aa".to_vec(),
+                response: Response::new(
+                    id(b"abcdefghij0123456789"),
+                    vec![],
+                    vec![
+                        Peer::try_from(&b"axje.u"[..]).unwrap().address(),
+                        Peer::try_from(&b"idhtnm"[..]).unwrap().address(),
+                    ],
+                    Some(b"aoeusnth".to_vec()),
+                ),
+            },
+        );
+    }
+
+    #[test]
+    fn announce_peer_test() {
+        round_trip(
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::AnnouncePeer {
+                    id: id(b"abcdefghij0123456789"),
+                    info_hash: InfoHash::new(*b"mnopqrstuvwxyz123456"),
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth".to_vec(),
+                },
+            },
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        round_trip(
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+            Message::Error {
+                transaction_id: b"aa".to_vec(),
+                code: GENERIC_ERROR,
+                message: String::from("A Generic Error Ocurred"),
+            },
+        );
+    }
+
+    #[test]
+    fn unknown_method_test() {
+        // Extra arguments of unknown methods aren't kept
+        let message = Message::try_from(
+            &b"d1:ad2:id20:abcdefghij01234567895:topic3:fooe1:q4:vote1:t2:aa1:y1:qe"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            message,
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::Unknown {
+                    id: id(b"abcdefghij0123456789"),
+                    method: String::from("vote"),
+                },
+            }
+        );
+        assert_eq!(
+            message.encode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe"
+        );
+        // The id is still required
+        assert!(Message::try_from(&b"d1:ade1:q4:vote1:t2:aa1:y1:qe"[..]).is_err());
+    }
+
+    #[test]
+    fn malformed_test() {
+        // Unknown keys are ignored
+        assert_eq!(
+            Message::try_from(
+                &b"d1:ad5:extrali1ee2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:UT011:y1:qe"[..]
+            )
+            .unwrap(),
+            Message::Query {
+                transaction_id: b"aa".to_vec(),
+                query: Query::Ping {
+                    id: id(b"abcdefghij0123456789"),
+                },
+            }
+        );
+        assert!(
+            Message::try_from(&b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:y1:qe"[..]).is_err()
+        );
+        assert!(Message::try_from(&b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe"[..]).is_err());
+        assert!(Message::try_from(
+            &b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe"[..]
+        )
+        .is_err());
+        assert!(Message::try_from(&b"garbage"[..]).is_err());
+    }
+}
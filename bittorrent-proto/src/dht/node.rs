@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::future;
+use tokio::{
+    net::{self, ToSocketAddrs, UdpSocket},
+    sync::oneshot,
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    dht::{
+        krpc::{self, Message, Query, Response},
+        NodeId, NodeInfo, PeerStore, RoutingTable, TokenManager, K,
+    },
+    error::{Error, Result},
+    InfoHash, MetaInfo,
+};
+
+/// How long to wait for a response to a query.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many queries an iterative lookup has in flight at once.
+const ALPHA: usize = 3;
+
+/// How often expired peers are removed from the peer store.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most peers returned in a single `get_peers` response, to keep it within one datagram.
+const MAX_VALUES: usize = 100;
+
+const MAX_DATAGRAM_LENGTH: usize = 65_507;
+
+/// A node of the mainline DHT, listening on a UDP socket.
+///
+/// Incoming queries are answered by a background task for as long as the node exists. The
+/// methods of this type send queries of its own: single queries to a known address, or
+/// iterative lookups which walk the network towards a target ID, a few queries at a time.
+#[derive(Debug)]
+pub struct DhtNode {
+    shared: Arc<Shared>,
+    query_timeout: Duration,
+    task: JoinHandle<()>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    id: NodeId,
+    socket: UdpSocket,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    routing_table: RoutingTable,
+    tokens: TokenManager,
+    peers: PeerStore,
+    pending: HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Message>)>,
+    next_transaction_id: u16,
+}
+
+/// The outcome of an iterative lookup.
+#[derive(Debug, Default)]
+struct Lookup {
+    /// The closest nodes which responded, along with any token they handed out.
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    values: Vec<SocketAddr>,
+}
+
+impl DhtNode {
+    /// Binds a socket to `address` and starts answering queries, using a random node ID.
+    pub async fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Self::from_socket(
+            UdpSocket::bind(address).await?,
+            NodeId::random(),
+        ))
+    }
+
+    /// Starts answering queries on `socket`. Must be called from within a Tokio runtime.
+    pub fn from_socket(socket: UdpSocket, id: NodeId) -> Self {
+        let now = Instant::now();
+        let shared = Arc::new(Shared {
+            id,
+            socket,
+            state: Mutex::new(State {
+                routing_table: RoutingTable::new(id),
+                tokens: TokenManager::new(now),
+                peers: PeerStore::default(),
+                pending: HashMap::new(),
+                next_transaction_id: rand::random(),
+            }),
+        });
+        let task = tokio::spawn(Arc::clone(&shared).run());
+        Self {
+            shared,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            task,
+        }
+    }
+
+    pub fn with_query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    /// A snapshot of the nodes currently known.
+    pub fn routing_table(&self) -> RoutingTable {
+        self.shared.state().routing_table.clone()
+    }
+
+    /// Sends a single query and waits for its response. The responding node is added to the
+    /// routing table.
+    pub async fn query(&self, address: SocketAddr, query: Query) -> Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let transaction_id = {
+            let mut state = self.shared.state();
+            let mut transaction_id = state.next_transaction_id;
+            while state
+                .pending
+                .contains_key(&transaction_id.to_be_bytes()[..])
+            {
+                transaction_id = transaction_id.wrapping_add(1);
+            }
+            state.next_transaction_id = transaction_id.wrapping_add(1);
+            let transaction_id = transaction_id.to_be_bytes().to_vec();
+            state
+                .pending
+                .insert(transaction_id.clone(), (address, sender));
+            transaction_id
+        };
+
+        let message = Message::Query {
+            transaction_id: transaction_id.clone(),
+            query,
+        };
+        if let Err(e) = self.shared.socket.send_to(&message.encode(), address).await {
+            self.shared.state().pending.remove(&transaction_id);
+            return Err(e.into());
+        }
+
+        match time::timeout(self.query_timeout, receiver).await {
+            Ok(Ok(Message::Response { response, .. })) => {
+                self.shared
+                    .state()
+                    .routing_table
+                    .insert(NodeInfo::new(response.id(), address), Instant::now());
+                Ok(response)
+            }
+            Ok(Ok(Message::Error { code, message, .. })) => Err(Error::DhtError(code, message)),
+            Ok(Ok(Message::Query { .. })) => unreachable!("only replies are matched to queries"),
+            Ok(Err(_)) | Err(_) => {
+                let mut state = self.shared.state();
+                state.pending.remove(&transaction_id);
+                let failed = state
+                    .routing_table
+                    .iter()
+                    .find(|node| node.address() == address)
+                    .map(NodeInfo::id);
+                if let Some(id) = failed {
+                    state.routing_table.record_failure(&id);
+                }
+                Err(Error::DhtTimeout(address))
+            }
+        }
+    }
+
+    /// Pings the node at `address`, returning its ID.
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId> {
+        let response = self.query(address, Query::Ping { id: self.id() }).await?;
+        Ok(response.id())
+    }
+
+    /// Fills the routing table by looking up the local node's ID, starting from the nodes at
+    /// `addresses`. Returns the number of nodes known afterwards, or an error if none of the
+    /// bootstrap nodes responded.
+    pub async fn bootstrap(&self, addresses: &[SocketAddr]) -> Result<usize> {
+        let target = self.id();
+        let responses = future::join_all(addresses.iter().map(|address| {
+            self.query(
+                *address,
+                Query::FindNode {
+                    id: self.id(),
+                    target,
+                },
+            )
+        }))
+        .await;
+
+        let mut responded = false;
+        for response in responses {
+            match response {
+                Ok(response) => {
+                    responded = true;
+                    let now = Instant::now();
+                    let mut state = self.shared.state();
+                    for node in response.nodes() {
+                        if node.id() != target {
+                            state.routing_table.insert(*node, now);
+                        }
+                    }
+                }
+                Err(e) => log::debug!("Bootstrap node did not respond: {}", e),
+            }
+        }
+        if !responded {
+            return Err(Error::NoDhtNodes);
+        }
+
+        self.lookup(target, None).await;
+        Ok(self.shared.state().routing_table.len())
+    }
+
+    /// Bootstraps from the `nodes` listed in a torrent, resolving their host names first.
+    pub async fn bootstrap_from_meta_info(&self, meta_info: &MetaInfo) -> Result<usize> {
+        let mut addresses = Vec::new();
+        for (host, port) in meta_info.nodes() {
+            match net::lookup_host((host.as_str(), port)).await {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(e) => log::debug!("Could not resolve DHT node {}:{}: {}", host, port, e),
+            }
+        }
+        self.bootstrap(&addresses).await
+    }
+
+    /// Finds the [`K`] nodes closest to `target` which respond to queries.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        self.lookup(target, None)
+            .await
+            .closest
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    /// Finds peers of the torrent with `info_hash`.
+    pub async fn get_peers(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        self.lookup(NodeId::from(info_hash), Some(info_hash))
+            .await
+            .values
+    }
+
+    /// Finds peers of the torrent with `info_hash`, then announces to the closest nodes that
+    /// the local node is one of them. If `port` is `None`, the nodes use the source port of
+    /// the announce instead. Returns the peers found, and fails if no node accepted the
+    /// announce.
+    pub async fn announce(
+        &self,
+        info_hash: InfoHash,
+        port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>> {
+        let lookup = self.lookup(NodeId::from(info_hash), Some(info_hash)).await;
+        let results = future::join_all(lookup.closest.into_iter().filter_map(|(node, token)| {
+            let query = Query::AnnouncePeer {
+                id: self.id(),
+                info_hash,
+                port: port.unwrap_or_default(),
+                implied_port: port.is_none(),
+                token: token?,
+            };
+            Some(self.query(node.address(), query))
+        }))
+        .await;
+
+        let mut last_error = Error::NoDhtNodes;
+        let mut accepted = false;
+        for result in results {
+            match result {
+                Ok(_) => accepted = true,
+                Err(e) => last_error = e,
+            }
+        }
+        if accepted {
+            Ok(lookup.values)
+        } else {
+            Err(last_error)
+        }
+    }
+
+    /// Walks towards `target`, querying the closest unqueried nodes until the [`K`] closest
+    /// nodes seen have all been queried. Sends `get_peers` queries if `info_hash` is set, and
+    /// `find_node` queries otherwise.
+    async fn lookup(&self, target: NodeId, info_hash: Option<InfoHash>) -> Lookup {
+        let mut candidates = BTreeMap::new();
+        for node in self.shared.state().routing_table.closest(&target, K) {
+            candidates.insert(node.id().distance(&target), node);
+        }
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut values = Vec::new();
+
+        loop {
+            let batch = candidates
+                .values()
+                .take(K)
+                .filter(|node: &&NodeInfo| !queried.contains(&node.id()))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            let query = |node: NodeInfo| {
+                let query = match info_hash {
+                    Some(info_hash) => Query::GetPeers {
+                        id: self.id(),
+                        info_hash,
+                    },
+                    None => Query::FindNode {
+                        id: self.id(),
+                        target,
+                    },
+                };
+                async move { (node, self.query(node.address(), query).await) }
+            };
+            for node in &batch {
+                queried.insert(node.id());
+            }
+            for (node, result) in future::join_all(batch.into_iter().map(query)).await {
+                let response = match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        log::debug!("Lookup query to {} failed: {}", node.address(), e);
+                        candidates.remove(&node.id().distance(&target));
+                        continue;
+                    }
+                };
+                for peer in response.values() {
+                    if !values.contains(peer) {
+                        values.push(*peer);
+                    }
+                }
+                for node in response.nodes() {
+                    if node.id() != self.id() {
+                        candidates
+                            .entry(node.id().distance(&target))
+                            .or_insert(*node);
+                    }
+                }
+                responded.insert(
+                    node.id().distance(&target),
+                    (node, response.token().map(<[u8]>::to_vec)),
+                );
+            }
+        }
+
+        Lookup {
+            closest: responded.into_values().take(K).collect(),
+            values,
+        }
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Shared {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("DHT state lock poisoned")
+    }
+
+    /// Receives messages until the node is dropped, answering queries and passing replies on
+    /// to the queries waiting for them.
+    async fn run(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_DATAGRAM_LENGTH];
+        let mut prune = time::interval(PRUNE_INTERVAL);
+        loop {
+            let (length, address) = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        log::debug!("Failed to receive DHT message: {}", e);
+                        continue;
+                    }
+                },
+                _ = prune.tick() => {
+                    self.state().peers.prune(Instant::now());
+                    continue;
+                }
+            };
+            let message = match Message::decode(&buf[..length]) {
+                Ok(message) => message,
+                Err(e) => {
+                    log::debug!("Ignoring malformed DHT message from {}: {}", address, e);
+                    continue;
+                }
+            };
+
+            match message {
+                Message::Query {
+                    transaction_id,
+                    query,
+                } => {
+                    let reply = self.answer(transaction_id, query, address);
+                    if let Err(e) = self.socket.send_to(&reply.encode(), address).await {
+                        log::debug!("Failed to answer DHT query from {}: {}", address, e);
+                    }
+                }
+                reply => {
+                    let mut state = self.state();
+                    match state.pending.remove(reply.transaction_id()) {
+                        // Replies must come from the address the query was sent to
+                        Some((expected, sender)) if expected == address => {
+                            let _ = sender.send(reply);
+                        }
+                        Some(pending) => {
+                            state
+                                .pending
+                                .insert(reply.transaction_id().to_vec(), pending);
+                            log::debug!("Ignoring DHT reply from unexpected address {}", address);
+                        }
+                        None => log::debug!("Ignoring unsolicited DHT reply from {}", address),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Builds the reply to a query received from `address`.
+    fn answer(&self, transaction_id: Vec<u8>, query: Query, address: SocketAddr) -> Message {
+        let now = Instant::now();
+        let mut state = self.state();
+        state
+            .routing_table
+            .insert(NodeInfo::new(query.id(), address), now);
+
+        let response = match query {
+            Query::Ping { .. } => Response::new(self.id, Vec::new(), Vec::new(), None),
+            Query::FindNode { target, .. } => Response::new(
+                self.id,
+                state.routing_table.closest(&target, K),
+                Vec::new(),
+                None,
+            ),
+            Query::GetPeers { info_hash, .. } => {
+                let token = state.tokens.generate(address.ip(), now);
+                let values = state.peers.peers(&info_hash, MAX_VALUES, now);
+                let nodes = if values.is_empty() {
+                    state.routing_table.closest(&NodeId::from(info_hash), K)
+                } else {
+                    Vec::new()
+                };
+                Response::new(self.id, nodes, values, Some(token))
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                if !state.tokens.validate(address.ip(), &token, now) {
+                    return Message::Error {
+                        transaction_id,
+                        code: krpc::PROTOCOL_ERROR,
+                        message: String::from("bad token"),
+                    };
+                }
+                let port = if implied_port { address.port() } else { port };
+                state
+                    .peers
+                    .announce(info_hash, SocketAddr::new(address.ip(), port), now);
+                Response::new(self.id, Vec::new(), Vec::new(), None)
+            }
+            Query::Unknown { method, .. } => {
+                log::debug!("Unknown DHT method {} from {}", method, address);
+                return Message::Error {
+                    transaction_id,
+                    code: krpc::METHOD_UNKNOWN,
+                    message: String::from("Method Unknown"),
+                };
+            }
+        };
+        Message::Response {
+            transaction_id,
+            response,
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    net::SocketAddr,
+};
+
+use crate::{
+    error::{Error, Result},
+    InfoHash, Peer,
+};
+
+/// The length of a compact node: a 20-byte node ID followed by a compact IPv4 address.
+pub const COMPACT_NODE_LENGTH: usize = 26;
+
+/// The 160-bit identifier of a DHT node. Node IDs and info hashes share the same keyspace, in
+/// which the distance between two IDs is their XOR.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct NodeId([u8; 20]);
+
+impl NodeId {
+    pub fn new(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The XOR distance to `other`. Distances compare in the same order as the numbers they
+    /// represent.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        distance
+    }
+
+    /// The number of leading bits this ID shares with `other`, or `None` if they're equal.
+    pub fn common_prefix_length(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", hex::encode(self.0))
+    }
+}
+
+impl From<InfoHash> for NodeId {
+    fn from(info_hash: InfoHash) -> Self {
+        Self(*info_hash.as_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for NodeId {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        bytes.try_into().map(Self).map_err(|_| {
+            Error::InvalidDhtMessage(format!("invalid node id length {}", bytes.len()))
+        })
+    }
+}
+
+/// The ID and address of a DHT node.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NodeInfo {
+    id: NodeId,
+    address: SocketAddr,
+}
+
+impl NodeInfo {
+    pub fn new(id: NodeId, address: SocketAddr) -> Self {
+        Self { id, address }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Parses a list of compact nodes, such as the `nodes` of a KRPC response.
+    pub fn decode_compact_list(bytes: &[u8]) -> Result<Vec<Self>> {
+        if !bytes.len().is_multiple_of(COMPACT_NODE_LENGTH) {
+            return Err(Error::InvalidDhtMessage(format!(
+                "compact node list of {} bytes is not a multiple of {}",
+                bytes.len(),
+                COMPACT_NODE_LENGTH
+            )));
+        }
+        bytes
+            .chunks(COMPACT_NODE_LENGTH)
+            .map(|chunk| {
+                Ok(Self::new(
+                    NodeId::try_from(&chunk[..20])?,
+                    Peer::try_from(&chunk[20..])?.address(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Appends the compact form of this node to `buf`. Nodes with IPv6 addresses have no
+    /// compact form in BEP 5, so nothing is appended for them.
+    pub fn encode_compact(&self, buf: &mut Vec<u8>) {
+        if self.address.is_ipv4() {
+            buf.extend_from_slice(self.id.as_bytes());
+            Peer::new(None, self.address).encode_compact(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_test() {
+        let a = NodeId::new([0; 20]);
+        let mut bytes = [0; 20];
+        bytes[2] = 0b0001_0000;
+        let b = NodeId::new(bytes);
+
+        assert_eq!(a.distance(&b), bytes);
+        assert_eq!(a.common_prefix_length(&b), Some(19));
+        assert_eq!(a.common_prefix_length(&a), None);
+        assert_eq!(a.common_prefix_length(&NodeId::new([0xff; 20])), Some(0));
+        assert!(a.distance(&b) < a.distance(&NodeId::new([0xff; 20])));
+    }
+
+    #[test]
+    fn compact_test() {
+        let node = NodeInfo::new(NodeId::new([7; 20]), "127.0.0.1:6881".parse().unwrap());
+        let mut bytes = Vec::new();
+        node.encode_compact(&mut bytes);
+        assert_eq!(bytes.len(), COMPACT_NODE_LENGTH);
+        assert_eq!(&bytes[20..], &[127, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(NodeInfo::decode_compact_list(&bytes).unwrap(), vec![node]);
+        assert!(NodeInfo::decode_compact_list(&bytes[1..]).is_err());
+
+        let mut bytes = Vec::new();
+        NodeInfo::new(NodeId::new([7; 20]), "[::1]:6881".parse().unwrap())
+            .encode_compact(&mut bytes);
+        assert!(bytes.is_empty());
+    }
+}
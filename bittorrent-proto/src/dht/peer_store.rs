@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::InfoHash;
+
+/// How long an announced peer is kept for without being announced again.
+pub const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// The most torrents a [`PeerStore`] keeps peers for by default.
+pub const DEFAULT_MAX_TORRENTS: usize = 1000;
+
+/// The most peers a [`PeerStore`] keeps for a single torrent by default.
+pub const DEFAULT_MAX_PEERS_PER_TORRENT: usize = 200;
+
+#[derive(Debug, Clone)]
+struct Torrent {
+    peers: HashMap<SocketAddr, Instant>,
+    last_announce: Instant,
+}
+
+/// The peers announced to a node with `announce_peer`, by torrent.
+///
+/// Both the number of torrents and the number of peers of each are limited, so that announces
+/// can't use up unbounded memory. When either limit is reached, expired peers are removed, and
+/// if that isn't enough, the torrent or peer announced least recently makes room. Peers expire
+/// after [`PEER_LIFETIME`], and [`PeerStore::prune`] should be called periodically to remove
+/// them from torrents which are no longer asked about.
+#[derive(Debug, Clone)]
+pub struct PeerStore {
+    torrents: HashMap<InfoHash, Torrent>,
+    max_torrents: usize,
+    max_peers: usize,
+}
+
+impl PeerStore {
+    /// `max_torrents`: the most torrents to keep peers for, at least 1.
+    ///
+    /// `max_peers`: the most peers to keep for each torrent, at least 1.
+    pub fn new(max_torrents: usize, max_peers: usize) -> Self {
+        Self {
+            torrents: HashMap::new(),
+            max_torrents: max_torrents.max(1),
+            max_peers: max_peers.max(1),
+        }
+    }
+
+    /// The number of torrents with stored peers, including peers which have expired but
+    /// haven't been pruned yet.
+    pub fn torrent_count(&self) -> usize {
+        self.torrents.len()
+    }
+
+    /// Records that `address` is a peer of `info_hash`.
+    pub fn announce(&mut self, info_hash: InfoHash, address: SocketAddr, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= self.max_torrents {
+            self.prune(now);
+            if self.torrents.len() >= self.max_torrents {
+                let oldest = self
+                    .torrents
+                    .iter()
+                    .min_by_key(|(_, torrent)| torrent.last_announce)
+                    .map(|(info_hash, _)| *info_hash);
+                if let Some(oldest) = oldest {
+                    self.torrents.remove(&oldest);
+                }
+            }
+        }
+
+        let torrent = self.torrents.entry(info_hash).or_insert_with(|| Torrent {
+            peers: HashMap::new(),
+            last_announce: now,
+        });
+        torrent.last_announce = now;
+        if !torrent.peers.contains_key(&address) && torrent.peers.len() >= self.max_peers {
+            torrent
+                .peers
+                .retain(|_, announced| !is_expired(*announced, now));
+            if torrent.peers.len() >= self.max_peers {
+                let oldest = torrent
+                    .peers
+                    .iter()
+                    .min_by_key(|(_, announced)| **announced)
+                    .map(|(address, _)| *address);
+                if let Some(oldest) = oldest {
+                    torrent.peers.remove(&oldest);
+                }
+            }
+        }
+        torrent.peers.insert(address, now);
+    }
+
+    /// Up to `limit` of the unexpired peers of `info_hash`, in no particular order.
+    pub fn peers(&self, info_hash: &InfoHash, limit: usize, now: Instant) -> Vec<SocketAddr> {
+        match self.torrents.get(info_hash) {
+            Some(torrent) => torrent
+                .peers
+                .iter()
+                .filter(|(_, announced)| !is_expired(**announced, now))
+                .map(|(address, _)| *address)
+                .take(limit)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes every expired peer, along with torrents left without any.
+    pub fn prune(&mut self, now: Instant) {
+        self.torrents.retain(|_, torrent| {
+            torrent
+                .peers
+                .retain(|_, announced| !is_expired(*announced, now));
+            !torrent.peers.is_empty()
+        });
+    }
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_TORRENTS, DEFAULT_MAX_PEERS_PER_TORRENT)
+    }
+}
+
+fn is_expired(announced: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(announced) >= PEER_LIFETIME
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn limits_test() {
+        let now = Instant::now();
+        let mut store = PeerStore::new(2, 3);
+        let (a, b, c) = (
+            InfoHash::new([1; 20]),
+            InfoHash::new([2; 20]),
+            InfoHash::new([3; 20]),
+        );
+
+        for port in 1..=5 {
+            store.announce(a, peer(port), now + Duration::from_secs(port as u64));
+        }
+        let mut peers = store.peers(&a, 10, now + Duration::from_secs(5));
+        peers.sort_unstable();
+        assert_eq!(peers, vec![peer(3), peer(4), peer(5)]);
+        assert_eq!(store.peers(&a, 2, now + Duration::from_secs(5)).len(), 2);
+
+        // The torrent announced least recently is dropped to make room
+        store.announce(b, peer(1), now + Duration::from_secs(6));
+        store.announce(a, peer(5), now + Duration::from_secs(7));
+        store.announce(c, peer(1), now + Duration::from_secs(8));
+        assert_eq!(store.torrent_count(), 2);
+        assert!(store.peers(&b, 10, now).is_empty());
+        assert_eq!(store.peers(&a, 10, now).len(), 3);
+        assert_eq!(store.peers(&c, 10, now), vec![peer(1)]);
+    }
+
+    #[test]
+    fn expiry_test() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        let (a, b) = (InfoHash::new([1; 20]), InfoHash::new([2; 20]));
+        store.announce(a, peer(1), now);
+        store.announce(a, peer(2), now + PEER_LIFETIME / 2);
+        store.announce(b, peer(1), now);
+
+        let later = now + PEER_LIFETIME;
+        assert_eq!(store.peers(&a, 10, later), vec![peer(2)]);
+        assert!(store.peers(&b, 10, later).is_empty());
+
+        store.prune(later);
+        assert_eq!(store.torrent_count(), 1);
+        assert_eq!(store.peers(&a, 10, now), vec![peer(2)]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::time::{Duration, Instant};
+
+use crate::dht::{NodeId, NodeInfo};
+
+/// The number of nodes kept in each bucket.
+pub const K: usize = 8;
+
+/// How many consecutive queries a node may fail before it can be replaced by a new node.
+const MAX_FAILURES: u32 = 2;
+
+/// How long since a node was last heard from before it's considered questionable.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// The nodes known to a DHT node, sorted into k-buckets by their distance from its own ID.
+///
+/// Bucket `i` holds nodes whose IDs share exactly `i` leading bits with the local ID, so each
+/// bucket covers half the keyspace of the one before it. A full bucket only accepts a new node
+/// once one of its own has repeatedly failed to respond.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    /// `id`: the ID of the local node.
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Vec::is_empty)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.buckets.iter().flatten().map(|entry| &entry.node)
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.iter().any(|node| node.id() == *id)
+    }
+
+    /// Records that `node` was heard from at `now`. Returns `false` if its bucket is full of
+    /// good nodes, in which case it isn't added.
+    pub fn insert(&mut self, node: NodeInfo, now: Instant) -> bool {
+        let bucket = match self.bucket_mut(&node.id()) {
+            Some(bucket) => bucket,
+            None => return false,
+        };
+
+        // Buckets are kept in order of when their nodes were last seen, oldest first
+        if let Some(index) = bucket.iter().position(|entry| entry.node.id() == node.id()) {
+            bucket.remove(index);
+        } else if bucket.len() >= K {
+            match bucket.iter().position(Entry::is_bad) {
+                Some(index) => {
+                    bucket.remove(index);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        });
+        true
+    }
+
+    /// Records that the node with `id` failed to respond to a query.
+    pub fn record_failure(&mut self, id: &NodeId) {
+        if let Some(entry) = self
+            .bucket_mut(id)
+            .and_then(|bucket| bucket.iter_mut().find(|entry| entry.node.id() == *id))
+        {
+            entry.failures += 1;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> Option<NodeInfo> {
+        let bucket = self.bucket_mut(id)?;
+        let index = bucket.iter().position(|entry| entry.node.id() == *id)?;
+        Some(bucket.remove(index).node)
+    }
+
+    /// Nodes which haven't been heard from in a while and should be pinged to check that
+    /// they're still alive.
+    pub fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| now.saturating_duration_since(entry.last_seen) >= QUESTIONABLE_AFTER)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// Up to `count` nodes which aren't known to be bad, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect::<Vec<_>>();
+        nodes.sort_unstable_by_key(|node| node.id().distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn bucket_mut(&mut self, id: &NodeId) -> Option<&mut Vec<Entry>> {
+        // The local node never goes in its own table
+        let index = self.id.common_prefix_length(id)?;
+        Some(&mut self.buckets[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn node(first_byte: u8, last_byte: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        let address = SocketAddr::from(([127, 0, 0, 1], 6881 + last_byte as u16));
+        NodeInfo::new(NodeId::new(id), address)
+    }
+
+    #[test]
+    fn insert_test() {
+        let mut table = RoutingTable::new(NodeId::new([0; 20]));
+        let now = Instant::now();
+        assert!(!table.insert(NodeInfo::new(table.id(), node(0, 0).address()), now));
+        assert!(table.is_empty());
+
+        // Every node starting with a set bit goes in the first bucket
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80, i), now));
+        }
+        assert!(!table.insert(node(0x80, 100), now));
+        assert!(table.insert(node(0x40, 100), now));
+        assert_eq!(table.len(), K + 1);
+
+        // Re-inserting a known node refreshes it rather than adding it again
+        assert!(table.insert(node(0x80, 3), now));
+        assert_eq!(table.len(), K + 1);
+
+        // A bad node makes room for a new one
+        for _ in 0..MAX_FAILURES {
+            table.record_failure(&node(0x80, 5).id());
+        }
+        assert!(table.insert(node(0x80, 100), now));
+        assert!(!table.contains(&node(0x80, 5).id()));
+        assert!(table.contains(&node(0x80, 100).id()));
+
+        assert_eq!(table.remove(&node(0x40, 100).id()), Some(node(0x40, 100)));
+        assert_eq!(table.remove(&node(0x40, 100).id()), None);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn closest_test() {
+        let mut table = RoutingTable::new(NodeId::new([0; 20]));
+        let now = Instant::now();
+        for first_byte in [0x80, 0x40, 0x20, 0x10, 0x08] {
+            table.insert(node(first_byte, 1), now);
+        }
+        table.record_failure(&node(0x20, 1).id());
+        table.record_failure(&node(0x20, 1).id());
+
+        let closest = table.closest(&node(0x30, 0).id(), 3);
+        assert_eq!(closest, vec![node(0x10, 1), node(0x08, 1), node(0x40, 1)]);
+        assert_eq!(table.closest(&node(0x30, 0).id(), 10).len(), 4);
+
+        assert!(table.questionable(now).is_empty());
+        assert_eq!(table.questionable(now + QUESTIONABLE_AFTER).len(), 5);
+    }
+}
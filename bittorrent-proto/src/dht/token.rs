@@ -0,0 +1,98 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use sha1::Sha1;
+
+/// How often the secret used to generate tokens changes. Tokens generated with the previous
+/// secret are still accepted, so a token stays valid for between one and two periods.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+const TOKEN_LENGTH: usize = 8;
+
+/// Generates the tokens handed out in `get_peers` responses, and checks the tokens sent back in
+/// `announce_peer` queries. A token is a hash of the querying IP address and a secret, so only
+/// the node it was given to can use it.
+#[derive(Debug, Clone)]
+pub struct TokenManager {
+    secret: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl TokenManager {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    /// The token for `ip`, rotating the secret first if it's due.
+    pub fn generate(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        token(ip, &self.secret)
+    }
+
+    /// Whether `token` was generated for `ip` with the current or previous secret.
+    pub fn validate(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        token == self::token(ip, &self.secret).as_slice()
+            || token == self::token(ip, &self.previous).as_slice()
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.rotated);
+        if elapsed >= TOKEN_ROTATION * 2 {
+            // Both secrets are stale, so no outstanding token should be accepted
+            self.previous = rand::random();
+            self.secret = rand::random();
+            self.rotated = now;
+        } else if elapsed >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated += TOKEN_ROTATION;
+        }
+    }
+}
+
+fn token(ip: IpAddr, secret: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(ip) => hasher.update(&ip.octets()),
+        IpAddr::V6(ip) => hasher.update(&ip.octets()),
+    }
+    hasher.update(secret);
+    hasher.digest().bytes()[..TOKEN_LENGTH].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_test() {
+        let now = Instant::now();
+        let mut tokens = TokenManager::new(now);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let token = tokens.generate(ip, now);
+        assert_eq!(token.len(), TOKEN_LENGTH);
+
+        assert!(tokens.validate(ip, &token, now));
+        assert!(!tokens.validate(IpAddr::from([10, 0, 0, 2]), &token, now));
+        assert!(!tokens.validate(ip, b"aoeusnth", now));
+
+        // Still valid after one rotation, but not after two
+        let later = now + TOKEN_ROTATION;
+        assert!(tokens.validate(ip, &token, later));
+        assert_ne!(tokens.generate(ip, later), token);
+        assert!(!tokens.validate(ip, &token, later + TOKEN_ROTATION));
+
+        // Tokens from long ago are rejected too
+        let mut tokens = TokenManager::new(now);
+        let token = tokens.generate(ip, now);
+        assert!(!tokens.validate(ip, &token, now + TOKEN_ROTATION * 10));
+    }
+}
@@ -1,15 +1,79 @@
 use thiserror::Error;
 
+use crate::{InfoHash, ValidationIssue};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("invalid metadata: {0}")]
     InvalidMetadata(String),
+    #[error("invalid torrent: {}", join_issues(.0))]
+    InvalidTorrent(Vec<ValidationIssue>),
     #[error("invalid socket address: {0}:{1}")]
     InvalidSocketAddress(String, u16),
-    #[error("invalid compact peer length: expected 6, got {0}")]
+    #[error("invalid compact peer length: expected 6 or 18, got {0}")]
     InvalidCompactPeerLength(usize),
+    #[error("unknown peer message id: {0}")]
+    UnknownMessageId(u8),
+    #[error("truncated peer message: expected {0} bytes, got {1}")]
+    TruncatedMessage(usize, usize),
+    #[error("peer message length mismatch: prefix says {0} bytes, got {1}")]
+    MessageLengthMismatch(u32, usize),
+    #[error("invalid length for peer message with id {0}: {1}")]
+    InvalidMessageLength(u8, u32),
+    #[error("invalid bitfield: {0}")]
+    InvalidBitfield(String),
+    #[error("invalid block: piece {0}, offset {1}, length {2}")]
+    InvalidBlock(u32, u32, u32),
+    #[error("peer message of {0} bytes exceeds maximum frame length of {1} bytes")]
+    FrameTooLarge(usize, usize),
+    #[error("invalid extension message: {0}")]
+    InvalidExtensionMessage(String),
+    #[error("unexpected peer message: {0}")]
+    UnexpectedMessage(String),
+    #[error("peer does not support extension {0}")]
+    UnsupportedExtension(String),
+    #[error("invalid handshake length: expected 68, got {0}")]
+    InvalidHandshakeLength(usize),
+    #[error("unsupported peer protocol: '{0}'")]
+    InvalidProtocol(String),
+    #[error("info hash mismatch: expected {0}, got {1}")]
+    InfoHashMismatch(InfoHash, InfoHash),
+    #[error("invalid info hash: {0}")]
+    InvalidInfoHash(String),
+    #[error("invalid magnet link: {0}")]
+    InvalidMagnetLink(String),
+    #[error("invalid tracker URL: {0}")]
+    InvalidTrackerUrl(String),
+    #[error("tracker does not support scrape: {0}")]
+    ScrapeNotSupported(String),
+    #[error("invalid tracker response: {0}")]
+    InvalidTrackerResponse(String),
+    #[error("tracker returned failure: {0}")]
+    TrackerFailure(String),
+    #[error("no response from tracker after {0} attempts")]
+    TrackerTimeout(u32),
+    #[error("invalid DHT message: {0}")]
+    InvalidDhtMessage(String),
+    #[error("DHT node returned error {0}: {1}")]
+    DhtError(i64, String),
+    #[error("no response from DHT node {0}")]
+    DhtTimeout(std::net::SocketAddr),
+    #[error("no DHT nodes responded")]
+    NoDhtNodes,
+    #[error("torrent has stopped")]
+    TorrentStopped,
+    #[error(transparent)]
+    HttpError(#[from] reqwest::Error),
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
+
+fn join_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
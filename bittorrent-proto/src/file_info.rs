@@ -1,13 +1,17 @@
 use bendy::{
     decoding::{self, FromBencode, Object},
     encoding::{self, SingleItemEncoder, ToBencode},
+    value::Value,
 };
 
-#[derive(Debug, PartialEq, Eq)]
+use crate::bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FileInfo {
     length: u64,
     path: Vec<String>,
     md5sum: Option<String>,
+    extra_fields: ExtraFields,
 }
 
 impl FileInfo {
@@ -22,6 +26,7 @@ impl FileInfo {
             length,
             path,
             md5sum,
+            extra_fields: ExtraFields::new(),
         }
     }
 
@@ -36,13 +41,31 @@ impl FileInfo {
     pub fn md5sum(&self) -> Option<&str> {
         self.md5sum.as_deref()
     }
+
+    /// Whether this is a padding file, marked by a `p` in its `attr` as described in BEP 47.
+    /// Padding files only hold zeros, and aren't meant to be written to disk.
+    pub fn is_padding(&self) -> bool {
+        matches!(self.extra_fields.get(&b"attr"[..]), Some(Value::Bytes(attr)) if attr.contains(&b'p'))
+    }
+
+    /// Keys which aren't part of the specification, such as `attr` or `sha1`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
+
+    pub fn extra_fields_mut(&mut self) -> &mut ExtraFields {
+        &mut self.extra_fields
+    }
 }
 
 impl ToBencode for FileInfo {
     const MAX_DEPTH: usize = 2;
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
-        encoder.emit_dict(|mut encoder| {
+        encoder.emit_unsorted_dict(|encoder| {
+            for (key, value) in self.extra_fields() {
+                encoder.emit_pair(key, value)?;
+            }
             encoder.emit_pair(b"length", self.length())?;
             if let Some(md5sum) = self.md5sum() {
                 encoder.emit_pair(b"md5sum", md5sum)?;
@@ -57,12 +80,25 @@ impl FromBencode for FileInfo {
     const EXPECTED_RECURSION_DEPTH: usize = 2;
 
     fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for FileInfo {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> Result<Self, decoding::Error>
     where
         Self: Sized,
     {
         let mut length = None;
         let mut path = None;
         let mut md5sum = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -70,18 +106,16 @@ impl FromBencode for FileInfo {
                 (b"length", val) => length = Some(u64::decode_bencode_object(val)?),
                 (b"path", val) => path = Some(Vec::decode_bencode_object(val)?),
                 (b"md5sum", val) => md5sum = Some(String::decode_bencode_object(val)?),
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
-                }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
         let length = length.ok_or_else(|| decoding::Error::missing_field("length"))?;
         let path = path.ok_or_else(|| decoding::Error::missing_field("path"))?;
 
-        Ok(Self::new(length, path, md5sum))
+        let mut file_info = Self::new(length, path, md5sum);
+        file_info.extra_fields = extra_fields;
+        Ok(file_info)
     }
 }
 
@@ -119,4 +153,31 @@ mod tests {
         // missing 'length' field
         assert!(FileInfo::from_bencode(b"d4:pathl7:testing7:another9:final.txtee").is_err());
     }
+
+    #[test]
+    fn extra_fields_test() {
+        let bencode = b"d4:attr1:x6:lengthi123456e4:pathl7:testing7:another9:final.txte3:zzzi1ee";
+        let decoded = FileInfo::from_bencode(bencode).unwrap();
+        assert_eq!(decoded.length(), 123456);
+        assert_eq!(
+            decoded.extra_fields().keys().cloned().collect::<Vec<_>>(),
+            vec![b"attr".to_vec(), b"zzz".to_vec()]
+        );
+        assert_eq!(&decoded.to_bencode().unwrap(), bencode);
+        assert!(!decoded.is_padding());
+        assert!(
+            FileInfo::from_bencode(b"d4:attr2:hp6:lengthi1e4:pathl4:.padee")
+                .unwrap()
+                .is_padding()
+        );
+
+        assert!(FileInfo::from_bencode_strict(bencode).is_err());
+        assert_eq!(
+            FileInfo::from_bencode_strict(
+                b"d6:lengthi123456e4:pathl7:testing7:another9:final.txtee"
+            )
+            .unwrap(),
+            file_info()
+        );
+    }
 }
@@ -0,0 +1,286 @@
+use std::{collections::BTreeMap, convert::TryInto};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::{Error, Result},
+};
+
+/// The deepest a file may be nested within a file tree.
+pub const MAX_FILE_TREE_DEPTH: usize = 32;
+
+/// A file in the `file tree` of a v2 torrent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileTreeEntry {
+    length: u64,
+    pieces_root: Option<[u8; 32]>,
+    extra_fields: ExtraFields,
+}
+
+impl FileTreeEntry {
+    /// `length`: the length of the file, in bytes.
+    ///
+    /// `pieces_root`: the root of the merkle tree of the file's 16 KiB blocks, which is only
+    /// missing for empty files.
+    pub fn new(length: u64, pieces_root: Option<[u8; 32]>) -> Self {
+        Self {
+            length,
+            pieces_root,
+            extra_fields: ExtraFields::new(),
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn pieces_root(&self) -> Option<&[u8; 32]> {
+        self.pieces_root.as_ref()
+    }
+
+    /// Keys which aren't part of the specification, such as `attr`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
+
+    pub fn extra_fields_mut(&mut self) -> &mut ExtraFields {
+        &mut self.extra_fields
+    }
+}
+
+impl ToBencode for FileTreeEntry {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_unsorted_dict(|encoder| {
+            for (key, value) in self.extra_fields() {
+                encoder.emit_pair(key, value)?;
+            }
+            encoder.emit_pair(b"length", self.length())?;
+            if let Some(pieces_root) = self.pieces_root() {
+                encoder.emit_pair_with(b"pieces root", |e| e.emit_bytes(pieces_root))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for FileTreeEntry {
+    const EXPECTED_RECURSION_DEPTH: usize = 2;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for FileTreeEntry {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut length = None;
+        let mut pieces_root = None;
+        let mut extra_fields = ExtraFields::new();
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"length", val) => length = Some(u64::decode_bencode_object(val)?),
+                (b"pieces root", val) => {
+                    let bytes = val.try_into_bytes()?;
+                    pieces_root = Some(bytes.try_into().map_err(|_| {
+                        decoding::Error::malformed_content(Error::InvalidMetadata(format!(
+                            "'pieces root' must be 32 bytes, got {}",
+                            bytes.len()
+                        )))
+                    })?);
+                }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
+            }
+        }
+
+        let length = length.ok_or_else(|| decoding::Error::missing_field("length"))?;
+        let mut entry = Self::new(length, pieces_root);
+        entry.extra_fields = extra_fields;
+        Ok(entry)
+    }
+}
+
+/// The `file tree` of a v2 torrent, as described in BEP 52. Each directory maps path
+/// components to the files and directories inside it. Files are laid out in the order of
+/// their paths, which is the order of [`FileTree::files`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FileTree {
+    File(FileTreeEntry),
+    Directory(BTreeMap<String, FileTree>),
+}
+
+impl FileTree {
+    /// Builds a tree from the path and entry of each file. Fails if a path is empty, or if a
+    /// file would also be a directory.
+    pub fn from_files(
+        files: impl IntoIterator<Item = (Vec<String>, FileTreeEntry)>,
+    ) -> Result<Self> {
+        let mut root = Self::Directory(BTreeMap::new());
+        for (path, entry) in files {
+            let conflict = || Error::InvalidMetadata(format!("conflicting file path {:?}", path));
+            let (name, parents) = path
+                .split_last()
+                .ok_or_else(|| Error::InvalidMetadata(String::from("empty file path")))?;
+            let mut directory = &mut root;
+            for component in parents {
+                directory = match directory {
+                    Self::Directory(children) => children
+                        .entry(component.clone())
+                        .or_insert_with(|| Self::Directory(BTreeMap::new())),
+                    Self::File(_) => return Err(conflict()),
+                };
+            }
+            match directory {
+                Self::Directory(children) if !children.contains_key(name) => {
+                    children.insert(name.clone(), Self::File(entry));
+                }
+                _ => return Err(conflict()),
+            }
+        }
+        Ok(root)
+    }
+
+    /// Every file in the tree with its path, in the order they're laid out in the torrent.
+    pub fn files(&self) -> Vec<(Vec<String>, &FileTreeEntry)> {
+        let mut files = Vec::new();
+        self.collect_files(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect_files<'a>(
+        &'a self,
+        prefix: &mut Vec<String>,
+        files: &mut Vec<(Vec<String>, &'a FileTreeEntry)>,
+    ) {
+        match self {
+            Self::File(entry) => files.push((prefix.clone(), entry)),
+            Self::Directory(children) => {
+                for (name, child) in children {
+                    prefix.push(name.clone());
+                    child.collect_files(prefix, files);
+                    prefix.pop();
+                }
+            }
+        }
+    }
+}
+
+impl ToBencode for FileTree {
+    const MAX_DEPTH: usize = MAX_FILE_TREE_DEPTH + 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        match self {
+            // Files are marked by a single entry with an empty key
+            Self::File(entry) => encoder.emit_dict(|mut e| e.emit_pair(b"", entry)),
+            Self::Directory(children) => encoder.emit_dict(|mut e| {
+                for (name, child) in children {
+                    e.emit_pair(name.as_bytes(), child)?;
+                }
+                Ok(())
+            }),
+        }
+    }
+}
+
+impl FromBencode for FileTree {
+    const EXPECTED_RECURSION_DEPTH: usize = MAX_FILE_TREE_DEPTH + 3;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for FileTree {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut entry = None;
+        let mut children = BTreeMap::new();
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"", val) => {
+                    entry = Some(FileTreeEntry::decode_bencode_object_with_mode(val, mode)?)
+                }
+                (name, val) => {
+                    let name = String::from_utf8(name.to_vec())
+                        .map_err(decoding::Error::malformed_content)?;
+                    children.insert(name, Self::decode_bencode_object_with_mode(val, mode)?);
+                }
+            }
+        }
+
+        match entry {
+            Some(entry) if children.is_empty() => Ok(Self::File(entry)),
+            Some(_) => Err(decoding::Error::malformed_content(Error::InvalidMetadata(
+                String::from("file tree entry is both a file and a directory"),
+            ))),
+            None => Ok(Self::Directory(children)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &[&str]) -> Vec<String> {
+        path.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn encoding_test() {
+        let tree = FileTree::from_files(vec![
+            (path(&["dir", "b"]), FileTreeEntry::new(5, Some([1; 32]))),
+            (path(&["a"]), FileTreeEntry::new(0, None)),
+        ])
+        .unwrap();
+        let mut expected =
+            b"d1:ad0:d6:lengthi0eee3:dird1:bd0:d6:lengthi5e11:pieces root32:".to_vec();
+        expected.extend_from_slice(&[1; 32]);
+        expected.extend_from_slice(b"eeee");
+        assert_eq!(tree.to_bencode().unwrap(), expected);
+        assert_eq!(FileTree::from_bencode(&expected).unwrap(), tree);
+
+        let files = tree.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, path(&["a"]));
+        assert_eq!(files[1].0, path(&["dir", "b"]));
+        assert_eq!(files[1].1.pieces_root(), Some(&[1; 32]));
+
+        assert!(FileTree::from_files(vec![
+            (path(&["a"]), FileTreeEntry::new(0, None)),
+            (path(&["a", "b"]), FileTreeEntry::new(0, None)),
+        ])
+        .is_err());
+        assert!(FileTree::from_files(vec![(vec![], FileTreeEntry::new(0, None))]).is_err());
+
+        // A file can't also have children, and roots must be 32 bytes
+        assert!(FileTree::from_bencode(b"d1:ad0:d6:lengthi0ee1:bdeee").is_err());
+        assert!(FileTree::from_bencode(b"d1:ad0:d6:lengthi1e11:pieces root1:xeee").is_err());
+    }
+}
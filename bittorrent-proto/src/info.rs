@@ -1,11 +1,141 @@
+use std::{collections::HashSet, convert::TryInto, fmt, ops::Range, sync::OnceLock};
+
+use either::Either;
+
 use bendy::{
     decoding::{self, FromBencode, Object},
     encoding::{self, SingleItemEncoder, ToBencode},
 };
 
-use crate::{error::*, file_info::FileInfo};
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::*,
+    file_info::FileInfo,
+    file_tree::FileTree,
+    merkle::MERKLE_BLOCK_SIZE,
+    validation::{is_safe_component, normalize_path, ValidationIssue},
+};
+
+/// The length of each piece hash in `pieces`.
+pub const PIECE_HASH_LENGTH: usize = 20;
+
+/// The largest valid `piece length`. Blocks are addressed with 32-bit offsets within a piece,
+/// so pieces must fit in a `u32`.
+pub const MAX_PIECE_LENGTH: u64 = 1 << 31;
+
+/// A contiguous part of a single file, as covered by a piece or block.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FileSegment {
+    file_index: usize,
+    offset: u64,
+    length: u64,
+}
+
+impl FileSegment {
+    /// `file_index`: the index of the file, in the order given by [`Info::files`]. This is
+    /// always 0 for single-file torrents.
+    ///
+    /// `offset`: the position of the segment within the file, in bytes.
+    ///
+    /// `length`: the length of the segment, in bytes.
+    pub fn new(file_index: usize, offset: u64, length: u64) -> Self {
+        Self {
+            file_index,
+            offset,
+            length,
+        }
+    }
+
+    pub fn file_index(&self) -> usize {
+        self.file_index
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+/// Where every file lies within the torrent, worked out once since nearly every piece lookup
+/// needs it.
+#[derive(Debug, Clone)]
+struct Layout {
+    /// The offset and length of every file, counted from the start of the first piece. Files
+    /// of v2-only torrents each start on a new piece, and are otherwise contiguous. Offsets
+    /// saturate at `u64::MAX` for torrents too large to address.
+    spans: Vec<(u64, u64)>,
+    /// The combined length of all files, or `None` if it doesn't fit in a `u64`.
+    total_length: Option<u64>,
+    /// The number of pieces needed to hold each file of a v2-only torrent.
+    aligned_piece_count: u64,
+}
+
+impl Layout {
+    fn new(info: &Info) -> Self {
+        let aligned = !info.is_v1() && info.piece_length > 0;
+        let mut offset = 0_u64;
+        let mut total_length = Some(0_u64);
+        let mut aligned_piece_count = 0_u64;
+        let spans = info
+            .file_lengths()
+            .map(|length| {
+                let start = offset;
+                total_length = total_length.and_then(|total| total.checked_add(length));
+                let span = if aligned {
+                    let pieces = length.div_ceil(info.piece_length);
+                    aligned_piece_count = aligned_piece_count.saturating_add(pieces);
+                    pieces.saturating_mul(info.piece_length)
+                } else {
+                    length
+                };
+                offset = offset.saturating_add(span);
+                (start, length)
+            })
+            .collect();
+        Self {
+            spans,
+            total_length,
+            aligned_piece_count,
+        }
+    }
+
+    /// The index of the first file which ends after `offset`, or the number of files if none
+    /// do.
+    fn file_at(&self, offset: u64) -> usize {
+        self.spans
+            .partition_point(|&(start, length)| start.saturating_add(length) <= offset)
+    }
+
+    fn end(&self) -> u64 {
+        self.spans
+            .last()
+            .map_or(0, |&(start, length)| start.saturating_add(length))
+    }
+}
+
+/// The [`Layout`] of an [`Info`], built on first use. It's derived from the other fields, so
+/// it's left out of comparisons.
+#[derive(Clone, Default)]
+struct LayoutCache(OnceLock<Layout>);
+
+impl PartialEq for LayoutCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for LayoutCache {}
+
+impl fmt::Debug for LayoutCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayoutCache").finish_non_exhaustive()
+    }
+}
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Info {
     name: String,
     piece_length: u64,
@@ -14,6 +144,10 @@ pub struct Info {
     files: Option<Vec<FileInfo>>,
     private: Option<bool>,
     md5sum: Option<String>,
+    meta_version: Option<u64>,
+    file_tree: Option<FileTree>,
+    extra_fields: ExtraFields,
+    layout: LayoutCache,
 }
 
 impl Info {
@@ -64,10 +198,56 @@ impl Info {
                 files,
                 private,
                 md5sum,
+                meta_version: None,
+                file_tree: None,
+                extra_fields: ExtraFields::new(),
+                layout: LayoutCache::default(),
             })
         }
     }
 
+    /// Creates the info dictionary of a v2-only torrent, as described in BEP 52.
+    ///
+    /// `piece_length`: as for v1 torrents, but at least 16 KiB. Each file starts at the
+    /// beginning of a piece.
+    ///
+    /// `file_tree`: every file of the torrent. The name of a single-file torrent is the only
+    /// entry in the tree.
+    pub fn new_v2(
+        name: String,
+        piece_length: u64,
+        file_tree: FileTree,
+        private: Option<bool>,
+    ) -> Result<Self> {
+        if let FileTree::File(_) = file_tree {
+            return Err(Error::InvalidMetadata(String::from(
+                "'file tree' must be a dictionary of paths",
+            )));
+        }
+        Ok(Self {
+            name,
+            piece_length,
+            pieces: Vec::new(),
+            length: None,
+            files: None,
+            private,
+            md5sum: None,
+            meta_version: Some(2),
+            file_tree: Some(file_tree),
+            extra_fields: ExtraFields::new(),
+            layout: LayoutCache::default(),
+        })
+    }
+
+    /// Adds a v2 `file tree` to a v1 dictionary, making a hybrid torrent. The v1 files should
+    /// include padding files, so that every file starts at the beginning of a piece in both.
+    pub fn with_file_tree(mut self, file_tree: FileTree) -> Self {
+        self.meta_version = Some(2);
+        self.file_tree = Some(file_tree);
+        self.layout = LayoutCache::default();
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -95,13 +275,250 @@ impl Info {
     pub fn md5sum(&self) -> Option<&str> {
         self.md5sum.as_deref()
     }
+
+    /// The version of the torrent format, which is 2 for v2 and hybrid torrents.
+    pub fn meta_version(&self) -> Option<u64> {
+        self.meta_version
+    }
+
+    pub fn file_tree(&self) -> Option<&FileTree> {
+        self.file_tree.as_ref()
+    }
+
+    /// Whether the dictionary has the v1 fields: `pieces`, and `length` or `files`.
+    pub fn is_v1(&self) -> bool {
+        self.length.is_some() || self.files.is_some()
+    }
+
+    /// Whether the dictionary has the v2 fields: `meta version` 2 and `file tree`.
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    /// Whether the dictionary has both the v1 and v2 fields.
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    /// Keys which aren't part of the specification, such as `source`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
+
+    pub fn extra_fields_mut(&mut self) -> &mut ExtraFields {
+        &mut self.extra_fields
+    }
+
+    /// Checks the structure of the dictionary, so that it can be safely used to lay out files on
+    /// disk. Every problem found is returned in [`Error::InvalidTorrent`], not only the first.
+    pub fn validate(&self) -> Result<()> {
+        let issues = self.issues();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidTorrent(issues))
+        }
+    }
+
+    pub(crate) fn issues(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let valid_piece_length = self.piece_length.is_power_of_two()
+            && self.piece_length <= MAX_PIECE_LENGTH
+            && (!self.is_v2() || self.piece_length >= MERKLE_BLOCK_SIZE);
+        if !valid_piece_length {
+            issues.push(ValidationIssue::InvalidPieceLength(self.piece_length));
+        }
+        let total_length = self.layout().total_length;
+        if !self.is_v1() {
+            // v2-only torrents keep their hashes in `piece layers` instead
+        } else if !self.pieces.len().is_multiple_of(PIECE_HASH_LENGTH) {
+            issues.push(ValidationIssue::InvalidPiecesLength(self.pieces.len()));
+        } else if let (true, Some(total_length)) = (valid_piece_length, total_length) {
+            let expected = total_length.div_ceil(self.piece_length);
+            let actual = self.piece_count() as u64;
+            if expected != actual {
+                issues.push(ValidationIssue::PieceCountMismatch { expected, actual });
+            }
+        }
+        if total_length.is_none() {
+            issues.push(ValidationIssue::TotalLengthOverflow);
+        }
+
+        if !is_safe_component(&self.name) {
+            issues.push(ValidationIssue::UnsafeName(self.name.clone()));
+        }
+        let v1_paths = self
+            .files()
+            .unwrap_or_default()
+            .iter()
+            .map(|file| file.path().to_vec());
+        check_paths(v1_paths, &mut issues);
+        if let Some(file_tree) = self.file_tree() {
+            check_paths(
+                file_tree.files().into_iter().map(|(path, _)| path),
+                &mut issues,
+            );
+        }
+        issues
+    }
+
+    /// The length of every file, in the order they're laid out in the torrent. These are the
+    /// files of `length` or `files` when present, including any padding files, and otherwise
+    /// those of the `file tree`.
+    pub fn file_lengths(&self) -> impl Iterator<Item = u64> + '_ {
+        match self.file_tree() {
+            Some(file_tree) if !self.is_v1() => Either::Right(
+                file_tree
+                    .files()
+                    .into_iter()
+                    .map(|(_, entry)| entry.length()),
+            ),
+            _ => Either::Left(
+                self.length.into_iter().chain(
+                    self.files()
+                        .unwrap_or_default()
+                        .iter()
+                        .map(FileInfo::length),
+                ),
+            ),
+        }
+    }
+
+    fn layout(&self) -> &Layout {
+        self.layout.0.get_or_init(|| Layout::new(self))
+    }
+
+    /// The combined length of all files, in bytes, saturating at `u64::MAX` for torrents too
+    /// large to address.
+    pub fn total_length(&self) -> u64 {
+        self.layout().total_length.unwrap_or(u64::MAX)
+    }
+
+    /// The number of pieces, according to `pieces`. For v2-only torrents this is the number of
+    /// pieces needed to hold each file.
+    pub fn piece_count(&self) -> u32 {
+        if self.is_v1() {
+            (self.pieces.len() / PIECE_HASH_LENGTH) as u32
+        } else {
+            self.layout().aligned_piece_count.min(u32::MAX as u64) as u32
+        }
+    }
+
+    /// The SHA-1 hash of the piece at `index`, which v2-only torrents don't have.
+    pub fn piece_hash(&self, index: u32) -> Option<&[u8; 20]> {
+        let start = index as usize * PIECE_HASH_LENGTH;
+        self.pieces
+            .get(start..start + PIECE_HASH_LENGTH)
+            .map(|hash| hash.try_into().unwrap())
+    }
+
+    /// The offset of the piece at `index`, counted from the beginning of the first file.
+    fn piece_offset(&self, index: u32) -> Option<u64> {
+        (index as u64).checked_mul(self.piece_length)
+    }
+
+    /// The length of the piece at `index`, which is less than `piece_length` only for the
+    /// last piece, or for v2-only torrents the last piece of each file.
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        if index >= self.piece_count() {
+            return None;
+        }
+        let start = self.piece_offset(index)?;
+        let end = if self.is_v1() {
+            self.total_length()
+        } else {
+            let layout = self.layout();
+            let (file_start, length) = *layout.spans.get(layout.file_at(start))?;
+            file_start.saturating_add(length)
+        };
+        end.checked_sub(start)
+            .filter(|&remaining| remaining > 0)
+            .map(|remaining| self.piece_length.min(remaining))
+    }
+
+    /// The length of the last piece, or 0 if there are no pieces.
+    pub fn last_piece_length(&self) -> u64 {
+        self.piece_count()
+            .checked_sub(1)
+            .and_then(|index| self.piece_size(index))
+            .unwrap_or(0)
+    }
+
+    /// The parts of each file covered by `length` bytes starting at `offset`, counted from the
+    /// beginning of the first file. Empty files, and the gaps after files of v2-only torrents,
+    /// are skipped. Returns `None` if the range goes past the end of the torrent.
+    pub fn segments(&self, offset: u64, length: u64) -> Option<Vec<FileSegment>> {
+        let end = offset.checked_add(length)?;
+        let layout = self.layout();
+        if end > layout.end() {
+            return None;
+        }
+
+        let mut segments = Vec::new();
+        let first = layout.file_at(offset);
+        for (file_index, &(file_start, file_length)) in layout.spans.iter().enumerate().skip(first)
+        {
+            let file_end = file_start.saturating_add(file_length);
+            if file_start >= end {
+                break;
+            }
+            if file_end > offset && file_length > 0 {
+                let start = offset.max(file_start);
+                segments.push(FileSegment::new(
+                    file_index,
+                    start - file_start,
+                    end.min(file_end) - start,
+                ));
+            }
+        }
+        Some(segments)
+    }
+
+    /// The parts of each file covered by the piece at `index`.
+    pub fn piece_segments(&self, index: u32) -> Option<Vec<FileSegment>> {
+        let size = self.piece_size(index)?;
+        self.segments(self.piece_offset(index)?, size)
+    }
+
+    /// The parts of each file covered by the block of `length` bytes at `begin` within the
+    /// piece at `index`. Returns `None` if the block doesn't fit within the piece.
+    pub fn block_segments(&self, index: u32, begin: u32, length: u32) -> Option<Vec<FileSegment>> {
+        let size = self.piece_size(index)?;
+        if begin as u64 + length as u64 > size {
+            return None;
+        }
+        self.segments(
+            self.piece_offset(index)?.checked_add(begin as u64)?,
+            length as u64,
+        )
+    }
+
+    /// The pieces which hold any part of the file at `file_index`. Empty files cover no pieces.
+    pub fn file_piece_range(&self, file_index: usize) -> Option<Range<u32>> {
+        let (start, length) = *self.layout().spans.get(file_index)?;
+        if self.piece_length == 0 {
+            return None;
+        }
+        let first = (start / self.piece_length) as u32;
+        if length == 0 {
+            return Some(first..first);
+        }
+        let last = ((start.saturating_add(length) - 1) / self.piece_length) as u32;
+        Some(first..last + 1)
+    }
 }
 
 impl ToBencode for Info {
-    const MAX_DEPTH: usize = 4;
+    const MAX_DEPTH: usize = FileTree::MAX_DEPTH + 1;
 
     fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
-        encoder.emit_dict(|mut encoder| {
+        encoder.emit_unsorted_dict(|encoder| {
+            for (key, value) in self.extra_fields() {
+                encoder.emit_pair(key, value)?;
+            }
+            if let Some(file_tree) = self.file_tree() {
+                encoder.emit_pair(b"file tree", file_tree)?;
+            }
             if let Some(files) = self.files() {
                 encoder.emit_pair(b"files", files)?;
             }
@@ -111,12 +528,17 @@ impl ToBencode for Info {
             if let Some(md5sum) = self.md5sum() {
                 encoder.emit_pair(b"md5sum", md5sum)?;
             }
+            if let Some(meta_version) = self.meta_version() {
+                encoder.emit_pair(b"meta version", meta_version)?;
+            }
             encoder.emit_pair(b"name", self.name())?;
             encoder.emit_pair(b"piece length", self.piece_length())?;
-            encoder.emit_pair_with(b"pieces", |encoder| {
-                encoder.emit_bytes(self.pieces())?;
-                Ok(())
-            })?;
+            if self.is_v1() {
+                encoder.emit_pair_with(b"pieces", |encoder| {
+                    encoder.emit_bytes(self.pieces())?;
+                    Ok(())
+                })?;
+            }
             if let Some(private) = self.private() {
                 encoder.emit_pair(b"private", if private { 1 } else { 0 })?;
             }
@@ -126,9 +548,21 @@ impl ToBencode for Info {
 }
 
 impl FromBencode for Info {
-    const EXPECTED_RECURSION_DEPTH: usize = 4;
+    const EXPECTED_RECURSION_DEPTH: usize = FileTree::EXPECTED_RECURSION_DEPTH + 1;
 
     fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for Info {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
     where
         Self: Sized,
     {
@@ -139,6 +573,9 @@ impl FromBencode for Info {
         let mut files = None;
         let mut private = None;
         let mut md5sum = None;
+        let mut meta_version = None;
+        let mut file_tree = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -147,32 +584,61 @@ impl FromBencode for Info {
                 (b"piece length", val) => piece_length = Some(u64::decode_bencode_object(val)?),
                 (b"pieces", val) => pieces = Some(val.try_into_bytes()?.to_vec()),
                 (b"length", val) => length = Some(u64::decode_bencode_object(val)?),
-                (b"files", val) => files = Some(Vec::decode_bencode_object(val)?),
+                (b"files", val) => {
+                    let mut list = val.try_into_list()?;
+                    let mut file_list = Vec::new();
+                    while let Some(file) = list.next_object()? {
+                        file_list.push(FileInfo::decode_bencode_object_with_mode(file, mode)?);
+                    }
+                    files = Some(file_list);
+                }
                 (b"private", val) => private = Some(u8::decode_bencode_object(val)? == 1),
                 (b"md5sum", val) => md5sum = Some(String::decode_bencode_object(val)?),
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
+                (b"meta version", val) => meta_version = Some(u64::decode_bencode_object(val)?),
+                (b"file tree", val) => {
+                    file_tree = Some(FileTree::decode_bencode_object_with_mode(val, mode)?)
                 }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
         let name = name.ok_or_else(|| decoding::Error::missing_field("name"))?;
         let piece_length =
             piece_length.ok_or_else(|| decoding::Error::missing_field("piece length"))?;
-        let pieces = pieces.ok_or_else(|| decoding::Error::missing_field("pieces"))?;
 
-        Ok(
-            Self::new(name, piece_length, pieces, length, files, private, md5sum)
-                .map_err(|err| decoding::Error::malformed_content(err))?,
-        )
+        let mut info = match file_tree {
+            Some(file_tree) if length.is_none() && files.is_none() => {
+                Self::new_v2(name, piece_length, file_tree, private)
+            }
+            file_tree => {
+                let pieces = pieces.ok_or_else(|| decoding::Error::missing_field("pieces"))?;
+                Self::new(name, piece_length, pieces, length, files, private, md5sum)
+                    .map(|info| Self { file_tree, ..info })
+            }
+        }
+        .map_err(decoding::Error::malformed_content)?;
+        info.meta_version = meta_version;
+        info.extra_fields = extra_fields;
+        Ok(info)
+    }
+}
+
+/// Reports the unsafe and duplicate paths among `paths`.
+fn check_paths(paths: impl Iterator<Item = Vec<String>>, issues: &mut Vec<ValidationIssue>) {
+    let mut normalized = HashSet::new();
+    for (file_index, path) in paths.enumerate() {
+        if path.is_empty() || !path.iter().all(|component| is_safe_component(component)) {
+            issues.push(ValidationIssue::UnsafePath { file_index, path });
+        } else if !normalized.insert(normalize_path(&path)) {
+            issues.push(ValidationIssue::DuplicatePath { file_index, path });
+        }
     }
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use crate::FileTreeEntry;
 
     pub(crate) fn info() -> Info {
         Info::new(
@@ -210,4 +676,315 @@ pub(crate) mod tests {
         )
         .is_err());
     }
+
+    fn multi_file_info() -> Info {
+        let files = [("a", 10), ("b", 0), ("c", 25), ("d", 3)]
+            .iter()
+            .map(|(name, length)| FileInfo::new(*length, vec![name.to_string()], None))
+            .collect();
+        let pieces = (0..3).flat_map(|i| [i; 20]).collect();
+        Info::new(
+            String::from("dir"),
+            16,
+            pieces,
+            None,
+            Some(files),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn piece_test() {
+        let info = multi_file_info();
+        assert_eq!(info.total_length(), 38);
+        assert_eq!(info.piece_count(), 3);
+        assert_eq!(info.piece_hash(1), Some(&[1; 20]));
+        assert_eq!(info.piece_hash(3), None);
+        assert_eq!(info.piece_size(0), Some(16));
+        assert_eq!(info.piece_size(2), Some(6));
+        assert_eq!(info.piece_size(3), None);
+        assert_eq!(info.last_piece_length(), 6);
+
+        let info = Info::new(
+            String::from("file"),
+            16,
+            vec![0; 40],
+            Some(32),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(info.total_length(), 32);
+        assert_eq!(info.last_piece_length(), 16);
+
+        // Offsets past the end of the address space don't overflow
+        let info = Info::new(
+            String::from("file"),
+            1 << 63,
+            vec![0; 60],
+            Some(u64::MAX),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(info.piece_size(1), Some((1 << 63) - 1));
+        assert_eq!(info.piece_size(2), None);
+        assert_eq!(info.piece_segments(2), None);
+        assert_eq!(info.block_segments(2, 0, 1), None);
+    }
+
+    #[test]
+    fn layout_cache_test() {
+        let info = multi_file_info();
+        assert_eq!(info.piece_size(0), Some(16));
+        // The cached layout isn't part of the dictionary
+        assert_eq!(info, multi_file_info());
+        assert_eq!(info.clone().piece_segments(2), info.piece_segments(2));
+
+        let hybrid = info.with_file_tree(v2_info().file_tree().unwrap().clone());
+        assert_eq!(hybrid.total_length(), 38);
+    }
+
+    #[test]
+    fn segments_test() {
+        let info = multi_file_info();
+        // Piece 0 spans all of 'a', skips the empty 'b', and ends partway through 'c'
+        assert_eq!(
+            info.piece_segments(0).unwrap(),
+            vec![FileSegment::new(0, 0, 10), FileSegment::new(2, 0, 6)]
+        );
+        assert_eq!(
+            info.piece_segments(2).unwrap(),
+            vec![FileSegment::new(2, 22, 3), FileSegment::new(3, 0, 3)]
+        );
+        assert_eq!(
+            info.block_segments(1, 4, 8).unwrap(),
+            vec![FileSegment::new(2, 10, 8)]
+        );
+        assert_eq!(info.block_segments(2, 4, 4), None);
+        assert_eq!(info.segments(30, 9), None);
+        assert_eq!(info.segments(38, 0), Some(vec![]));
+
+        assert_eq!(info.file_piece_range(0), Some(0..1));
+        assert_eq!(info.file_piece_range(1), Some(0..0));
+        assert_eq!(info.file_piece_range(2), Some(0..3));
+        assert_eq!(info.file_piece_range(3), Some(2..3));
+        assert_eq!(info.file_piece_range(4), None);
+
+        let single = super::tests::info();
+        assert_eq!(
+            single.segments(0, 321).unwrap(),
+            vec![FileSegment::new(0, 0, 321)]
+        );
+    }
+
+    #[test]
+    fn validate_test() {
+        assert!(multi_file_info().validate().is_ok());
+
+        let files = [
+            vec!["a"],
+            vec!["..", "etc", "passwd"],
+            vec![],
+            vec!["/abs"],
+            vec!["dir", "", "b"],
+            vec!["A"],
+            vec!["a."],
+        ]
+        .iter()
+        .map(|path| FileInfo::new(1, path.iter().map(|c| c.to_string()).collect(), None))
+        .collect();
+        let info = Info::new(
+            String::from(".."),
+            1000,
+            vec![0; 45],
+            None,
+            Some(files),
+            None,
+            None,
+        )
+        .unwrap();
+        let issues = match info.validate() {
+            Err(Error::InvalidTorrent(issues)) => issues,
+            other => panic!("expected validation issues, got {:?}", other),
+        };
+        let path = |p: &[&str]| p.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::InvalidPieceLength(1000),
+                ValidationIssue::InvalidPiecesLength(45),
+                ValidationIssue::UnsafeName(String::from("..")),
+                ValidationIssue::UnsafePath {
+                    file_index: 1,
+                    path: path(&["..", "etc", "passwd"])
+                },
+                ValidationIssue::UnsafePath {
+                    file_index: 2,
+                    path: vec![]
+                },
+                ValidationIssue::UnsafePath {
+                    file_index: 3,
+                    path: path(&["/abs"])
+                },
+                ValidationIssue::UnsafePath {
+                    file_index: 4,
+                    path: path(&["dir", "", "b"])
+                },
+                ValidationIssue::DuplicatePath {
+                    file_index: 5,
+                    path: path(&["A"])
+                },
+                ValidationIssue::DuplicatePath {
+                    file_index: 6,
+                    path: path(&["a."])
+                },
+            ]
+        );
+
+        // 38 bytes in 16 byte pieces needs 3 hashes
+        let mut info = multi_file_info();
+        info.pieces.truncate(40);
+        assert_eq!(
+            info.issues(),
+            vec![ValidationIssue::PieceCountMismatch {
+                expected: 3,
+                actual: 2
+            }]
+        );
+
+        // File lengths which add up past u64::MAX are rejected rather than wrapping around
+        let files = [u64::MAX, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, length)| FileInfo::new(*length, vec![i.to_string()], None))
+            .collect();
+        let info = Info::new(
+            String::from("dir"),
+            1 << 20,
+            vec![0; 20],
+            None,
+            Some(files),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(info.issues(), vec![ValidationIssue::TotalLengthOverflow]);
+
+        // Pieces longer than a u32 can't be addressed by the wire protocol
+        let info = Info::new(
+            String::from("file"),
+            1 << 32,
+            vec![0; 20],
+            Some(1),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            info.issues(),
+            vec![ValidationIssue::InvalidPieceLength(1 << 32)]
+        );
+    }
+
+    fn v2_info() -> Info {
+        let files = [("a", 20_000), ("b", 0), ("c", 40_000)]
+            .iter()
+            .map(|(name, length)| {
+                let root = if *length > 0 { Some([1; 32]) } else { None };
+                (vec![name.to_string()], FileTreeEntry::new(*length, root))
+            });
+        let file_tree = FileTree::from_files(files).unwrap();
+        Info::new_v2(String::from("dir"), 1 << 15, file_tree, None).unwrap()
+    }
+
+    #[test]
+    fn v2_test() {
+        let info = v2_info();
+        assert!(info.is_v2() && !info.is_v1() && !info.is_hybrid());
+        let bencode = info.to_bencode().unwrap();
+        assert!(!bencode.windows(8).any(|w| w == b"6:pieces"));
+        assert_eq!(Info::from_bencode(&bencode).unwrap(), info);
+        assert!(info.validate().is_ok());
+
+        // Every file starts on a new piece, so 'c' starts at 32768 rather than 20000
+        assert_eq!(info.total_length(), 60_000);
+        assert_eq!(info.piece_count(), 3);
+        assert_eq!(info.piece_hash(0), None);
+        assert_eq!(info.piece_size(0), Some(20_000));
+        assert_eq!(info.piece_size(1), Some(1 << 15));
+        assert_eq!(info.piece_size(2), Some(40_000 - (1 << 15)));
+        assert_eq!(
+            info.segments(10_000, 30_000).unwrap(),
+            vec![
+                FileSegment::new(0, 10_000, 10_000),
+                FileSegment::new(2, 0, 7232)
+            ]
+        );
+        assert_eq!(info.file_piece_range(2), Some(1..3));
+        assert_eq!(info.segments(0, 72_769), None);
+
+        // Hybrid torrents keep the v1 layout, which has padding files to line up the pieces
+        let v1 = Info::new(
+            String::from("dir"),
+            1 << 15,
+            vec![0; 60],
+            None,
+            Some(vec![
+                FileInfo::new(20_000, vec![String::from("a")], None),
+                FileInfo::new(
+                    12_768,
+                    vec![String::from(".pad"), String::from("12768")],
+                    None,
+                ),
+                FileInfo::new(0, vec![String::from("b")], None),
+                FileInfo::new(40_000, vec![String::from("c")], None),
+            ]),
+            None,
+            None,
+        )
+        .unwrap();
+        let hybrid = v1.with_file_tree(info.file_tree().unwrap().clone());
+        assert!(hybrid.is_hybrid());
+        assert_eq!(hybrid.meta_version(), Some(2));
+        assert_eq!(hybrid.piece_count(), 3);
+        assert_eq!(hybrid.file_piece_range(3), Some(1..3));
+        assert_eq!(
+            Info::from_bencode_strict(&hybrid.to_bencode().unwrap()).unwrap(),
+            hybrid
+        );
+
+        // v2 pieces must be at least one merkle block
+        let small = Info::new_v2(
+            String::from("dir"),
+            1 << 13,
+            info.file_tree().unwrap().clone(),
+            None,
+        )
+        .unwrap();
+        assert!(matches!(small.validate(), Err(Error::InvalidTorrent(_))));
+    }
+
+    #[test]
+    fn extra_fields_test() {
+        let bencode = b"d5:filesld4:attr1:p6:lengthi10e4:pathl4:.padeed6:lengthi321e4:pathl4:fileeee4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblah6:source3:abce";
+        let info = Info::from_bencode(bencode).unwrap();
+        assert_eq!(info.files().unwrap().len(), 2);
+        assert_eq!(info.extra_fields().len(), 1);
+        assert!(info.files().unwrap()[0]
+            .extra_fields()
+            .contains_key(&b"attr"[..]));
+        assert_eq!(&info.to_bencode().unwrap(), bencode);
+
+        assert!(Info::from_bencode_strict(bencode).is_err());
+        // unknown keys in nested dictionaries are rejected too
+        let nested = b"d5:filesld4:attr1:p6:lengthi10e4:pathl4:.padeee4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblahe";
+        assert!(Info::from_bencode(nested).is_ok());
+        assert!(Info::from_bencode_strict(nested).is_err());
+    }
 }
@@ -0,0 +1,248 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use data_encoding::BASE32;
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+/// Characters which are percent-encoded in tracker requests; everything except the
+/// unreserved characters of RFC 3986.
+pub(crate) const URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// The SHA1 hash of the bencoded `info` dictionary of a torrent, which identifies it to
+/// trackers and peers.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct InfoHash([u8; 20]);
+
+impl InfoHash {
+    pub fn new(bytes: [u8; 20]) -> Self {
+        Self(bytes)
+    }
+
+    /// Hashes the raw bytes of a bencoded `info` dictionary. These should be the exact bytes
+    /// found in the torrent, since re-encoding a decoded `Info` may not reproduce them.
+    pub fn from_info_bytes(info: &[u8]) -> Self {
+        Self(Sha1::from(info).digest().bytes())
+    }
+
+    /// Parses a 40-character hexadecimal info hash, in either case.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidInfoHash(hex.to_string()))?;
+        Self::try_from(bytes.as_slice()).map_err(|_| Error::InvalidInfoHash(hex.to_string()))
+    }
+
+    /// Parses a 32-character base32 info hash, in either case.
+    pub fn from_base32(base32: &str) -> Result<Self> {
+        let bytes = BASE32
+            .decode(base32.to_ascii_uppercase().as_bytes())
+            .map_err(|_| Error::InvalidInfoHash(base32.to_string()))?;
+        Self::try_from(bytes.as_slice()).map_err(|_| Error::InvalidInfoHash(base32.to_string()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The lowercase hexadecimal form of this hash.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// The uppercase base32 form of this hash, as found in some magnet links.
+    pub fn to_base32(&self) -> String {
+        BASE32.encode(&self.0)
+    }
+
+    /// The percent-encoded form of this hash, as sent in tracker requests.
+    pub fn url_encode(&self) -> String {
+        percent_encode(&self.0, URL_ENCODE_SET).to_string()
+    }
+}
+
+impl Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHash({})", self)
+    }
+}
+
+impl FromStr for InfoHash {
+    type Err = Error;
+
+    /// Parses either the hexadecimal or the base32 form of an info hash.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.len() {
+            40 => Self::from_hex(s),
+            32 => Self::from_base32(s),
+            _ => Err(Error::InvalidInfoHash(s.to_string())),
+        }
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for InfoHash {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        bytes
+            .try_into()
+            .map(Self::new)
+            .map_err(|_| Error::InvalidInfoHash(hex::encode(bytes)))
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The SHA-256 hash of the bencoded `info` dictionary of a v2 torrent, as described in BEP 52.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct InfoHashV2([u8; 32]);
+
+impl InfoHashV2 {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Hashes the raw bytes of a bencoded `info` dictionary, like [`InfoHash::from_info_bytes`].
+    pub fn from_info_bytes(info: &[u8]) -> Self {
+        Self(Sha256::digest(info).into())
+    }
+
+    /// The first 20 bytes of this hash, which stand in for the info hash of v2 torrents in
+    /// handshakes, tracker requests and the DHT.
+    pub fn truncated(&self) -> InfoHash {
+        InfoHash::new(self.0[..20].try_into().unwrap())
+    }
+
+    /// Parses a 64-character hexadecimal info hash, in either case.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidInfoHash(hex.to_string()))?;
+        Self::try_from(bytes.as_slice()).map_err(|_| Error::InvalidInfoHash(hex.to_string()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The lowercase hexadecimal form of this hash.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl Display for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for InfoHashV2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InfoHashV2({})", self)
+    }
+}
+
+impl FromStr for InfoHashV2 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_hex(s)
+    }
+}
+
+impl From<[u8; 32]> for InfoHashV2 {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for InfoHashV2 {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        bytes
+            .try_into()
+            .map(Self::new)
+            .map_err(|_| Error::InvalidInfoHash(hex::encode(bytes)))
+    }
+}
+
+impl AsRef<[u8]> for InfoHashV2 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_hash() -> InfoHash {
+        InfoHash::from_hex("80bbb5c4986d3dd4c52f8dab517451203c4fab1d").unwrap()
+    }
+
+    #[test]
+    fn hashing_test() {
+        assert_eq!(
+            InfoHash::from_info_bytes(b"d4:name4:teste").to_hex(),
+            Sha1::from(b"d4:name4:teste").digest().to_string()
+        );
+        let v2 = InfoHashV2::from_info_bytes(b"d4:name4:teste");
+        assert_eq!(v2.as_bytes()[..], Sha256::digest(b"d4:name4:teste")[..]);
+        assert_eq!(v2.truncated().as_bytes()[..], v2.as_bytes()[..20]);
+    }
+
+    #[test]
+    fn conversion_test() {
+        let info_hash = info_hash();
+        assert_eq!(info_hash.as_bytes()[..3], [0x80, 0xbb, 0xb5]);
+        assert_eq!(info_hash.to_base32(), "QC53LREYNU65JRJPRWVVC5CREA6E7KY5");
+        assert_eq!(
+            info_hash.url_encode(),
+            "%80%BB%B5%C4%98m%3D%D4%C5%2F%8D%ABQtQ%20%3CO%AB%1D"
+        );
+        assert_eq!(
+            info_hash.to_string(),
+            "80bbb5c4986d3dd4c52f8dab517451203c4fab1d"
+        );
+
+        assert_eq!(
+            "80BBB5C4986D3DD4C52F8DAB517451203C4FAB1D"
+                .parse::<InfoHash>()
+                .unwrap(),
+            info_hash
+        );
+        assert_eq!(
+            "qc53lreynu65jrjprwvvc5crea6e7ky5"
+                .parse::<InfoHash>()
+                .unwrap(),
+            info_hash
+        );
+        assert!("80bbb5c4".parse::<InfoHash>().is_err());
+        assert!(InfoHash::from_hex("zzbbb5c4986d3dd4c52f8dab517451203c4fab1d").is_err());
+        assert!(InfoHash::try_from(&[0_u8; 19][..]).is_err());
+    }
+}
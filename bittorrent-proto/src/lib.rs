@@ -1,11 +1,24 @@
+pub mod bencode;
+pub mod dht;
 pub mod error;
 mod file_info;
+mod file_tree;
 mod info;
+mod info_hash;
+mod magnet;
+pub mod merkle;
 mod meta_info;
-mod peer;
+pub mod peer;
+mod torrent_builder;
 pub mod tracker;
+mod validation;
 
 pub use file_info::FileInfo;
-pub use info::Info;
-pub use meta_info::MetaInfo;
+pub use file_tree::{FileTree, FileTreeEntry, MAX_FILE_TREE_DEPTH};
+pub use info::{FileSegment, Info, MAX_PIECE_LENGTH};
+pub use info_hash::{InfoHash, InfoHashV2};
+pub use magnet::MagnetLink;
+pub use meta_info::{MetaInfo, PieceLayers};
 pub use peer::Peer;
+pub use torrent_builder::{TorrentBuilder, TorrentVersion};
+pub use validation::ValidationIssue;
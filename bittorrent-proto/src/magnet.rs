@@ -0,0 +1,403 @@
+use std::{
+    fmt::{self, Display},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use bendy::value::Value;
+use percent_encoding::utf8_percent_encode;
+use reqwest::Url;
+
+use crate::{
+    error::{Error, Result},
+    info_hash::URL_ENCODE_SET,
+    InfoHash, InfoHashV2, MetaInfo,
+};
+
+/// The `urn:btmh:` prefix is followed by a multihash; this is the code and length of SHA-256.
+const SHA256_MULTIHASH_PREFIX: &str = "1220";
+
+/// A magnet link, as described in BEP 9, which identifies a torrent by its info hash so that
+/// its metadata can be fetched from peers.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MagnetLink {
+    info_hash: Option<InfoHash>,
+    info_hash_v2: Option<InfoHashV2>,
+    display_name: Option<String>,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    peers: Vec<String>,
+    select_only: Vec<RangeInclusive<usize>>,
+}
+
+impl MagnetLink {
+    /// `info_hash`: the v1 info hash of the torrent (`xt=urn:btih:`).
+    ///
+    /// `info_hash_v2`: the v2 info hash of the torrent (`xt=urn:btmh:`). At least one of the
+    /// two hashes must be present.
+    ///
+    /// `display_name`: a name to show while the metadata is being fetched (`dn`).
+    ///
+    /// `trackers`: tracker URLs (`tr`).
+    ///
+    /// `web_seeds`: web seed URLs, as described in BEP 19 (`ws`).
+    ///
+    /// `peers`: peer addresses in `host:port` form (`x.pe`).
+    ///
+    /// `select_only`: the indices of the files to download, as described in BEP 53 (`so`).
+    /// An empty list selects every file.
+    pub fn new(
+        info_hash: Option<InfoHash>,
+        info_hash_v2: Option<InfoHashV2>,
+        display_name: Option<String>,
+        trackers: Vec<String>,
+        web_seeds: Vec<String>,
+        peers: Vec<String>,
+        select_only: Vec<RangeInclusive<usize>>,
+    ) -> Result<Self> {
+        if info_hash.is_none() && info_hash_v2.is_none() {
+            return Err(Error::InvalidMagnetLink(String::from(
+                "missing info hash (xt)",
+            )));
+        }
+        if let Some(range) = select_only.iter().find(|range| range.is_empty()) {
+            return Err(Error::InvalidMagnetLink(format!(
+                "invalid file range {}-{}",
+                range.start(),
+                range.end()
+            )));
+        }
+
+        Ok(Self {
+            info_hash,
+            info_hash_v2,
+            display_name,
+            trackers,
+            web_seeds,
+            peers,
+            select_only,
+        })
+    }
+
+    pub fn info_hash(&self) -> Option<InfoHash> {
+        self.info_hash
+    }
+
+    pub fn info_hash_v2(&self) -> Option<InfoHashV2> {
+        self.info_hash_v2
+    }
+
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    pub fn trackers(&self) -> &[String] {
+        &self.trackers
+    }
+
+    pub fn web_seeds(&self) -> &[String] {
+        &self.web_seeds
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn select_only(&self) -> &[RangeInclusive<usize>] {
+        &self.select_only
+    }
+
+    /// Whether the file at `index` should be downloaded, according to `select_only`.
+    pub fn is_selected(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|range| range.contains(&index))
+    }
+}
+
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(info_hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", info_hash));
+        }
+        if let Some(info_hash_v2) = self.info_hash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{}{}",
+                SHA256_MULTIHASH_PREFIX, info_hash_v2
+            ));
+        }
+        if let Some(display_name) = self.display_name() {
+            params.push(format!("dn={}", encode(display_name)));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", encode(tracker)));
+        }
+        for web_seed in &self.web_seeds {
+            params.push(format!("ws={}", encode(web_seed)));
+        }
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", encode(peer)));
+        }
+        if !self.select_only.is_empty() {
+            let ranges = self
+                .select_only
+                .iter()
+                .map(|range| match (range.start(), range.end()) {
+                    (start, end) if start == end => start.to_string(),
+                    (start, end) => format!("{}-{}", start, end),
+                })
+                .collect::<Vec<_>>();
+            params.push(format!("so={}", ranges.join(",")));
+        }
+
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let url = Url::parse(s).map_err(|e| Error::InvalidMagnetLink(e.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(Error::InvalidMagnetLink(format!(
+                "unexpected scheme {}",
+                url.scheme()
+            )));
+        }
+
+        let mut info_hash = None;
+        let mut info_hash_v2 = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut web_seeds = Vec::new();
+        let mut peers = Vec::new();
+        let mut select_only = Vec::new();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        let hash = hash.parse::<InfoHash>()?;
+                        if info_hash.replace(hash).is_some_and(|old| old != hash) {
+                            return Err(Error::InvalidMagnetLink(String::from(
+                                "conflicting btih info hashes",
+                            )));
+                        }
+                    } else if let Some(multihash) = value.strip_prefix("urn:btmh:") {
+                        let hash = multihash
+                            .strip_prefix(SHA256_MULTIHASH_PREFIX)
+                            .ok_or_else(|| {
+                                Error::InvalidMagnetLink(format!(
+                                    "unsupported multihash {}",
+                                    multihash
+                                ))
+                            })?
+                            .parse::<InfoHashV2>()?;
+                        if info_hash_v2.replace(hash).is_some_and(|old| old != hash) {
+                            return Err(Error::InvalidMagnetLink(String::from(
+                                "conflicting btmh info hashes",
+                            )));
+                        }
+                    }
+                    // Other URNs, such as those of other networks, are of no use here
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "ws" => web_seeds.push(value.into_owned()),
+                "x.pe" => peers.push(value.into_owned()),
+                "so" => select_only = parse_select_only(&value)?,
+                _ => {}
+            }
+        }
+
+        Self::new(
+            info_hash,
+            info_hash_v2,
+            display_name,
+            trackers,
+            web_seeds,
+            peers,
+            select_only,
+        )
+    }
+}
+
+impl From<&MetaInfo> for MagnetLink {
+    fn from(meta_info: &MetaInfo) -> Self {
+        let announce_list = meta_info.announce_list().into_iter().flatten().flatten();
+        let mut trackers = Vec::<String>::new();
+        for tracker in
+            std::iter::once(meta_info.announce()).chain(announce_list.map(String::as_str))
+        {
+            // Trackerless torrents have an empty `announce`
+            if !tracker.is_empty() && !trackers.iter().any(|t| t == tracker) {
+                trackers.push(tracker.to_string());
+            }
+        }
+
+        // BEP 19 allows `url-list` to be either a single URL or a list of them
+        let web_seeds = match meta_info.extra_fields().get(&b"url-list"[..]) {
+            Some(Value::Bytes(url)) => vec![String::from_utf8_lossy(url).into_owned()],
+            Some(Value::List(urls)) => urls
+                .iter()
+                .filter_map(|url| match url {
+                    Value::Bytes(url) => Some(String::from_utf8_lossy(url).into_owned()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Self {
+            info_hash: Some(meta_info.info_hash()).filter(|_| meta_info.info().is_v1()),
+            info_hash_v2: meta_info.info_hash_v2(),
+            display_name: Some(meta_info.info().name().to_string()),
+            trackers,
+            web_seeds,
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, URL_ENCODE_SET).to_string()
+}
+
+/// Parses a BEP 53 file selection such as `0,2,4-6`.
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>> {
+    let invalid = || Error::InvalidMagnetLink(format!("invalid file selection {}", value));
+    value
+        .split(',')
+        .map(|item| {
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start = start.parse::<usize>().map_err(|_| invalid())?;
+            let end = end.parse::<usize>().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX_HASH: &str = "80bbb5c4986d3dd4c52f8dab517451203c4fab1d";
+    const V2_HASH: &str = "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+
+    fn magnet_link() -> MagnetLink {
+        MagnetLink::new(
+            Some(InfoHash::from_hex(HEX_HASH).unwrap()),
+            Some(InfoHashV2::from_hex(V2_HASH).unwrap()),
+            Some(String::from("Some Name & More")),
+            vec![
+                String::from("http://tracker.example/announce?passkey=a&b=c"),
+                String::from("udp://backup.example:6969"),
+            ],
+            vec![String::from("http://seed.example/files/")],
+            vec![String::from("127.0.0.1:6881"), String::from("[::1]:6882")],
+            vec![0..=0, 2..=4],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn formatting_test() {
+        assert_eq!(
+            magnet_link().to_string(),
+            format!(
+                "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=Some%20Name%20%26%20More\
+                 &tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fpasskey%3Da%26b%3Dc\
+                 &tr=udp%3A%2F%2Fbackup.example%3A6969&ws=http%3A%2F%2Fseed.example%2Ffiles%2F\
+                 &x.pe=127.0.0.1%3A6881&x.pe=%5B%3A%3A1%5D%3A6882&so=0,2-4",
+                HEX_HASH, V2_HASH
+            )
+        );
+        assert_eq!(
+            MagnetLink::new(
+                Some(InfoHash::from_hex(HEX_HASH).unwrap()),
+                None,
+                None,
+                vec![],
+                vec![],
+                vec![],
+                vec![]
+            )
+            .unwrap()
+            .to_string(),
+            format!("magnet:?xt=urn:btih:{}", HEX_HASH)
+        );
+    }
+
+    #[test]
+    fn parsing_test() {
+        assert_eq!(
+            magnet_link().to_string().parse::<MagnetLink>().unwrap(),
+            magnet_link()
+        );
+
+        let magnet = "magnet:?xt=urn:btih:QC53LREYNU65JRJPRWVVC5CREA6E7KY5&dn=some+name\
+                      &tr=udp://a.example:80&tr=udp://b.example:80&xt=urn:ed2k:abc&foo=bar&so=7";
+        let magnet = magnet.parse::<MagnetLink>().unwrap();
+        assert_eq!(
+            magnet.info_hash(),
+            Some(InfoHash::from_hex(HEX_HASH).unwrap())
+        );
+        assert_eq!(magnet.info_hash_v2(), None);
+        assert_eq!(magnet.display_name(), Some("some name"));
+        assert_eq!(
+            magnet.trackers(),
+            &["udp://a.example:80", "udp://b.example:80"]
+        );
+        assert!(magnet.is_selected(7));
+        assert!(!magnet.is_selected(6));
+
+        // v2-only links are allowed
+        let magnet = format!("magnet:?xt=urn:btmh:1220{}", V2_HASH)
+            .parse::<MagnetLink>()
+            .unwrap();
+        assert_eq!(magnet.info_hash(), None);
+        assert_eq!(magnet.info_hash_v2().unwrap().to_hex(), V2_HASH);
+        assert!(magnet.is_selected(100));
+    }
+
+    #[test]
+    fn malformed_test() {
+        let malformed = [
+            String::from("not a url"),
+            format!("http://example.com/?xt=urn:btih:{}", HEX_HASH),
+            String::from("magnet:?dn=no+hash"),
+            String::from("magnet:?xt=urn:btih:80bbb5c4"),
+            String::from("magnet:?xt=urn:btih:zzbbb5c4986d3dd4c52f8dab517451203c4fab1d"),
+            format!("magnet:?xt=urn:btmh:1114{}", V2_HASH),
+            format!("magnet:?xt=urn:btmh:1220{}", HEX_HASH),
+            format!("magnet:?xt=urn:btih:{}&so=4-2", HEX_HASH),
+            format!("magnet:?xt=urn:btih:{}&so=1,,2", HEX_HASH),
+            format!("magnet:?xt=urn:btih:{}&so=a", HEX_HASH),
+            format!(
+                "magnet:?xt=urn:btih:{}&xt=urn:btih:{}",
+                HEX_HASH,
+                "0".repeat(40)
+            ),
+        ];
+        for magnet in &malformed {
+            assert!(magnet.parse::<MagnetLink>().is_err(), "{}", magnet);
+        }
+
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty_range = vec![3..=2];
+        assert!(MagnetLink::new(
+            Some(InfoHash::from_hex(HEX_HASH).unwrap()),
+            None,
+            None,
+            vec![],
+            vec![],
+            vec![],
+            empty_range
+        )
+        .is_err());
+    }
+}
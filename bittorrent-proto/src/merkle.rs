@@ -0,0 +1,84 @@
+//! The per-file merkle trees of v2 torrents, as described in BEP 52.
+
+use sha2::{Digest, Sha256};
+
+/// The size of the blocks which form the leaves of each file's merkle tree. The last block of
+/// a file may be shorter.
+pub const MERKLE_BLOCK_SIZE: u64 = 1 << 14;
+
+/// The hash of a leaf beyond the end of a file.
+pub const ZERO_HASH: [u8; 32] = [0; 32];
+
+/// The SHA-256 hash of a single block, or of two concatenated child hashes.
+pub fn hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// The root of a tree with `width` leaves, where `width` is a power of two. The first leaves
+/// are `hashes`, and the rest are `pad`.
+pub fn merkle_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    assert!(width.is_power_of_two() && hashes.len() <= width);
+    let mut layer = hashes.to_vec();
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    layer[0]
+}
+
+/// The `pieces root` and piece layer of a file, given the hashes of its blocks. The piece
+/// layer is empty for files no longer than one piece, since they don't need one.
+pub fn file_hashes(block_hashes: &[[u8; 32]], piece_length: u64) -> ([u8; 32], Vec<[u8; 32]>) {
+    let blocks_per_piece = (piece_length / MERKLE_BLOCK_SIZE) as usize;
+    if block_hashes.len() <= blocks_per_piece {
+        let width = block_hashes.len().next_power_of_two();
+        return (merkle_root(block_hashes, width, ZERO_HASH), Vec::new());
+    }
+
+    let layer = block_hashes
+        .chunks(blocks_per_piece)
+        .map(|piece| merkle_root(piece, blocks_per_piece, ZERO_HASH))
+        .collect::<Vec<_>>();
+    let pad = merkle_root(&[], blocks_per_piece, ZERO_HASH);
+    let root = merkle_root(&layer, layer.len().next_power_of_two(), pad);
+    (root, layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_hashes_test() {
+        let blocks = (0..5_u8).map(|i| hash(&[i])).collect::<Vec<_>>();
+        let node = |a: [u8; 32], b: [u8; 32]| hash(&[a, b].concat());
+
+        // A single block is its own root
+        assert_eq!(file_hashes(&blocks[..1], 1 << 16), (blocks[0], vec![]));
+        assert_eq!(
+            file_hashes(&blocks[..3], 1 << 16),
+            (
+                node(node(blocks[0], blocks[1]), node(blocks[2], ZERO_HASH)),
+                vec![]
+            )
+        );
+
+        // Five blocks in pieces of two: the last piece and a whole piece are padded with zeros
+        let layer = vec![
+            node(blocks[0], blocks[1]),
+            node(blocks[2], blocks[3]),
+            node(blocks[4], ZERO_HASH),
+        ];
+        let pad = node(ZERO_HASH, ZERO_HASH);
+        let root = node(node(layer[0], layer[1]), node(layer[2], pad));
+        assert_eq!(file_hashes(&blocks, 1 << 15), (root, layer));
+        assert_eq!(
+            root,
+            merkle_root(&blocks, 8, ZERO_HASH),
+            "the piece layer doesn't change the root"
+        );
+    }
+}
@@ -1,12 +1,28 @@
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
+
 use bendy::{
     decoding::{self, FromBencode, Object},
     encoding::{self, SingleItemEncoder, ToBencode},
+    value::Value,
 };
 use chrono::{DateTime, TimeZone, Utc};
 
-use crate::{error::Error, info::Info};
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::Error,
+    info::Info,
+    validation::ValidationIssue,
+    FileTree, InfoHash, InfoHashV2, MagnetLink,
+};
+
+/// The `piece layers` of a v2 torrent: the concatenated SHA-256 piece hashes of each file
+/// longer than one piece, keyed by the file's `pieces root`.
+pub type PieceLayers = BTreeMap<[u8; 32], Vec<u8>>;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct MetaInfo {
     announce: String,
     info: Info,
@@ -15,10 +31,14 @@ pub struct MetaInfo {
     comment: Option<String>,
     created_by: Option<String>,
     encoding: Option<String>,
+    info_hash: InfoHash,
+    info_hash_v2: Option<InfoHashV2>,
+    piece_layers: Option<PieceLayers>,
+    extra_fields: ExtraFields,
 }
 
 impl MetaInfo {
-    /// `announce`: the URL of the tracker.
+    /// `announce`: the URL of the tracker, or empty for trackerless torrents.
     ///
     /// `info`: metadata for the download.
     ///
@@ -32,6 +52,9 @@ impl MetaInfo {
     ///
     /// `encoding`: the string encoding format used to generate the `pieces` part of the
     /// `info` dictionary
+    ///
+    /// The info hashes are computed by encoding `info`. Torrents which are decoded use the hashes
+    /// of the original `info` dictionary bytes instead.
     pub fn new(
         announce: String,
         info: Info,
@@ -40,6 +63,80 @@ impl MetaInfo {
         comment: Option<String>,
         created_by: Option<String>,
         encoding: Option<String>,
+    ) -> Self {
+        let info_bytes = info
+            .to_bencode()
+            .expect("info dictionary should always be encodable");
+        let (info_hash, info_hash_v2) = info_hashes(&info, &info_bytes);
+        Self::with_info_hash(
+            announce,
+            info,
+            info_hash,
+            info_hash_v2,
+            announce_list,
+            creation_date,
+            comment,
+            created_by,
+            encoding,
+        )
+    }
+
+    /// Completes a magnet link with the `info` dictionary fetched from peers, e.g. by a
+    /// [`MetadataDownload`](crate::peer::MetadataDownload), which has already checked it against
+    /// the info hash. The trackers of the link form a single tier, and its web seeds are kept
+    /// under `url-list`.
+    pub fn from_magnet(magnet: &MagnetLink, info: Info) -> crate::error::Result<Self> {
+        let info_hash_v2 = magnet.info_hash_v2().filter(|_| info.is_v2());
+        let info_hash = match (magnet.info_hash(), info_hash_v2) {
+            (Some(info_hash), _) if info.is_v1() => info_hash,
+            (_, Some(info_hash_v2)) => info_hash_v2.truncated(),
+            _ => {
+                return Err(Error::InvalidMagnetLink(String::from(
+                    "no info hash matching the version of the torrent",
+                )))
+            }
+        };
+        let trackers = magnet.trackers();
+        let announce_list = if trackers.len() > 1 {
+            Some(vec![trackers.to_vec()])
+        } else {
+            None
+        };
+        let mut meta_info = Self::with_info_hash(
+            trackers.first().cloned().unwrap_or_default(),
+            info,
+            info_hash,
+            info_hash_v2,
+            announce_list,
+            None,
+            None,
+            None,
+            None,
+        );
+        if !magnet.web_seeds().is_empty() {
+            let web_seeds = magnet
+                .web_seeds()
+                .iter()
+                .map(|url| Value::Bytes(url.as_bytes().to_vec().into()))
+                .collect();
+            meta_info
+                .extra_fields
+                .insert(b"url-list".to_vec(), Value::List(web_seeds));
+        }
+        Ok(meta_info)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_info_hash(
+        announce: String,
+        info: Info,
+        info_hash: InfoHash,
+        info_hash_v2: Option<InfoHashV2>,
+        announce_list: Option<Vec<Vec<String>>>,
+        creation_date: Option<DateTime<Utc>>,
+        comment: Option<String>,
+        created_by: Option<String>,
+        encoding: Option<String>,
     ) -> Self {
         Self {
             announce,
@@ -49,9 +146,20 @@ impl MetaInfo {
             comment,
             created_by,
             encoding,
+            info_hash,
+            info_hash_v2,
+            piece_layers: None,
+            extra_fields: ExtraFields::new(),
         }
     }
 
+    /// Adds the `piece layers` of a v2 or hybrid torrent.
+    pub fn with_piece_layers(mut self, piece_layers: PieceLayers) -> Self {
+        self.piece_layers = Some(piece_layers);
+        self
+    }
+
+    /// The URL of the tracker, which is empty if the torrent has no `announce` key.
     pub fn announce(&self) -> &str {
         &self.announce
     }
@@ -79,14 +187,107 @@ impl MetaInfo {
     pub fn encoding(&self) -> Option<&str> {
         self.encoding.as_deref()
     }
+
+    /// The hash which identifies this torrent to trackers and peers. This is the SHA1 hash of
+    /// the `info` dictionary, or for v2-only torrents the truncated SHA-256 hash.
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    /// The SHA-256 hash of the `info` dictionary, for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<InfoHashV2> {
+        self.info_hash_v2
+    }
+
+    pub fn piece_layers(&self) -> Option<&PieceLayers> {
+        self.piece_layers.as_ref()
+    }
+
+    /// A magnet link for this torrent, including its name, every tracker and any web seeds
+    /// listed under `url-list`.
+    pub fn to_magnet(&self) -> MagnetLink {
+        MagnetLink::from(self)
+    }
+
+    /// The DHT nodes listed under `nodes`, as described in BEP 5. Hosts may be either IP
+    /// addresses or domain names. Malformed entries are skipped.
+    pub fn nodes(&self) -> Vec<(String, u16)> {
+        let nodes = match self.extra_fields().get(&b"nodes"[..]) {
+            Some(Value::List(nodes)) => nodes,
+            _ => return Vec::new(),
+        };
+        nodes
+            .iter()
+            .filter_map(|node| match node {
+                Value::List(pair) => match pair.as_slice() {
+                    [Value::Bytes(host), Value::Integer(port)] => Some((
+                        String::from_utf8_lossy(host).into_owned(),
+                        u16::try_from(*port).ok()?,
+                    )),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Checks the `info` dictionary as [`Info::validate`] does, that every file of a v2 torrent
+    /// longer than one piece has a piece layer, and that every tracker URL parses. An empty
+    /// `announce` is allowed, since trackerless torrents may leave it out.
+    pub fn validate(&self) -> crate::error::Result<()> {
+        let mut issues = self.info.issues();
+        let piece_length = self.info.piece_length();
+        let files = self
+            .info
+            .file_tree()
+            .map(FileTree::files)
+            .unwrap_or_default();
+        for (path, entry) in files {
+            if entry.length() <= piece_length || piece_length == 0 {
+                continue;
+            }
+            let expected = entry.length().div_ceil(piece_length) as usize * 32;
+            let layer = entry
+                .pieces_root()
+                .and_then(|root| self.piece_layers()?.get(root));
+            if layer.is_none_or(|layer| layer.len() != expected) {
+                issues.push(ValidationIssue::InvalidPieceLayer(path));
+            }
+        }
+        let announce_list = self.announce_list().map(Vec::as_slice).unwrap_or_default();
+        for url in std::iter::once(&self.announce).chain(announce_list.iter().flatten()) {
+            if !url.is_empty() && reqwest::Url::parse(url).is_err() {
+                issues.push(ValidationIssue::InvalidAnnounceUrl(url.clone()));
+            }
+        }
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidTorrent(issues))
+        }
+    }
+
+    /// Keys which aren't part of the specification, such as `url-list` or `nodes`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
+
+    pub fn extra_fields_mut(&mut self) -> &mut ExtraFields {
+        &mut self.extra_fields
+    }
 }
 
 impl ToBencode for MetaInfo {
-    const MAX_DEPTH: usize = 5;
+    const MAX_DEPTH: usize = Info::MAX_DEPTH + 1;
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
-        encoder.emit_dict(|mut encoder| {
-            encoder.emit_pair(b"announce", self.announce())?;
+        encoder.emit_unsorted_dict(|encoder| {
+            for (key, value) in self.extra_fields() {
+                encoder.emit_pair(key, value)?;
+            }
+            if !self.announce().is_empty() {
+                encoder.emit_pair(b"announce", self.announce())?;
+            }
             if let Some(announce_list) = self.announce_list() {
                 encoder.emit_pair(b"announce-list", announce_list)?;
             }
@@ -103,15 +304,37 @@ impl ToBencode for MetaInfo {
                 encoder.emit_pair(b"encoding", encoding)?;
             }
             encoder.emit_pair(b"info", self.info())?;
+            if let Some(piece_layers) = self.piece_layers() {
+                encoder.emit_pair_with(b"piece layers", |e| {
+                    e.emit_dict(|mut e| {
+                        for (root, layer) in piece_layers {
+                            e.emit_pair_with(root, |e| e.emit_bytes(layer))?;
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
             Ok(())
         })
     }
 }
 
 impl FromBencode for MetaInfo {
-    const EXPECTED_RECURSION_DEPTH: usize = 5;
+    const EXPECTED_RECURSION_DEPTH: usize = Info::EXPECTED_RECURSION_DEPTH + 1;
 
     fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for MetaInfo {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> Result<Self, decoding::Error>
     where
         Self: Sized,
     {
@@ -122,12 +345,20 @@ impl FromBencode for MetaInfo {
         let mut comment = None;
         let mut created_by = None;
         let mut encoding = None;
+        let mut piece_layers = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
             match pair {
                 (b"announce", val) => announce = Some(String::decode_bencode_object(val)?),
-                (b"info", val) => info = Some(Info::decode_bencode_object(val)?),
+                (b"info", val) => {
+                    // Hash the original bytes, since re-encoding may not reproduce them exactly
+                    let raw_info = val.try_into_dictionary()?.into_raw()?;
+                    let decoded = Info::from_bencode_with_mode(raw_info, mode)?;
+                    let info_hashes = info_hashes(&decoded, raw_info);
+                    info = Some((decoded, info_hashes));
+                }
                 (b"announce-list", val) => announce_list = Some(Vec::decode_bencode_object(val)?),
                 (b"creation date", val) => {
                     let seconds = i64::decode_bencode_object(val)?;
@@ -142,26 +373,56 @@ impl FromBencode for MetaInfo {
                 (b"comment", val) => comment = Some(String::decode_bencode_object(val)?),
                 (b"created by", val) => created_by = Some(String::decode_bencode_object(val)?),
                 (b"encoding", val) => encoding = Some(String::decode_bencode_object(val)?),
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
+                (b"piece layers", val) => {
+                    let mut layers = PieceLayers::new();
+                    let mut dict = val.try_into_dictionary()?;
+                    while let Some((root, layer)) = dict.next_pair()? {
+                        let root = root.try_into().map_err(|_| {
+                            decoding::Error::malformed_content(Error::InvalidMetadata(format!(
+                                "piece layer key must be 32 bytes, got {}",
+                                root.len()
+                            )))
+                        })?;
+                        layers.insert(root, layer.try_into_bytes()?.to_vec());
+                    }
+                    piece_layers = Some(layers);
                 }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
-        let announce = announce.ok_or_else(|| decoding::Error::missing_field("announce"))?;
-        let info = info.ok_or_else(|| decoding::Error::missing_field("info"))?;
+        // Trackerless torrents find peers through the DHT instead, and may leave this out
+        let announce = announce.unwrap_or_default();
+        let (info, (info_hash, info_hash_v2)) =
+            info.ok_or_else(|| decoding::Error::missing_field("info"))?;
 
-        Ok(Self::new(
+        let mut meta_info = Self::with_info_hash(
             announce,
             info,
+            info_hash,
+            info_hash_v2,
             announce_list,
             creation_date,
             comment,
             created_by,
             encoding,
-        ))
+        );
+        meta_info.piece_layers = piece_layers;
+        meta_info.extra_fields = extra_fields;
+        Ok(meta_info)
+    }
+}
+
+/// The v1 and v2 info hashes of the bencoded `info` dictionary.
+fn info_hashes(info: &Info, info_bytes: &[u8]) -> (InfoHash, Option<InfoHashV2>) {
+    let info_hash_v2 = if info.is_v2() {
+        Some(InfoHashV2::from_info_bytes(info_bytes))
+    } else {
+        None
+    };
+    match info_hash_v2 {
+        Some(info_hash_v2) if !info.is_v1() => (info_hash_v2.truncated(), Some(info_hash_v2)),
+        _ => (InfoHash::from_info_bytes(info_bytes), info_hash_v2),
     }
 }
 
@@ -195,6 +456,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn info_hash_test() {
+        let info_bytes = crate::info::tests::info().to_bencode().unwrap();
+        assert_eq!(
+            meta_info().info_hash(),
+            InfoHash::from_info_bytes(&info_bytes)
+        );
+
+        // Decoded torrents hash the original bytes of the info dictionary
+        let torrent = b"d8:announce18:http://someurl.com4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblah7:privatei2eee";
+        let meta_info = MetaInfo::from_bencode(torrent).unwrap();
+        assert_eq!(
+            meta_info.info_hash(),
+            InfoHash::from_info_bytes(&torrent[38..torrent.len() - 1])
+        );
+        assert_ne!(
+            meta_info.info_hash(),
+            InfoHash::from_info_bytes(&meta_info.info().to_bencode().unwrap())
+        );
+    }
+
     #[test]
     fn decoding_test() {
         assert_eq!(
@@ -203,11 +485,158 @@ mod tests {
                 b"d8:announce18:http://someurl.com13:announce-listll18:http://primary.url25:http://second-primary.urlel17:http://backup.urlee7:comment17:this is a comment10:created by16:author goes here13:creation datei1234567890e8:encoding5:UTF-84:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblah7:privatei0eee"
                 ).unwrap()
         );
-        // missing 'announce' field
-        assert!(
-            MetaInfo::from_bencode(
-                b"d13:announce-listll18:http://primary.url25:http://second-primary.urlel17:http://backup.urlee7:comment17:this is a comment10:created by16:author goes here13:creation datei1234567890e8:encoding5:UTF-84:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblah7:privatei0eee"
-                ).is_err()
+        // missing 'info' field
+        assert!(MetaInfo::from_bencode(b"d8:announce18:http://someurl.come").is_err());
+    }
+
+    #[test]
+    fn magnet_test() {
+        let magnet = meta_info().to_magnet();
+        assert_eq!(magnet.info_hash(), Some(meta_info().info_hash()));
+        assert_eq!(magnet.display_name(), Some("some name"));
+        assert_eq!(
+            magnet.trackers(),
+            &[
+                "http://someurl.com",
+                "http://primary.url",
+                "http://second-primary.url",
+                "http://backup.url"
+            ]
         );
+        assert!(magnet.web_seeds().is_empty());
+
+        let bencode = b"d8:announce18:http://someurl.com4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblahe8:url-listl17:http://seed.url/a17:http://seed.url/bee";
+        let magnet = MetaInfo::from_bencode(bencode).unwrap().to_magnet();
+        assert_eq!(
+            magnet.web_seeds(),
+            &["http://seed.url/a", "http://seed.url/b"]
+        );
+        assert_eq!(magnet.to_string().parse::<MagnetLink>().unwrap(), magnet);
+
+        let meta_info = MetaInfo::from_bencode(bencode).unwrap();
+        let resolved = MetaInfo::from_magnet(&magnet, meta_info.info().clone()).unwrap();
+        assert_eq!(resolved.info_hash(), meta_info.info_hash());
+        assert_eq!(resolved.announce(), "http://someurl.com");
+        assert_eq!(resolved.announce_list(), None);
+        assert_eq!(resolved.to_magnet(), magnet);
+
+        // Trackerless torrents have no `tr` parameters
+        let trackerless = b"d4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblahee";
+        let magnet = MetaInfo::from_bencode(trackerless).unwrap().to_magnet();
+        assert!(magnet.trackers().is_empty());
+        assert!(!magnet.to_string().contains("tr="));
+    }
+
+    #[test]
+    fn extra_fields_test() {
+        let bencode = b"d8:announce18:http://someurl.com4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblah7:privatei0e6:source3:abce5:nodesll9:127.0.0.1i6881eee9:publisher3:foo8:url-listl17:http://seed.url/aee";
+        let decoded = MetaInfo::from_bencode(bencode).unwrap();
+        assert_eq!(
+            decoded.extra_fields().keys().cloned().collect::<Vec<_>>(),
+            vec![
+                b"nodes".to_vec(),
+                b"publisher".to_vec(),
+                b"url-list".to_vec()
+            ]
+        );
+        assert_eq!(decoded.info().extra_fields().len(), 1);
+        assert_eq!(&decoded.to_bencode().unwrap(), bencode);
+        assert_eq!(decoded.nodes(), vec![(String::from("127.0.0.1"), 6881)]);
+        assert!(meta_info().nodes().is_empty());
+
+        // Trackerless torrents only list DHT nodes
+        let trackerless = b"d4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces16:blahblahblahblahe5:nodesll9:127.0.0.1i6881eeee";
+        let decoded = MetaInfo::from_bencode(trackerless).unwrap();
+        assert_eq!(decoded.announce(), "");
+        assert_eq!(decoded.nodes(), vec![(String::from("127.0.0.1"), 6881)]);
+        assert_eq!(&decoded.to_bencode().unwrap(), trackerless);
+
+        assert!(MetaInfo::from_bencode_strict(bencode).is_err());
+        assert_eq!(
+            MetaInfo::from_bencode_strict(&meta_info().to_bencode().unwrap()).unwrap(),
+            meta_info()
+        );
+    }
+
+    #[test]
+    fn validate_test() {
+        let info = Info::new(
+            String::from("name"),
+            16,
+            vec![0; 20],
+            Some(10),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let meta_info = |announce: &str, announce_list| {
+            MetaInfo::new(
+                announce.to_string(),
+                info.clone(),
+                announce_list,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(meta_info("udp://tracker.example:80", None)
+            .validate()
+            .is_ok());
+        assert!(meta_info("", None).validate().is_ok());
+
+        let announce_list = vec![vec![String::from("http://a.example"), String::from("nope")]];
+        match meta_info("::bad", Some(announce_list)).validate() {
+            Err(Error::InvalidTorrent(issues)) => assert_eq!(
+                issues,
+                vec![
+                    ValidationIssue::InvalidAnnounceUrl(String::from("::bad")),
+                    ValidationIssue::InvalidAnnounceUrl(String::from("nope")),
+                ]
+            ),
+            other => panic!("expected validation issues, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v2_test() {
+        let layer = vec![2; 64];
+        let file_tree = FileTree::from_files(vec![(
+            vec![String::from("file")],
+            crate::FileTreeEntry::new(40_000, Some([1; 32])),
+        )])
+        .unwrap();
+        let info = Info::new_v2(String::from("file"), 1 << 15, file_tree, None).unwrap();
+        let meta_info = MetaInfo::new(
+            String::from("http://someurl.com"),
+            info.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let info_bytes = info.to_bencode().unwrap();
+        let info_hash_v2 = InfoHashV2::from_info_bytes(&info_bytes);
+        assert_eq!(meta_info.info_hash_v2(), Some(info_hash_v2));
+        assert_eq!(meta_info.info_hash(), info_hash_v2.truncated());
+        assert!(matches!(
+            meta_info.validate(),
+            Err(Error::InvalidTorrent(issues))
+                if issues == vec![ValidationIssue::InvalidPieceLayer(vec![String::from("file")])]
+        ));
+
+        let meta_info = meta_info.with_piece_layers(vec![([1; 32], layer)].into_iter().collect());
+        assert!(meta_info.validate().is_ok());
+        let decoded = MetaInfo::from_bencode_strict(&meta_info.to_bencode().unwrap()).unwrap();
+        assert_eq!(decoded, meta_info);
+
+        let magnet = meta_info.to_magnet();
+        assert_eq!(magnet.info_hash(), None);
+        assert_eq!(magnet.info_hash_v2(), Some(info_hash_v2));
+        let resolved = MetaInfo::from_magnet(&magnet, info).unwrap();
+        assert_eq!(resolved.info_hash(), meta_info.info_hash());
+        assert_eq!(resolved.info_hash_v2(), Some(info_hash_v2));
     }
 }
@@ -1,41 +1,120 @@
 use std::{
-    convert::TryFrom,
+    convert::{TryFrom, TryInto},
     net::{SocketAddr, ToSocketAddrs},
 };
 
 use bendy::decoding::{self, FromBencode, Object};
 use tokio::task;
 
+mod bitfield;
+mod codec;
+mod extension;
+mod fast;
+mod handshake;
 mod message;
+mod metadata;
+mod pex;
 
-use crate::error::Error;
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::{Error, Result},
+};
+
+pub use bitfield::Bitfield;
+pub use codec::MessageCodec;
+pub use extension::{ExtensionHandler, ExtensionHandshake, ExtensionRegistry, HANDSHAKE_ID};
+pub use fast::{allowed_fast_set, DEFAULT_ALLOWED_FAST_COUNT};
+pub use handshake::{Handshake, ReservedBits};
+pub use message::{Message, MessageType};
+pub use metadata::{
+    metadata_piece_count, MetadataDownload, MetadataMessage, MetadataServer, MAX_METADATA_SIZE,
+    METADATA_PIECE_LENGTH, UT_METADATA,
+};
+pub use pex::{PexFlags, PexMessage, PexState, MAX_PEX_PEERS, PEX_INTERVAL, UT_PEX};
 
-pub use message::Message;
+/// The length of a compact IPv4 peer: a 4-byte address followed by a 2-byte port.
+pub const COMPACT_IPV4_LENGTH: usize = 6;
+
+/// The length of a compact IPv6 peer: a 16-byte address followed by a 2-byte port.
+pub const COMPACT_IPV6_LENGTH: usize = 18;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Peer {
     peer_id: Option<String>,
     address: SocketAddr,
+    extra_fields: ExtraFields,
 }
 
 impl Peer {
     pub fn new(peer_id: Option<String>, address: SocketAddr) -> Self {
-        Self { peer_id, address }
+        Self {
+            peer_id,
+            address,
+            extra_fields: ExtraFields::new(),
+        }
     }
 
     pub fn address(&self) -> SocketAddr {
         self.address
     }
+
+    /// Parses a list of compact peers, such as the `peers` or `peers6` of a tracker response,
+    /// where every entry is IPv6 if `ipv6` is set and IPv4 otherwise.
+    pub fn decode_compact_list(bytes: &[u8], ipv6: bool) -> Result<Vec<Self>> {
+        let length = if ipv6 {
+            COMPACT_IPV6_LENGTH
+        } else {
+            COMPACT_IPV4_LENGTH
+        };
+        if !bytes.len().is_multiple_of(length) {
+            return Err(Error::InvalidCompactPeerLength(bytes.len()));
+        }
+        bytes.chunks(length).map(Self::try_from).collect()
+    }
+
+    /// Appends the compact form of this peer to `buf`, which is 6 bytes for an IPv4 address
+    /// and 18 bytes for an IPv6 address.
+    pub fn encode_compact(&self, buf: &mut Vec<u8>) {
+        match self.address {
+            SocketAddr::V4(address) => buf.extend_from_slice(&address.ip().octets()),
+            SocketAddr::V6(address) => buf.extend_from_slice(&address.ip().octets()),
+        }
+        buf.extend_from_slice(&self.address.port().to_be_bytes());
+    }
+
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(COMPACT_IPV6_LENGTH);
+        self.encode_compact(&mut result);
+        result
+    }
+
+    /// Keys of a tracker's peer dictionary which aren't part of the specification.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
 }
 
 impl FromBencode for Peer {
-    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for Peer {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
     where
         Self: Sized,
     {
         let mut peer_id = None;
         let mut ip = None;
         let mut port = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -47,11 +126,7 @@ impl FromBencode for Peer {
                     ip = Some(String::decode_bencode_object(val)?)
                 }
                 (b"port", val) => port = Some(u16::decode_bencode_object(val)?),
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
-                }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
@@ -63,21 +138,29 @@ impl FromBencode for Peer {
                 decoding::Error::malformed_content(Error::InvalidSocketAddress(ip, port))
             })?;
 
-        Ok(Self::new(peer_id, address))
+        let mut peer = Self::new(peer_id, address);
+        peer.extra_fields = extra_fields;
+        Ok(peer)
     }
 }
 
 impl TryFrom<&[u8]> for Peer {
     type Error = Error;
 
-    fn try_from(bytes: &[u8]) -> crate::error::Result<Self> {
-        if bytes.len() == 6 {
-            let ip = [bytes[0], bytes[1], bytes[2], bytes[3]];
-            let port = (bytes[4] as u16) * 100 + bytes[5] as u16;
-            Ok(Self::new(None, SocketAddr::from((ip, port))))
-        } else {
-            Err(Error::InvalidCompactPeerLength(bytes.len()))
+    /// Parses a single compact peer, in either the IPv4 or IPv6 form.
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != COMPACT_IPV4_LENGTH && bytes.len() != COMPACT_IPV6_LENGTH {
+            return Err(Error::InvalidCompactPeerLength(bytes.len()));
         }
+
+        let (ip, port) = bytes.split_at(bytes.len() - 2);
+        let port = u16::from_be_bytes(port.try_into().unwrap());
+        let address = if bytes.len() == COMPACT_IPV4_LENGTH {
+            SocketAddr::from((<[u8; 4]>::try_from(ip).unwrap(), port))
+        } else {
+            SocketAddr::from((<[u8; 16]>::try_from(ip).unwrap(), port))
+        };
+        Ok(Self::new(None, address))
     }
 }
 
@@ -135,10 +218,55 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn extra_fields_test() {
+        let bencode = b"d2:ip9:127.0.0.14:porti6080e6:uploadi1ee";
+        let peer = Peer::from_bencode(bencode).unwrap();
+        assert_eq!(peer.address(), "127.0.0.1:6080".parse().unwrap());
+        assert_eq!(peer.extra_fields().len(), 1);
+        assert!(Peer::from_bencode_strict(bencode).is_err());
+    }
+
     #[test]
     fn conversion_test() {
-        let bytes: &[u8] = &[127, 0, 0, 1, 60, 80];
+        let bytes: &[u8] = &[127, 0, 0, 1, 0x17, 0xc0];
         let peer = Peer::new(None, "127.0.0.1:6080".parse().unwrap());
         assert_eq!(Peer::try_from(bytes).unwrap(), peer);
+        assert_eq!(peer.to_compact(), bytes);
+
+        let mut bytes = vec![
+            0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x02, 0x02, 0xb3, 0xff, 0xfe, 0x1e,
+        ];
+        bytes.extend_from_slice(&[0x83, 0x29, 0x1a, 0xe1]);
+        let peer = Peer::new(None, "[fe80::202:b3ff:fe1e:8329]:6881".parse().unwrap());
+        assert_eq!(Peer::try_from(bytes.as_slice()).unwrap(), peer);
+        assert_eq!(peer.to_compact(), bytes);
+
+        for length in &[0, 1, 5, 7, 17, 19] {
+            assert!(matches!(
+                Peer::try_from(&vec![0; *length][..]),
+                Err(Error::InvalidCompactPeerLength(l)) if l == *length
+            ));
+        }
+    }
+
+    #[test]
+    fn compact_list_test() {
+        let bytes = [127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 1, 0x1a, 0xe2];
+        let peers = Peer::decode_compact_list(&bytes, false).unwrap();
+        assert_eq!(
+            peers,
+            vec![
+                Peer::new(None, "127.0.0.1:6881".parse().unwrap()),
+                Peer::new(None, "10.0.0.1:6882".parse().unwrap())
+            ]
+        );
+        // 12 bytes is not a whole number of IPv6 peers
+        assert!(Peer::decode_compact_list(&bytes, true).is_err());
+        assert!(matches!(
+            Peer::decode_compact_list(&bytes[..11], false),
+            Err(Error::InvalidCompactPeerLength(11))
+        ));
+        assert!(Peer::decode_compact_list(&[], true).unwrap().is_empty());
     }
 }
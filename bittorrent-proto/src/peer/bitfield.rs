@@ -0,0 +1,150 @@
+use crate::{
+    error::{Error, Result},
+    peer::Message,
+};
+
+/// The pieces a peer has, one bit per piece. The high bit of the first byte is piece 0, as in
+/// [`Message::Bitfield`].
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl Bitfield {
+    /// A bitfield of `len` pieces, none of which are set.
+    pub fn new(len: u32) -> Self {
+        Self {
+            bytes: vec![0; (len as usize).div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield of `len` pieces, all of which are set.
+    pub fn full(len: u32) -> Self {
+        let mut bitfield = Self::new(len);
+        bitfield.bytes.fill(0xff);
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Parses the payload of a `bitfield` message for a torrent of `len` pieces. Fails if the
+    /// payload has the wrong length or any spare bits are set.
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Self> {
+        let mut bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        if bytes.len() != (len as usize).div_ceil(8) {
+            return Err(Error::InvalidBitfield(format!(
+                "expected {} bytes for {} pieces, got {}",
+                (len as usize).div_ceil(8),
+                len,
+                bytes.len()
+            )));
+        }
+        bitfield.clear_spare_bits();
+        if bitfield.bytes != bytes {
+            return Err(Error::InvalidBitfield(String::from("spare bits are set")));
+        }
+        Ok(bitfield)
+    }
+
+    /// The number of pieces.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Whether the piece at `index` is set. Pieces beyond the end are never set.
+    pub fn has(&self, index: u32) -> bool {
+        index < self.len && self.bytes[index as usize / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets or clears the piece at `index`. Pieces beyond the end are ignored.
+    pub fn set(&mut self, index: u32, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        let mask = 0x80 >> (index % 8);
+        if value {
+            self.bytes[index as usize / 8] |= mask;
+        } else {
+            self.bytes[index as usize / 8] &= !mask;
+        }
+    }
+
+    /// The number of pieces which are set.
+    pub fn count(&self) -> u32 {
+        self.bytes.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    /// Whether every piece is set.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// The indices of the pieces which are set, in order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter(move |&index| self.has(index))
+    }
+
+    /// The message announcing these pieces to a peer. With the fast extension, `have all` or
+    /// `have none` can be sent instead when they apply.
+    pub fn to_message(&self, fast_extension: bool) -> Message {
+        match (fast_extension, self.count()) {
+            (true, 0) => Message::HaveNone,
+            (true, count) if count == self.len => Message::HaveAll,
+            _ => Message::Bitfield(self.bytes.clone()),
+        }
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() as u32 * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfield_test() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        bitfield.set(10, true);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert!(bitfield.has(9) && !bitfield.has(8) && !bitfield.has(10));
+        assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 9]);
+        assert_eq!(bitfield.count(), 2);
+        bitfield.set(0, false);
+        assert_eq!(bitfield.count(), 1);
+
+        let full = Bitfield::full(10);
+        assert_eq!(full.as_bytes(), &[0xff, 0b1100_0000]);
+        assert!(full.is_complete());
+        assert_eq!(full.to_message(true), Message::HaveAll);
+        assert_eq!(Bitfield::new(10).to_message(true), Message::HaveNone);
+        assert_eq!(
+            full.to_message(false),
+            Message::Bitfield(vec![0xff, 0b1100_0000])
+        );
+
+        assert_eq!(Bitfield::from_bytes(&[0xff, 0xc0], 10).unwrap(), full);
+        assert!(Bitfield::from_bytes(&[0xff, 0xe0], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+        assert!(Bitfield::new(0).is_complete());
+    }
+}
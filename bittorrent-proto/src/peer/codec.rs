@@ -0,0 +1,198 @@
+use std::convert::TryInto;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::message::{Message, LENGTH_PREFIX_SIZE};
+use crate::error::{Error, Result};
+
+/// The default limit on the size of a single frame, not counting the length prefix. This is
+/// comfortably larger than a `piece` message carrying a 16 KiB block, and large enough for the
+/// `bitfield` of a torrent with a million pieces.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 1 << 18;
+
+/// A [`Decoder`] and [`Encoder`] for length-prefixed peer wire messages. Used with
+/// `tokio_util::codec::Framed`, this turns any `AsyncRead + AsyncWrite` into a `Stream` and
+/// `Sink` of [`Message`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageCodec {
+    max_frame_length: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    /// `max_frame_length`: the largest length prefix that will be accepted or produced. Frames
+    /// which exceed this are rejected before their contents are buffered.
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self { max_frame_length }
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes(src[..LENGTH_PREFIX_SIZE].try_into().unwrap()) as usize;
+        if length > self.max_frame_length {
+            return Err(Error::FrameTooLarge(length, self.max_frame_length));
+        }
+
+        let frame_length = LENGTH_PREFIX_SIZE + length;
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_length);
+        Message::decode(&frame).map(Some)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Message>> {
+        match self.decode(buf)? {
+            Some(message) => Ok(Some(message)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                let expected = if buf.len() < LENGTH_PREFIX_SIZE {
+                    LENGTH_PREFIX_SIZE
+                } else {
+                    LENGTH_PREFIX_SIZE
+                        + u32::from_be_bytes(buf[..LENGTH_PREFIX_SIZE].try_into().unwrap()) as usize
+                };
+                let actual = buf.len();
+                buf.advance(actual);
+                Err(Error::TruncatedMessage(expected, actual))
+            }
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<()> {
+        self.encode(&message, dst)
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<()> {
+        let length = message.length() as usize;
+        if length > self.max_frame_length {
+            return Err(Error::FrameTooLarge(length, self.max_frame_length));
+        }
+        dst.extend_from_slice(&Vec::from(message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+
+    #[test]
+    fn partial_read_test() {
+        let mut codec = MessageCodec::new();
+        let bytes = Vec::from(Message::Have { index: 7 });
+        let mut src = BytesMut::new();
+
+        for byte in &bytes[..bytes.len() - 1] {
+            src.extend_from_slice(&[*byte]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.extend_from_slice(&bytes[bytes.len() - 1..]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Message::Have { index: 7 })
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn multiple_frames_test() {
+        let mut codec = MessageCodec::new();
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&Vec::from(Message::KeepAlive));
+        src.extend_from_slice(&Vec::from(Message::Unchoke));
+        src.extend_from_slice(&[0, 0]);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::KeepAlive));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(matches!(
+            codec.decode_eof(&mut src),
+            Err(Error::TruncatedMessage(4, 2))
+        ));
+    }
+
+    #[test]
+    fn max_frame_length_test() {
+        let mut codec = MessageCodec::with_max_frame_length(16);
+        let mut src = BytesMut::from(&[0, 0, 0, 17, 5][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::FrameTooLarge(17, 16))
+        ));
+
+        let mut dst = BytesMut::new();
+        assert!(matches!(
+            codec.encode(Message::Bitfield(vec![0; 16]), &mut dst),
+            Err(Error::FrameTooLarge(17, 16))
+        ));
+        assert!(dst.is_empty());
+        codec
+            .encode(Message::Bitfield(vec![0; 15]), &mut dst)
+            .unwrap();
+        assert_eq!(dst.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn framed_test() {
+        let (client, server) = tokio::io::duplex(8);
+        let mut sink = FramedWrite::new(client, MessageCodec::new());
+        let mut stream = FramedRead::new(server, MessageCodec::new());
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Interested,
+            Message::Piece {
+                index: 3,
+                begin: 0,
+                block: vec![42; 100],
+            },
+        ];
+
+        let sent = messages.clone();
+        let writer = tokio::spawn(async move {
+            for message in sent {
+                sink.send(message).await.unwrap();
+            }
+        });
+        for message in messages {
+            assert_eq!(stream.next().await.unwrap().unwrap(), message);
+        }
+        writer.await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+}
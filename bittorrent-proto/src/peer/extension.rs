@@ -0,0 +1,523 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::{Error, Result},
+    peer::{Message, MetadataMessage, MetadataServer, UT_METADATA},
+};
+
+/// The extended message id reserved for the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The dictionary sent as the first extended message on a connection, as described in BEP 10.
+/// Every field is optional.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ExtensionHandshake {
+    extensions: BTreeMap<String, u8>,
+    client: Option<String>,
+    port: Option<u16>,
+    your_ip: Option<IpAddr>,
+    request_queue: Option<u32>,
+    metadata_size: Option<usize>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    extra_fields: ExtraFields,
+}
+
+impl ExtensionHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertises support for the extension `name`, which the remote side should send with
+    /// extended message id `id`. An id of 0 tells the remote side the extension is disabled.
+    pub fn with_extension(mut self, name: &str, id: u8) -> Self {
+        self.extensions.insert(name.to_string(), id);
+        self
+    }
+
+    /// `client`: the name and version of the client (`v`).
+    pub fn with_client(mut self, client: String) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// `port`: the local TCP port the sender listens on (`p`).
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// `your_ip`: the address of the receiving side, as seen by the sender (`yourip`).
+    pub fn with_your_ip(mut self, your_ip: IpAddr) -> Self {
+        self.your_ip = Some(your_ip);
+        self
+    }
+
+    /// `request_queue`: how many outstanding requests the sender accepts (`reqq`).
+    pub fn with_request_queue(mut self, request_queue: u32) -> Self {
+        self.request_queue = Some(request_queue);
+        self
+    }
+
+    /// `metadata_size`: the size of the `info` dictionary, as described in BEP 9.
+    pub fn with_metadata_size(mut self, metadata_size: usize) -> Self {
+        self.metadata_size = Some(metadata_size);
+        self
+    }
+
+    pub fn with_ipv4(mut self, ipv4: Ipv4Addr) -> Self {
+        self.ipv4 = Some(ipv4);
+        self
+    }
+
+    pub fn with_ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    /// Every extension in the `m` dictionary, including disabled ones.
+    pub fn extensions(&self) -> &BTreeMap<String, u8> {
+        &self.extensions
+    }
+
+    /// The id to send messages of the extension `name` with, or `None` if the sender doesn't
+    /// support it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .get(name)
+            .copied()
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        self.your_ip
+    }
+
+    pub fn request_queue(&self) -> Option<u32> {
+        self.request_queue
+    }
+
+    pub fn metadata_size(&self) -> Option<usize> {
+        self.metadata_size
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4
+    }
+
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6
+    }
+
+    /// Keys which aren't part of BEP 10, such as `upload_only`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
+
+    pub fn extra_fields_mut(&mut self) -> &mut ExtraFields {
+        &mut self.extra_fields
+    }
+
+    /// This handshake as an extended message.
+    pub fn to_message(&self) -> Message {
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: self
+                .to_bencode()
+                .expect("extension handshake should always be encodable"),
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        Self::from_bencode(payload).map_err(|e| Error::InvalidExtensionMessage(e.to_string()))
+    }
+}
+
+impl FromBencode for ExtensionHandshake {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for ExtensionHandshake {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut handshake = Self::new();
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"ipv4", val) => {
+                    let bytes = <[u8; 4]>::try_from(val.try_into_bytes()?)?;
+                    handshake.ipv4 = Some(Ipv4Addr::from(bytes));
+                }
+                (b"ipv6", val) => {
+                    let bytes = <[u8; 16]>::try_from(val.try_into_bytes()?)?;
+                    handshake.ipv6 = Some(Ipv6Addr::from(bytes));
+                }
+                (b"m", val) => {
+                    let mut extensions = val.try_into_dictionary()?;
+                    while let Some((name, id)) = extensions.next_pair()? {
+                        handshake.extensions.insert(
+                            String::from_utf8_lossy(name).into_owned(),
+                            u8::decode_bencode_object(id)?,
+                        );
+                    }
+                }
+                (b"metadata_size", val) => {
+                    handshake.metadata_size = Some(usize::decode_bencode_object(val)?)
+                }
+                (b"p", val) => handshake.port = Some(u16::decode_bencode_object(val)?),
+                (b"reqq", val) => handshake.request_queue = Some(u32::decode_bencode_object(val)?),
+                (b"v", val) => {
+                    handshake.client = Some(String::from_utf8_lossy(val.try_into_bytes()?).into())
+                }
+                (b"yourip", val) => {
+                    let bytes = val.try_into_bytes()?;
+                    handshake.your_ip = match bytes.len() {
+                        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes)?)),
+                        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes)?)),
+                        _ => None,
+                    };
+                }
+                (other, val) => decode_extra_field(&mut handshake.extra_fields, mode, other, val)?,
+            }
+        }
+        Ok(handshake)
+    }
+}
+
+impl ToBencode for ExtensionHandshake {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_unsorted_dict(|encoder| {
+            for (key, value) in self.extra_fields() {
+                encoder.emit_pair(key, value)?;
+            }
+            if let Some(ipv4) = self.ipv4 {
+                encoder.emit_pair_with(b"ipv4", |e| e.emit_bytes(&ipv4.octets()))?;
+            }
+            if let Some(ipv6) = self.ipv6 {
+                encoder.emit_pair_with(b"ipv6", |e| e.emit_bytes(&ipv6.octets()))?;
+            }
+            encoder.emit_pair(b"m", &self.extensions)?;
+            if let Some(metadata_size) = self.metadata_size {
+                encoder.emit_pair(b"metadata_size", metadata_size)?;
+            }
+            if let Some(port) = self.port {
+                encoder.emit_pair(b"p", port)?;
+            }
+            if let Some(request_queue) = self.request_queue {
+                encoder.emit_pair(b"reqq", request_queue)?;
+            }
+            if let Some(client) = &self.client {
+                encoder.emit_pair(b"v", client)?;
+            }
+            match self.your_ip {
+                Some(IpAddr::V4(ip)) => {
+                    encoder.emit_pair_with(b"yourip", |e| e.emit_bytes(&ip.octets()))?
+                }
+                Some(IpAddr::V6(ip)) => {
+                    encoder.emit_pair_with(b"yourip", |e| e.emit_bytes(&ip.octets()))?
+                }
+                None => {}
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Handles the messages of a single extension on a single connection.
+pub trait ExtensionHandler: Send {
+    /// Adds any fields this extension needs to the local extension handshake.
+    fn extend_handshake(&self, handshake: ExtensionHandshake) -> ExtensionHandshake {
+        handshake
+    }
+
+    /// Called whenever the remote extension handshake arrives. Peers may send it more than
+    /// once to update their settings.
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) {}
+
+    /// Handles the payload of a message sent for this extension, returning the payload of a
+    /// reply to send back, if any.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+/// Serves metadata to peers which request it.
+impl ExtensionHandler for MetadataServer {
+    fn extend_handshake(&self, handshake: ExtensionHandshake) -> ExtensionHandshake {
+        handshake.with_metadata_size(self.metadata_size())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let message = MetadataMessage::decode(payload)?;
+        Ok(self.respond(&message).map(|reply| reply.encode()))
+    }
+}
+
+/// The extensions supported on one side of a connection. Each registered extension is given a
+/// local extended message id, in order of registration starting from 1, and incoming extended
+/// messages are routed to its handler.
+pub struct ExtensionRegistry {
+    handshake: ExtensionHandshake,
+    handlers: Vec<(String, Box<dyn ExtensionHandler>)>,
+    remote: Option<ExtensionHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::with_handshake(ExtensionHandshake::new())
+    }
+
+    /// `handshake`: fields other than `m` to send in the local handshake, such as `v`.
+    pub fn with_handshake(handshake: ExtensionHandshake) -> Self {
+        Self {
+            handshake,
+            handlers: Vec::new(),
+            remote: None,
+        }
+    }
+
+    /// Registers `handler` for the extension `name`, returning its local id. Registering a
+    /// name again replaces the previous handler but keeps its id.
+    pub fn register(&mut self, name: &str, handler: Box<dyn ExtensionHandler>) -> u8 {
+        if let Some(id) = self.local_id(name) {
+            self.handlers[id as usize - 1].1 = handler;
+            return id;
+        }
+        assert!(
+            self.handlers.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.handlers.push((name.to_string(), handler));
+        self.handlers.len() as u8
+    }
+
+    /// The id the remote side should send messages of the extension `name` with.
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.handlers
+            .iter()
+            .position(|(registered, _)| registered == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    /// The id to send messages of the extension `name` with, once the remote handshake has
+    /// arrived.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.as_ref()?.extension_id(name)
+    }
+
+    /// The most recent extension handshake received from the remote side.
+    pub fn remote_handshake(&self) -> Option<&ExtensionHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The handshake to send to the remote side, listing every registered extension.
+    pub fn handshake(&self) -> ExtensionHandshake {
+        let mut handshake = self.handshake.clone();
+        for (index, (name, handler)) in self.handlers.iter().enumerate() {
+            handshake = handler.extend_handshake(handshake.with_extension(name, index as u8 + 1));
+        }
+        handshake
+    }
+
+    /// Builds a message of the extension `name` to send to the remote side.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Result<Message> {
+        let id = self
+            .remote_id(name)
+            .ok_or_else(|| Error::UnsupportedExtension(name.to_string()))?;
+        Ok(Message::Extended { id, payload })
+    }
+
+    /// Handles an extended message from the remote side, returning any reply to send back.
+    /// Handshakes are recorded and passed on to every handler.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Option<Message>> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::decode(payload)?;
+            for (_, handler) in &mut self.handlers {
+                handler.on_handshake(&handshake);
+            }
+            self.remote = Some(handshake);
+            return Ok(None);
+        }
+
+        let (name, handler) = self
+            .handlers
+            .get_mut(id as usize - 1)
+            .ok_or_else(|| Error::InvalidExtensionMessage(format!("unknown extended id {}", id)))?;
+        let reply = match handler.on_message(payload)? {
+            Some(reply) => reply,
+            None => return Ok(None),
+        };
+        // Looked up through the field, as `name` still borrows the handlers
+        match self
+            .remote
+            .as_ref()
+            .and_then(|remote| remote.extension_id(name))
+        {
+            Some(remote_id) => Ok(Some(Message::Extended {
+                id: remote_id,
+                payload: reply,
+            })),
+            None => {
+                log::debug!("Dropping {} reply to peer which doesn't support it", name);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionRegistry")
+            .field("handshake", &self.handshake)
+            .field(
+                "handlers",
+                &self
+                    .handlers
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("remote", &self.remote)
+            .finish()
+    }
+}
+
+/// Registers a [`MetadataServer`] under [`UT_METADATA`].
+impl From<MetadataServer> for ExtensionRegistry {
+    fn from(server: MetadataServer) -> Self {
+        let mut registry = Self::new();
+        registry.register(UT_METADATA, Box::new(server));
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_test() {
+        // Example from BEP 10, with a few more fields
+        let bytes = b"d4:ipv44:\x7f\x00\x00\x011:md11:LT_metadatai1e6:ut_pexi0ee1:pi6881e4:reqqi250e11:upload_onlyi1e1:v13:\xc2\xb5Torrent 1.26:yourip4:\x0a\x00\x00\x02e";
+        let handshake = ExtensionHandshake::decode(bytes).unwrap();
+        assert_eq!(handshake.extension_id("LT_metadata"), Some(1));
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.extensions().len(), 2);
+        assert_eq!(handshake.port(), Some(6881));
+        assert_eq!(handshake.client(), Some("µTorrent 1.2"));
+        assert_eq!(handshake.request_queue(), Some(250));
+        assert_eq!(handshake.your_ip(), Some(IpAddr::from([10, 0, 0, 2])));
+        assert_eq!(handshake.ipv4(), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(handshake.extra_fields().len(), 1);
+        assert_eq!(handshake.to_bencode().unwrap(), bytes);
+
+        let handshake = ExtensionHandshake::new()
+            .with_extension(UT_METADATA, 3)
+            .with_metadata_size(31235)
+            .with_ipv6(Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            ExtensionHandshake::decode(&handshake.to_bencode().unwrap()).unwrap(),
+            handshake
+        );
+        assert!(ExtensionHandshake::decode(b"d1:mi1ee").is_err());
+    }
+
+    struct Echo;
+
+    impl ExtensionHandler for Echo {
+        fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(Some(payload.to_vec()))
+        }
+    }
+
+    #[test]
+    fn registry_test() {
+        let mut registry =
+            ExtensionRegistry::with_handshake(ExtensionHandshake::new().with_client("test".into()));
+        assert_eq!(registry.register("echo", Box::new(Echo)), 1);
+        let server = MetadataServer::new(b"d4:name4:teste".to_vec());
+        assert_eq!(registry.register(UT_METADATA, Box::new(server)), 2);
+        assert_eq!(registry.register("echo", Box::new(Echo)), 1);
+
+        let handshake = registry.handshake();
+        assert_eq!(handshake.extension_id("echo"), Some(1));
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(2));
+        assert_eq!(handshake.metadata_size(), Some(14));
+        assert_eq!(handshake.client(), Some("test"));
+
+        // Nothing can be sent before the remote handshake arrives
+        assert!(matches!(
+            registry.message("echo", Vec::new()),
+            Err(Error::UnsupportedExtension(_))
+        ));
+        let remote = ExtensionHandshake::new()
+            .with_extension("echo", 7)
+            .with_extension(UT_METADATA, 9);
+        let remote = match remote.to_message() {
+            Message::Extended { id, payload } => registry.handle(id, &payload).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(remote, None);
+        assert_eq!(registry.remote_id("echo"), Some(7));
+
+        assert_eq!(
+            registry.handle(1, b"hello").unwrap(),
+            Some(Message::Extended {
+                id: 7,
+                payload: b"hello".to_vec()
+            })
+        );
+        let request = MetadataMessage::Request { piece: 0 }.encode();
+        let reply = match registry.handle(2, &request).unwrap() {
+            Some(Message::Extended { id: 9, payload }) => MetadataMessage::decode(&payload),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(
+            reply.unwrap(),
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 14,
+                data: b"d4:name4:teste".to_vec()
+            }
+        );
+        assert!(registry.handle(3, b"").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::net::Ipv4Addr;
+
+use sha1::Sha1;
+
+use crate::InfoHash;
+
+/// The number of allowed fast pieces suggested by BEP 6.
+pub const DEFAULT_ALLOWED_FAST_COUNT: usize = 10;
+
+/// The pieces a peer at `ip` may request while choked, generated by the canonical algorithm of
+/// BEP 6. Both sides can compute the same set, so a peer can't choose which pieces it gets for
+/// free by reconnecting. Returns fewer than `count` pieces only if there are fewer pieces in
+/// the torrent.
+pub fn allowed_fast_set(
+    info_hash: InfoHash,
+    ip: Ipv4Addr,
+    piece_count: u32,
+    count: usize,
+) -> Vec<u32> {
+    let count = count.min(piece_count as usize);
+    let mut allowed = Vec::with_capacity(count);
+
+    // Only the /24 network counts, so peers behind the same NAT share a set
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash.as_bytes());
+    while allowed.len() < count {
+        x = Sha1::from(&x).digest().bytes().to_vec();
+        for chunk in x.chunks(4) {
+            if allowed.len() >= count {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y % piece_count;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from BEP 6
+    #[test]
+    fn allowed_fast_set_test() {
+        let info_hash = InfoHash::new([0xaa; 20]);
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(
+            allowed_fast_set(info_hash, ip, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(info_hash, ip, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+
+        // Only the first three octets matter
+        assert_eq!(
+            allowed_fast_set(info_hash, Ipv4Addr::new(80, 4, 4, 1), 1313, 7),
+            allowed_fast_set(info_hash, ip, 1313, 7)
+        );
+        let mut small = allowed_fast_set(info_hash, ip, 3, DEFAULT_ALLOWED_FAST_COUNT);
+        small.sort_unstable();
+        assert_eq!(small, vec![0, 1, 2]);
+        assert!(allowed_fast_set(info_hash, ip, 0, DEFAULT_ALLOWED_FAST_COUNT).is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use std::convert::{TryFrom, TryInto};
+
+use crate::{
+    error::{Error, Result},
+    InfoHash,
+};
+
+/// The protocol string sent at the start of every handshake.
+pub const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// The length of an encoded handshake, in bytes.
+pub const HANDSHAKE_LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+/// The eight reserved bytes of a handshake, which peers use to advertise the protocol
+/// extensions they support. Bit assignments are listed in BEP 4.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct ReservedBits([u8; 8]);
+
+impl ReservedBits {
+    // (byte index, mask) pairs for each known extension
+    const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+    const DHT: (usize, u8) = (7, 0x01);
+    const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+    pub fn new(bytes: [u8; 8]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(&self) -> [u8; 8] {
+        self.0
+    }
+
+    /// Whether the extension protocol described in BEP 10 is supported.
+    pub fn extension_protocol(&self) -> bool {
+        self.get(Self::EXTENSION_PROTOCOL)
+    }
+
+    /// Whether the DHT described in BEP 5 is supported.
+    pub fn dht(&self) -> bool {
+        self.get(Self::DHT)
+    }
+
+    /// Whether the fast extension described in BEP 6 is supported.
+    pub fn fast_extension(&self) -> bool {
+        self.get(Self::FAST_EXTENSION)
+    }
+
+    pub fn with_extension_protocol(self, enabled: bool) -> Self {
+        self.with(Self::EXTENSION_PROTOCOL, enabled)
+    }
+
+    pub fn with_dht(self, enabled: bool) -> Self {
+        self.with(Self::DHT, enabled)
+    }
+
+    pub fn with_fast_extension(self, enabled: bool) -> Self {
+        self.with(Self::FAST_EXTENSION, enabled)
+    }
+
+    /// The bits set on both sides of a connection, i.e. the extensions which may be used.
+    pub fn intersection(&self, other: &ReservedBits) -> Self {
+        let mut bytes = self.0;
+        for (byte, other) in bytes.iter_mut().zip(other.0.iter()) {
+            *byte &= other;
+        }
+        Self(bytes)
+    }
+
+    fn get(&self, (index, mask): (usize, u8)) -> bool {
+        self.0[index] & mask != 0
+    }
+
+    fn with(mut self, (index, mask): (usize, u8), enabled: bool) -> Self {
+        if enabled {
+            self.0[index] |= mask;
+        } else {
+            self.0[index] &= !mask;
+        }
+        self
+    }
+}
+
+/// The handshake which opens every peer connection, as described in BEP 3.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Handshake {
+    reserved: ReservedBits,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// `reserved`: the extensions supported by the sender.
+    ///
+    /// `info_hash`: the info hash of the torrent being exchanged.
+    ///
+    /// `peer_id`: the 20-byte identifier of the sender.
+    pub fn new(reserved: ReservedBits, info_hash: InfoHash, peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn reserved(&self) -> ReservedBits {
+        self.reserved
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    /// Appends the wire representation of this handshake to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.reserve(HANDSHAKE_LENGTH);
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved.bytes());
+        buf.extend_from_slice(self.info_hash.as_bytes());
+        buf.extend_from_slice(&self.peer_id);
+    }
+
+    /// Parses a handshake from `bytes`, which must contain exactly one handshake.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let protocol_length = *bytes.first().ok_or(Error::InvalidHandshakeLength(0))? as usize;
+        let protocol = bytes.get(1..1 + protocol_length).unwrap_or(&bytes[1..]);
+        if protocol != PROTOCOL {
+            return Err(Error::InvalidProtocol(
+                String::from_utf8_lossy(protocol).into_owned(),
+            ));
+        }
+        if bytes.len() != HANDSHAKE_LENGTH {
+            return Err(Error::InvalidHandshakeLength(bytes.len()));
+        }
+
+        let rest = &bytes[1 + PROTOCOL.len()..];
+        Ok(Self::new(
+            ReservedBits::new(rest[..8].try_into().unwrap()),
+            InfoHash::new(rest[8..28].try_into().unwrap()),
+            rest[28..48].try_into().unwrap(),
+        ))
+    }
+}
+
+impl From<&Handshake> for Vec<u8> {
+    fn from(handshake: &Handshake) -> Self {
+        let mut result = Vec::new();
+        handshake.encode(&mut result);
+        result
+    }
+}
+
+impl TryFrom<&[u8]> for Handshake {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        Handshake::decode(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> Handshake {
+        Handshake::new(
+            ReservedBits::default()
+                .with_extension_protocol(true)
+                .with_fast_extension(true),
+            InfoHash::new([1; 20]),
+            *b"-BT0000-abcdefghijkl",
+        )
+    }
+
+    #[test]
+    fn reserved_bits_test() {
+        let reserved = ReservedBits::new([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(reserved.extension_protocol());
+        assert!(reserved.dht());
+        assert!(reserved.fast_extension());
+        assert_eq!(
+            reserved,
+            ReservedBits::default()
+                .with_extension_protocol(true)
+                .with_dht(true)
+                .with_fast_extension(true)
+        );
+        assert!(!reserved.with_dht(false).dht());
+
+        let other = ReservedBits::default()
+            .with_dht(true)
+            .with_fast_extension(true);
+        assert_eq!(reserved.intersection(&other), other);
+    }
+
+    #[test]
+    fn encoding_test() {
+        let bytes = Vec::from(&handshake());
+        assert_eq!(bytes.len(), HANDSHAKE_LENGTH);
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+        assert_eq!(&bytes[28..48], &[1; 20]);
+        assert_eq!(&bytes[48..], b"-BT0000-abcdefghijkl");
+    }
+
+    #[test]
+    fn decoding_test() {
+        let bytes = Vec::from(&handshake());
+        assert_eq!(Handshake::try_from(bytes.as_slice()).unwrap(), handshake());
+
+        assert!(matches!(
+            Handshake::decode(&bytes[..60]),
+            Err(Error::InvalidHandshakeLength(60))
+        ));
+        let mut wrong_protocol = bytes.clone();
+        wrong_protocol[1] = b'b';
+        assert!(matches!(
+            Handshake::decode(&wrong_protocol),
+            Err(Error::InvalidProtocol(protocol)) if protocol == "bitTorrent protocol"
+        ));
+        assert!(matches!(
+            Handshake::decode(&[]),
+            Err(Error::InvalidHandshakeLength(0))
+        ));
+    }
+}
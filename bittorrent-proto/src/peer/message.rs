@@ -1,12 +1,14 @@
-use std::mem::size_of;
+use std::{
+    convert::{TryFrom, TryInto},
+    mem::size_of,
+};
 
-pub struct Message {
-    length: u32,
-    id: MessageType,
-    payload: Vec<u8>,
-}
+use crate::error::{Error, Result};
+
+/// Size of the big-endian length prefix which precedes every message on the wire.
+pub const LENGTH_PREFIX_SIZE: usize = size_of::<u32>();
 
-#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum MessageType {
     Choke = 0,
@@ -18,15 +20,472 @@ pub enum MessageType {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
+}
+
+impl MessageType {
+    /// Whether this message is part of the fast extension described in BEP 6, and so may only
+    /// be sent if both sides advertise it.
+    pub fn is_fast_extension(&self) -> bool {
+        matches!(
+            self,
+            MessageType::SuggestPiece
+                | MessageType::HaveAll
+                | MessageType::HaveNone
+                | MessageType::RejectRequest
+                | MessageType::AllowedFast
+        )
+    }
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(MessageType::Choke),
+            1 => Ok(MessageType::Unchoke),
+            2 => Ok(MessageType::Interested),
+            3 => Ok(MessageType::NotInterested),
+            4 => Ok(MessageType::Have),
+            5 => Ok(MessageType::Bitfield),
+            6 => Ok(MessageType::Request),
+            7 => Ok(MessageType::Piece),
+            8 => Ok(MessageType::Cancel),
+            13 => Ok(MessageType::SuggestPiece),
+            14 => Ok(MessageType::HaveAll),
+            15 => Ok(MessageType::HaveNone),
+            16 => Ok(MessageType::RejectRequest),
+            17 => Ok(MessageType::AllowedFast),
+            20 => Ok(MessageType::Extended),
+            other => Err(Error::UnknownMessageId(other)),
+        }
+    }
+}
+
+/// A message of the peer wire protocol, as described in BEP 3.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    /// A zero-length message, sent to keep a connection alive.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// `index`: the zero-based index of a piece that has just been downloaded and verified.
+    Have {
+        index: u32,
+    },
+    /// A bitfield with each index that the sender has set to 1. The high bit of the first
+    /// byte corresponds to piece index 0, and spare bits at the end are cleared.
+    Bitfield(Vec<u8>),
+    /// `index`: the zero-based piece index.
+    ///
+    /// `begin`: the zero-based byte offset within the piece.
+    ///
+    /// `length`: the requested length, in bytes.
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// `index`: the zero-based piece index.
+    ///
+    /// `begin`: the zero-based byte offset within the piece.
+    ///
+    /// `block`: the block of data itself, which is a subset of the piece.
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    /// Same layout as `Request`, used to cancel a pending request.
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// `index`: a piece the sender suggests downloading, e.g. because it's in the sender's cache.
+    SuggestPiece {
+        index: u32,
+    },
+    /// Replaces the bitfield when the sender has every piece.
+    HaveAll,
+    /// Replaces the bitfield when the sender has no pieces.
+    HaveNone,
+    /// Same layout as `Request`, sent to tell the receiver that a request won't be served.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// `index`: a piece the receiver may request even while choked.
+    AllowedFast {
+        index: u32,
+    },
+    /// A message of the extension protocol, as described in BEP 10.
+    ///
+    /// `id`: the extended message id, as assigned by the receiving side.
+    ///
+    /// `payload`: the body of the extended message, whose format depends on the extension.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// The type of this message, or `None` for keep-alives, which have no message id.
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self {
+            Message::KeepAlive => None,
+            Message::Choke => Some(MessageType::Choke),
+            Message::Unchoke => Some(MessageType::Unchoke),
+            Message::Interested => Some(MessageType::Interested),
+            Message::NotInterested => Some(MessageType::NotInterested),
+            Message::Have { .. } => Some(MessageType::Have),
+            Message::Bitfield(_) => Some(MessageType::Bitfield),
+            Message::Request { .. } => Some(MessageType::Request),
+            Message::Piece { .. } => Some(MessageType::Piece),
+            Message::Cancel { .. } => Some(MessageType::Cancel),
+            Message::SuggestPiece { .. } => Some(MessageType::SuggestPiece),
+            Message::HaveAll => Some(MessageType::HaveAll),
+            Message::HaveNone => Some(MessageType::HaveNone),
+            Message::RejectRequest { .. } => Some(MessageType::RejectRequest),
+            Message::AllowedFast { .. } => Some(MessageType::AllowedFast),
+            Message::Extended { .. } => Some(MessageType::Extended),
+        }
+    }
+
+    /// The value of the length prefix for this message, i.e. the number of bytes that follow
+    /// the prefix on the wire.
+    pub fn length(&self) -> u32 {
+        let payload_length = match self {
+            Message::KeepAlive => return 0,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 0,
+            Message::Have { .. } | Message::SuggestPiece { .. } | Message::AllowedFast { .. } => 4,
+            Message::Bitfield(bitfield) => bitfield.len(),
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 12,
+            Message::Piece { block, .. } => 8 + block.len(),
+            Message::Extended { payload, .. } => 1 + payload.len(),
+        };
+        (size_of::<MessageType>() + payload_length) as u32
+    }
+
+    /// Appends the wire representation of this message, including the length prefix, to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.reserve(LENGTH_PREFIX_SIZE + self.length() as usize);
+        buf.extend_from_slice(&self.length().to_be_bytes());
+        if let Some(message_type) = self.message_type() {
+            buf.push(message_type as u8);
+        }
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have { index }
+            | Message::SuggestPiece { index }
+            | Message::AllowedFast { index } => buf.extend_from_slice(&index.to_be_bytes()),
+            Message::Bitfield(bitfield) => buf.extend_from_slice(bitfield),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.extend_from_slice(&index.to_be_bytes());
+                buf.extend_from_slice(&begin.to_be_bytes());
+                buf.extend_from_slice(block);
+            }
+            Message::Extended { id, payload } => {
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+        }
+    }
+
+    /// Parses a single message from `frame`, which must contain exactly one length-prefixed
+    /// message.
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        if frame.len() < LENGTH_PREFIX_SIZE {
+            return Err(Error::TruncatedMessage(LENGTH_PREFIX_SIZE, frame.len()));
+        }
+        let (prefix, body) = frame.split_at(LENGTH_PREFIX_SIZE);
+        let length = u32::from_be_bytes(prefix.try_into().unwrap());
+        if body.len() < length as usize {
+            return Err(Error::TruncatedMessage(
+                LENGTH_PREFIX_SIZE + length as usize,
+                frame.len(),
+            ));
+        } else if body.len() > length as usize {
+            return Err(Error::MessageLengthMismatch(length, body.len()));
+        }
+
+        let (id, payload) = match body.split_first() {
+            Some((id, payload)) => (*id, payload),
+            None => return Ok(Message::KeepAlive),
+        };
+        let message_type = MessageType::try_from(id)?;
+        let expect_length = |expected: usize| {
+            if payload.len() == expected {
+                Ok(())
+            } else {
+                Err(Error::InvalidMessageLength(id, length))
+            }
+        };
+
+        match message_type {
+            MessageType::Choke => expect_length(0).map(|_| Message::Choke),
+            MessageType::Unchoke => expect_length(0).map(|_| Message::Unchoke),
+            MessageType::Interested => expect_length(0).map(|_| Message::Interested),
+            MessageType::NotInterested => expect_length(0).map(|_| Message::NotInterested),
+            MessageType::Have => {
+                expect_length(4)?;
+                Ok(Message::Have {
+                    index: read_u32(payload, 0),
+                })
+            }
+            MessageType::Bitfield => Ok(Message::Bitfield(payload.to_vec())),
+            MessageType::Request => {
+                expect_length(12)?;
+                Ok(Message::Request {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    length: read_u32(payload, 8),
+                })
+            }
+            MessageType::Piece => {
+                if payload.len() < 8 {
+                    return Err(Error::InvalidMessageLength(id, length));
+                }
+                Ok(Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec(),
+                })
+            }
+            MessageType::Cancel => {
+                expect_length(12)?;
+                Ok(Message::Cancel {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    length: read_u32(payload, 8),
+                })
+            }
+            MessageType::SuggestPiece => {
+                expect_length(4)?;
+                Ok(Message::SuggestPiece {
+                    index: read_u32(payload, 0),
+                })
+            }
+            MessageType::HaveAll => expect_length(0).map(|_| Message::HaveAll),
+            MessageType::HaveNone => expect_length(0).map(|_| Message::HaveNone),
+            MessageType::RejectRequest => {
+                expect_length(12)?;
+                Ok(Message::RejectRequest {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    length: read_u32(payload, 8),
+                })
+            }
+            MessageType::AllowedFast => {
+                expect_length(4)?;
+                Ok(Message::AllowedFast {
+                    index: read_u32(payload, 0),
+                })
+            }
+            MessageType::Extended => match payload.split_first() {
+                Some((extended_id, payload)) => Ok(Message::Extended {
+                    id: *extended_id,
+                    payload: payload.to_vec(),
+                }),
+                None => Err(Error::InvalidMessageLength(id, length)),
+            },
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl From<&Message> for Vec<u8> {
+    fn from(message: &Message) -> Self {
+        let mut result = Vec::new();
+        message.encode(&mut result);
+        result
+    }
 }
 
 impl From<Message> for Vec<u8> {
     fn from(message: Message) -> Self {
-        let mut result =
-            Vec::with_capacity(size_of::<u32>() + size_of::<MessageType>() + message.payload.len());
-        result.extend(message.length.to_be_bytes().to_vec());
-        result.push(message.id as u8);
-        result.extend(message.payload);
-        result
+        Vec::from(&message)
+    }
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = Error;
+
+    fn try_from(frame: &[u8]) -> Result<Self> {
+        Message::decode(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<(Message, Vec<u8>)> {
+        vec![
+            (Message::KeepAlive, vec![0, 0, 0, 0]),
+            (Message::Choke, vec![0, 0, 0, 1, 0]),
+            (Message::Unchoke, vec![0, 0, 0, 1, 1]),
+            (Message::Interested, vec![0, 0, 0, 1, 2]),
+            (Message::NotInterested, vec![0, 0, 0, 1, 3]),
+            (
+                Message::Have { index: 258 },
+                vec![0, 0, 0, 5, 4, 0, 0, 1, 2],
+            ),
+            (
+                Message::Bitfield(vec![0b1010_0000, 0xff]),
+                vec![0, 0, 0, 3, 5, 0b1010_0000, 0xff],
+            ),
+            (
+                Message::Request {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0],
+            ),
+            (
+                Message::Piece {
+                    index: 1,
+                    begin: 2,
+                    block: b"abc".to_vec(),
+                },
+                vec![0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 2, b'a', b'b', b'c'],
+            ),
+            (
+                Message::Cancel {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0],
+            ),
+            (
+                Message::SuggestPiece { index: 5 },
+                vec![0, 0, 0, 5, 13, 0, 0, 0, 5],
+            ),
+            (Message::HaveAll, vec![0, 0, 0, 1, 14]),
+            (Message::HaveNone, vec![0, 0, 0, 1, 15]),
+            (
+                Message::RejectRequest {
+                    index: 1,
+                    begin: 16384,
+                    length: 16384,
+                },
+                vec![0, 0, 0, 13, 16, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0],
+            ),
+            (
+                Message::AllowedFast { index: 258 },
+                vec![0, 0, 0, 5, 17, 0, 0, 1, 2],
+            ),
+            (
+                Message::Extended {
+                    id: 3,
+                    payload: b"de".to_vec(),
+                },
+                vec![0, 0, 0, 4, 20, 3, b'd', b'e'],
+            ),
+        ]
+    }
+
+    #[test]
+    fn encoding_test() {
+        for (message, bytes) in messages() {
+            assert_eq!(message.length() as usize + LENGTH_PREFIX_SIZE, bytes.len());
+            assert_eq!(Vec::from(&message), bytes);
+        }
+    }
+
+    #[test]
+    fn decoding_test() {
+        for (message, bytes) in messages() {
+            assert_eq!(Message::try_from(bytes.as_slice()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn decoding_errors_test() {
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 1, 9]),
+            Err(Error::UnknownMessageId(9))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0]),
+            Err(Error::TruncatedMessage(4, 2))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 5, 4, 0, 0]),
+            Err(Error::TruncatedMessage(9, 7))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 1, 0, 0]),
+            Err(Error::MessageLengthMismatch(1, 2))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 3, 4, 0, 0]),
+            Err(Error::InvalidMessageLength(4, 3))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 5, 7, 0, 0, 0, 1]),
+            Err(Error::InvalidMessageLength(7, 5))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 2, 14, 0]),
+            Err(Error::InvalidMessageLength(14, 2))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 1, 18]),
+            Err(Error::UnknownMessageId(18))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 1, 20]),
+            Err(Error::InvalidMessageLength(20, 1))
+        ));
     }
 }
@@ -0,0 +1,551 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::{
+    bencode,
+    error::{Error, Result},
+    Info, InfoHash, InfoHashV2,
+};
+
+/// The name under which the metadata extension is registered in the extension handshake.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The length of every metadata piece except possibly the last.
+pub const METADATA_PIECE_LENGTH: usize = 16 * 1024;
+
+/// The largest `metadata_size` accepted from a peer.
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a peer is ignored for after its first offence. This doubles with every further
+/// offence, up to [`MAX_PENALTY`].
+pub const BASE_PENALTY: Duration = Duration::from_secs(30);
+
+pub const MAX_PENALTY: Duration = Duration::from_secs(60 * 60);
+
+/// A message of the metadata extension, as described in BEP 9. These are sent as the payload
+/// of [`Message::Extended`](crate::peer::Message::Extended).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    /// `total_size`: the length of the whole metadata, which should match the `metadata_size`
+    /// of the sender's extension handshake.
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// Sent in response to a request for a piece the sender can't or won't provide.
+    Reject {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage {
+    pub fn piece(&self) -> u32 {
+        match self {
+            MetadataMessage::Request { piece }
+            | MetadataMessage::Data { piece, .. }
+            | MetadataMessage::Reject { piece } => *piece,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = self
+            .to_bencode()
+            .expect("metadata message headers have sorted keys and a fixed depth");
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    /// Parses an extended message payload. Data messages are a bencoded dictionary followed
+    /// directly by the piece itself.
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let invalid = |reason: String| Error::InvalidExtensionMessage(reason);
+        let header_length = bencode::value_length(payload)
+            .ok_or_else(|| invalid(String::from("malformed ut_metadata message")))?;
+        let (header, data) = payload.split_at(header_length);
+        let header = Header::from_bencode(header).map_err(|e| invalid(e.to_string()))?;
+
+        let piece = header
+            .piece
+            .ok_or_else(|| invalid(String::from("missing piece")))?;
+        let message = match header.msg_type {
+            Some(0) => MetadataMessage::Request { piece },
+            Some(1) => MetadataMessage::Data {
+                piece,
+                total_size: header
+                    .total_size
+                    .ok_or_else(|| invalid(String::from("missing total_size")))?,
+                data: data.to_vec(),
+            },
+            Some(2) => MetadataMessage::Reject { piece },
+            Some(other) => return Err(invalid(format!("unknown msg_type {}", other))),
+            None => return Err(invalid(String::from("missing msg_type"))),
+        };
+        if !data.is_empty() && !matches!(message, MetadataMessage::Data { .. }) {
+            return Err(invalid(String::from("trailing data")));
+        }
+        Ok(message)
+    }
+}
+
+/// The bencoded part of a metadata message.
+struct Header {
+    msg_type: Option<u8>,
+    piece: Option<u32>,
+    total_size: Option<usize>,
+}
+
+impl FromBencode for Header {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut header = Header {
+            msg_type: None,
+            piece: None,
+            total_size: None,
+        };
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"msg_type", val) => header.msg_type = Some(u8::decode_bencode_object(val)?),
+                (b"piece", val) => header.piece = Some(u32::decode_bencode_object(val)?),
+                (b"total_size", val) => {
+                    header.total_size = Some(usize::decode_bencode_object(val)?)
+                }
+                _ => {}
+            }
+        }
+        Ok(header)
+    }
+}
+
+impl ToBencode for MetadataMessage {
+    const MAX_DEPTH: usize = 1;
+
+    /// Encodes the dictionary at the start of the message, without the data of data messages.
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        encoder.emit_dict(|mut encoder| {
+            let msg_type = match self {
+                MetadataMessage::Request { .. } => 0,
+                MetadataMessage::Data { .. } => 1,
+                MetadataMessage::Reject { .. } => 2,
+            };
+            encoder.emit_pair(b"msg_type", msg_type)?;
+            encoder.emit_pair(b"piece", self.piece())?;
+            if let MetadataMessage::Data { total_size, .. } = self {
+                encoder.emit_pair(b"total_size", total_size)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The number of metadata pieces for metadata of `size` bytes.
+pub fn metadata_piece_count(size: usize) -> usize {
+    size.div_ceil(METADATA_PIECE_LENGTH)
+}
+
+/// Answers metadata requests from peers using the raw bytes of a torrent's `info` dictionary.
+#[derive(Debug, Clone)]
+pub struct MetadataServer {
+    metadata: Vec<u8>,
+}
+
+impl MetadataServer {
+    /// `metadata`: the bencoded `info` dictionary, exactly as it was hashed.
+    pub fn new(metadata: Vec<u8>) -> Self {
+        Self { metadata }
+    }
+
+    /// The `metadata_size` to advertise in the extension handshake.
+    pub fn metadata_size(&self) -> usize {
+        self.metadata.len()
+    }
+
+    /// The reply to a message from a peer: the requested piece, or a rejection if there is no
+    /// such piece. Messages other than requests need no reply.
+    pub fn respond(&self, message: &MetadataMessage) -> Option<MetadataMessage> {
+        let piece = match message {
+            MetadataMessage::Request { piece } => *piece,
+            _ => return None,
+        };
+        let start = piece as usize * METADATA_PIECE_LENGTH;
+        if start >= self.metadata.len() {
+            return Some(MetadataMessage::Reject { piece });
+        }
+        let end = (start + METADATA_PIECE_LENGTH).min(self.metadata.len());
+        Some(MetadataMessage::Data {
+            piece,
+            total_size: self.metadata.len(),
+            data: self.metadata[start..end].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    offences: u32,
+    until: Instant,
+}
+
+/// Assembles the metadata of a torrent from pieces received from any number of peers, as when
+/// resolving a magnet link.
+///
+/// Each missing piece is handed out to one peer at a time. Peers that send malformed pieces, or
+/// pieces of metadata which doesn't match the info hash, are penalized: they aren't given any
+/// requests for a while, and the delay grows with each offence.
+#[derive(Debug, Clone)]
+pub struct MetadataDownload {
+    info_hash: InfoHash,
+    size: usize,
+    pieces: Vec<Option<(Vec<u8>, SocketAddr)>>,
+    requested: Vec<Option<SocketAddr>>,
+    penalties: HashMap<SocketAddr, Penalty>,
+}
+
+impl MetadataDownload {
+    /// `info_hash`: the hash the assembled metadata must match.
+    ///
+    /// `size`: the `metadata_size` advertised by a peer.
+    pub fn new(info_hash: InfoHash, size: usize) -> Result<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(Error::InvalidExtensionMessage(format!(
+                "invalid metadata_size {}",
+                size
+            )));
+        }
+        let piece_count = metadata_piece_count(size);
+        Ok(Self {
+            info_hash,
+            size,
+            pieces: vec![None; piece_count],
+            requested: vec![None; piece_count],
+            penalties: HashMap::new(),
+        })
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Whether requests may be sent to `peer` at `now`, i.e. it isn't serving a penalty.
+    pub fn is_allowed(&self, peer: SocketAddr, now: Instant) -> bool {
+        self.penalties
+            .get(&peer)
+            .is_none_or(|penalty| penalty.until <= now)
+    }
+
+    /// The next piece to request from `peer`, which is then considered in flight until it
+    /// arrives or is rejected. Returns `None` if every missing piece is already in flight, or
+    /// if `peer` is serving a penalty.
+    pub fn next_request(&mut self, peer: SocketAddr, now: Instant) -> Option<MetadataMessage> {
+        if !self.is_allowed(peer, now) {
+            return None;
+        }
+        let index = (0..self.pieces.len())
+            .find(|i| self.pieces[*i].is_none() && self.requested[*i].is_none())?;
+        self.requested[index] = Some(peer);
+        Some(MetadataMessage::Request {
+            piece: index as u32,
+        })
+    }
+
+    /// Handles a metadata message from `peer`, storing the piece of a data message. Returns an
+    /// error, and penalizes the peer, if the message doesn't fit the metadata being assembled.
+    pub fn handle(
+        &mut self,
+        peer: SocketAddr,
+        message: MetadataMessage,
+        now: Instant,
+    ) -> Result<()> {
+        match message {
+            MetadataMessage::Request { .. } => Ok(()),
+            MetadataMessage::Reject { piece } => {
+                self.release(peer, piece as usize);
+                Ok(())
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let index = piece as usize;
+                self.release(peer, index);
+                if let Err(e) = self.check_piece(index, total_size, &data) {
+                    self.penalize(peer, now);
+                    return Err(e);
+                }
+                if self.pieces[index].is_none() {
+                    self.pieces[index] = Some((data, peer));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Forgets every request in flight to `peer`, e.g. because it disconnected.
+    pub fn release_peer(&mut self, peer: SocketAddr) {
+        for requested in &mut self.requested {
+            if *requested == Some(peer) {
+                *requested = None;
+            }
+        }
+    }
+
+    /// Checks the assembled metadata against the info hash and decodes it. If it doesn't match,
+    /// every peer which contributed a piece is penalized and the download starts over.
+    pub fn finish(&mut self, now: Instant) -> Result<Info> {
+        if !self.is_complete() {
+            return Err(Error::InvalidMetadata(String::from(
+                "metadata download is incomplete",
+            )));
+        }
+        let mut metadata = Vec::with_capacity(self.size);
+        for (data, _) in self.pieces.iter().flatten() {
+            metadata.extend_from_slice(data);
+        }
+
+        // v2-only torrents are identified by their truncated SHA-256 info hash instead
+        let info_hash = InfoHash::from_info_bytes(&metadata);
+        if info_hash != self.info_hash
+            && InfoHashV2::from_info_bytes(&metadata).truncated() != self.info_hash
+        {
+            let peers = self
+                .pieces
+                .iter_mut()
+                .filter_map(|piece| piece.take().map(|(_, peer)| peer))
+                .collect::<Vec<_>>();
+            for peer in peers {
+                self.penalize(peer, now);
+            }
+            return Err(Error::InfoHashMismatch(self.info_hash, info_hash));
+        }
+        Info::from_bencode(&metadata).map_err(|e| Error::InvalidMetadata(e.to_string()))
+    }
+
+    fn check_piece(&self, index: usize, total_size: usize, data: &[u8]) -> Result<()> {
+        if total_size != self.size {
+            return Err(Error::InvalidExtensionMessage(format!(
+                "metadata total_size {} does not match {}",
+                total_size, self.size
+            )));
+        }
+        if index >= self.pieces.len() {
+            return Err(Error::InvalidExtensionMessage(format!(
+                "metadata piece {} out of range",
+                index
+            )));
+        }
+        let expected = METADATA_PIECE_LENGTH.min(self.size - index * METADATA_PIECE_LENGTH);
+        if data.len() != expected {
+            return Err(Error::InvalidExtensionMessage(format!(
+                "metadata piece {} has {} bytes, expected {}",
+                index,
+                data.len(),
+                expected
+            )));
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, peer: SocketAddr, index: usize) {
+        if let Some(requested) = self.requested.get_mut(index) {
+            if *requested == Some(peer) {
+                *requested = None;
+            }
+        }
+    }
+
+    fn penalize(&mut self, peer: SocketAddr, now: Instant) {
+        self.release_peer(peer);
+        let offences = self.penalties.get(&peer).map_or(0, |p| p.offences) + 1;
+        let delay = BASE_PENALTY
+            .checked_mul(2_u32.saturating_pow(offences - 1))
+            .map_or(MAX_PENALTY, |delay| delay.min(MAX_PENALTY));
+        self.penalties.insert(
+            peer,
+            Penalty {
+                offences,
+                until: now + delay,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bendy::encoding::ToBencode;
+
+    use super::*;
+
+    fn info() -> Info {
+        Info::new(
+            String::from("some name"),
+            16384,
+            vec![7; 20 * 2000],
+            Some(16384 * 2000),
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn message_test() {
+        let messages = vec![
+            (
+                MetadataMessage::Request { piece: 0 },
+                b"d8:msg_typei0e5:piecei0ee".to_vec(),
+            ),
+            (
+                MetadataMessage::Data {
+                    piece: 1,
+                    total_size: 16390,
+                    data: b"xxxxxx".to_vec(),
+                },
+                b"d8:msg_typei1e5:piecei1e10:total_sizei16390eexxxxxx".to_vec(),
+            ),
+            (
+                MetadataMessage::Reject { piece: 2 },
+                b"d8:msg_typei2e5:piecei2ee".to_vec(),
+            ),
+        ];
+        for (message, bytes) in messages {
+            assert_eq!(message.encode(), bytes);
+            assert_eq!(MetadataMessage::decode(&bytes).unwrap(), message);
+        }
+
+        for malformed in [
+            &b"d8:msg_typei0ee"[..],
+            b"d8:msg_typei1e5:piecei0eexxxx",
+            b"d8:msg_typei3e5:piecei0ee",
+            b"d8:msg_typei0e5:piecei0eextra",
+            b"d8:msg_typei0e",
+        ] {
+            assert!(MetadataMessage::decode(malformed).is_err());
+        }
+    }
+
+    #[test]
+    fn exchange_test() {
+        let info = info();
+        let metadata = info.to_bencode().unwrap();
+        let info_hash = InfoHash::from_info_bytes(&metadata);
+        let server = MetadataServer::new(metadata.clone());
+        assert_eq!(metadata_piece_count(server.metadata_size()), 3);
+
+        let now = Instant::now();
+        let mut download = MetadataDownload::new(info_hash, server.metadata_size()).unwrap();
+        let requests = [peer(1), peer(2), peer(1)]
+            .iter()
+            .map(|peer| download.next_request(*peer, now).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(requests[2], MetadataMessage::Request { piece: 2 });
+        assert_eq!(download.next_request(peer(3), now), None);
+
+        // A rejected piece can be requested from someone else
+        download
+            .handle(peer(2), MetadataMessage::Reject { piece: 1 }, now)
+            .unwrap();
+        assert_eq!(
+            download.next_request(peer(3), now),
+            Some(MetadataMessage::Request { piece: 1 })
+        );
+
+        for (peer, request) in [peer(1), peer(3), peer(1)].iter().zip(&requests) {
+            let data = server.respond(request).unwrap();
+            download.handle(*peer, data, now).unwrap();
+        }
+        assert!(download.is_complete());
+        assert_eq!(download.finish(now).unwrap(), info);
+
+        assert_eq!(
+            server.respond(&MetadataMessage::Request { piece: 3 }),
+            Some(MetadataMessage::Reject { piece: 3 })
+        );
+        assert_eq!(server.respond(&MetadataMessage::Reject { piece: 0 }), None);
+    }
+
+    #[test]
+    fn bad_peer_test() {
+        let metadata = info().to_bencode().unwrap();
+        let server = MetadataServer::new(metadata.clone());
+        let now = Instant::now();
+        let mut download =
+            MetadataDownload::new(InfoHash::from_info_bytes(&metadata), metadata.len()).unwrap();
+        assert!(MetadataDownload::new(InfoHash::new([0; 20]), 0).is_err());
+        assert!(MetadataDownload::new(InfoHash::new([0; 20]), MAX_METADATA_SIZE + 1).is_err());
+
+        // A truncated piece is rejected and the peer is ignored for a while
+        download.next_request(peer(1), now).unwrap();
+        let truncated = MetadataMessage::Data {
+            piece: 0,
+            total_size: metadata.len(),
+            data: vec![0; 100],
+        };
+        assert!(download.handle(peer(1), truncated, now).is_err());
+        assert!(!download.is_allowed(peer(1), now));
+        assert_eq!(download.next_request(peer(1), now), None);
+        assert!(download.is_allowed(peer(1), now + BASE_PENALTY));
+
+        // Pieces which don't hash to the info hash penalize everyone who sent them
+        for index in 0..3 {
+            let mut data = server
+                .respond(&MetadataMessage::Request { piece: index })
+                .unwrap();
+            if let MetadataMessage::Data { data, .. } = &mut data {
+                data[0] ^= 1;
+            }
+            download.handle(peer(2 + index as u16), data, now).unwrap();
+        }
+        assert!(matches!(
+            download.finish(now),
+            Err(Error::InfoHashMismatch(_, _))
+        ));
+        assert!(!download.is_complete());
+        assert!((2..5).all(|port| !download.is_allowed(peer(port), now)));
+
+        // A second offence doubles the penalty
+        let wrong_size = MetadataMessage::Data {
+            piece: 0,
+            total_size: 1,
+            data: Vec::new(),
+        };
+        assert!(download.handle(peer(1), wrong_size, now).is_err());
+        assert!(!download.is_allowed(peer(1), now + BASE_PENALTY));
+        assert!(download.is_allowed(peer(1), now + BASE_PENALTY * 2));
+    }
+}
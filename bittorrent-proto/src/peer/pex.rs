@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{self, SingleItemEncoder, ToBencode},
+};
+
+use crate::{
+    error::{Error, Result},
+    Peer,
+};
+
+/// The name under which peer exchange is registered in the extension handshake.
+pub const UT_PEX: &str = "ut_pex";
+
+/// The shortest time allowed between two peer exchange messages on a connection.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most peers a single message may add, and separately the most it may drop.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// What is known about an added peer, sent in `added.f` and `added6.f`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct PexFlags(u8);
+
+impl PexFlags {
+    /// The peer prefers encrypted connections.
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    /// The peer is a seed or partial seed.
+    pub const SEED: PexFlags = PexFlags(0x02);
+    /// The peer supports uTP.
+    pub const UTP: PexFlags = PexFlags(0x04);
+    /// The peer supports the holepunch extension.
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    /// The sender connected to the peer, so it's known to be reachable.
+    pub const OUTGOING: PexFlags = PexFlags(0x10);
+
+    pub fn new(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(&self, other: PexFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: PexFlags) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A peer exchange message, as described in BEP 11. These are sent as the payload of
+/// [`Message::Extended`](crate::peer::Message::Extended).
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PexMessage {
+    added: Vec<(SocketAddr, PexFlags)>,
+    dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// `added`: peers the sender has connected to since its last message, with their flags.
+    ///
+    /// `dropped`: peers the sender has disconnected from since its last message.
+    pub fn new(added: Vec<(SocketAddr, PexFlags)>, dropped: Vec<SocketAddr>) -> Self {
+        Self { added, dropped }
+    }
+
+    pub fn added(&self) -> &[(SocketAddr, PexFlags)] {
+        &self.added
+    }
+
+    pub fn dropped(&self) -> &[SocketAddr] {
+        &self.dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bencode()
+            .expect("peer exchange messages have sorted keys and a fixed depth")
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        Self::from_bencode(payload).map_err(|e| Error::InvalidExtensionMessage(e.to_string()))
+    }
+}
+
+impl FromBencode for PexMessage {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut added = Vec::new();
+        let mut added_flags = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_flags = Vec::new();
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        let mut dict = object.try_into_dictionary()?;
+
+        let compact =
+            |val: Object, ipv6| -> std::result::Result<Vec<SocketAddr>, decoding::Error> {
+                Ok(Peer::decode_compact_list(val.try_into_bytes()?, ipv6)?
+                    .iter()
+                    .map(Peer::address)
+                    .collect())
+            };
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"added", val) => added = compact(val, false)?,
+                (b"added.f", val) => added_flags = val.try_into_bytes()?.to_vec(),
+                (b"added6", val) => added6 = compact(val, true)?,
+                (b"added6.f", val) => added6_flags = val.try_into_bytes()?.to_vec(),
+                (b"dropped", val) => dropped = compact(val, false)?,
+                (b"dropped6", val) => dropped6 = compact(val, true)?,
+                _ => {}
+            }
+        }
+
+        // Flags are optional, and missing ones are treated as empty
+        let with_flags = |peers: Vec<SocketAddr>, flags: Vec<u8>| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(move |(i, peer)| (peer, PexFlags::new(flags.get(i).copied().unwrap_or(0))))
+        };
+        let added = with_flags(added, added_flags)
+            .chain(with_flags(added6, added6_flags))
+            .collect();
+        dropped.extend(dropped6);
+        Ok(Self::new(added, dropped))
+    }
+}
+
+impl ToBencode for PexMessage {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> std::result::Result<(), encoding::Error> {
+        let mut added = Vec::new();
+        let mut added_flags = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_flags = Vec::new();
+        for (address, flags) in &self.added {
+            let (peers, peer_flags) = if address.is_ipv4() {
+                (&mut added, &mut added_flags)
+            } else {
+                (&mut added6, &mut added6_flags)
+            };
+            Peer::new(None, *address).encode_compact(peers);
+            peer_flags.push(flags.bits());
+        }
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for address in &self.dropped {
+            let peers = if address.is_ipv4() {
+                &mut dropped
+            } else {
+                &mut dropped6
+            };
+            Peer::new(None, *address).encode_compact(peers);
+        }
+
+        encoder.emit_dict(|mut encoder| {
+            encoder.emit_pair_with(b"added", |e| e.emit_bytes(&added))?;
+            encoder.emit_pair_with(b"added.f", |e| e.emit_bytes(&added_flags))?;
+            encoder.emit_pair_with(b"added6", |e| e.emit_bytes(&added6))?;
+            encoder.emit_pair_with(b"added6.f", |e| e.emit_bytes(&added6_flags))?;
+            encoder.emit_pair_with(b"dropped", |e| e.emit_bytes(&dropped))?;
+            encoder.emit_pair_with(b"dropped6", |e| e.emit_bytes(&dropped6))
+        })
+    }
+}
+
+/// Tracks what has been sent to one peer, to produce the peer exchange messages for it.
+///
+/// Each message describes the changes to the set of connected peers since the previous one.
+/// Messages are sent at most once every [`PEX_INTERVAL`], and changes beyond
+/// [`MAX_PEX_PEERS`] are held back for the next message.
+#[derive(Debug, Clone, Default)]
+pub struct PexState {
+    advertised: HashMap<SocketAddr, PexFlags>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message to send at `now`, given the peers currently connected, or `None` if it's
+    /// too soon or nothing has changed. `connected` shouldn't include the receiving peer.
+    pub fn next_message(
+        &mut self,
+        connected: &HashMap<SocketAddr, PexFlags>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.saturating_duration_since(last_sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let mut added = connected
+            .iter()
+            .filter(|(address, _)| !self.advertised.contains_key(address))
+            .map(|(address, flags)| (*address, *flags))
+            .collect::<Vec<_>>();
+        added.sort_unstable_by_key(|(address, _)| *address);
+        added.truncate(MAX_PEX_PEERS);
+        let mut dropped = self
+            .advertised
+            .keys()
+            .filter(|address| !connected.contains_key(address))
+            .copied()
+            .collect::<Vec<_>>();
+        dropped.sort_unstable();
+        dropped.truncate(MAX_PEX_PEERS);
+
+        let message = PexMessage::new(added, dropped);
+        if message.is_empty() {
+            return None;
+        }
+        for (address, flags) in message.added() {
+            self.advertised.insert(*address, *flags);
+        }
+        for address in message.dropped() {
+            self.advertised.remove(address);
+        }
+        self.last_sent = Some(now);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_test() {
+        let message = PexMessage::new(
+            vec![
+                (
+                    "127.0.0.1:6881".parse().unwrap(),
+                    PexFlags::SEED.with(PexFlags::OUTGOING),
+                ),
+                ("[::1]:6882".parse().unwrap(), PexFlags::UTP),
+            ],
+            vec!["10.0.0.1:80".parse().unwrap()],
+        );
+        let mut expected = b"d5:added6:\x7f\x00\x00\x01\x1a\xe17:added.f1:\x12".to_vec();
+        expected.extend_from_slice(b"6:added618:");
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(
+            b"\x01\x1a\xe28:added6.f1:\x047:dropped6:\x0a\x00\x00\x01\x00\x508:dropped60:e",
+        );
+        assert_eq!(message.encode(), expected);
+        assert_eq!(PexMessage::decode(&expected).unwrap(), message);
+        assert!(message.added()[0].1.contains(PexFlags::SEED));
+        assert!(!message.added()[0].1.contains(PexFlags::UTP));
+
+        // Missing flags and unknown keys are fine, but malformed peer lists aren't
+        let message = PexMessage::decode(b"d5:added6:\x7f\x00\x00\x01\x1a\xe11:xi1ee").unwrap();
+        assert_eq!(message.added()[0].1, PexFlags::default());
+        assert!(PexMessage::decode(b"d5:added5:\x7f\x00\x00\x01\x1ae").is_err());
+    }
+
+    #[test]
+    fn state_test() {
+        let peer = |i: u16| SocketAddr::from(([10, 0, 0, 1], i));
+        let mut connected = (0..60)
+            .map(|i| (peer(i), PexFlags::default()))
+            .collect::<HashMap<_, _>>();
+        let mut state = PexState::new();
+        let now = Instant::now();
+
+        let message = state.next_message(&connected, now).unwrap();
+        assert_eq!(message.added().len(), MAX_PEX_PEERS);
+        assert!(message.dropped().is_empty());
+        assert_eq!(state.next_message(&connected, now + PEX_INTERVAL / 2), None);
+
+        // The peers held back go in the next message, along with any changes since
+        connected.remove(&peer(0));
+        connected.remove(&peer(59));
+        let message = state.next_message(&connected, now + PEX_INTERVAL).unwrap();
+        assert_eq!(message.added().len(), 9);
+        assert_eq!(message.dropped(), &[peer(0)]);
+
+        assert_eq!(state.next_message(&connected, now + PEX_INTERVAL * 2), None);
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use bendy::value::Value;
+use chrono::{DateTime, Utc};
+use sha1::Sha1;
+
+use crate::{
+    error::{Error, Result},
+    merkle::{self, MERKLE_BLOCK_SIZE},
+    FileInfo, FileTree, FileTreeEntry, Info, MetaInfo, PieceLayers,
+};
+
+/// The smallest and largest piece lengths picked automatically.
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+
+/// The number of pieces an automatically picked piece length aims to stay under.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// The kinds of metadata a [`TorrentBuilder`] creates.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TorrentVersion {
+    /// SHA-1 piece hashes only, as described in BEP 3.
+    #[default]
+    V1,
+    /// Per-file merkle trees only, as described in BEP 52.
+    V2,
+    /// Both, with padding files in the v1 file list so that pieces line up with the v2 files.
+    Hybrid,
+}
+
+/// Creates a [`MetaInfo`] for a file or directory on disk, hashing its contents.
+///
+/// Files in a directory are listed in lexicographic order of their paths, so the same
+/// directory always produces the same torrent. Symbolic links inside a directory are skipped,
+/// so that the content can't loop or come from outside the directory.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    announce: String,
+    name: Option<String>,
+    piece_length: Option<u64>,
+    private: Option<bool>,
+    announce_list: Option<Vec<Vec<String>>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<DateTime<Utc>>,
+    version: TorrentVersion,
+    threads: usize,
+}
+
+impl TorrentBuilder {
+    /// `path`: the file or directory to create a torrent for.
+    ///
+    /// `announce`: the URL of the tracker.
+    pub fn new(path: impl Into<PathBuf>, announce: String) -> Self {
+        Self {
+            path: path.into(),
+            announce,
+            name: None,
+            piece_length: None,
+            private: None,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            version: TorrentVersion::V1,
+            threads: 1,
+        }
+    }
+
+    /// Overrides the name of the torrent, which defaults to the last component of the path.
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Sets the piece length, which must be a power of two no smaller than 16 KiB. If unset,
+    /// one is picked based on the total size of the content.
+    pub fn with_piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = Some(private);
+        self
+    }
+
+    pub fn with_announce_list(mut self, announce_list: Vec<Vec<String>>) -> Self {
+        self.announce_list = Some(announce_list);
+        self
+    }
+
+    pub fn with_comment(mut self, comment: String) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: String) -> Self {
+        self.created_by = Some(created_by);
+        self
+    }
+
+    pub fn with_creation_date(mut self, creation_date: DateTime<Utc>) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    pub fn with_version(mut self, version: TorrentVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Hashes pieces on up to `threads` threads. The result is the same for any number of
+    /// threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Walks the content and hashes every piece.
+    pub fn build(self) -> Result<MetaInfo> {
+        let metadata = fs::metadata(&self.path)?;
+        let name = match self.name.clone() {
+            Some(name) => name,
+            None => path_to_string(self.path.file_name().ok_or_else(|| {
+                Error::InvalidMetadata(format!("{} has no file name", self.path.display()))
+            })?)?,
+        };
+
+        let files = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut files)?;
+            if files.is_empty() {
+                return Err(Error::InvalidMetadata(format!(
+                    "{} contains no files",
+                    self.path.display()
+                )));
+            }
+            files.sort_by(|(a, _), (b, _)| a.cmp(b));
+            files
+        } else {
+            vec![(Vec::new(), metadata.len())]
+        };
+
+        let total_length = files.iter().map(|(_, length)| length).sum();
+        let piece_length = match self.piece_length {
+            Some(piece_length)
+                if !piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH =>
+            {
+                return Err(Error::InvalidMetadata(format!(
+                    "invalid piece length {}",
+                    piece_length
+                )))
+            }
+            Some(piece_length) => piece_length,
+            None => pick_piece_length(total_length),
+        };
+
+        let mut sources = files
+            .iter()
+            .map(|(path, length)| {
+                (
+                    Some(path.iter().fold(self.path.clone(), |p, c| p.join(c))),
+                    *length,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let (file_tree, piece_layers) = if self.version == TorrentVersion::V1 {
+            (None, None)
+        } else {
+            // The name of a single file is the only entry of its tree
+            let paths = files.iter().map(|(path, _)| {
+                if metadata.is_dir() {
+                    path.clone()
+                } else {
+                    vec![name.clone()]
+                }
+            });
+            let (file_tree, piece_layers) =
+                build_file_tree(paths, &sources, piece_length, self.threads)?;
+            (Some(file_tree), Some(piece_layers))
+        };
+
+        let info = match file_tree {
+            Some(file_tree) if self.version == TorrentVersion::V2 => {
+                Info::new_v2(name, piece_length, file_tree, self.private)?
+            }
+            file_tree => {
+                let mut files = files
+                    .into_iter()
+                    .map(|(path, length)| FileInfo::new(length, path, None))
+                    .collect::<Vec<_>>();
+                if file_tree.is_some() && metadata.is_dir() {
+                    pad_files(&mut files, &mut sources, piece_length);
+                }
+                let total_length = sources.iter().map(|(_, length)| length).sum();
+                let pieces = hash_pieces(&sources, piece_length, total_length, self.threads)?;
+
+                let (length, files) = if metadata.is_dir() {
+                    (None, Some(files))
+                } else {
+                    (Some(total_length), None)
+                };
+                let info = Info::new(
+                    name,
+                    piece_length,
+                    pieces,
+                    length,
+                    files,
+                    self.private,
+                    None,
+                )?;
+                match file_tree {
+                    Some(file_tree) => info.with_file_tree(file_tree),
+                    None => info,
+                }
+            }
+        };
+
+        let meta_info = MetaInfo::new(
+            self.announce,
+            info,
+            self.announce_list,
+            self.creation_date,
+            self.comment,
+            self.created_by,
+            None,
+        );
+        Ok(match piece_layers {
+            Some(piece_layers) => meta_info.with_piece_layers(piece_layers),
+            None => meta_info,
+        })
+    }
+}
+
+/// Picks the smallest power of two that keeps the piece count near [`TARGET_PIECE_COUNT`].
+fn pick_piece_length(total_length: u64) -> u64 {
+    let ideal = (total_length / TARGET_PIECE_COUNT)
+        .max(1)
+        .next_power_of_two();
+    ideal.clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Collects the path components and lengths of every file below `dir`, skipping symbolic links.
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<(Vec<String>, u64)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.file_type().is_symlink() {
+            log::debug!("Skipping symbolic link {}", entry.path().display());
+            continue;
+        }
+        prefix.push(path_to_string(&entry.file_name())?);
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+fn path_to_string(component: &OsStr) -> Result<String> {
+    component
+        .to_str()
+        .map(String::from)
+        .ok_or_else(|| Error::InvalidMetadata(format!("path {:?} is not valid UTF-8", component)))
+}
+
+/// Inserts a padding file after every file which doesn't end on a piece boundary, except the
+/// last non-empty one, as described in BEP 47. Padding files have no source, and read as zeros.
+fn pad_files(
+    files: &mut Vec<FileInfo>,
+    sources: &mut Vec<(Option<PathBuf>, u64)>,
+    piece_length: u64,
+) {
+    let mut padded_files = Vec::with_capacity(files.len() * 2);
+    let mut padded_sources = Vec::with_capacity(files.len() * 2);
+    let last = files.iter().rposition(|file| file.length() > 0);
+    for (i, (file, source)) in files.drain(..).zip(sources.drain(..)).enumerate() {
+        let padding = (piece_length - file.length() % piece_length) % piece_length;
+        padded_files.push(file);
+        padded_sources.push(source);
+        if padding > 0 && last.is_some_and(|last| i < last) {
+            let mut pad = FileInfo::new(
+                padding,
+                vec![String::from(".pad"), padding.to_string()],
+                None,
+            );
+            pad.extra_fields_mut()
+                .insert(b"attr".to_vec(), Value::Bytes(b"p"[..].into()));
+            padded_files.push(pad);
+            padded_sources.push((None, padding));
+        }
+    }
+    *files = padded_files;
+    *sources = padded_sources;
+}
+
+/// Builds the v2 file tree and piece layers, hashing the files on separate threads.
+fn build_file_tree(
+    paths: impl Iterator<Item = Vec<String>>,
+    files: &[(Option<PathBuf>, u64)],
+    piece_length: u64,
+    threads: usize,
+) -> Result<(FileTree, PieceLayers)> {
+    let chunk_size = files.len().div_ceil(threads);
+    let hashes = thread::scope(|scope| {
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|file| hash_file_blocks(file, piece_length))
+                        .collect::<io::Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+
+    let mut piece_layers = PieceLayers::new();
+    let mut entries = Vec::with_capacity(files.len());
+    for ((path, (_, length)), hashes) in paths.zip(files).zip(hashes.into_iter().flatten()) {
+        let pieces_root = hashes.map(|(root, layer)| {
+            if !layer.is_empty() {
+                piece_layers.insert(root, layer.concat());
+            }
+            root
+        });
+        entries.push((path, FileTreeEntry::new(*length, pieces_root)));
+    }
+    Ok((FileTree::from_files(entries)?, piece_layers))
+}
+
+/// The `pieces root` and piece layer of a single file, or `None` if it's empty.
+#[allow(clippy::type_complexity)]
+fn hash_file_blocks(
+    file: &(Option<PathBuf>, u64),
+    piece_length: u64,
+) -> io::Result<Option<([u8; 32], Vec<[u8; 32]>)>> {
+    if file.1 == 0 {
+        return Ok(None);
+    }
+    let mut reader = ContentReader::new(std::slice::from_ref(file), 0)?;
+    let mut block_hashes = Vec::with_capacity(file.1.div_ceil(MERKLE_BLOCK_SIZE) as usize);
+    let mut buf = vec![0; MERKLE_BLOCK_SIZE as usize];
+    let mut remaining = file.1;
+    while remaining > 0 {
+        let length = MERKLE_BLOCK_SIZE.min(remaining) as usize;
+        reader.read_exact(&mut buf[..length])?;
+        block_hashes.push(merkle::hash(&buf[..length]));
+        remaining -= length as u64;
+    }
+    Ok(Some(merkle::file_hashes(&block_hashes, piece_length)))
+}
+
+/// Hashes every piece of the concatenation of `files`, splitting the pieces into contiguous
+/// ranges which are hashed on separate threads.
+fn hash_pieces(
+    files: &[(Option<PathBuf>, u64)],
+    piece_length: u64,
+    total_length: u64,
+    threads: usize,
+) -> Result<Vec<u8>> {
+    let piece_count = total_length.div_ceil(piece_length);
+    let chunk_size = piece_count.div_ceil(threads as u64);
+    let ranges = (0..threads as u64)
+        .map(|i| (i * chunk_size).min(piece_count)..((i + 1) * chunk_size).min(piece_count))
+        .filter(|range| !range.is_empty())
+        .collect::<Vec<_>>();
+
+    let hashes = thread::scope(|scope| {
+        let handles = ranges
+            .into_iter()
+            .map(|range| {
+                scope.spawn(move || hash_piece_range(files, piece_length, total_length, range))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect::<io::Result<Vec<_>>>()
+    })?;
+
+    Ok(hashes.concat())
+}
+
+fn hash_piece_range(
+    files: &[(Option<PathBuf>, u64)],
+    piece_length: u64,
+    total_length: u64,
+    pieces: Range<u64>,
+) -> io::Result<Vec<u8>> {
+    let mut reader = ContentReader::new(files, pieces.start * piece_length)?;
+    let mut hashes = Vec::with_capacity(pieces.end as usize * 20 - pieces.start as usize * 20);
+    let mut buf = vec![0; piece_length as usize];
+
+    for piece in pieces {
+        let start = piece * piece_length;
+        let length = piece_length.min(total_length - start) as usize;
+        reader.read_exact(&mut buf[..length])?;
+        hashes.extend_from_slice(&Sha1::from(&buf[..length]).digest().bytes());
+    }
+    Ok(hashes)
+}
+
+/// Reads the concatenation of a list of files, starting from an arbitrary offset. Files
+/// without a path are padding, and read as zeros.
+struct ContentReader<'a> {
+    files: &'a [(Option<PathBuf>, u64)],
+    current: Option<Source>,
+    index: usize,
+}
+
+enum Source {
+    File(File),
+    Padding(u64),
+}
+
+impl<'a> ContentReader<'a> {
+    fn new(files: &'a [(Option<PathBuf>, u64)], mut offset: u64) -> io::Result<Self> {
+        let mut index = 0;
+        while index < files.len() && offset >= files[index].1 {
+            offset -= files[index].1;
+            index += 1;
+        }
+
+        let current = match files.get(index) {
+            Some(file) => Some(Self::open(file, offset)?),
+            None => None,
+        };
+        Ok(Self {
+            files,
+            current,
+            index,
+        })
+    }
+
+    fn open((path, length): &(Option<PathBuf>, u64), offset: u64) -> io::Result<Source> {
+        Ok(match path {
+            Some(path) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Source::File(file)
+            }
+            None => Source::Padding(length - offset),
+        })
+    }
+}
+
+impl Read for ContentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(source) = &mut self.current {
+            let read = match source {
+                Source::File(file) => file.read(buf)?,
+                Source::Padding(remaining) => {
+                    let read = (buf.len() as u64).min(*remaining) as usize;
+                    buf[..read].fill(0);
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.index += 1;
+            self.current = match self.files.get(self.index) {
+                Some(file) => Some(Self::open(file, 0)?),
+                None => None,
+            };
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_piece_length_test() {
+        assert_eq!(pick_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(pick_piece_length(1_000_000), MIN_PIECE_LENGTH);
+        assert_eq!(pick_piece_length(1_109_805_040), 1 << 20);
+        assert_eq!(pick_piece_length(u64::MAX), MAX_PIECE_LENGTH);
+    }
+}
@@ -1,2 +1,8 @@
 pub mod announce;
+mod client;
+mod list;
 pub mod scrape;
+pub mod udp;
+
+pub use client::TrackerClient;
+pub use list::{TrackerList, TrackerState};
@@ -1,16 +1,19 @@
 use std::{
-    convert::TryFrom,
     fmt::{self, Display},
-    net::IpAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-use bendy::decoding::{self, FromBencode, ListDecoder, Object};
-use either::Either;
+use bendy::decoding::{self, FromBencode, Object};
+use percent_encoding::percent_encode;
 use reqwest::Url;
 
-use crate::Peer;
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    info_hash::URL_ENCODE_SET,
+    InfoHash, Peer,
+};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Event {
     Started,
     Stopped,
@@ -33,8 +36,8 @@ impl Display for Event {
 #[derive(Debug, PartialEq, Eq)]
 pub struct Request {
     announce_url: Url,
-    info_hash: String,
-    peer_id: String,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
     ip: Option<IpAddr>,
     port: u16,
     uploaded: u64,
@@ -46,15 +49,21 @@ pub struct Request {
     numwant: Option<u64>,
     key: Option<String>,
     trackerid: Option<String>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
 }
 
 impl Request {
-    /// Creates a `Request` from a URL and parameters. Existing query parameters in the URL
-    /// will be overwritten when this `Request` is passed to `reqwest::Url::from`.
+    /// Creates a `Request` from a URL and parameters. Existing query parameters in the URL,
+    /// such as a passkey, are kept when this `Request` is passed to `reqwest::Url::from`.
+    ///
+    /// `info_hash` and `peer_id` are the raw bytes, which are percent-encoded when the URL is
+    /// built.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         announce_url: Url,
-        info_hash: String,
-        peer_id: String,
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
         ip: Option<IpAddr>,
         port: u16,
         uploaded: u64,
@@ -82,20 +91,55 @@ impl Request {
             numwant,
             key,
             trackerid,
+            ipv4: None,
+            ipv6: None,
         }
     }
+
+    /// Announces an IPv4 address in addition to the one the request is sent from, as
+    /// described in BEP 7. This lets a dual-stack client join the IPv4 swarm when announcing
+    /// over IPv6.
+    pub fn with_ipv4(mut self, ipv4: Ipv4Addr) -> Self {
+        self.ipv4 = Some(ipv4);
+        self
+    }
+
+    /// Announces an IPv6 address in addition to the one the request is sent from, as
+    /// described in BEP 7. This lets a dual-stack client join the IPv6 swarm when announcing
+    /// over IPv4.
+    pub fn with_ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    pub fn announce_url(&self) -> &Url {
+        &self.announce_url
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
 }
 
 impl From<Request> for Url {
     fn from(mut request: Request) -> Self {
-        // We don't want the info hash to be double-encoded, so set it directly first
-        request
+        // The info hash and peer ID are arbitrary bytes rather than UTF-8, so they're encoded
+        // by hand and set directly, after any existing query
+        let existing_query = request
             .announce_url
-            .set_query(Some(&format!("info_hash={}", request.info_hash)));
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(|query| format!("{}&", query))
+            .unwrap_or_default();
+        request.announce_url.set_query(Some(&format!(
+            "{}info_hash={}&peer_id={}",
+            existing_query,
+            request.info_hash.url_encode(),
+            percent_encode(&request.peer_id, URL_ENCODE_SET)
+        )));
 
         // Now add the rest of the params
         let mut query_pairs = request.announce_url.query_pairs_mut();
-        query_pairs.append_pair("peer_id", &request.peer_id);
         if let Some(ip) = request.ip {
             query_pairs.append_pair("ip", &ip.to_string());
         }
@@ -119,6 +163,12 @@ impl From<Request> for Url {
         if let Some(trackerid) = request.trackerid {
             query_pairs.append_pair("trackerid", &trackerid);
         }
+        if let Some(ipv4) = request.ipv4 {
+            query_pairs.append_pair("ipv4", &ipv4.to_string());
+        }
+        if let Some(ipv6) = request.ipv6 {
+            query_pairs.append_pair("ipv6", &ipv6.to_string());
+        }
 
         // Drop mutable reference to announce_url so we can move it
         drop(query_pairs);
@@ -137,9 +187,12 @@ pub struct Response {
     incomplete: Option<u64>,
     downloaded: Option<u64>,
     peers: Option<Vec<Peer>>,
+    peers6: Option<Vec<Peer>>,
+    extra_fields: ExtraFields,
 }
 
 impl Response {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         failure_reason: Option<String>,
         warning_message: Option<String>,
@@ -150,6 +203,7 @@ impl Response {
         incomplete: Option<u64>,
         downloaded: Option<u64>,
         peers: Option<Vec<Peer>>,
+        peers6: Option<Vec<Peer>>,
     ) -> Self {
         Self {
             failure_reason,
@@ -161,18 +215,87 @@ impl Response {
             incomplete,
             downloaded,
             peers,
+            peers6,
+            extra_fields: ExtraFields::new(),
         }
     }
 
+    /// A human-readable reason the request failed. If present, no other keys are.
+    pub fn failure_reason(&self) -> Option<&str> {
+        self.failure_reason.as_deref()
+    }
+
+    /// A human-readable warning. The response is otherwise processed normally.
+    pub fn warning_message(&self) -> Option<&str> {
+        self.warning_message.as_deref()
+    }
+
+    /// The number of seconds to wait before announcing again.
+    pub fn interval(&self) -> Option<u64> {
+        self.interval
+    }
+
+    /// The minimum number of seconds to wait before announcing again.
+    pub fn min_interval(&self) -> Option<u64> {
+        self.min_interval
+    }
+
+    /// A string which should be sent back as `trackerid` in subsequent announces.
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    /// The number of peers with the entire file, i.e. seeders.
+    pub fn complete(&self) -> Option<u64> {
+        self.complete
+    }
+
+    /// The number of non-seeder peers, i.e. leechers.
+    pub fn incomplete(&self) -> Option<u64> {
+        self.incomplete
+    }
+
+    /// The total number of times the tracker has registered a completion.
+    pub fn downloaded(&self) -> Option<u64> {
+        self.downloaded
+    }
+
     pub fn peers(&self) -> Option<&[Peer]> {
         self.peers.as_deref()
     }
+
+    /// IPv6 peers, which BEP 7 trackers send separately from the IPv4 `peers`.
+    pub fn peers6(&self) -> Option<&[Peer]> {
+        self.peers6.as_deref()
+    }
+
+    /// Both the IPv4 and IPv6 peers.
+    pub fn all_peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers().into_iter().chain(self.peers6()).flatten()
+    }
+
+    /// Keys which aren't part of the specification, such as `external ip`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
 }
 
 impl FromBencode for Response {
     const EXPECTED_RECURSION_DEPTH: usize = 2;
 
     fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for Response {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> Result<Self, decoding::Error>
     where
         Self: Sized,
     {
@@ -185,6 +308,8 @@ impl FromBencode for Response {
         let mut incomplete = None;
         let mut downloaded = None;
         let mut peers = None;
+        let mut peers6 = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -203,32 +328,38 @@ impl FromBencode for Response {
                 (b"downloaded", val) => downloaded = Some(u64::decode_bencode_object(val)?),
                 (b"peers", val) => {
                     // Peer list is either a list of dictionaries or a byte string
-                    let peers_obj: Either<ListDecoder, &[u8]> =
-                        Either::from(val.bytes_or_else(|obj| Err(obj.try_into_list().unwrap())));
-                    let mut peer_list = Vec::new();
-                    match peers_obj {
-                        Either::Left(mut list) => {
+                    let peer_list = match val {
+                        Object::List(mut list) => {
+                            let mut peer_list = Vec::new();
                             while let Some(obj) = list.next_object()? {
-                                peer_list.push(Peer::decode_bencode_object(obj)?)
+                                peer_list.push(Peer::decode_bencode_object_with_mode(obj, mode)?)
                             }
+                            peer_list
                         }
-                        Either::Right(bytes) => {
-                            for chunk in bytes.chunks(6) {
-                                peer_list.push(Peer::try_from(chunk)?);
-                            }
+                        Object::Bytes(bytes) => Peer::decode_compact_list(bytes, false)?,
+                        Object::Integer(_) => {
+                            return Err(decoding::Error::unexpected_token(
+                                "List or ByteString",
+                                "Integer",
+                            ))
+                        }
+                        Object::Dict(_) => {
+                            return Err(decoding::Error::unexpected_token(
+                                "List or ByteString",
+                                "Dict",
+                            ))
                         }
-                    }
+                    };
                     peers = Some(peer_list);
                 }
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
+                (b"peers6", val) => {
+                    peers6 = Some(Peer::decode_compact_list(val.try_into_bytes()?, true)?)
                 }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
-        Ok(Self::new(
+        let mut response = Self::new(
             failure_reason,
             warning_message,
             interval,
@@ -238,6 +369,129 @@ impl FromBencode for Response {
             incomplete,
             downloaded,
             peers,
-        ))
+            peers6,
+        );
+        response.extra_fields = extra_fields;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_test() {
+        let request = Request::new(
+            Url::parse("http://tracker.example/announce?passkey=abc").unwrap(),
+            InfoHash::new([0xab; 20]),
+            *b"-BT0000-abc def~ghij",
+            None,
+            6881,
+            1,
+            2,
+            3,
+            Some(Event::Started),
+            true,
+            None,
+            Some(50),
+            None,
+            Some(String::from("id 1")),
+        );
+        assert_eq!(
+            Url::from(request).as_str(),
+            format!(
+                "http://tracker.example/announce?passkey=abc&info_hash={}&peer_id={}\
+                 &port=6881&uploaded=1&downloaded=2&left=3&event=started&compact=1&numwant=50\
+                 &trackerid=id+1",
+                "%AB".repeat(20),
+                "-BT0000-abc%20def~ghij"
+            )
+        );
+    }
+
+    #[test]
+    fn dual_stack_test() {
+        let request = Request::new(
+            Url::parse("http://tracker.example/announce").unwrap(),
+            InfoHash::new([0xab; 20]),
+            [b'a'; 20],
+            None,
+            6881,
+            0,
+            0,
+            0,
+            None,
+            true,
+            None,
+            None,
+            None,
+            None,
+        )
+        .with_ipv4("192.0.2.1".parse().unwrap())
+        .with_ipv6("2001:db8::1".parse().unwrap());
+        assert!(Url::from(request)
+            .as_str()
+            .ends_with("&compact=1&ipv4=192.0.2.1&ipv6=2001%3Adb8%3A%3A1"));
+
+        let mut bencode = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers636:".to_vec();
+        bencode.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bencode.extend_from_slice(&[0x1a, 0xe2]);
+        bencode.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        bencode.extend_from_slice(&[0x1a, 0xe3, b'e']);
+        let response = Response::from_bencode(&bencode).unwrap();
+        assert_eq!(
+            response.peers6().unwrap(),
+            &[
+                Peer::new(None, "[::1]:6882".parse().unwrap()),
+                Peer::new(None, "[2001:db8::1]:6883".parse().unwrap())
+            ]
+        );
+        assert_eq!(
+            response
+                .all_peers()
+                .map(|peer| peer.address().port())
+                .collect::<Vec<_>>(),
+            vec![6881, 6882, 6883]
+        );
+
+        // IPv6 peers must be 18 bytes each
+        assert!(Response::from_bencode(b"d6:peers66:\x7f\x00\x00\x01\x1a\xe1e").is_err());
+    }
+
+    #[test]
+    fn accessors_test() {
+        let bencode = b"d8:completei5e10:downloadedi7e10:incompletei3e8:intervali1800e12:min intervali60e5:peers0:10:tracker id3:abc15:warning message4:slowe";
+        let response = Response::from_bencode(bencode).unwrap();
+        assert_eq!(response.failure_reason(), None);
+        assert_eq!(response.warning_message(), Some("slow"));
+        assert_eq!(response.interval(), Some(1800));
+        assert_eq!(response.min_interval(), Some(60));
+        assert_eq!(response.tracker_id(), Some("abc"));
+        assert_eq!(response.complete(), Some(5));
+        assert_eq!(response.incomplete(), Some(3));
+        assert_eq!(response.downloaded(), Some(7));
+        assert_eq!(response.peers(), Some(&[][..]));
+
+        let response = Response::from_bencode(b"d14:failure reason9:not founde").unwrap();
+        assert_eq!(response.failure_reason(), Some("not found"));
+
+        // Peers which are neither a list nor a byte string are an error, not a panic
+        assert!(Response::from_bencode(b"d5:peersi1ee").is_err());
+        assert!(Response::from_bencode(b"d5:peersd1:ai1eee").is_err());
+    }
+
+    #[test]
+    fn extra_fields_test() {
+        let bencode =
+            b"d11:external ip4:\x7f\x00\x00\x018:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe17:trackerli1eee";
+        let response = Response::from_bencode(bencode).unwrap();
+        assert_eq!(response.peers().unwrap().len(), 1);
+        assert_eq!(
+            response.extra_fields().keys().cloned().collect::<Vec<_>>(),
+            vec![b"external ip".to_vec(), b"tracker".to_vec()]
+        );
+        assert!(Response::from_bencode_strict(bencode).is_err());
+        assert!(Response::from_bencode_strict(b"d8:intervali1800ee").is_ok());
     }
 }
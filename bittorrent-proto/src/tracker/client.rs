@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use bendy::decoding::FromBencode;
+use reqwest::{Client, Url};
+
+use crate::{
+    error::{Error, Result},
+    tracker::{announce, scrape},
+};
+
+/// The user agent sent with every request unless another is configured.
+pub const DEFAULT_USER_AGENT: &str = concat!("bittorrent-rs/", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a tracker to respond unless another timeout is configured.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client for HTTP trackers, which sends announce and scrape requests and decodes the
+/// responses.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    client: Client,
+}
+
+impl TrackerClient {
+    pub fn new() -> Result<Self> {
+        Self::with_config(DEFAULT_USER_AGENT, DEFAULT_TIMEOUT)
+    }
+
+    /// `user_agent`: the `User-Agent` header sent with each request.
+    ///
+    /// `timeout`: how long to wait for each request to complete, including reading the
+    /// response.
+    pub fn with_config(user_agent: &str, timeout: Duration) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(user_agent)
+            .timeout(timeout)
+            .build()?;
+        Ok(Self { client })
+    }
+
+    /// Sends an announce. A `failure reason` from the tracker is returned as
+    /// [`Error::TrackerFailure`].
+    pub async fn announce(&self, request: announce::Request) -> Result<announce::Response> {
+        let bytes = self.get(Url::from(request)).await?;
+        let response = announce::Response::from_bencode(&bytes)
+            .map_err(|e| Error::InvalidTrackerResponse(e.to_string()))?;
+
+        if let Some(reason) = response.failure_reason() {
+            return Err(Error::TrackerFailure(reason.to_string()));
+        }
+        if let Some(warning) = response.warning_message() {
+            log::warn!("Tracker warning: {}", warning);
+        }
+        Ok(response)
+    }
+
+    /// Fetches the stats of each torrent in the request. A `failure reason` from the tracker
+    /// is returned as [`Error::TrackerFailure`].
+    pub async fn scrape(&self, request: scrape::Request) -> Result<scrape::Response> {
+        let bytes = self.get(Url::from(request)).await?;
+        let response = scrape::Response::from_bencode(&bytes)
+            .map_err(|e| Error::InvalidTrackerResponse(e.to_string()))?;
+
+        if let Some(reason) = response.failure_reason() {
+            return Err(Error::TrackerFailure(reason.to_string()));
+        }
+        Ok(response)
+    }
+
+    async fn get(&self, url: Url) -> Result<Vec<u8>> {
+        log::debug!("Sending tracker request to {}", url);
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
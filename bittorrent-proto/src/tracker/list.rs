@@ -0,0 +1,376 @@
+use std::time::{Duration, Instant};
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{tracker::announce, MetaInfo};
+
+/// How long to wait between announces when a tracker doesn't send an `interval`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long to wait before retrying a tracker after its first failure. This doubles with every
+/// consecutive failure, up to [`MAX_RETRY_DELAY`].
+pub const BASE_RETRY_DELAY: Duration = Duration::from_secs(15);
+
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// The longest `interval` or `min interval` a tracker is trusted with. Longer ones are cut down
+/// to this.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What is known about a single tracker from previous announces to it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TrackerState {
+    url: String,
+    next_announce: Option<Instant>,
+    min_next_announce: Option<Instant>,
+    failures: u32,
+    last_error: Option<String>,
+    tracker_id: Option<String>,
+}
+
+impl TrackerState {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            next_announce: None,
+            min_next_announce: None,
+            failures: 0,
+            last_error: None,
+            tracker_id: None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// When the next regular announce is due, or `None` if this tracker hasn't been tried.
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.next_announce
+    }
+
+    /// The earliest time another announce may be sent, according to `min interval`.
+    pub fn min_next_announce(&self) -> Option<Instant> {
+        self.min_next_announce
+    }
+
+    /// Whether a regular announce is due at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_announce.is_none_or(|next| next <= now)
+    }
+
+    /// The number of consecutive failed announces.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// The `tracker id` to send back in subsequent announces.
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    fn record_success(&mut self, response: &announce::Response, now: Instant) {
+        let interval = response
+            .interval()
+            .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+        self.next_announce = Some(after(now, interval));
+        self.min_next_announce = response
+            .min_interval()
+            .map(|min_interval| after(now, Duration::from_secs(min_interval)));
+        self.failures = 0;
+        self.last_error = None;
+        // Trackers only send their id once, so keep the previous one if it's missing
+        if let Some(tracker_id) = response.tracker_id() {
+            self.tracker_id = Some(tracker_id.to_string());
+        }
+    }
+
+    fn record_failure(&mut self, error: String, now: Instant) {
+        self.failures += 1;
+        let delay = BASE_RETRY_DELAY
+            .checked_mul(2_u32.saturating_pow(self.failures - 1))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+        self.next_announce = Some(after(now, delay));
+        self.last_error = Some(error);
+    }
+}
+
+/// The time `delay` after `now`, with `delay` limited to [`MAX_INTERVAL`].
+fn after(now: Instant, delay: Duration) -> Instant {
+    now.checked_add(delay.min(MAX_INTERVAL)).unwrap_or(now)
+}
+
+/// The trackers of a torrent, grouped into tiers as described in BEP 12.
+///
+/// Each tier is shuffled once when the list is created. Trackers should be tried in the order
+/// returned by [`TrackerList::iter`]: every tracker of the first tier, then every tracker of
+/// the next, and so on. A tracker which answers is moved to the front of its tier, so that it
+/// is tried first next time.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TrackerList {
+    tiers: Vec<Vec<TrackerState>>,
+}
+
+impl TrackerList {
+    /// `announce`: the tracker to use if `announce_list` is missing or empty.
+    ///
+    /// `announce_list`: tiers of tracker URLs. Empty tiers are dropped.
+    pub fn new(announce: &str, announce_list: Option<&[Vec<String>]>) -> Self {
+        Self::with_rng(announce, announce_list, &mut rand::thread_rng())
+    }
+
+    /// Like [`TrackerList::new`], but shuffles the tiers using `rng`.
+    pub fn with_rng<R: Rng + ?Sized>(
+        announce: &str,
+        announce_list: Option<&[Vec<String>]>,
+        rng: &mut R,
+    ) -> Self {
+        let mut tiers = announce_list
+            .unwrap_or_default()
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| {
+                let mut tier = tier
+                    .iter()
+                    .cloned()
+                    .map(TrackerState::new)
+                    .collect::<Vec<_>>();
+                tier.shuffle(rng);
+                tier
+            })
+            .collect::<Vec<_>>();
+
+        // Clients which support announce-list ignore announce, unless there's nothing else
+        if tiers.is_empty() {
+            tiers.push(vec![TrackerState::new(announce.to_string())]);
+        }
+        Self { tiers }
+    }
+
+    pub fn from_meta_info(meta_info: &MetaInfo) -> Self {
+        Self::new(
+            meta_info.announce(),
+            meta_info.announce_list().map(Vec::as_slice),
+        )
+    }
+
+    pub fn tiers(&self) -> &[Vec<TrackerState>] {
+        &self.tiers
+    }
+
+    /// Every tracker, in the order they should be tried.
+    pub fn iter(&self) -> impl Iterator<Item = &TrackerState> {
+        self.tiers.iter().flatten()
+    }
+
+    pub fn get(&self, url: &str) -> Option<&TrackerState> {
+        self.iter().find(|tracker| tracker.url() == url)
+    }
+
+    /// Records a successful announce to the tracker at `url`, moving it to the front of its
+    /// tier. Returns `false` if there is no such tracker.
+    pub fn record_success(
+        &mut self,
+        url: &str,
+        response: &announce::Response,
+        now: Instant,
+    ) -> bool {
+        match self.position(url) {
+            Some((tier, index)) => {
+                let mut tracker = self.tiers[tier].remove(index);
+                tracker.record_success(response, now);
+                self.tiers[tier].insert(0, tracker);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed announce to the tracker at `url`, which delays its next announce.
+    /// Returns `false` if there is no such tracker.
+    pub fn record_failure(&mut self, url: &str, error: String, now: Instant) -> bool {
+        match self.position(url) {
+            Some((tier, index)) => {
+                self.tiers[tier][index].record_failure(error, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn position(&self, url: &str) -> Option<(usize, usize)> {
+        self.tiers.iter().enumerate().find_map(|(tier, trackers)| {
+            trackers
+                .iter()
+                .position(|tracker| tracker.url() == url)
+                .map(|index| (tier, index))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bendy::decoding::FromBencode;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn announce_list() -> Vec<Vec<String>> {
+        vec![
+            (0..5).map(|i| format!("http://a{}.example", i)).collect(),
+            vec![],
+            vec![String::from("udp://b0.example:80")],
+            (0..3).map(|i| format!("http://c{}.example", i)).collect(),
+        ]
+    }
+
+    fn urls(list: &TrackerList) -> Vec<Vec<&str>> {
+        list.tiers()
+            .iter()
+            .map(|tier| tier.iter().map(TrackerState::url).collect())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_test() {
+        let announce_list = announce_list();
+        let list = TrackerList::with_rng(
+            "http://ignored.example",
+            Some(&announce_list),
+            &mut StdRng::seed_from_u64(1),
+        );
+        let tiers = urls(&list);
+        assert_eq!(tiers.len(), 3);
+        assert_eq!(tiers[1], vec!["udp://b0.example:80"]);
+
+        // Trackers are shuffled within their tiers, but never move between them
+        for (tier, original) in [
+            (&tiers[0], &announce_list[0]),
+            (&tiers[2], &announce_list[3]),
+        ] {
+            let mut sorted = tier.clone();
+            sorted.sort_unstable();
+            assert_eq!(&sorted, original);
+        }
+        assert_ne!(
+            tiers[0],
+            announce_list[0]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        );
+        // The same seed gives the same order
+        assert_eq!(
+            list,
+            TrackerList::with_rng(
+                "http://ignored.example",
+                Some(&announce_list),
+                &mut StdRng::seed_from_u64(1)
+            )
+        );
+        assert_eq!(list.iter().count(), 9);
+
+        let list = TrackerList::new("http://only.example", Some(&[vec![]]));
+        assert_eq!(urls(&list), vec![vec!["http://only.example"]]);
+        let list = TrackerList::new("http://only.example", None);
+        assert_eq!(urls(&list), vec![vec!["http://only.example"]]);
+    }
+
+    #[test]
+    fn promotion_test() {
+        let announce_list = announce_list();
+        let mut list =
+            TrackerList::with_rng("", Some(&announce_list), &mut StdRng::seed_from_u64(2));
+        let before = urls(&list)
+            .into_iter()
+            .map(|tier| tier.into_iter().map(String::from).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        let response = announce::Response::from_bencode(b"d8:intervali900e5:peers0:e").unwrap();
+
+        // The first tier fails completely, and the third tracker of the last tier answers
+        for url in &before[0] {
+            assert!(list.record_failure(url, String::from("timed out"), now));
+        }
+        assert!(list.record_success(&before[2][2], &response, now));
+        assert!(!list.record_success("http://unknown.example", &response, now));
+
+        let after = urls(&list);
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1], before[1]);
+        assert_eq!(
+            after[2],
+            vec![
+                before[2][2].as_str(),
+                before[2][0].as_str(),
+                before[2][1].as_str()
+            ]
+        );
+        assert_eq!(list.iter().next().unwrap().url(), before[0][0]);
+    }
+
+    #[test]
+    fn state_test() {
+        let mut list = TrackerList::new("http://tracker.example/announce", None);
+        let url = "http://tracker.example/announce";
+        let now = Instant::now();
+        assert!(list.get(url).unwrap().is_due(now));
+
+        list.record_failure(url, String::from("connection refused"), now);
+        list.record_failure(url, String::from("timed out"), now);
+        let tracker = list.get(url).unwrap();
+        assert_eq!(tracker.failures(), 2);
+        assert_eq!(tracker.last_error(), Some("timed out"));
+        assert_eq!(tracker.next_announce(), Some(now + BASE_RETRY_DELAY * 2));
+        assert!(!tracker.is_due(now));
+
+        for _ in 0..40 {
+            list.record_failure(url, String::from("timed out"), now);
+        }
+        assert_eq!(
+            list.get(url).unwrap().next_announce(),
+            Some(now + MAX_RETRY_DELAY)
+        );
+
+        let response = announce::Response::from_bencode(
+            b"d8:intervali900e12:min intervali60e5:peers0:10:tracker id3:abce",
+        )
+        .unwrap();
+        list.record_success(url, &response, now);
+        let tracker = list.get(url).unwrap();
+        assert_eq!(tracker.failures(), 0);
+        assert_eq!(tracker.last_error(), None);
+        assert_eq!(
+            tracker.next_announce(),
+            Some(now + Duration::from_secs(900))
+        );
+        assert_eq!(
+            tracker.min_next_announce(),
+            Some(now + Duration::from_secs(60))
+        );
+        assert_eq!(tracker.tracker_id(), Some("abc"));
+        assert!(tracker.is_due(now + Duration::from_secs(900)));
+
+        // The tracker id is kept when later responses leave it out
+        let response = announce::Response::from_bencode(b"d5:peers0:e").unwrap();
+        list.record_success(url, &response, now);
+        let tracker = list.get(url).unwrap();
+        assert_eq!(tracker.tracker_id(), Some("abc"));
+        assert_eq!(tracker.next_announce(), Some(now + DEFAULT_INTERVAL));
+        assert_eq!(tracker.min_next_announce(), None);
+
+        // Hostile intervals are limited rather than overflowing
+        let response = announce::Response::from_bencode(
+            b"d8:intervali18446744073709551615e12:min intervali18446744073709551615e5:peers0:e",
+        )
+        .unwrap();
+        list.record_success(url, &response, now);
+        let tracker = list.get(url).unwrap();
+        assert_eq!(tracker.next_announce(), Some(now + MAX_INTERVAL));
+        assert_eq!(tracker.min_next_announce(), Some(now + MAX_INTERVAL));
+    }
+}
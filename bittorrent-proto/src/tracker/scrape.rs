@@ -1,30 +1,144 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::TryFrom};
 
 use bendy::decoding::{self, FromBencode, Object};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use sha1::Digest;
+
+use crate::{
+    bencode::{decode_extra_field, DecodingMode, ExtraFields, FromBencodeWithMode},
+    error::{Error, Result},
+    InfoHash,
+};
+
+/// Derives the scrape URL of a tracker from its announce URL. By convention, this is only
+/// possible if the last path segment starts with `announce`, which is replaced with `scrape`;
+/// anything following it, and the query, are kept.
+///
+/// `http://example.com/x/announce.php?passkey=abc` becomes
+/// `http://example.com/x/scrape.php?passkey=abc`, while `http://example.com/a` has no scrape
+/// URL.
+pub fn scrape_url(announce_url: &Url) -> Result<Url> {
+    let (prefix, last_segment) = announce_url
+        .path()
+        .rsplit_once('/')
+        .ok_or_else(|| Error::ScrapeNotSupported(announce_url.to_string()))?;
+    let suffix = last_segment
+        .strip_prefix("announce")
+        .ok_or_else(|| Error::ScrapeNotSupported(announce_url.to_string()))?;
+
+    let mut scrape_url = announce_url.clone();
+    scrape_url.set_path(&format!("{}/scrape{}", prefix, suffix));
+    Ok(scrape_url)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Request {
+    scrape_url: Url,
+    info_hashes: Vec<InfoHash>,
+}
+
+impl Request {
+    /// `scrape_url`: the URL to scrape. Existing query parameters, such as a passkey, are
+    /// kept when this `Request` is passed to `reqwest::Url::from`.
+    ///
+    /// `info_hashes`: the torrents to fetch stats for. If empty, some trackers return stats
+    /// for every torrent they track.
+    pub fn new(scrape_url: Url, info_hashes: Vec<InfoHash>) -> Self {
+        Self {
+            scrape_url,
+            info_hashes,
+        }
+    }
+
+    /// Creates a `Request` for the scrape URL corresponding to `announce_url`, failing if the
+    /// tracker doesn't follow the scrape convention.
+    pub fn from_announce_url(announce_url: &Url, info_hashes: Vec<InfoHash>) -> Result<Self> {
+        Ok(Self::new(scrape_url(announce_url)?, info_hashes))
+    }
+
+    pub fn scrape_url(&self) -> &Url {
+        &self.scrape_url
+    }
+
+    pub fn info_hashes(&self) -> &[InfoHash] {
+        &self.info_hashes
+    }
+}
+
+impl From<Request> for Url {
+    fn from(mut request: Request) -> Self {
+        // Info hashes are encoded by hand, since they're arbitrary bytes rather than UTF-8
+        let params = request
+            .scrape_url
+            .query()
+            .filter(|query| !query.is_empty())
+            .map(String::from)
+            .into_iter()
+            .chain(
+                request
+                    .info_hashes
+                    .iter()
+                    .map(|info_hash| format!("info_hash={}", info_hash.url_encode())),
+            )
+            .collect::<Vec<_>>();
+        if !params.is_empty() {
+            request.scrape_url.set_query(Some(&params.join("&")));
+        }
+        request.scrape_url
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Response {
-    files: HashMap<Digest, TorrentStats>,
+    files: HashMap<InfoHash, TorrentStats>,
+    failure_reason: Option<String>,
+    extra_fields: ExtraFields,
 }
 
 impl Response {
-    pub fn new(files: HashMap<Digest, TorrentStats>) -> Self {
-        Self { files }
+    pub fn new(files: HashMap<InfoHash, TorrentStats>) -> Self {
+        Self {
+            files,
+            failure_reason: None,
+            extra_fields: ExtraFields::new(),
+        }
+    }
+
+    /// Why the scrape failed. If present, `files` is usually left out and is then empty.
+    pub fn failure_reason(&self) -> Option<&str> {
+        self.failure_reason.as_deref()
     }
 
-    pub fn files(&self) -> &HashMap<Digest, TorrentStats> {
+    pub fn files(&self) -> &HashMap<InfoHash, TorrentStats> {
         &self.files
     }
+
+    /// Keys which aren't part of the specification, such as `flags`.
+    pub fn extra_fields(&self) -> &ExtraFields {
+        &self.extra_fields
+    }
 }
 
 impl FromBencode for Response {
-    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    fn decode_bencode_object(object: Object) -> std::result::Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        Self::decode_bencode_object_with_mode(object, DecodingMode::Lenient)
+    }
+}
+
+impl FromBencodeWithMode for Response {
+    fn decode_bencode_object_with_mode(
+        object: Object,
+        mode: DecodingMode,
+    ) -> std::result::Result<Self, decoding::Error>
     where
         Self: Sized,
     {
         let mut files = None;
+        let mut failure_reason = None;
+        let mut extra_fields = ExtraFields::new();
         let mut dict = object.try_into_dictionary()?;
 
         while let Some(pair) = dict.next_pair()? {
@@ -32,36 +146,36 @@ impl FromBencode for Response {
                 (b"files", val) => {
                     let mut files_map = HashMap::new();
                     let mut files_dict = val.try_into_dictionary()?;
-                    while let Some(pair) = files_dict.next_pair()? {
-                        match pair {
-                            (bytes, stats_obj) => {
-                                let digest = hex::encode(bytes).parse().unwrap();
-                                let _ = files_map.insert(
-                                    digest,
-                                    bendy::serde::from_bytes(
-                                        stats_obj.try_into_dictionary()?.into_raw()?,
-                                    )?,
-                                );
-                            }
-                        }
+                    while let Some((bytes, stats_obj)) = files_dict.next_pair()? {
+                        let info_hash = InfoHash::try_from(bytes)
+                            .map_err(decoding::Error::malformed_content)?;
+                        let _ = files_map.insert(
+                            info_hash,
+                            bendy::serde::from_bytes(stats_obj.try_into_dictionary()?.into_raw()?)?,
+                        );
                     }
                     files = Some(files_map);
                 }
-                // TODO: Add unofficial extension fields
-                (other, _) => {
-                    return Err(decoding::Error::unexpected_field(String::from_utf8_lossy(
-                        other,
-                    )));
+                (b"failure reason", val) => {
+                    failure_reason = Some(String::decode_bencode_object(val)?)
                 }
+                (other, val) => decode_extra_field(&mut extra_fields, mode, other, val)?,
             }
         }
 
-        let files = files.ok_or_else(|| decoding::Error::missing_field("files"))?;
-        Ok(Self::new(files))
+        let files = match files {
+            Some(files) => files,
+            None if failure_reason.is_some() => HashMap::new(),
+            None => return Err(decoding::Error::missing_field("files")),
+        };
+        let mut response = Self::new(files);
+        response.failure_reason = failure_reason;
+        response.extra_fields = extra_fields;
+        Ok(response)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct TorrentStats {
     complete: u64,
     downloaded: u64,
@@ -78,4 +192,96 @@ impl TorrentStats {
             name,
         }
     }
+
+    /// The number of peers with the entire file, i.e. seeders.
+    pub fn complete(&self) -> u64 {
+        self.complete
+    }
+
+    /// The total number of times the tracker has registered a completion.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// The number of non-seeder peers, i.e. leechers.
+    pub fn incomplete(&self) -> u64 {
+        self.incomplete
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive(announce_url: &str) -> Result<String> {
+        scrape_url(&Url::parse(announce_url).unwrap()).map(String::from)
+    }
+
+    #[test]
+    fn scrape_url_test() {
+        assert_eq!(
+            derive("http://example.com/announce").unwrap(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            derive("http://example.com/x/announce").unwrap(),
+            "http://example.com/x/scrape"
+        );
+        assert_eq!(
+            derive("http://example.com/announce.php").unwrap(),
+            "http://example.com/scrape.php"
+        );
+        assert_eq!(
+            derive("http://example.com/announce?x2%0644").unwrap(),
+            "http://example.com/scrape?x2%0644"
+        );
+        assert_eq!(
+            derive("http://example.com:6969/tracker/announce.php?passkey=abc123").unwrap(),
+            "http://example.com:6969/tracker/scrape.php?passkey=abc123"
+        );
+
+        assert_eq!(
+            derive("http://example.com/announce?x=2/4").unwrap(),
+            "http://example.com/scrape?x=2/4"
+        );
+
+        // Trackers whose last path segment doesn't start with 'announce' don't support scrape
+        for url in &[
+            "http://example.com/a",
+            "http://example.com/x%064announce",
+            "http://example.com/announce/",
+            "http://example.com/announce/x",
+            "http://example.com/",
+        ] {
+            assert!(
+                matches!(derive(url), Err(Error::ScrapeNotSupported(_))),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn url_test() {
+        let request = Request::from_announce_url(
+            &Url::parse("http://example.com/announce.php?passkey=abc").unwrap(),
+            vec![InfoHash::new([0xab; 20]), InfoHash::new([0x01; 20])],
+        )
+        .unwrap();
+        assert_eq!(
+            Url::from(request).as_str(),
+            format!(
+                "http://example.com/scrape.php?passkey=abc&info_hash={}&info_hash={}",
+                "%AB".repeat(20),
+                "%01".repeat(20)
+            )
+        );
+
+        let request = Request::new(Url::parse("http://example.com/scrape").unwrap(), vec![]);
+        assert_eq!(Url::from(request).as_str(), "http://example.com/scrape");
+    }
 }
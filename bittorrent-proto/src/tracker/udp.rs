@@ -0,0 +1,581 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::Url;
+use tokio::{
+    net::{self, UdpSocket},
+    time::{self, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    tracker::{announce::Event, scrape::TorrentStats},
+    InfoHash, Peer,
+};
+
+/// The magic constant sent in place of a connection ID in connect requests.
+pub const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+/// How long a connection ID may be used for after it was received.
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long to wait for the first response to a request. Every retransmission doubles this.
+pub const DEFAULT_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The number of retransmissions after which a request is given up on, at which point the
+/// timeout has reached 15 * 2^8 seconds.
+pub const DEFAULT_MAX_RETRANSMISSIONS: u32 = 8;
+
+/// The timeout stops doubling after this many retransmissions, as described in BEP 15.
+const MAX_BACKOFF_EXPONENT: u32 = 8;
+
+/// The most info hashes which may be sent in a single scrape request.
+pub const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
+/// Large enough for any response a tracker will send in practice.
+const MAX_DATAGRAM_LENGTH: usize = 1 << 16;
+
+/// The action of a UDP tracker request or response, as described in BEP 15.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u32)]
+pub enum Action {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+impl TryFrom<u32> for Action {
+    type Error = Error;
+
+    fn try_from(id: u32) -> Result<Self> {
+        match id {
+            0 => Ok(Action::Connect),
+            1 => Ok(Action::Announce),
+            2 => Ok(Action::Scrape),
+            3 => Ok(Action::Error),
+            _ => Err(Error::InvalidTrackerResponse(format!(
+                "unknown action {}",
+                id
+            ))),
+        }
+    }
+}
+
+/// The parameters of a UDP announce request. Unlike HTTP announces, these are sent as raw
+/// bytes, so nothing is percent-encoded.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AnnounceRequest {
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    downloaded: u64,
+    left: u64,
+    uploaded: u64,
+    event: Option<Event>,
+    ip: Option<Ipv4Addr>,
+    key: u32,
+    num_want: Option<u32>,
+    port: u16,
+}
+
+impl AnnounceRequest {
+    /// `ip`: the address peers should connect to, if it differs from the one the request is
+    /// sent from.
+    ///
+    /// `key`: a random value which lets the tracker recognize this client if its address
+    /// changes.
+    ///
+    /// `num_want`: the number of peers wanted, or `None` to let the tracker decide.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        downloaded: u64,
+        left: u64,
+        uploaded: u64,
+        event: Option<Event>,
+        ip: Option<Ipv4Addr>,
+        key: u32,
+        num_want: Option<u32>,
+        port: u16,
+    ) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            downloaded,
+            left,
+            uploaded,
+            event,
+            ip,
+            key,
+            num_want,
+            port,
+        }
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        self.info_hash
+    }
+
+    /// Appends the body of this request, which follows the connection ID, action and
+    /// transaction ID, to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let event: u32 = match self.event {
+            None | Some(Event::Empty) => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+
+        buf.extend_from_slice(self.info_hash.as_bytes());
+        buf.extend_from_slice(&self.peer_id);
+        buf.extend_from_slice(&self.downloaded.to_be_bytes());
+        buf.extend_from_slice(&self.left.to_be_bytes());
+        buf.extend_from_slice(&self.uploaded.to_be_bytes());
+        buf.extend_from_slice(&event.to_be_bytes());
+        buf.extend_from_slice(&self.ip.map_or([0; 4], |ip| ip.octets()));
+        buf.extend_from_slice(&self.key.to_be_bytes());
+        buf.extend_from_slice(&self.num_want.map_or(-1, |n| n as i32).to_be_bytes());
+        buf.extend_from_slice(&self.port.to_be_bytes());
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AnnounceResponse {
+    interval: u32,
+    leechers: u32,
+    seeders: u32,
+    peers: Vec<Peer>,
+}
+
+impl AnnounceResponse {
+    /// `interval`: the number of seconds to wait before announcing again.
+    pub fn new(interval: u32, leechers: u32, seeders: u32, peers: Vec<Peer>) -> Self {
+        Self {
+            interval,
+            leechers,
+            seeders,
+            peers,
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn leechers(&self) -> u32 {
+        self.leechers
+    }
+
+    pub fn seeders(&self) -> u32 {
+        self.seeders
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+}
+
+/// A response datagram from a UDP tracker.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Response {
+    Connect {
+        connection_id: u64,
+    },
+    Announce(AnnounceResponse),
+    /// The stats of each scraped torrent, in the order they were requested.
+    Scrape(Vec<TorrentStats>),
+    Error(String),
+}
+
+impl Response {
+    /// Parses a response datagram, returning its transaction ID along with it. Peers in
+    /// announce responses are IPv6 if `ipv6` is set, i.e. if the request was sent over IPv6.
+    pub fn decode(bytes: &[u8], ipv6: bool) -> Result<(u32, Self)> {
+        if bytes.len() < 8 {
+            return Err(Error::InvalidTrackerResponse(format!(
+                "response of {} bytes is too short",
+                bytes.len()
+            )));
+        }
+        let action = Action::try_from(read_u32(&bytes[0..4]))?;
+        let transaction_id = read_u32(&bytes[4..8]);
+        let body = &bytes[8..];
+        let too_short = || {
+            Error::InvalidTrackerResponse(format!(
+                "{:?} response of {} bytes is too short",
+                action,
+                bytes.len()
+            ))
+        };
+
+        let response = match action {
+            Action::Connect => Response::Connect {
+                connection_id: u64::from_be_bytes(
+                    body.get(..8).ok_or_else(too_short)?.try_into().unwrap(),
+                ),
+            },
+            Action::Announce => {
+                if body.len() < 12 {
+                    return Err(too_short());
+                }
+                Response::Announce(AnnounceResponse::new(
+                    read_u32(&body[0..4]),
+                    read_u32(&body[4..8]),
+                    read_u32(&body[8..12]),
+                    Peer::decode_compact_list(&body[12..], ipv6)?,
+                ))
+            }
+            Action::Scrape => {
+                if !body.len().is_multiple_of(12) {
+                    return Err(Error::InvalidTrackerResponse(format!(
+                        "scrape response body of {} bytes is not a multiple of 12",
+                        body.len()
+                    )));
+                }
+                Response::Scrape(
+                    body.chunks(12)
+                        .map(|stats| {
+                            TorrentStats::new(
+                                read_u32(&stats[0..4]) as u64,
+                                read_u32(&stats[4..8]) as u64,
+                                read_u32(&stats[8..12]) as u64,
+                                None,
+                            )
+                        })
+                        .collect(),
+                )
+            }
+            Action::Error => Response::Error(String::from_utf8_lossy(body).into_owned()),
+        };
+        Ok((transaction_id, response))
+    }
+}
+
+/// A client for a single UDP tracker, as described in BEP 15.
+///
+/// Connection IDs are obtained as needed and reused until they expire. Requests which go
+/// unanswered are retransmitted with exponential backoff, and responses whose transaction ID
+/// doesn't match the outstanding request are ignored.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+    connection_lifetime: Duration,
+}
+
+impl UdpTracker {
+    /// Resolves the host of a `udp://` tracker URL and binds a local socket to talk to it.
+    pub async fn new(url: &Url) -> Result<Self> {
+        let invalid = || Error::InvalidTrackerUrl(url.to_string());
+        if url.scheme() != "udp" {
+            return Err(invalid());
+        }
+        let host = url.host_str().ok_or_else(invalid)?;
+        let port = url.port().ok_or_else(invalid)?;
+        let address = net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(invalid)?;
+
+        let local = if address.is_ipv6() {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+        Ok(Self::from_socket(socket))
+    }
+
+    /// Uses a socket which is already connected to the tracker.
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            connection: None,
+            base_timeout: DEFAULT_BASE_TIMEOUT,
+            max_retransmissions: DEFAULT_MAX_RETRANSMISSIONS,
+            connection_lifetime: CONNECTION_ID_LIFETIME,
+        }
+    }
+
+    /// `base_timeout`: how long to wait for the first response to each request.
+    pub fn with_base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+
+    /// `max_retransmissions`: how many times a request is sent again before giving up. The
+    /// timeout stops doubling after the 8th retransmission.
+    pub fn with_max_retransmissions(mut self, max_retransmissions: u32) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    /// `connection_lifetime`: how long a connection ID is reused for before a new one is
+    /// requested.
+    pub fn with_connection_lifetime(mut self, connection_lifetime: Duration) -> Self {
+        self.connection_lifetime = connection_lifetime;
+        self
+    }
+
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let mut body = Vec::with_capacity(82);
+        request.encode(&mut body);
+        match self.transact(Action::Announce, &body).await? {
+            Response::Announce(response) => Ok(response),
+            other => Err(unexpected_response(Action::Announce, &other)),
+        }
+    }
+
+    /// Fetches the stats of each torrent in `info_hashes`, in order. Long lists are split
+    /// across several requests.
+    pub async fn scrape(&mut self, info_hashes: &[InfoHash]) -> Result<Vec<TorrentStats>> {
+        let mut result = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_INFO_HASHES) {
+            let body = chunk
+                .iter()
+                .flat_map(|info_hash| info_hash.as_bytes().iter().copied())
+                .collect::<Vec<_>>();
+            match self.transact(Action::Scrape, &body).await? {
+                Response::Scrape(stats) if stats.len() == chunk.len() => result.extend(stats),
+                Response::Scrape(stats) => {
+                    return Err(Error::InvalidTrackerResponse(format!(
+                        "requested stats for {} torrents, got {}",
+                        chunk.len(),
+                        stats.len()
+                    )))
+                }
+                other => return Err(unexpected_response(Action::Scrape, &other)),
+            }
+        }
+        Ok(result)
+    }
+
+    fn timeout(&self, attempt: u32) -> Duration {
+        self.base_timeout
+            .saturating_mul(2_u32.pow(attempt.min(MAX_BACKOFF_EXPONENT)))
+    }
+
+    /// The current connection ID, unless it has expired.
+    fn connection_id(&self) -> Option<u64> {
+        self.connection
+            .filter(|(_, received)| received.elapsed() < self.connection_lifetime)
+            .map(|(connection_id, _)| connection_id)
+    }
+
+    /// Sends a request with the given action and body, retransmitting it until a response
+    /// arrives. A connection ID is requested first whenever the current one has expired, which
+    /// may happen while waiting. Connect requests and the request itself share one count of
+    /// attempts, so the timeout keeps doubling across both.
+    async fn transact(&mut self, action: Action, body: &[u8]) -> Result<Response> {
+        let mut connection_id = self.connection_id();
+        let mut attempt = 0;
+        loop {
+            let timeout = self.timeout(attempt);
+            match connection_id {
+                None => {
+                    let transaction_id = rand::random();
+                    let packet = header(PROTOCOL_ID, Action::Connect, transaction_id);
+                    match self.exchange(&packet, transaction_id, timeout).await? {
+                        Some(Response::Connect {
+                            connection_id: received,
+                        }) => {
+                            self.connection = Some((received, Instant::now()));
+                            connection_id = Some(received);
+                            continue;
+                        }
+                        Some(Response::Error(message)) => {
+                            return Err(Error::TrackerFailure(message))
+                        }
+                        Some(other) => return Err(unexpected_response(Action::Connect, &other)),
+                        None => log::debug!("Connect request timed out (attempt {})", attempt + 1),
+                    }
+                }
+                Some(connection_id) => {
+                    let transaction_id = rand::random();
+                    let mut packet = header(connection_id, action, transaction_id);
+                    packet.extend_from_slice(body);
+                    match self.exchange(&packet, transaction_id, timeout).await? {
+                        Some(Response::Error(message)) => {
+                            return Err(Error::TrackerFailure(message))
+                        }
+                        Some(response) => return Ok(response),
+                        None => {
+                            log::debug!("{:?} request timed out (attempt {})", action, attempt + 1)
+                        }
+                    }
+                }
+            }
+
+            if attempt >= self.max_retransmissions {
+                return Err(Error::TrackerTimeout(attempt.saturating_add(1)));
+            }
+            attempt += 1;
+            connection_id = self.connection_id();
+        }
+    }
+
+    /// Sends `packet` once, then waits up to `timeout` for a response with a matching
+    /// transaction ID. Returns `Ok(None)` on timeout.
+    async fn exchange(
+        &self,
+        packet: &[u8],
+        transaction_id: u32,
+        timeout: Duration,
+    ) -> Result<Option<Response>> {
+        self.socket.send(packet).await?;
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; MAX_DATAGRAM_LENGTH];
+
+        loop {
+            let length = match time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(length) => length?,
+                Err(_) => return Ok(None),
+            };
+            match Response::decode(&buf[..length], ipv6) {
+                Ok((id, response)) if id == transaction_id => return Ok(Some(response)),
+                Ok((id, _)) => log::debug!("Ignoring response to stale transaction {}", id),
+                Err(e) => log::debug!("Ignoring malformed tracker response: {}", e),
+            }
+        }
+    }
+}
+
+fn header(connection_id: u64, action: Action, transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&(action as u32).to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+fn unexpected_response(action: Action, response: &Response) -> Error {
+    Error::InvalidTrackerResponse(format!(
+        "unexpected response to {:?} request: {:?}",
+        action, response
+    ))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_request_test() {
+        let request = AnnounceRequest::new(
+            InfoHash::new([1; 20]),
+            *b"-BT0000-abcdefghijkl",
+            2,
+            3,
+            4,
+            Some(Event::Started),
+            None,
+            0xdead_beef,
+            None,
+            6881,
+        );
+        let mut packet = header(0x0102_0304_0506_0708, Action::Announce, 9);
+        request.encode(&mut packet);
+
+        assert_eq!(packet.len(), 98);
+        assert_eq!(
+            &packet[..16],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 1, 0, 0, 0, 9]
+        );
+        assert_eq!(&packet[16..36], &[1; 20]);
+        assert_eq!(&packet[36..56], b"-BT0000-abcdefghijkl");
+        assert_eq!(
+            &packet[56..80],
+            &[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4]
+        );
+        // event, ip, key, num_want, port
+        assert_eq!(&packet[80..84], &[0, 0, 0, 2]);
+        assert_eq!(&packet[84..88], &[0, 0, 0, 0]);
+        assert_eq!(&packet[88..92], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&packet[92..96], &[0xff; 4]);
+        assert_eq!(&packet[96..], &[0x1a, 0xe1]);
+
+        assert_eq!(
+            header(PROTOCOL_ID, Action::Connect, 7),
+            [0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 0, 0, 0, 7]
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_test() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let tracker = UdpTracker::from_socket(socket).with_max_retransmissions(u32::MAX);
+        assert_eq!(tracker.timeout(0), Duration::from_secs(15));
+        assert_eq!(tracker.timeout(8), Duration::from_secs(3840));
+        assert_eq!(tracker.timeout(u32::MAX), Duration::from_secs(3840));
+
+        let tracker = tracker.with_base_timeout(Duration::MAX);
+        assert_eq!(tracker.timeout(1), Duration::MAX);
+    }
+
+    #[test]
+    fn response_decoding_test() {
+        let mut connect = vec![0, 0, 0, 0, 0, 0, 0, 5];
+        connect.extend_from_slice(&42_u64.to_be_bytes());
+        assert_eq!(
+            Response::decode(&connect, false).unwrap(),
+            (5, Response::Connect { connection_id: 42 })
+        );
+        assert!(Response::decode(&connect[..12], false).is_err());
+
+        let mut announce = vec![0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 7, 8, 0, 0, 0, 2, 0, 0, 0, 1];
+        announce.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 1, 0x1a, 0xe2]);
+        let (_, response) = Response::decode(&announce, false).unwrap();
+        assert_eq!(
+            response,
+            Response::Announce(AnnounceResponse::new(
+                1800,
+                2,
+                1,
+                vec![
+                    Peer::new(None, "127.0.0.1:6881".parse().unwrap()),
+                    Peer::new(None, "10.0.0.1:6882".parse().unwrap())
+                ]
+            ))
+        );
+        // The same 12 bytes of peers are not a whole number of IPv6 peers
+        assert!(Response::decode(&announce, true).is_err());
+
+        let mut announce_v6 = announce[..20].to_vec();
+        announce_v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        announce_v6.extend_from_slice(&[0x1a, 0xe1]);
+        let (_, response) = Response::decode(&announce_v6, true).unwrap();
+        match response {
+            Response::Announce(response) => assert_eq!(
+                response.peers(),
+                &[Peer::new(None, "[::1]:6881".parse().unwrap())]
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let scrape = [0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        assert_eq!(
+            Response::decode(&scrape, false).unwrap(),
+            (7, Response::Scrape(vec![TorrentStats::new(1, 2, 3, None)]))
+        );
+        assert!(Response::decode(&scrape[..19], false).is_err());
+
+        assert_eq!(
+            Response::decode(b"\x00\x00\x00\x03\x00\x00\x00\x08oops", false).unwrap(),
+            (8, Response::Error(String::from("oops")))
+        );
+        assert!(Response::decode(&[0, 0, 0, 9, 0, 0, 0, 1], false).is_err());
+        assert!(Response::decode(&[0, 0, 0, 1], false).is_err());
+    }
+}
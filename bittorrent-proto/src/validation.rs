@@ -0,0 +1,100 @@
+use std::fmt::{self, Display, Formatter};
+
+/// A structural problem found by [`Info::validate`](crate::Info::validate) or
+/// [`MetaInfo::validate`](crate::MetaInfo::validate).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ValidationIssue {
+    /// The length of `pieces` isn't a multiple of 20.
+    InvalidPiecesLength(usize),
+    /// `pieces` doesn't hold one hash for each piece of the total length.
+    PieceCountMismatch { expected: u64, actual: u64 },
+    /// `piece length` isn't a nonzero power of two, or is larger than
+    /// [`MAX_PIECE_LENGTH`](crate::MAX_PIECE_LENGTH).
+    InvalidPieceLength(u64),
+    /// The combined length of all files doesn't fit in a `u64`.
+    TotalLengthOverflow,
+    /// `name` can't be used as a file or directory name.
+    UnsafeName(String),
+    /// A file path has an empty, `.`, `..` or absolute component.
+    UnsafePath {
+        file_index: usize,
+        path: Vec<String>,
+    },
+    /// A file path is the same as an earlier one once normalized.
+    DuplicatePath {
+        file_index: usize,
+        path: Vec<String>,
+    },
+    /// A file of a v2 torrent is missing its piece layer, or the layer has the wrong length.
+    InvalidPieceLayer(Vec<String>),
+    /// A tracker URL in `announce` or `announce-list` doesn't parse.
+    InvalidAnnounceUrl(String),
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPiecesLength(length) => {
+                write!(f, "'pieces' length {} is not a multiple of 20", length)
+            }
+            Self::PieceCountMismatch { expected, actual } => write!(
+                f,
+                "expected {} piece hashes for the total length, got {}",
+                expected, actual
+            ),
+            Self::InvalidPieceLength(length) => {
+                write!(
+                    f,
+                    "piece length {} is not a power of two up to 2^31",
+                    length
+                )
+            }
+            Self::TotalLengthOverflow => write!(f, "total length of files overflows"),
+            Self::UnsafeName(name) => write!(f, "unsafe name '{}'", name),
+            Self::UnsafePath { file_index, path } => {
+                write!(f, "unsafe path for file {}: {:?}", file_index, path)
+            }
+            Self::DuplicatePath { file_index, path } => {
+                write!(f, "duplicate path for file {}: {:?}", file_index, path)
+            }
+            Self::InvalidPieceLayer(path) => write!(f, "invalid piece layer for file {:?}", path),
+            Self::InvalidAnnounceUrl(url) => write!(f, "invalid announce URL '{}'", url),
+        }
+    }
+}
+
+/// Whether `component` can be used as a single file or directory name without escaping the
+/// directory it's placed in.
+pub(crate) fn is_safe_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let is_drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    !matches!(component, "" | "." | "..")
+        && !is_drive
+        && !component.contains(&['/', '\\', '\0'][..])
+}
+
+/// The form of a path used to detect duplicates. Paths which differ only in case or in
+/// trailing dots and spaces refer to the same file on some platforms.
+pub(crate) fn normalize_path(path: &[String]) -> Vec<String> {
+    path.iter()
+        .map(|component| component.trim_end_matches(&['.', ' '][..]).to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn component_test() {
+        assert!(is_safe_component("file.txt"));
+        assert!(is_safe_component(".pad"));
+        for unsafe_component in ["", ".", "..", "a/b", "..\\b", "C:", "c:file", "a\0"] {
+            assert!(!is_safe_component(unsafe_component), "{}", unsafe_component);
+        }
+        assert_eq!(
+            normalize_path(&[String::from("Dir. "), String::from("FILE")]),
+            vec!["dir", "file"]
+        );
+    }
+}
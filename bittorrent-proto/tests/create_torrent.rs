@@ -0,0 +1,298 @@
+use std::{fs, path::PathBuf};
+
+use bendy::{decoding::FromBencode, encoding::ToBencode};
+use bittorrent_proto::{InfoHash, InfoHashV2, MetaInfo, TorrentBuilder, TorrentVersion};
+use chrono::{TimeZone, Utc};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Creates a fresh directory under the system temp directory for a single test.
+fn temp_dir(test: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("bittorrent-proto-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn contents(length: usize, seed: u8) -> Vec<u8> {
+    (0..length)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[test]
+fn create_fedora_layout() {
+    let fixture =
+        MetaInfo::from_bencode(&fs::read("tests/fixtures/test.torrent").unwrap()).unwrap();
+    let fixture_files = fixture.info().files().unwrap();
+
+    // The same layout as the fixture, with the files shrunk so that the first piece spans both
+    let root = temp_dir("fedora").join(fixture.info().name());
+    fs::create_dir_all(&root).unwrap();
+    let iso = contents(300_000, 1);
+    let checksum = contents(2032, 2);
+    // Written in reverse order, which shouldn't affect the file order in the torrent
+    fs::write(root.join(&fixture_files[1].path()[0]), &checksum).unwrap();
+    fs::write(root.join(&fixture_files[0].path()[0]), &iso).unwrap();
+
+    let meta_info = TorrentBuilder::new(&root, fixture.announce().to_string())
+        .with_piece_length(fixture.info().piece_length())
+        .with_creation_date(*fixture.creation_date().unwrap())
+        .build()
+        .unwrap();
+
+    assert_eq!(meta_info.announce(), fixture.announce());
+    assert_eq!(meta_info.creation_date(), fixture.creation_date());
+    let info = meta_info.info();
+    assert_eq!(info.name(), fixture.info().name());
+    assert_eq!(info.piece_length(), 2_u64.pow(18));
+    assert!(info.length().is_none());
+    assert!(info.private().is_none());
+
+    let files = info.files().unwrap();
+    assert_eq!(files.len(), fixture_files.len());
+    for (file, fixture_file) in files.iter().zip(fixture_files) {
+        assert_eq!(file.path(), fixture_file.path());
+    }
+    assert_eq!(files[0].length(), 300_000);
+    assert_eq!(files[1].length(), 2032);
+
+    let content = [iso, checksum].concat();
+    let expected = content
+        .chunks(2_usize.pow(18))
+        .flat_map(|piece| Sha1::from(piece).digest().bytes())
+        .collect::<Vec<_>>();
+    assert_eq!(info.pieces().len(), 40);
+    assert_eq!(info.pieces(), expected.as_slice());
+
+    // The result round-trips, and is the same however many threads hash it
+    let decoded = MetaInfo::from_bencode(&meta_info.to_bencode().unwrap()).unwrap();
+    assert_eq!(decoded.info_hash(), meta_info.info_hash());
+    let parallel = TorrentBuilder::new(&root, fixture.announce().to_string())
+        .with_piece_length(2_u64.pow(14))
+        .with_threads(4)
+        .build()
+        .unwrap();
+    let sequential = TorrentBuilder::new(&root, fixture.announce().to_string())
+        .with_piece_length(2_u64.pow(14))
+        .build()
+        .unwrap();
+    assert_eq!(parallel.info().pieces().len(), 19 * 20);
+    assert_eq!(parallel, sequential);
+
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn create_single_file() {
+    let dir = temp_dir("single");
+    let path = dir.join("Fedora-Spins-32-1.6-x86_64-CHECKSUM");
+    fs::write(&path, contents(40_000, 3)).unwrap();
+
+    let meta_info = TorrentBuilder::new(&path, String::from("http://tracker.example/announce"))
+        .with_private(true)
+        .with_announce_list(vec![vec![String::from("http://backup.example/announce")]])
+        .with_comment(String::from("a comment"))
+        .with_created_by(String::from("bittorrent-rs"))
+        .with_creation_date(Utc.timestamp_opt(1234567890, 0).unwrap())
+        .build()
+        .unwrap();
+
+    let info = meta_info.info();
+    assert_eq!(info.name(), "Fedora-Spins-32-1.6-x86_64-CHECKSUM");
+    assert_eq!(info.length(), Some(40_000));
+    assert!(info.files().is_none());
+    assert_eq!(info.private(), Some(true));
+    // 40 KB is small enough for the minimum piece length of 16 KiB
+    assert_eq!(info.piece_length(), 2_u64.pow(14));
+    assert_eq!(info.pieces().len(), 3 * 20);
+    assert_eq!(meta_info.comment(), Some("a comment"));
+    assert_eq!(meta_info.created_by(), Some("bittorrent-rs"));
+    assert_eq!(meta_info.announce_list().unwrap().len(), 1);
+
+    assert!(TorrentBuilder::new(&path, String::new())
+        .with_piece_length(1000)
+        .build()
+        .is_err());
+    assert!(TorrentBuilder::new(dir.join("missing"), String::new())
+        .build()
+        .is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn node(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+    sha256(&[left, right].concat())
+}
+
+/// Writes `a.bin`, an empty `b/empty` and `b/c.bin` under a fresh directory, and returns it
+/// along with the contents of the two non-empty files.
+fn v2_content(test: &str) -> (PathBuf, Vec<u8>, Vec<u8>) {
+    let root = temp_dir(test).join("content");
+    fs::create_dir_all(root.join("b")).unwrap();
+    let a = contents(20_000, 4);
+    let c = contents(70_000, 5);
+    fs::write(root.join("a.bin"), &a).unwrap();
+    fs::write(root.join("b").join("c.bin"), &c).unwrap();
+    fs::write(root.join("b").join("empty"), []).unwrap();
+    (root, a, c)
+}
+
+/// The expected `pieces root` of `a.bin` and `c.bin`, and the piece layer of `c.bin`, in
+/// 32 KiB pieces of two 16 KiB blocks.
+fn v2_hashes(a: &[u8], c: &[u8]) -> ([u8; 32], [u8; 32], Vec<u8>) {
+    let a_blocks = a.chunks(1 << 14).map(sha256).collect::<Vec<_>>();
+    let a_root = node(a_blocks[0], a_blocks[1]);
+
+    let c_blocks = c.chunks(1 << 14).map(sha256).collect::<Vec<_>>();
+    assert_eq!(c_blocks.len(), 5);
+    let layer = [
+        node(c_blocks[0], c_blocks[1]),
+        node(c_blocks[2], c_blocks[3]),
+        node(c_blocks[4], [0; 32]),
+    ];
+    let pad = node([0; 32], [0; 32]);
+    let c_root = node(node(layer[0], layer[1]), node(layer[2], pad));
+    (a_root, c_root, layer.concat())
+}
+
+#[test]
+fn create_v2() {
+    let (root, a, c) = v2_content("v2");
+    let (a_root, c_root, c_layer) = v2_hashes(&a, &c);
+
+    let meta_info = TorrentBuilder::new(&root, String::from("http://tracker.example/announce"))
+        .with_version(TorrentVersion::V2)
+        .with_piece_length(1 << 15)
+        .with_threads(2)
+        .build()
+        .unwrap();
+    let info = meta_info.info();
+    assert!(info.is_v2() && !info.is_v1());
+    assert!(info.pieces().is_empty());
+
+    let files = info.file_tree().unwrap().files();
+    let paths = files
+        .iter()
+        .map(|(path, _)| path.join("/"))
+        .collect::<Vec<_>>();
+    assert_eq!(paths, vec!["a.bin", "b/c.bin", "b/empty"]);
+    assert_eq!(files[0].1.pieces_root(), Some(&a_root));
+    assert_eq!(files[1].1.pieces_root(), Some(&c_root));
+    assert_eq!(files[2].1.pieces_root(), None);
+    let piece_layers = meta_info.piece_layers().unwrap();
+    assert_eq!(piece_layers.len(), 1);
+    assert_eq!(piece_layers[&c_root], c_layer);
+    assert!(meta_info.validate().is_ok());
+
+    let bencode = meta_info.to_bencode().unwrap();
+    let decoded = MetaInfo::from_bencode(&bencode).unwrap();
+    assert_eq!(decoded, meta_info);
+    let info_hash_v2 = InfoHashV2::from_info_bytes(&info.to_bencode().unwrap());
+    assert_eq!(decoded.info_hash_v2(), Some(info_hash_v2));
+    assert_eq!(decoded.info_hash(), info_hash_v2.truncated());
+    assert_eq!(info.piece_count(), 4);
+
+    // A single file is the only entry of its own tree
+    let single = TorrentBuilder::new(root.join("a.bin"), String::new())
+        .with_version(TorrentVersion::V2)
+        .with_piece_length(1 << 15)
+        .build()
+        .unwrap();
+    let files = single.info().file_tree().unwrap().files();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, vec![String::from("a.bin")]);
+    assert_eq!(files[0].1.pieces_root(), Some(&a_root));
+    assert!(single.piece_layers().unwrap().is_empty());
+
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn create_hybrid() {
+    let (root, a, c) = v2_content("hybrid");
+    let (a_root, c_root, c_layer) = v2_hashes(&a, &c);
+
+    let meta_info = TorrentBuilder::new(&root, String::from("http://tracker.example/announce"))
+        .with_version(TorrentVersion::Hybrid)
+        .with_piece_length(1 << 15)
+        .build()
+        .unwrap();
+    let info = meta_info.info();
+    assert!(info.is_hybrid());
+
+    // 'a.bin' is padded out to a whole piece, but nothing follows the last non-empty file
+    let files = info.files().unwrap();
+    let paths = files
+        .iter()
+        .map(|file| (file.path().join("/"), file.length()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec![
+            (String::from("a.bin"), 20_000),
+            (String::from(".pad/12768"), 12_768),
+            (String::from("b/c.bin"), 70_000),
+            (String::from("b/empty"), 0),
+        ]
+    );
+    assert!(files[1].extra_fields().contains_key(&b"attr"[..]));
+
+    let content = [a.clone(), vec![0; 12_768], c.clone()].concat();
+    let expected = content
+        .chunks(1 << 15)
+        .flat_map(|piece| Sha1::from(piece).digest().bytes())
+        .collect::<Vec<_>>();
+    assert_eq!(info.pieces(), expected.as_slice());
+    assert_eq!(info.file_piece_range(2), Some(1..4));
+
+    let tree_files = info.file_tree().unwrap().files();
+    assert_eq!(tree_files[0].1.pieces_root(), Some(&a_root));
+    assert_eq!(tree_files[1].1.pieces_root(), Some(&c_root));
+    assert_eq!(meta_info.piece_layers().unwrap()[&c_root], c_layer);
+    assert!(meta_info.validate().is_ok());
+
+    // Both hashes are exposed, and the v1 one is used on the wire
+    let info_bytes = info.to_bencode().unwrap();
+    assert_eq!(
+        meta_info.info_hash(),
+        InfoHash::from_info_bytes(&info_bytes)
+    );
+    assert_eq!(
+        meta_info.info_hash_v2(),
+        Some(InfoHashV2::from_info_bytes(&info_bytes))
+    );
+    let magnet = meta_info.to_magnet();
+    assert_eq!(magnet.info_hash(), Some(meta_info.info_hash()));
+    assert_eq!(magnet.info_hash_v2(), meta_info.info_hash_v2());
+
+    let decoded = MetaInfo::from_bencode(&meta_info.to_bencode().unwrap()).unwrap();
+    assert_eq!(decoded, meta_info);
+
+    fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn create_skips_symlinks() {
+    let dir = temp_dir("symlink");
+    let root = dir.join("content");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file"), b"contents").unwrap();
+    fs::write(dir.join("outside"), b"outside the root").unwrap();
+    // A loop back to the root, and a link to a file outside it
+    std::os::unix::fs::symlink(&root, root.join("loop")).unwrap();
+    std::os::unix::fs::symlink(dir.join("outside"), root.join("link")).unwrap();
+
+    let meta_info = TorrentBuilder::new(&root, String::new()).build().unwrap();
+    let files = meta_info.info().files().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].path(), [String::from("file")]);
+    assert_eq!(files[0].length(), 8);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,150 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bendy::decoding::FromBencode;
+use bittorrent_proto::{
+    dht::{
+        krpc::{Message, Query, METHOD_UNKNOWN},
+        DhtNode, NodeId, K,
+    },
+    error::Error,
+    InfoHash, MetaInfo,
+};
+
+const NODE_COUNT: usize = 20;
+
+/// Starts a network of nodes on loopback, each bootstrapped from the first.
+async fn network() -> Vec<DhtNode> {
+    let mut nodes = Vec::with_capacity(NODE_COUNT);
+    for _ in 0..NODE_COUNT {
+        let node = DhtNode::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_query_timeout(Duration::from_millis(500));
+        nodes.push(node);
+    }
+    let seed = nodes[0].local_addr().unwrap();
+    for node in &nodes[1..] {
+        node.bootstrap(&[seed]).await.unwrap();
+    }
+    nodes
+}
+
+#[tokio::test]
+async fn ping_test() {
+    let a = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let b = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    assert_eq!(a.ping(b.local_addr().unwrap()).await.unwrap(), b.id());
+
+    // Both sides learn about each other
+    assert!(a.routing_table().contains(&b.id()));
+    assert!(b.routing_table().contains(&a.id()));
+}
+
+#[tokio::test]
+async fn bootstrap_test() {
+    let nodes = network().await;
+    for node in &nodes {
+        assert!(node.routing_table().len() >= K, "{:?}", node.id());
+    }
+
+    // A lookup from anywhere finds the nodes closest to the target
+    let target = NodeId::random();
+    let mut expected = nodes.iter().map(DhtNode::id).collect::<Vec<_>>();
+    expected.sort_unstable_by_key(|id| id.distance(&target));
+    let found = nodes[NODE_COUNT - 1].find_node(target).await;
+    let mut found = found.iter().map(|node| node.id()).collect::<Vec<_>>();
+    found.push(nodes[NODE_COUNT - 1].id());
+    found.sort_unstable_by_key(|id| id.distance(&target));
+    assert_eq!(found[..K], expected[..K]);
+}
+
+#[tokio::test]
+async fn announce_test() {
+    let nodes = network().await;
+    let info_hash = InfoHash::new(rand::random());
+    assert!(nodes[3].get_peers(info_hash).await.is_empty());
+
+    nodes[3].announce(info_hash, Some(6881)).await.unwrap();
+    nodes[7].announce(info_hash, None).await.unwrap();
+
+    let peers = nodes[12].get_peers(info_hash).await;
+    assert!(peers.contains(&SocketAddr::from(([127, 0, 0, 1], 6881))));
+    assert!(peers.contains(&nodes[7].local_addr().unwrap()));
+    assert_eq!(peers.len(), 2);
+}
+
+#[tokio::test]
+async fn bad_token_test() {
+    let a = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let b = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let query = Query::AnnouncePeer {
+        id: a.id(),
+        info_hash: InfoHash::new([1; 20]),
+        port: 6881,
+        implied_port: false,
+        token: b"aoeusnth".to_vec(),
+    };
+    match a.query(b.local_addr().unwrap(), query).await {
+        Err(Error::DhtError(203, _)) => {}
+        other => panic!("expected a protocol error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn unknown_method_test() {
+    let node = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket
+        .send_to(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:xy1:y1:qe",
+            node.local_addr().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let mut buf = [0; 1500];
+    let (length, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    match Message::decode(&buf[..length]).unwrap() {
+        Message::Error {
+            transaction_id,
+            code,
+            ..
+        } => {
+            assert_eq!(transaction_id, b"xy");
+            assert_eq!(code, METHOD_UNKNOWN);
+        }
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn timeout_test() {
+    let a = DhtNode::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .with_query_timeout(Duration::from_millis(100));
+    let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = silent.local_addr().unwrap();
+    assert!(matches!(a.ping(address).await, Err(Error::DhtTimeout(a)) if a == address));
+    assert!(matches!(
+        a.bootstrap(&[address]).await,
+        Err(Error::NoDhtNodes)
+    ));
+}
+
+#[tokio::test]
+async fn bootstrap_from_meta_info_test() {
+    let seed = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let node = DhtNode::bind("127.0.0.1:0").await.unwrap();
+    let port = seed.local_addr().unwrap().port();
+    let bencode = format!(
+        "d8:announce18:http://someurl.com4:infod6:lengthi321e4:name9:some name12:piece lengthi1234e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i{}eeee",
+        port
+    );
+    let meta_info = MetaInfo::from_bencode(bencode.as_bytes()).unwrap();
+    assert_eq!(node.bootstrap_from_meta_info(&meta_info).await.unwrap(), 1);
+    assert!(node.routing_table().contains(&seed.id()));
+}
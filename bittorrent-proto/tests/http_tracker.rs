@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use bittorrent_proto::{
+    error::Error,
+    tracker::{
+        announce::{Event, Request},
+        scrape, TrackerClient,
+    },
+    InfoHash,
+};
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::oneshot,
+};
+
+/// Serves a single HTTP request with `body`, returning the URL to request and a receiver for
+/// the head of the request that was received.
+async fn serve_once(body: &'static [u8]) -> (Url, oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/announce",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let (sender, receiver) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let _ = sender.send(String::from_utf8(head).unwrap());
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        stream.write_all(&response).await.unwrap();
+    });
+    (url, receiver)
+}
+
+fn request(announce_url: Url) -> Request {
+    Request::new(
+        announce_url,
+        InfoHash::new([0x01; 20]),
+        *b"-BT0000-abcdefghijkl",
+        None,
+        6881,
+        0,
+        0,
+        1000,
+        Some(Event::Started),
+        true,
+        None,
+        None,
+        None,
+        None,
+    )
+}
+
+#[tokio::test]
+async fn http_announce() {
+    let (url, head) = serve_once(
+        b"d8:intervali900e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:xyz15:warning message8:careful!e",
+    )
+    .await;
+    let client = TrackerClient::with_config("test-agent/1.0", Duration::from_secs(5)).unwrap();
+    let response = client.announce(request(url)).await.unwrap();
+
+    assert_eq!(response.interval(), Some(900));
+    assert_eq!(response.min_interval(), Some(60));
+    assert_eq!(response.tracker_id(), Some("xyz"));
+    assert_eq!(response.warning_message(), Some("careful!"));
+    assert_eq!(response.peers().unwrap().len(), 1);
+
+    let head = head.await.unwrap().to_ascii_lowercase();
+    assert!(head.starts_with(&format!(
+        "get /announce?info_hash={}&peer_id=-bt0000-abcdefghijkl&port=6881",
+        "%01".repeat(20)
+    )));
+    assert!(head.contains("user-agent: test-agent/1.0\r\n"));
+}
+
+#[tokio::test]
+async fn http_announce_failure() {
+    let (url, _) = serve_once(b"d14:failure reason17:torrent not founde").await;
+    let client = TrackerClient::new().unwrap();
+    assert!(matches!(
+        client.announce(request(url)).await,
+        Err(Error::TrackerFailure(reason)) if reason == "torrent not found"
+    ));
+
+    let (url, _) = serve_once(b"<html>not bencode</html>").await;
+    assert!(matches!(
+        client.announce(request(url)).await,
+        Err(Error::InvalidTrackerResponse(_))
+    ));
+}
+
+#[tokio::test]
+async fn http_announce_timeout() {
+    // Accepts connections but never responds
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/announce",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let client = TrackerClient::with_config("test-agent/1.0", Duration::from_millis(100)).unwrap();
+    assert!(matches!(
+        client.announce(request(url)).await,
+        Err(Error::HttpError(e)) if e.is_timeout()
+    ));
+    drop(listener);
+}
+
+#[tokio::test]
+async fn http_scrape() {
+    let (url, head) = serve_once(
+        b"d5:filesd20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01d8:completei5e10:downloadedi50e10:incompletei10eeee",
+    )
+    .await;
+    let client = TrackerClient::new().unwrap();
+    let response = client
+        .scrape(
+            scrape::Request::from_announce_url(
+                &url,
+                vec![InfoHash::new([1; 20]), InfoHash::new([2; 20])],
+            )
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let stats = &response.files()[&InfoHash::new([1; 20])];
+    assert_eq!(
+        (stats.complete(), stats.downloaded(), stats.incomplete()),
+        (5, 50, 10)
+    );
+    assert!(head.await.unwrap().starts_with(&format!(
+        "GET /scrape?info_hash={}&info_hash={} ",
+        "%01".repeat(20),
+        "%02".repeat(20)
+    )));
+}
+
+#[tokio::test]
+async fn http_scrape_failure() {
+    let (url, _) = serve_once(b"d14:failure reason15:scrape disablede").await;
+    let client = TrackerClient::new().unwrap();
+    let request = scrape::Request::from_announce_url(&url, vec![InfoHash::new([1; 20])]).unwrap();
+    assert!(matches!(
+        client.scrape(request).await,
+        Err(Error::TrackerFailure(reason)) if reason == "scrape disabled"
+    ));
+}
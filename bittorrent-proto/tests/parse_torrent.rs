@@ -14,6 +14,10 @@ fn parse_torrent() {
     assert!(meta_info.comment().is_none());
     assert!(meta_info.created_by().is_none());
     assert!(meta_info.encoding().is_none());
+    assert_eq!(
+        "80bbb5c4986d3dd4c52f8dab517451203c4fab1d",
+        meta_info.info_hash().to_hex()
+    );
 
     let info = meta_info.info();
     assert_eq!("Fedora-SoaS-Live-x86_64-32", info.name());
@@ -37,4 +41,8 @@ fn parse_torrent() {
         files[1].path()
     );
     assert!(files[0].md5sum().is_none());
+
+    assert!(meta_info.validate().is_ok());
+    assert_eq!(4234, info.piece_count());
+    assert_eq!(Some(4233..4234), info.file_piece_range(1));
 }
@@ -1,11 +1,12 @@
-use bendy::{decoding::FromBencode, encoding::ToBencode};
+use bendy::decoding::FromBencode;
 use bittorrent_proto::{
-    tracker::announce::{Event, Request, Response},
+    tracker::{
+        announce::{Event, Request},
+        TrackerClient,
+    },
     MetaInfo,
 };
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
-use reqwest::{StatusCode, Url};
-use sha1::Sha1;
+use reqwest::Url;
 
 #[tokio::test]
 async fn tracker_announce() {
@@ -14,15 +15,15 @@ async fn tracker_announce() {
     let file_contents = std::fs::read("tests/fixtures/test.torrent").unwrap();
     let meta_info = MetaInfo::from_bencode(&file_contents).unwrap();
     let info = meta_info.info();
-    let info_hash = Sha1::from(&info.to_bencode().unwrap()).digest();
+    let info_hash = meta_info.info_hash();
     assert_eq!(
         &info_hash.to_string(),
         "80bbb5c4986d3dd4c52f8dab517451203c4fab1d"
     );
     let request = Request::new(
         Url::parse(meta_info.announce()).unwrap(),
-        percent_encode(&info_hash.bytes(), NON_ALPHANUMERIC).to_string(),
-        percent_encode(b"abcdefghijklmnopqrst", NON_ALPHANUMERIC).to_string(),
+        info_hash,
+        *b"abcdefghijklmnopqrst",
         None,
         6881,
         0,
@@ -35,8 +36,6 @@ async fn tracker_announce() {
         None,
         None,
     );
-    let response = reqwest::get(Url::from(request)).await.unwrap();
-    assert_eq!(response.status(), StatusCode::OK);
-    let response = Response::from_bencode(&response.bytes().await.unwrap());
+    let response = TrackerClient::new().unwrap().announce(request).await;
     assert!(response.is_ok());
 }
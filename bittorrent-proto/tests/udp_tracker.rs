@@ -0,0 +1,261 @@
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bittorrent_proto::{
+    error::Error,
+    tracker::{
+        announce::Event,
+        udp::{AnnounceRequest, UdpTracker, PROTOCOL_ID},
+    },
+    InfoHash,
+};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+/// A minimal UDP tracker which answers every request from a fixed swarm.
+struct StandInTracker {
+    socket: UdpSocket,
+    /// The number of connect requests received.
+    connects: Arc<AtomicUsize>,
+    /// The number of requests to ignore before answering, to force retransmissions.
+    dropped: usize,
+}
+
+impl StandInTracker {
+    async fn start(dropped: usize) -> (Url, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}/announce", socket.local_addr().unwrap())).unwrap();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let tracker = Self {
+            socket,
+            connects: connects.clone(),
+            dropped,
+        };
+        tokio::spawn(tracker.run());
+        (url, connects)
+    }
+
+    async fn run(mut self) {
+        let mut connection_id = 0_u64;
+        let mut buf = vec![0; 2048];
+        loop {
+            let (length, from) = self.socket.recv_from(&mut buf).await.unwrap();
+            if self.dropped > 0 {
+                self.dropped -= 1;
+                continue;
+            }
+
+            let request = &buf[..length];
+            let received_id = u64::from_be_bytes(request[..8].try_into().unwrap());
+            let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+            let transaction_id = &request[12..16];
+            let mut response = action.to_be_bytes().to_vec();
+            response.extend_from_slice(transaction_id);
+
+            match action {
+                0 => {
+                    assert_eq!(received_id, PROTOCOL_ID);
+                    self.connects.fetch_add(1, Ordering::SeqCst);
+                    connection_id += 1;
+                    response.extend_from_slice(&connection_id.to_be_bytes());
+                }
+                _ if received_id != connection_id => {
+                    response = error(transaction_id, "invalid connection id");
+                }
+                1 if request[16..36] == [0xff; 20] => {
+                    response = error(transaction_id, "unregistered torrent");
+                }
+                1 => {
+                    // Answer a stale transaction first, which the client should ignore
+                    let mut stale = response.clone();
+                    stale[7] ^= 0xff;
+                    stale.extend_from_slice(&[0; 12]);
+                    self.socket.send_to(&stale, from).await.unwrap();
+
+                    response.extend_from_slice(&[0, 0, 0x07, 0x08, 0, 0, 0, 1, 0, 0, 0, 2]);
+                    response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                    response.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+                }
+                2 => {
+                    for i in 0..(length as u32 - 16) / 20 {
+                        for stat in &[i, i + 1, i + 2] {
+                            response.extend_from_slice(&stat.to_be_bytes());
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+            self.socket.send_to(&response, from).await.unwrap();
+        }
+    }
+}
+
+fn error(transaction_id: &[u8], message: &str) -> Vec<u8> {
+    let mut response = vec![0, 0, 0, 3];
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+fn announce_request(info_hash: InfoHash) -> AnnounceRequest {
+    AnnounceRequest::new(
+        info_hash,
+        *b"-BT0000-abcdefghijkl",
+        0,
+        1000,
+        0,
+        Some(Event::Started),
+        None,
+        1234,
+        Some(50),
+        6881,
+    )
+}
+
+#[tokio::test]
+async fn udp_announce_and_scrape() {
+    let (url, connects) = StandInTracker::start(0).await;
+    let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+    let response = tracker
+        .announce(&announce_request(InfoHash::new([1; 20])))
+        .await
+        .unwrap();
+    assert_eq!(response.interval(), 1800);
+    assert_eq!(response.leechers(), 1);
+    assert_eq!(response.seeders(), 2);
+    let addresses = response
+        .peers()
+        .iter()
+        .map(|peer| peer.address().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(addresses, vec!["127.0.0.1:6881", "10.0.0.2:6882"]);
+
+    let stats = tracker
+        .scrape(&[InfoHash::new([1; 20]), InfoHash::new([2; 20])])
+        .await
+        .unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        (
+            stats[1].complete(),
+            stats[1].downloaded(),
+            stats[1].incomplete()
+        ),
+        (1, 2, 3)
+    );
+    // More info hashes than fit in one datagram are split across requests
+    let stats = tracker
+        .scrape(&[InfoHash::new([3; 20]); 100])
+        .await
+        .unwrap();
+    assert_eq!(stats.len(), 100);
+    assert_eq!(stats[74].complete(), 0);
+
+    // The connection ID is reused while it is still valid
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn udp_connection_expiry() {
+    let (url, connects) = StandInTracker::start(0).await;
+    let mut tracker = UdpTracker::new(&url)
+        .await
+        .unwrap()
+        .with_connection_lifetime(Duration::from_millis(200));
+    let request = announce_request(InfoHash::new([1; 20]));
+
+    tracker.announce(&request).await.unwrap();
+    tracker.announce(&request).await.unwrap();
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    tracker.announce(&request).await.unwrap();
+    assert_eq!(connects.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn udp_retransmission() {
+    // Both the first connect request and its retransmission are lost
+    let (url, connects) = StandInTracker::start(2).await;
+    let mut tracker = UdpTracker::new(&url)
+        .await
+        .unwrap()
+        .with_base_timeout(Duration::from_millis(20));
+    assert!(tracker
+        .announce(&announce_request(InfoHash::new([1; 20])))
+        .await
+        .is_ok());
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+    // A tracker which never answers
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("udp://{}", silent.local_addr().unwrap())).unwrap();
+    let mut tracker = UdpTracker::new(&url)
+        .await
+        .unwrap()
+        .with_base_timeout(Duration::from_millis(10))
+        .with_max_retransmissions(2);
+    assert!(matches!(
+        tracker.scrape(&[InfoHash::new([1; 20])]).await,
+        Err(Error::TrackerTimeout(3))
+    ));
+}
+
+#[tokio::test]
+async fn udp_shared_backoff() {
+    // A tracker which only answers the second connect request, and never an announce
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 2048];
+        loop {
+            let (length, from) = socket.recv_from(&mut buf).await.unwrap();
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            if length >= 16 && action == 0 && count == 2 {
+                let mut response = 0_u32.to_be_bytes().to_vec();
+                response.extend_from_slice(&buf[12..16]);
+                response.extend_from_slice(&7_u64.to_be_bytes());
+                socket.send_to(&response, from).await.unwrap();
+            }
+        }
+    });
+
+    let mut tracker = UdpTracker::new(&url)
+        .await
+        .unwrap()
+        .with_base_timeout(Duration::from_millis(10))
+        .with_max_retransmissions(2);
+    assert!(matches!(
+        tracker
+            .announce(&announce_request(InfoHash::new([1; 20])))
+            .await,
+        Err(Error::TrackerTimeout(3))
+    ));
+    // The lost connect request counts towards the announce's retransmissions
+    assert_eq!(received.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn udp_error_response() {
+    let (url, _) = StandInTracker::start(0).await;
+    let mut tracker = UdpTracker::new(&url).await.unwrap();
+    assert!(matches!(
+        tracker.announce(&announce_request(InfoHash::new([0xff; 20]))).await,
+        Err(Error::TrackerFailure(message)) if message == "unregistered torrent"
+    ));
+
+    assert!(matches!(
+        UdpTracker::new(&Url::parse("http://127.0.0.1:6969/announce").unwrap()).await,
+        Err(Error::InvalidTrackerUrl(_))
+    ));
+}